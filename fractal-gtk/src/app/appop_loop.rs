@@ -31,6 +31,9 @@ pub enum InternalCommand {
     #[allow(dead_code)]
     PurchaseSticker(StickerGroup),
 
+    ReplyTo(Message),
+    ScrollToMessage(String),
+
     ToInvite(Member),
     RmInvite(String),
 }
@@ -44,6 +47,12 @@ pub fn appop_loop(rx: Receiver<InternalCommand>) {
                 Ok(InternalCommand::AddRoomMessage(msg, pos, prev, force_full, last)) => {
                     APPOP!(add_room_message, (msg, pos, prev, force_full, last));
                 }
+                Ok(InternalCommand::ReplyTo(msg)) => {
+                    APPOP!(reply_to, (msg));
+                }
+                Ok(InternalCommand::ScrollToMessage(evid)) => {
+                    APPOP!(scroll_to_message, (evid));
+                }
                 Ok(InternalCommand::ToInvite(member)) => {
                     APPOP!(add_to_invite, (member));
                 }
@@ -90,6 +90,32 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                 Ok(BKResponse::AccountDestruction) => {
                     APPOP!(account_destruction_logoff);
                 }
+                Ok(BKResponse::Devices(devices, current)) => {
+                    APPOP!(set_devices, (devices, current));
+                }
+                Ok(BKResponse::RenamedDevice(_, _)) => {
+                    APPOP!(device_renamed);
+                }
+                Ok(BKResponse::SsoUrl(url)) => {
+                    if let Err(err) = Command::new("xdg-open").arg(&url).spawn() {
+                        println!("ERROR: can't open the browser: {:?}", err);
+                    }
+                }
+                Ok(BKResponse::ServerDiscovered(server, info)) => {
+                    APPOP!(set_login_server, (server, info));
+                }
+                Ok(BKResponse::RegisterAvailable(username, available)) => {
+                    APPOP!(username_available, (username, available));
+                }
+                Ok(BKResponse::RegisterEmailToken(sid, secret)) => {
+                    APPOP!(register_email_token, (sid, secret));
+                }
+                Ok(BKResponse::UiaStage(state)) => {
+                    APPOP!(uia_stage, (state));
+                }
+                Ok(BKResponse::DeletedDevice(_)) => {
+                    APPOP!(device_deleted);
+                }
                 Ok(BKResponse::Avatar(path)) => {
                     let av = Some(path);
                     APPOP!(set_avatar, (av));
@@ -120,6 +146,30 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                 Ok(BKResponse::RoomMembers(room, members)) => {
                     APPOP!(set_room_members, (room, members));
                 }
+                Ok(BKResponse::RoomSenders(room, members)) => {
+                    APPOP!(add_room_members, (room, members));
+                }
+                Ok(BKResponse::Typing(room, users)) => {
+                    APPOP!(set_typing, (room, users));
+                }
+                Ok(BKResponse::Receipts(room, receipts)) => {
+                    APPOP!(set_receipts, (room, receipts));
+                }
+                Ok(BKResponse::FullyRead(room, evid)) => {
+                    APPOP!(set_fully_read, (room, evid));
+                }
+                Ok(BKResponse::Presence(presence)) => {
+                    APPOP!(set_presence, (presence));
+                }
+                Ok(BKResponse::Redacted(room, evid)) => {
+                    APPOP!(redact_message, (room, evid));
+                }
+                Ok(BKResponse::MessagesDecrypted(room, msgs)) => {
+                    APPOP!(decrypted_messages, (room, msgs));
+                }
+                Ok(BKResponse::Reactions(room, reactions)) => {
+                    APPOP!(add_reactions, (room, reactions));
+                }
                 Ok(BKResponse::RoomMessages(msgs)) => {
                     let init = false;
                     APPOP!(show_room_messages, (msgs, init));
@@ -128,8 +178,12 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                     let init = true;
                     APPOP!(show_room_messages, (msgs, init));
                 }
-                Ok(BKResponse::RoomMessagesTo(msgs)) => {
+                Ok(BKResponse::RoomMessagesBackPaginated(_, msgs, token)) => {
                     APPOP!(show_room_messages_top, (msgs));
+                    if token.is_none() {
+                        // start of the room reached, there's nothing more to load
+                        APPOP!(load_more_normal);
+                    }
                 }
                 Ok(BKResponse::SentMsg(txid, evid)) => {
                     APPOP!(msg_sent, (txid, evid));
@@ -164,12 +218,19 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                     let t = Some(topic);
                     APPOP!(room_topic_change, (roomid, t));
                 }
+                Ok(BKResponse::RoomEncrypted(roomid)) => {
+                    APPOP!(room_encryption_change, (roomid));
+                }
                 Ok(BKResponse::NewRoomAvatar(roomid)) => {
                     APPOP!(new_room_avatar, (roomid));
                 }
                 Ok(BKResponse::RoomMemberEvent(ev)) => {
                     APPOP!(room_member_event, (ev));
                 }
+                Ok(BKResponse::RoomPowerLevels(roomid, levels)) => {
+                    APPOP!(room_power_levels, (roomid, levels));
+                }
+                Ok(BKResponse::RoomEvent(_)) => { }
                 Ok(BKResponse::Media(fname)) => {
                     Command::new("xdg-open")
                                 .arg(&fname)
@@ -202,6 +263,21 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                     println!("ERROR: {:?}", err);
                     APPOP!(show_error_dialog, (error));
                 },
+                Ok(BKResponse::RenameDeviceError(err)) => {
+                    let error = i18n("Couldn’t rename the session");
+                    println!("ERROR: {:?}", err);
+                    APPOP!(show_error_dialog, (error));
+                },
+                Ok(BKResponse::DeleteDeviceError(err)) => {
+                    let error = i18n("Couldn’t sign out the session");
+                    println!("ERROR: {:?}", err);
+                    APPOP!(show_error_dialog, (error));
+                },
+                Ok(BKResponse::UiaError(err)) => {
+                    let error = i18n("Couldn’t complete the authentication");
+                    println!("ERROR: {:?}", err);
+                    APPOP!(show_error_dialog, (error));
+                },
                 Ok(BKResponse::ChangePasswordError(err)) => {
                     let error = i18n("Couldn’t change the password");
                     println!("ERROR: {:?}", err);
@@ -233,6 +309,27 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                     APPOP!(show_error, (error));
                     APPOP!(room_panel, (panel));
                 },
+                Ok(BKResponse::RegisterError(err)) => {
+                    println!("ERROR: {:?}", err);
+                    let error = match err {
+                        Error::MatrixError(ref js) if js["errcode"] == "M_USER_IN_USE" => i18n("This username is already taken"),
+                        _ => i18n("Couldn’t create the account, try again"),
+                    };
+                    APPOP!(register_error, (error));
+                },
+                Ok(BKResponse::DiscoverServerError(err)) => {
+                    println!("ERROR: {:?}", err);
+                    APPOP!(login_server_error);
+                },
+                Ok(BKResponse::RegisterAvailableError(err)) => {
+                    println!("ERROR: {:?}", err);
+                    APPOP!(username_invalid);
+                },
+                Ok(BKResponse::RegisterEmailTokenError(err)) => {
+                    println!("ERROR: {:?}", err);
+                    let error = i18n("Couldn’t send the validation email");
+                    APPOP!(register_error, (error));
+                },
                 Ok(BKResponse::LoginError(_)) => {
                     let error = i18n("Can’t login, try again");
                     let st = AppState::Login;
@@ -255,6 +352,14 @@ pub fn backend_loop(rx: Receiver<BKResponse>) {
                         }
                     }
                 }
+                Ok(BKResponse::SendReactionError(_)) => {
+                    let error = i18n("Error sending the reaction");
+                    APPOP!(show_error, (error));
+                }
+                Ok(BKResponse::EditMsgError(_)) => {
+                    let error = i18n("Error editing message");
+                    APPOP!(show_error, (error));
+                }
                 Ok(BKResponse::DirectoryError(_)) => {
                     let error = i18n("Error searching for rooms");
                     APPOP!(reset_directory_state);
@@ -24,6 +24,9 @@ impl App {
         let advanced_toggle = self.ui.builder
             .get_object::<gtk::EventBox>("account_settings_advanced_toggle")
             .expect("Can't find account_settings_advanced_toggle in ui file.");
+        let sessions_toggle = self.ui.builder
+            .get_object::<gtk::EventBox>("account_settings_sessions_toggle")
+            .expect("Can't find account_settings_sessions_toggle in ui file.");
         let delete_toggle = self.ui.builder
             .get_object::<gtk::EventBox>("account_settings_delete_toggle")
             .expect("Can't find account_settings_delete_toggle in ui file.");
@@ -42,6 +45,12 @@ impl App {
         let name_btn = self.ui.builder
             .get_object::<gtk::Button>("account_settings_name_button")
             .expect("Can't find account_settings_name_button in ui file.");
+        let status_entry = self.ui.builder
+            .get_object::<gtk::Entry>("account_settings_status")
+            .expect("Can't find account_settings_status in ui file.");
+        let status_btn = self.ui.builder
+            .get_object::<gtk::Button>("account_settings_status_button")
+            .expect("Can't find account_settings_status_button in ui file.");
         let password_btn = self.ui.builder
             .get_object::<gtk::Button>("account_settings_password")
             .expect("Can't find account_settings_password in ui file.");
@@ -116,6 +125,15 @@ impl App {
             op.lock().unwrap().update_username_account_settings();
         }));
 
+        let button = status_btn.clone();
+        status_entry.connect_activate(move |_w| {
+            let _ = button.emit("clicked", &[]);
+        });
+
+        status_btn.connect_clicked(clone!(op, status_entry => move |_w| {
+            op.lock().unwrap().update_status_msg(status_entry.get_text());
+        }));
+
         /*
            fn update_password_strength(builder: &gtk::Builder) {
            let bar = builder
@@ -217,6 +235,21 @@ impl App {
             glib::signal::Inhibit(false)
         }));
 
+        sessions_toggle.connect_button_press_event(clone!(builder => move |this, _| {
+            let widget = builder
+                .get_object::<gtk::Revealer>("account_settings_sessions")
+                .expect("Can't find account_settings_sessions in ui file.");
+            if widget.get_reveal_child() {
+                this.get_style_context().unwrap().remove_class("advanced_revealer_divider");
+                widget.set_reveal_child(false);
+            }
+            else {
+                this.get_style_context().unwrap().add_class("advanced_revealer_divider");
+                widget.set_reveal_child(true);
+            }
+            glib::signal::Inhibit(false)
+        }));
+
         delete_toggle.connect_button_press_event(clone!(builder => move |this, _| {
             let widget = builder
                 .get_object::<gtk::Revealer>("account_settings_delete")
@@ -2,6 +2,9 @@ extern crate gtk;
 use self::gtk::prelude::*;
 
 use app::App;
+use appop::AppState;
+
+use fractal_api::util::user_server_name;
 
 impl App {
     pub fn connect_login_view(&self) {
@@ -17,6 +20,7 @@ impl App {
 
         self.connect_login_button();
         self.set_login_focus_chain();
+        self.connect_register_view();
     }
     pub fn set_login_focus_chain(&self) {
         let focus_chain = [
@@ -52,6 +56,34 @@ impl App {
             .get_object("login_password")
             .expect("Couldn't find login_password in ui file.");
 
+        let server: gtk::Entry = self.ui.builder
+            .get_object("login_server")
+            .expect("Couldn't find login_server in ui file.");
+
+        // the server of a user id or the typed server is discovered when the user leaves the
+        // entry, the op is locked when the entries are changed by the op itself
+        let op = self.op.clone();
+        username.connect_focus_out_event(move |w, _| {
+            let name = w.get_text().and_then(|u| user_server_name(&u));
+            if let (Some(name), Ok(guard)) = (name, op.try_lock()) {
+                guard.discover_login_server(name);
+            }
+            Inhibit(false)
+        });
+        let op = self.op.clone();
+        server.connect_changed(move |_| {
+            if let Ok(mut guard) = op.try_lock() {
+                guard.login_server_custom = true;
+            }
+        });
+        let op = self.op.clone();
+        server.connect_focus_out_event(move |w, _| {
+            if let Ok(guard) = op.try_lock() {
+                guard.discover_login_server(w.get_text().unwrap_or_default());
+            }
+            Inhibit(false)
+        });
+
         let op = self.op.clone();
         btn.connect_clicked(move |_| op.lock().unwrap().login());
         let op = self.op.clone();
@@ -59,8 +91,68 @@ impl App {
         let op = self.op.clone();
         password.connect_activate(move |_| op.lock().unwrap().login());
 
+        let sso_cancel: gtk::Button = self.ui.builder
+            .get_object("sso_cancel_button")
+            .expect("Couldn't find sso_cancel_button in ui file.");
+        let op = self.op.clone();
+        sso_cancel.connect_clicked(move |_| op.lock().unwrap().sso_cancel());
+
         self.ui.builder
             .get_object::<gtk::Label>("login_error_msg")
             .expect("Can't find login_error_msg in ui file.").hide();
     }
+
+    pub fn connect_register_view(&self) {
+        let login_btn: gtk::Button = self.ui.builder
+            .get_object("login_register_button")
+            .expect("Couldn't find login_register_button in ui file.");
+        let cancel_btn: gtk::Button = self.ui.builder
+            .get_object("register_cancel_button")
+            .expect("Couldn't find register_cancel_button in ui file.");
+        let btn: gtk::Button = self.ui.builder
+            .get_object("register_button")
+            .expect("Couldn't find register_button in ui file.");
+        let username: gtk::Entry = self.ui.builder
+            .get_object("register_username")
+            .expect("Couldn't find register_username in ui file.");
+        let password: gtk::Entry = self.ui.builder
+            .get_object("register_password")
+            .expect("Couldn't find register_password in ui file.");
+        let status: gtk::Label = self.ui.builder
+            .get_object("register_username_status")
+            .expect("Couldn't find register_username_status in ui file.");
+
+        let op = self.op.clone();
+        login_btn.connect_clicked(move |_| op.lock().unwrap().set_state(AppState::Register));
+        let op = self.op.clone();
+        cancel_btn.connect_clicked(move |_| op.lock().unwrap().set_state(AppState::Login));
+
+        let op = self.op.clone();
+        btn.connect_clicked(move |_| op.lock().unwrap().register());
+        for name in ["register_password_confirm", "register_email", "register_server"].iter() {
+            let entry: gtk::Entry = self.ui.builder
+                .get_object(name)
+                .expect("Couldn't find register entry in ui file.");
+            let op = self.op.clone();
+            entry.connect_activate(move |_| op.lock().unwrap().register());
+        }
+
+        // the availability is checked when the user leaves the username entry. The op is locked
+        // when the entries are changed by the op itself, like when the form is cleaned
+        username.connect_changed(move |_| status.hide());
+        let op = self.op.clone();
+        username.connect_focus_out_event(move |_, _| {
+            if let Ok(guard) = op.try_lock() {
+                guard.check_username();
+            }
+            Inhibit(false)
+        });
+
+        let op = self.op.clone();
+        password.connect_changed(move |_| {
+            if let Ok(guard) = op.try_lock() {
+                guard.register_password_changed();
+            }
+        });
+    }
 }
@@ -2,6 +2,8 @@ use gtk::prelude::*;
 use gtk;
 use gdk;
 
+use globals;
+
 mod attach;
 mod autocomplete;
 mod direct;
@@ -64,6 +66,17 @@ impl App {
             }
         });
 
+        let op = self.op.clone();
+        window.connect_property_has_toplevel_focus_notify(move |w| {
+            op.lock().unwrap().set_window_focus(w.has_toplevel_focus());
+        });
+
+        let op = self.op.clone();
+        gtk::timeout_add_seconds(globals::IDLE_CHECK_INTERVAL, move || {
+            op.lock().unwrap().check_idle();
+            gtk::Continue(true)
+        });
+
         self.create_load_more_spn();
         self.connect_more_members_btn();
         self.create_actions();
@@ -90,5 +90,22 @@ impl App {
             revealer.set_reveal_child(false);
             scroll_down(&s, true);
         });
+
+        let jump_btn = self.ui.builder
+            .get_object::<gtk::Button>("jump_unread_btn")
+            .expect("Can't find jump_unread_btn in ui file.");
+        let close_btn = self.ui.builder
+            .get_object::<gtk::Button>("unread_close_btn")
+            .expect("Can't find unread_close_btn in ui file.");
+
+        let op = self.op.clone();
+        jump_btn.connect_clicked(move |_| {
+            op.lock().unwrap().jump_to_first_unread();
+        });
+
+        let op = self.op.clone();
+        close_btn.connect_clicked(move |_| {
+            op.lock().unwrap().hide_unread_banner();
+        });
     }
 }
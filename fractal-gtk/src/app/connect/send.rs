@@ -1,6 +1,8 @@
 extern crate gtk;
+extern crate gdk;
 use self::gtk::prelude::*;
 
+use glib;
 use app::App;
 
 impl App {
@@ -16,6 +18,26 @@ impl App {
             entry.set_text("");
         });
 
+        op = self.op.clone();
+        msg_entry.connect_key_press_event(move |entry, ev| {
+            let empty = entry.get_text().map_or(true, |t| t.is_empty());
+            match ev.get_keyval() {
+                gdk::enums::key::Up if empty => {
+                    op.lock().unwrap().edit_last_message();
+                    return glib::signal::Inhibit(true);
+                }
+                gdk::enums::key::Escape => {
+                    let mut op = op.lock().unwrap();
+                    op.cancel_editing();
+                    op.stop_replying();
+                }
+                _ => {
+                    op.lock().unwrap().send_typing();
+                }
+            }
+            glib::signal::Inhibit(false)
+        });
+
         op = self.op.clone();
         msg_entry.connect_paste_clipboard(move |_| {
             op.lock().unwrap().paste();
@@ -11,7 +11,7 @@ use glib;
 use gio::ApplicationExt;
 use gio::ApplicationExtManual;
 
-use backend::Backend;
+use cache;
 use backend::BKResponse;
 use appop::AppOp;
 
@@ -76,7 +76,7 @@ impl App {
             let (tx, rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
             let (itx, irx): (Sender<InternalCommand>, Receiver<InternalCommand>) = channel();
 
-            let bk = Backend::new(tx);
+            let bk = cache::backend(tx);
             let apptx = bk.run();
 
             // Set up the textdomain for gettext
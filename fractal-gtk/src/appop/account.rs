@@ -1,6 +1,11 @@
 extern crate gtk;
+extern crate chrono;
 
 use self::gtk::prelude::*;
+use self::chrono::prelude::*;
+use std::sync::mpsc::Sender;
+
+use i18n::i18n;
 
 use appop::AppOp;
 use appop::AppState;
@@ -9,7 +14,9 @@ use backend::BKCommand;
 use widgets;
 use widgets::AvatarExt;
 
+use fractal_api::types::Device;
 use fractal_api::types::UserInfo;
+use fractal_api::uia::UiaState;
 
 impl AppOp {
     pub fn set_three_pid(&self, data: Option<Vec<UserInfo>>) {
@@ -164,6 +171,9 @@ impl AppOp {
         let name_btn = self.ui.builder
             .get_object::<gtk::Button>("account_settings_name_button")
             .expect("Can't find account_settings_name_button in ui file.");
+        let status = self.ui.builder
+            .get_object::<gtk::Entry>("account_settings_status")
+            .expect("Can't find account_settings_status in ui file.");
         let uid = self.ui.builder
             .get_object::<gtk::Label>("account_settings_uid")
             .expect("Can't find account_settings_uid in ui file.");
@@ -200,9 +210,13 @@ impl AppOp {
         let destruction_flag = self.ui.builder
             .get_object::<gtk::CheckButton>("account_settings_delete_check")
             .expect("Can't find account_settings_delete_check in ui file.");
+        let sessions = self.ui.builder
+            .get_object::<gtk::Revealer>("account_settings_sessions")
+            .expect("Can't find account_settings_sessions in ui file.");
 
         stack.set_visible_child_name("loading");
         self.get_three_pid();
+        self.get_devices();
         uid.set_text(&self.uid.clone().unwrap_or_default());
         homeserver.set_text(&self.server_url);
         name.set_text(&self.username.clone().unwrap_or_default());
@@ -213,6 +227,8 @@ impl AppOp {
         avatar_btn.set_sensitive(true);
         self.show_avatar(self.avatar.clone());
 
+        status.set_text(&self.status_msg.clone().unwrap_or_default());
+
         name_btn.hide();
         name.set_editable(true);
         let image = gtk::Image::new_from_icon_name("emblem-ok-symbolic", 1);
@@ -224,6 +240,7 @@ impl AppOp {
         password_btn.set_sensitive(true);
 
         advanced.set_reveal_child(false);
+        sessions.set_reveal_child(false);
         delete.set_reveal_child(false);
         destruction_flag.set_active(false);
         destruction_btn.set_sensitive(false);
@@ -250,6 +267,9 @@ impl AppOp {
         let password = self.ui.builder
             .get_object::<gtk::Button>("account_settings_password")
             .expect("Can't find account_settings_password in ui file.");
+        let status = self.ui.builder
+            .get_object::<gtk::Box>("account_settings_status_box")
+            .expect("Can't find account_settings_status_box in ui file.");
 
         let mut first_email = true;
         let mut first_phone = true;
@@ -258,7 +278,7 @@ impl AppOp {
         let mut child = grid.get_child_at(1, i);
         while child.is_some() {
             if let Some(child) = child.clone() {
-                if child != phone && child != email && child != password {
+                if child != phone && child != email && child != password && child != status {
                     grid.remove_row(i);
                 }
                 else {
@@ -444,13 +464,21 @@ impl AppOp {
         let delete_box = self.ui.builder
             .get_object::<gtk::Box>("account_settings_delete_box")
             .expect("Can't find account_settings_delete_box in ui file.");
+        let sessions = self.ui.builder
+            .get_object::<gtk::Revealer>("account_settings_sessions")
+            .expect("Can't find account_settings_sessions in ui file.");
+        let sessions_toggle = self.ui.builder
+            .get_object::<gtk::EventBox>("account_settings_sessions_toggle")
+            .expect("Can't find account_settings_sessions_toggle in ui file.");
         let b = self.ui.builder
             .get_object::<gtk::Box>("account_settings_box")
             .expect("Can't find account_settings_delete_box in ui file.");
 
         advanced_toggle.get_style_context().unwrap().remove_class("advanced_revealer_divider");
+        sessions_toggle.get_style_context().unwrap().remove_class("advanced_revealer_divider");
         delete_toggle.get_style_context().unwrap().remove_class("advanced_revealer_divider");
         advanced.set_reveal_child(false);
+        sessions.set_reveal_child(false);
         delete.set_reveal_child(false);
         advanced_box.queue_draw();
         delete_box.queue_draw();
@@ -476,12 +504,10 @@ impl AppOp {
 
         if let Some(old) = old_password.get_text() {
             if let Some(new) = new_password.get_text() {
-                if let Some(mxid) = self.uid.clone() {
-                    if old != "" && new != "" {
-                        password_btn.set_sensitive(false);
-                        password_btn_stack.set_visible_child_name("spinner");
-                        let _ = self.backend.send(BKCommand::ChangePassword(mxid, old, new));
-                    }
+                if old != "" && new != "" {
+                    password_btn.set_sensitive(false);
+                    password_btn_stack.set_visible_child_name("spinner");
+                    let _ = self.backend.send(BKCommand::ChangePassword(old, new));
                 }
             }
         }
@@ -550,22 +576,168 @@ impl AppOp {
 
         let flag = mark.get_active();
         if let Some(password) = entry.get_text() {
-            if let Some(mxid) = self.uid.clone() {
-                let backend = self.backend.clone();
-                dialog.connect_response(clone!(mxid, password, flag => move |w, r| {
-                    match gtk::ResponseType::from(r) {
-                        gtk::ResponseType::Ok => {
-                            let _ = backend.send(BKCommand::AccountDestruction(mxid.clone(), password.clone(), flag));
-                        },
-                        _ => {}
-                    }
-                    w.destroy();
-                }));
-                dialog.show_all();
-            }
+            let backend = self.backend.clone();
+            dialog.connect_response(clone!(password, flag => move |w, r| {
+                match gtk::ResponseType::from(r) {
+                    gtk::ResponseType::Ok => {
+                        let _ = backend.send(BKCommand::AccountDestruction(password.clone(), flag));
+                    },
+                    _ => {}
+                }
+                w.destroy();
+            }));
+            dialog.show_all();
         }
     }
+    /// The account requests only need the password, that the backend sends, so any other
+    /// stage can't be completed from here and the request is cancelled
+    pub fn uia_stage(&mut self, state: UiaState) {
+        if let AppState::Register = self.state {
+            self.register_stage(state);
+            return;
+        }
+
+        self.backend.send(BKCommand::UiaCancel).unwrap();
+        let error = state.error.unwrap_or(i18n("The server requires an authentication that isn’t supported"));
+        self.show_password_error_dialog(error);
+    }
+
     pub fn account_destruction_logoff(&self) {
         /* Do logout */
     }
+
+    pub fn get_devices(&self) {
+        self.backend.send(BKCommand::GetDevices).unwrap();
+    }
+
+    /// Fills the sessions list, our device first
+    pub fn set_devices(&self, mut devices: Vec<Device>, current: String) {
+        let list = self.ui.builder
+            .get_object::<gtk::ListBox>("account_settings_sessions_list")
+            .expect("Can't find account_settings_sessions_list in ui file.");
+        let device_id = self.ui.builder
+            .get_object::<gtk::Label>("account_settings_device_id")
+            .expect("Can't find account_settings_device_id in ui file.");
+
+        device_id.set_text(&current);
+
+        for ch in list.get_children().iter() {
+            list.remove(ch);
+        }
+
+        devices.sort_by_key(|d| (d.device_id != current, -d.last_seen_ts.unwrap_or_default()));
+        for device in devices.iter() {
+            list.add(&self.build_device_row(device, device.device_id == current));
+        }
+        list.show_all();
+    }
+
+    // +-------------------------------------+
+    // | name                   [ Sign out ] |
+    // | device id · ip · last seen          |
+    // +-------------------------------------+
+    fn build_device_row(&self, device: &Device, current: bool) -> gtk::ListBoxRow {
+        let row = gtk::ListBoxRow::new();
+        let bx = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        let top = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+
+        let name = gtk::Entry::new();
+        let display_name = device.display_name.clone().unwrap_or_default();
+        name.set_text(&display_name);
+        name.set_placeholder_text(i18n("Unnamed session").as_str());
+        let backend = self.backend.clone();
+        let id = device.device_id.clone();
+        name.connect_activate(move |w| {
+            if let Some(text) = w.get_text() {
+                if text != display_name {
+                    backend.send(BKCommand::RenameDevice(id.clone(), text)).unwrap();
+                }
+            }
+        });
+        top.pack_start(&name, true, true, 0);
+
+        if current {
+            let label = gtk::Label::new(i18n("This session").as_str());
+            if let Some(style) = label.get_style_context() {
+                style.add_class("dim-label");
+            }
+            top.pack_end(&label, false, false, 0);
+        } else {
+            let button = gtk::Button::new_with_label(i18n("Sign out").as_str());
+            if let Some(style) = button.get_style_context() {
+                style.add_class("destructive-action");
+            }
+            let id = device.device_id.clone();
+            let parent = self.ui.builder
+                .get_object::<gtk::Window>("main_window")
+                .expect("Can't find main_window in ui file.");
+            let backend = self.backend.clone();
+            button.connect_clicked(move |_| {
+                sign_out_device_dialog(&parent, &backend, &id);
+            });
+            top.pack_end(&button, false, false, 0);
+        }
+
+        let mut details = vec![device.device_id.clone()];
+        if let Some(ref ip) = device.last_seen_ip {
+            details.push(ip.clone());
+        }
+        if let Some(ts) = device.last_seen_ts {
+            let dt = Local.timestamp(ts / 1000, 0);
+            details.push(dt.format("%e %b %Y %H:%M").to_string().trim().to_string());
+        }
+        let info = gtk::Label::new(details.join(" · ").as_str());
+        info.set_halign(gtk::Align::Start);
+        info.set_selectable(true);
+        if let Some(style) = info.get_style_context() {
+            style.add_class("dim-label");
+        }
+
+        bx.pack_start(&top, false, false, 0);
+        bx.pack_start(&info, false, false, 0);
+        bx.set_margin_top(6);
+        bx.set_margin_bottom(6);
+        row.add(&bx);
+
+        row
+    }
+
+    pub fn device_renamed(&self) {
+        self.get_devices();
+    }
+
+    pub fn device_deleted(&self) {
+        self.get_devices();
+    }
+}
+
+/// Asks for the password before signing out another session
+fn sign_out_device_dialog(parent: &gtk::Window, backend: &Sender<BKCommand>, device: &str) {
+    let msg = i18n("Enter your password to sign out this session");
+    let flags = gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT;
+    let dialog = gtk::MessageDialog::new(Some(parent), flags, gtk::MessageType::Question, gtk::ButtonsType::None, &msg);
+
+    dialog.add_button(i18n("Cancel").as_str(), gtk::ResponseType::Cancel.into());
+    dialog.add_button(i18n("Sign out").as_str(), gtk::ResponseType::Ok.into());
+
+    let entry = gtk::Entry::new();
+    entry.set_visibility(false);
+    entry.set_activates_default(true);
+    dialog.set_default_response(gtk::ResponseType::Ok.into());
+    if let Some(area) = dialog.get_message_area().and_then(|w| w.downcast::<gtk::Box>().ok()) {
+        area.pack_start(&entry, false, false, 0);
+    }
+
+    let backend = backend.clone();
+    let device = device.to_string();
+    dialog.connect_response(move |w, r| {
+        if let gtk::ResponseType::Ok = gtk::ResponseType::from(r) {
+            let password = entry.get_text().unwrap_or_default();
+            if !password.is_empty() {
+                backend.send(BKCommand::DeleteDevice(device.clone(), password)).unwrap();
+            }
+        }
+        w.destroy();
+    });
+    dialog.show_all();
 }
@@ -1,28 +1,44 @@
 extern crate gtk;
+extern crate rand;
 
 use i18n::i18n;
 
 use globals;
 use self::gtk::prelude::*;
+use self::rand::{thread_rng, Rng};
 
 use appop::AppOp;
 use appop::state::AppState;
 
 use cache;
-use backend::Backend;
 use backend::BKCommand;
 use backend::BKResponse;
 
+use std::env;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::{Sender, Receiver};
 
 use app::backend_loop;
 
+use passwd;
 use passwd::PasswordStorage;
 
+use fractal_api::types::ServerInfo;
+use fractal_api::util::user_server_name;
+use fractal_api::uia;
+use fractal_api::uia::AuthData;
+use fractal_api::uia::UiaState;
+
 impl AppOp {
     pub fn bk_login(&mut self, uid: String, token: String) {
         self.logged_in = true;
+        if let Some((username, password)) = self.register_pass.take() {
+            self.store_pass(username, password, self.server_url.clone(), self.identity_url.clone())
+                .unwrap_or_else(|_| {
+                    // TODO: show an error
+                    println!("Error: Can't store the password using libsecret");
+                });
+        }
         self.clean_login();
         if let Err(_) = self.store_token(uid.clone(), token) {
             println!("Error: Can't store the token using libsecret");
@@ -61,12 +77,12 @@ impl AppOp {
         self.backend.send(BKCommand::ShutDown).unwrap();
 
         let (tx, rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
-        let bk = Backend::new(tx);
+        let bk = cache::backend(tx);
         self.backend = bk.run();
         backend_loop(rx);
     }
 
-    pub fn clean_login(&self) {
+    pub fn clean_login(&mut self) {
         let user_entry: gtk::Entry = self.ui.builder
             .get_object("login_username")
             .expect("Can't find login_username in ui file.");
@@ -84,9 +100,127 @@ impl AppOp {
         pass_entry.set_text("");
         server_entry.set_text(globals::DEFAULT_HOMESERVER);
         idp_entry.set_text(globals::DEFAULT_IDENTITYSERVER);
+
+        self.login_server = None;
+        self.login_pending = None;
+        self.register_pass = None;
+        self.login_server_custom = false;
+        self.show_login_password(true);
+        self.set_login_button_label(false);
+    }
+
+    pub fn set_login_button_label(&self, sso: bool) {
+        let button: gtk::Button = self.ui.builder
+            .get_object("login_button")
+            .expect("Can't find login_button in ui file.");
+
+        let label = match sso {
+            true => i18n("Log In with Browser"),
+            false => i18n("Log In"),
+        };
+        button.set_label(&label);
+    }
+
+    pub fn show_login_password(&self, show: bool) {
+        for name in ["login_password_label", "login_password"].iter() {
+            self.ui.builder
+                .get_object::<gtk::Widget>(name)
+                .expect("Can't find login_password in ui file.")
+                .set_visible(show);
+        }
+    }
+
+    /// Looks for the homeserver of `server`, a server name or a URL, to fill the login form.
+    /// Nothing is done if it was already discovered
+    pub fn discover_login_server(&self, server: String) {
+        let discovered = self.login_server.as_ref().map(|&(ref s, _)| *s == server).unwrap_or(false);
+        if server.is_empty() || discovered {
+            return;
+        }
+
+        self.backend.send(BKCommand::DiscoverServer(server)).unwrap();
+    }
+
+    pub fn set_login_server(&mut self, server: String, info: ServerInfo) {
+        if let AppState::Login = self.state {} else {
+            return;
+        }
+
+        let server_entry: gtk::Entry = self.ui.builder
+            .get_object("login_server")
+            .expect("Can't find login_server in ui file.");
+        let idp_entry: gtk::Entry = self.ui.builder
+            .get_object("login_idp")
+            .expect("Can't find login_idp in ui file.");
+        let login_error: gtk::Label = self.ui.builder
+            .get_object("login_error_msg")
+            .expect("Can't find login_error_msg in ui file.");
+
+        // the server typed by the user is kept, the discovered one is only used if it's the
+        // discovery of that same server
+        let typed = server_entry.get_text().unwrap_or_default();
+        if self.login_server_custom && typed != server {
+            if self.login_pending.take().is_some() {
+                self.login();
+            }
+            return;
+        }
+
+        server_entry.set_text(&info.homeserver);
+        if let Some(ref identity) = info.identity_server {
+            idp_entry.set_text(identity);
+        }
+        // servers that only offer SSO don't use the password
+        self.show_login_password(!info.sso_only());
+        self.set_login_button_label(info.sso_only());
+        login_error.hide();
+
+        self.login_server = Some((server, info));
+        if self.login_pending.take().is_some() {
+            self.login();
+        }
+    }
+
+    pub fn login_server_error(&mut self) {
+        let server_entry: gtk::Entry = self.ui.builder
+            .get_object("login_server")
+            .expect("Can't find login_server in ui file.");
+        let login_error: gtk::Label = self.ui.builder
+            .get_object("login_error_msg")
+            .expect("Can't find login_error_msg in ui file.");
+
+        // the login doesn't need the discovery, it's done with the server of the entry
+        if let Some(name) = self.login_pending.take() {
+            let info = ServerInfo {
+                homeserver: server_entry.get_text().unwrap_or_default(),
+                ..ServerInfo::default()
+            };
+            self.login_server = Some((name, info));
+            self.login();
+            return;
+        }
+
+        login_error.set_text(&i18n("Can’t find a Matrix server at this address"));
+        login_error.show();
     }
 
     pub fn login(&mut self) {
+        // the server of a user id is discovered before the login, unless the user typed the
+        // server to use
+        let name = self.ui.builder
+            .get_object::<gtk::Entry>("login_username")
+            .and_then(|e| e.get_text())
+            .and_then(|u| user_server_name(&u))
+            .filter(|_| !self.login_server_custom);
+        if let Some(name) = name {
+            let discovered = self.login_server.as_ref().map(|&(ref s, _)| *s == name).unwrap_or(false);
+            if !discovered {
+                self.login_pending = Some(name.clone());
+                self.backend.send(BKCommand::DiscoverServer(name)).unwrap();
+                return;
+            }
+        }
+
         let user_entry: gtk::Entry = self.ui.builder
             .get_object("login_username")
             .expect("Can't find login_username in ui file.");
@@ -108,6 +242,15 @@ impl AppOp {
         let server = server_entry.get_text();
         let identity = idp_entry.get_text();
 
+        let sso = self.login_server.as_ref()
+            .filter(|&&(_, ref info)| info.sso_only())
+            .map(|&(_, ref info)| info.uses_cas());
+        if let Some(cas) = sso {
+            login_error.hide();
+            self.sso_login(server, identity, cas);
+            return;
+        }
+
         if username.clone().unwrap_or_default().is_empty() ||
            password.clone().unwrap_or_default().is_empty() {
             login_error.set_text(i18n("Invalid username or password").as_str());
@@ -125,6 +268,32 @@ impl AppOp {
         self.connect(username, password, server, identity);
     }
 
+    /// Logs in with the browser, the backend sends the URL to open and the login finishes
+    /// when the browser is redirected to us with the login token
+    pub fn sso_login(&mut self, server: Option<String>, identity: Option<String>, cas: bool) {
+        self.server_url = server.unwrap_or(String::from(globals::DEFAULT_HOMESERVER));
+        self.identity_url = identity.unwrap_or(String::from(globals::DEFAULT_IDENTITYSERVER));
+
+        self.set_state(AppState::Loading);
+        self.show_sso_cancel(true);
+        self.since = None;
+        let ser = self.server_url.clone();
+        self.backend.send(BKCommand::SsoLogin(ser, cas)).unwrap();
+    }
+
+    /// Stops waiting for the browser and goes back to the login
+    pub fn sso_cancel(&mut self) {
+        self.backend.send(BKCommand::SsoCancel).unwrap();
+        self.set_state(AppState::Login);
+    }
+
+    pub fn show_sso_cancel(&self, show: bool) {
+        self.ui.builder
+            .get_object::<gtk::Button>("sso_cancel_button")
+            .expect("Can't find sso_cancel_button in ui file.")
+            .set_visible(show);
+    }
+
     pub fn set_login_pass(&self, username: &str, password: &str, server: &str, identity: &str) {
         let user_entry: gtk::Entry = self.ui.builder
             .get_object("login_username")
@@ -145,7 +314,122 @@ impl AppOp {
         idp_entry.set_text(identity);
     }
 
-    #[allow(dead_code)]
+    pub fn clean_register(&mut self) {
+        let server_entry: gtk::Entry = self.ui.builder
+            .get_object("login_server")
+            .expect("Can't find login_server in ui file.");
+        let stack: gtk::Stack = self.ui.builder
+            .get_object("register_button_stack")
+            .expect("Can't find register_button_stack in ui file.");
+
+        for name in ["register_username", "register_password", "register_password_confirm", "register_email"].iter() {
+            self.ui.builder
+                .get_object::<gtk::Entry>(name)
+                .expect("Can't find register entry in ui file.")
+                .set_text("");
+        }
+        for name in ["register_username_status", "register_password_hint", "register_error_msg"].iter() {
+            self.ui.builder
+                .get_object::<gtk::Label>(name)
+                .expect("Can't find register label in ui file.")
+                .hide();
+        }
+
+        // the server chosen in the login advanced options
+        let server = server_entry.get_text().unwrap_or_default();
+        self.ui.builder
+            .get_object::<gtk::Entry>("register_server")
+            .expect("Can't find register_server in ui file.")
+            .set_text(match server.is_empty() {
+                true => globals::DEFAULT_HOMESERVER,
+                false => &server,
+            });
+
+        self.ui.builder
+            .get_object::<gtk::LevelBar>("register_password_strength")
+            .expect("Can't find register_password_strength in ui file.")
+            .set_value(0.0);
+        stack.set_visible_child_name("button");
+        self.register_sid = None;
+        self.register_pass = None;
+    }
+
+    /// Asks the server if the username in the register form can be used
+    pub fn check_username(&self) {
+        let user_entry: gtk::Entry = self.ui.builder
+            .get_object("register_username")
+            .expect("Can't find register_username in ui file.");
+        let server_entry: gtk::Entry = self.ui.builder
+            .get_object("register_server")
+            .expect("Can't find register_server in ui file.");
+
+        let username = user_entry.get_text().unwrap_or_default();
+        let server = server_entry.get_text().unwrap_or_default();
+        if username.is_empty() || server.is_empty() {
+            return;
+        }
+
+        self.backend.send(BKCommand::RegisterAvailable(username, server)).unwrap();
+    }
+
+    pub fn username_available(&self, username: String, available: bool) {
+        let user_entry: gtk::Entry = self.ui.builder
+            .get_object("register_username")
+            .expect("Can't find register_username in ui file.");
+        let status: gtk::Label = self.ui.builder
+            .get_object("register_username_status")
+            .expect("Can't find register_username_status in ui file.");
+
+        // the username was changed while we waited
+        if user_entry.get_text().unwrap_or_default() != username {
+            return;
+        }
+
+        let msg = match available {
+            true => i18n("This username is available"),
+            false => i18n("This username is already taken"),
+        };
+        status.set_text(&msg);
+        status.show();
+    }
+
+    pub fn username_invalid(&self) {
+        let status: gtk::Label = self.ui.builder
+            .get_object("register_username_status")
+            .expect("Can't find register_username_status in ui file.");
+        status.set_text(&i18n("This username isn’t valid, use only lowercase letters, numbers and ._=-/"));
+        status.show();
+    }
+
+    /// Shows the strength of the password that is being typed
+    pub fn register_password_changed(&self) {
+        let pass_entry: gtk::Entry = self.ui.builder
+            .get_object("register_password")
+            .expect("Can't find register_password in ui file.");
+        let level: gtk::LevelBar = self.ui.builder
+            .get_object("register_password_strength")
+            .expect("Can't find register_password_strength in ui file.");
+        let hint: gtk::Label = self.ui.builder
+            .get_object("register_password_hint")
+            .expect("Can't find register_password_hint in ui file.");
+
+        let strength = passwd::strength(&pass_entry.get_text().unwrap_or_default());
+        level.set_value(strength as f64);
+
+        let msg = match strength {
+            0 => {
+                hint.hide();
+                return;
+            }
+            1 => i18n("Too weak, use a longer password"),
+            2 => i18n("Fair, mixing letters, numbers and symbols makes it stronger"),
+            3 => i18n("Good password"),
+            _ => i18n("Strong password"),
+        };
+        hint.set_text(&msg);
+        hint.show();
+    }
+
     pub fn register(&mut self) {
         let user_entry: gtk::Entry = self.ui.builder
             .get_object("register_username")
@@ -159,44 +443,184 @@ impl AppOp {
         let server_entry: gtk::Entry = self.ui.builder
             .get_object("register_server")
             .expect("Can't find register_server in ui file.");
-        let _idp_entry: gtk::Entry = self.ui.builder
+        let idp_entry: gtk::Entry = self.ui.builder
             .get_object("login_idp")
             .expect("Can't find login_idp in ui file.");
+        let stack: gtk::Stack = self.ui.builder
+            .get_object("register_button_stack")
+            .expect("Can't find register_button_stack in ui file.");
 
-        let username = match user_entry.get_text() {
-            Some(s) => s,
-            None => String::from(""),
-        };
-        let password = match pass_entry.get_text() {
-            Some(s) => s,
-            None => String::from(""),
-        };
-        let passconf = match pass_conf.get_text() {
-            Some(s) => s,
-            None => String::from(""),
-        };
+        let username = user_entry.get_text().unwrap_or_default();
+        let password = pass_entry.get_text().unwrap_or_default();
+        let passconf = pass_conf.get_text().unwrap_or_default();
+
+        if username.is_empty() || password.is_empty() {
+            self.register_error(i18n("Choose a username and a password"));
+            return;
+        }
 
         if password != passconf {
-            self.show_error(i18n("Passwords didn’t match, try again"));
+            self.register_error(i18n("Passwords didn’t match, try again"));
+            return;
+        }
+
+        if passwd::strength(&password) < 2 {
+            self.register_error(i18n("The password is too weak, try a longer one"));
             return;
         }
 
         self.server_url = match server_entry.get_text() {
-            Some(s) => s,
-            None => String::from(globals::DEFAULT_HOMESERVER),
+            Some(ref s) if !s.is_empty() => s.clone(),
+            _ => String::from(globals::DEFAULT_HOMESERVER),
+        };
+        self.identity_url = match idp_entry.get_text() {
+            Some(ref s) if !s.is_empty() => s.clone(),
+            _ => String::from(globals::DEFAULT_IDENTITYSERVER),
         };
-        /* FIXME ask also for the identity server */
 
-        //self.store_pass(username.clone(), password.clone(), server_url.clone())
-        //    .unwrap_or_else(|_| {
-        //        // TODO: show an error
-        //        println!("Error: Can't store the password using libsecret");
-        //    });
+        // the password is stored when the account is created and the session starts
+        self.register_pass = Some((username.clone(), password.clone()));
+
+        self.ui.builder
+            .get_object::<gtk::Label>("register_error_msg")
+            .expect("Can't find register_error_msg in ui file.")
+            .hide();
+        stack.set_visible_child_name("spinner");
+        self.register_sid = None;
 
-        let uname = username.clone();
-        let pass = password.clone();
         let ser = self.server_url.clone();
-        self.backend.send(BKCommand::Register(uname, pass, ser)).unwrap();
+        self.backend.send(BKCommand::Register(username, password, ser)).unwrap();
+    }
+
+    pub fn register_error(&self, error: String) {
+        let error_label: gtk::Label = self.ui.builder
+            .get_object("register_error_msg")
+            .expect("Can't find register_error_msg in ui file.");
+        let stack: gtk::Stack = self.ui.builder
+            .get_object("register_button_stack")
+            .expect("Can't find register_button_stack in ui file.");
+
+        error_label.set_text(&error);
+        error_label.show();
+        stack.set_visible_child_name("button");
+    }
+
+    /// Asks the user to complete the next stage of the registration. The dummy stage is done
+    /// by the backend, the terms and the email stages here, others aren't supported
+    pub fn register_stage(&mut self, state: UiaState) {
+        let email_entry: gtk::Entry = self.ui.builder
+            .get_object("register_email")
+            .expect("Can't find register_email in ui file.");
+        let email = email_entry.get_text().unwrap_or_default();
+
+        if let Some(error) = state.error.clone() {
+            let error_label: gtk::Label = self.ui.builder
+                .get_object("register_error_msg")
+                .expect("Can't find register_error_msg in ui file.");
+            error_label.set_text(&error);
+            error_label.show();
+        }
+
+        let stages = state.next_stages();
+        let stage = stages.iter()
+            .find(|s| *s == uia::TERMS || (*s == uia::EMAIL && !email.is_empty()))
+            .cloned();
+
+        match stage {
+            Some(ref s) if s == uia::TERMS => {
+                let lang = env::var("LANG").unwrap_or_default();
+                let policies = state.terms_policies(lang.get(0..2).unwrap_or("en"));
+                self.show_register_terms_dialog(policies);
+            }
+            Some(_) => match self.register_sid.clone() {
+                Some((sid, secret)) => self.show_register_email_dialog(sid, secret),
+                None => {
+                    let secret: String = thread_rng().gen_ascii_chars().take(36).collect();
+                    let id_server = self.identity_url.clone();
+                    self.backend.send(BKCommand::RegisterEmailToken(id_server, email, secret)).unwrap();
+                }
+            },
+            None => {
+                self.backend.send(BKCommand::UiaCancel).unwrap();
+                let error = match stages.iter().any(|s| s == uia::EMAIL) {
+                    true => i18n("This server requires an email address"),
+                    false => i18n("This server asks for a verification that isn’t supported, create the account from the web"),
+                };
+                self.register_error(error);
+            }
+        };
+    }
+
+    pub fn register_email_token(&mut self, sid: String, secret: String) {
+        self.register_sid = Some((sid.clone(), secret.clone()));
+        self.show_register_email_dialog(sid, secret);
+    }
+
+    pub fn show_register_email_dialog(&self, sid: String, secret: String) {
+        let parent = self.ui.builder
+            .get_object::<gtk::Window>("main_window")
+            .expect("Can't find main_window in ui file.");
+        let stack: gtk::Stack = self.ui.builder
+            .get_object("register_button_stack")
+            .expect("Can't find register_button_stack in ui file.");
+
+        let msg = i18n("To create the account, go to your inbox and follow the link you received. Once you’ve done that, click “Continue”");
+        let flags = gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT;
+        let dialog = gtk::MessageDialog::new(Some(&parent), flags, gtk::MessageType::Info, gtk::ButtonsType::None, &msg);
+        dialog.add_button(&i18n("Cancel"), gtk::ResponseType::Cancel.into());
+        dialog.add_button(&i18n("Continue"), gtk::ResponseType::Ok.into());
+
+        let backend = self.backend.clone();
+        let id_server = self.identity_url.clone();
+        dialog.connect_response(move |w, r| {
+            match gtk::ResponseType::from(r) {
+                gtk::ResponseType::Ok => {
+                    let data = AuthData::Email(sid.clone(), secret.clone(), id_server.clone());
+                    backend.send(BKCommand::UiaAuth(data)).unwrap();
+                },
+                _ => {
+                    backend.send(BKCommand::UiaCancel).unwrap();
+                    stack.set_visible_child_name("button");
+                }
+            }
+            w.destroy();
+        });
+        dialog.show_all();
+    }
+
+    /// Shows the (name, url) of the policies to accept
+    pub fn show_register_terms_dialog(&self, policies: Vec<(String, String)>) {
+        let parent = self.ui.builder
+            .get_object::<gtk::Window>("main_window")
+            .expect("Can't find main_window in ui file.");
+        let stack: gtk::Stack = self.ui.builder
+            .get_object("register_button_stack")
+            .expect("Can't find register_button_stack in ui file.");
+
+        let msg = i18n("To create the account you have to accept the terms of the server");
+        let flags = gtk::DialogFlags::MODAL | gtk::DialogFlags::DESTROY_WITH_PARENT;
+        let dialog = gtk::MessageDialog::new(Some(&parent), flags, gtk::MessageType::Question, gtk::ButtonsType::None, &msg);
+        for (name, url) in policies {
+            let link = gtk::LinkButton::new_with_label(&url, Some(name.as_str()));
+            dialog.get_content_area().add(&link);
+        }
+        dialog.add_button(&i18n("Cancel"), gtk::ResponseType::Cancel.into());
+        dialog.add_button(&i18n("Accept"), gtk::ResponseType::Accept.into());
+
+        let backend = self.backend.clone();
+        dialog.connect_response(move |w, r| {
+            match gtk::ResponseType::from(r) {
+                gtk::ResponseType::Accept => {
+                    backend.send(BKCommand::UiaAuth(AuthData::Terms)).unwrap();
+                },
+                _ => {
+                    backend.send(BKCommand::UiaCancel).unwrap();
+                    stack.set_visible_child_name("button");
+                }
+            }
+            w.destroy();
+        });
+        dialog.show_all();
     }
 
     pub fn connect(&mut self, username: Option<String>, password: Option<String>, server: Option<String>, identity: Option<String>) -> Option<()> {
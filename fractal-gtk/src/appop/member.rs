@@ -14,6 +14,8 @@ use backend::BKCommand;
 
 use types::Member;
 use types::Event;
+use types::EventContent;
+use types::Membership;
 
 
 #[derive(Debug, Clone)]
@@ -125,12 +127,14 @@ impl AppOp {
         }
     }
 
-    pub fn set_room_members(&mut self, roomid: String, members: Vec<Member>) {
+    pub fn set_room_members(&mut self, roomid: String, mut members: Vec<Member>) {
+        self.fill_presence(&mut members);
         if let Some(r) = self.rooms.get_mut(&roomid) {
             r.members = HashMap::new();
             for m in members {
                 r.members.insert(m.uid.clone(), m);
             }
+            r.members_loaded = true;
         }
 
         self.recalculate_room_name(roomid.clone());
@@ -142,6 +146,16 @@ impl AppOp {
         }
     }
 
+    /// Adds the lazy loaded members that sent the messages that are going to be shown
+    pub fn add_room_members(&mut self, roomid: String, mut members: Vec<Member>) {
+        self.fill_presence(&mut members);
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            for m in members {
+                r.members.insert(m.uid.clone(), m);
+            }
+        }
+    }
+
     pub fn reload_members(&mut self) {
         self.clean_member_list();
         self.show_all_members();
@@ -151,18 +165,24 @@ impl AppOp {
         // NOTE: maybe we should show this events in the message list to notify enters and leaves
         // to the user
 
+        let content = match ev.content {
+            EventContent::Member(ref c) => c.clone(),
+            _ => return,
+        };
+
         let sender = ev.sender.clone();
-        match ev.content["membership"].as_str() {
-            Some("leave") => {
+        match content.membership {
+            Membership::Leave => {
                 if let Some(r) = self.rooms.get_mut(&ev.room.clone()) {
                     r.members.remove(&sender);
                 }
             }
-            Some("join") => {
+            Membership::Join => {
                 let m = Member {
-                    avatar: Some(strn!(ev.content["avatar_url"].as_str().unwrap_or(""))),
-                    alias: Some(strn!(ev.content["displayname"].as_str().unwrap_or(""))),
+                    avatar: Some(content.avatar_url.clone().unwrap_or_default()),
+                    alias: Some(content.displayname.clone().unwrap_or_default()),
                     uid: sender.clone(),
+                    presence: self.presence.get(&sender).cloned(),
                 };
                 if let Some(r) = self.rooms.get_mut(&ev.room.clone()) {
                     r.members.insert(m.uid.clone(), m.clone());
@@ -177,11 +197,8 @@ impl AppOp {
             return;
         }
 
-        match ev.content["membership"].as_str() {
-            Some("leave") => {
-                self.show_all_members();
-            }
-            Some("join") => {
+        match content.membership {
+            Membership::Leave | Membership::Join => {
                 self.show_all_members();
             }
             // ignoring other memberships
@@ -189,6 +206,16 @@ impl AppOp {
         }
     }
 
+    pub fn room_power_levels(&mut self, roomid: String, levels: HashMap<String, i32>) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.power_levels = levels;
+        }
+
+        if roomid == self.active_room.clone().unwrap_or_default() {
+            self.show_all_members();
+        }
+    }
+
     pub fn user_search_finished(&self, users: Vec<Member>) {
         match self.search_type {
             SearchType::Invite => {
@@ -1,7 +1,7 @@
 extern crate comrak;
 extern crate tree_magic;
 
-use i18n::i18n;
+use i18n::{i18n, i18n_k};
 
 use std::path::Path;
 
@@ -21,6 +21,8 @@ use widgets;
 use backend::BKCommand;
 
 use types::Message;
+use types::Receipts;
+use types::Reaction;
 
 
 #[derive(Debug, Clone)]
@@ -50,6 +52,9 @@ impl AppOp {
         for ch in messages.get_children().iter().skip(1) {
             messages.remove(ch);
         }
+        self.receipt_boxes.clear();
+        self.message_rows.clear();
+        self.unread_divider = None;
     }
 
     /// This function is used to mark as read the last message of a room when the focus comes in,
@@ -92,16 +97,13 @@ impl AppOp {
         }
     }
 
+    /// The divider is placed after the message pointed by the room m.fully_read marker. The
+    /// backend moves a marker on an event that isn't shown to the last message before it
     pub fn is_last_viewed(&self, msg: &Message) -> LastViewed {
-        match self.last_viewed_messages.get(&msg.room) {
-            Some(lvm) if lvm == msg => {
-                match self.rooms.get(&msg.room) {
-                    Some(r) => {
-                        match r.messages.last() {
-                            Some(m) if m == msg => LastViewed::Last,
-                            _ => LastViewed::Inline,
-                        }
-                    },
+        match self.rooms.get(&msg.room) {
+            Some(r) if r.fully_read.is_some() && r.fully_read == msg.id => {
+                match r.messages.last() {
+                    Some(m) if m == msg => LastViewed::Last,
                     _ => LastViewed::Inline,
                 }
             },
@@ -133,6 +135,8 @@ impl AppOp {
         }
 
         if msg.room == self.active_room.clone().unwrap_or_default() {
+            let mut receipts = None;
+            let mut unread_divider = None;
             if let Some(r) = self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
                 let m;
                 {
@@ -157,6 +161,9 @@ impl AppOp {
                         Some(ref p) if self.should_group(&msg, p) => mb.small_widget(),
                         Some(_) if self.has_small_mtype(&msg) => mb.small_widget(),
                         _ => mb.widget(),
+                    };
+                    if let Some(ref id) = msg.id {
+                        receipts = Some((id.clone(), mb.receipts.clone(), m.clone()));
                     }
                 }
 
@@ -173,9 +180,171 @@ impl AppOp {
                         MsgPos::Bottom => messages.add(&divider),
                         MsgPos::Top => messages.insert(&divider, 2),
                     };
+                    unread_divider = Some(divider);
+                } else if last == LastViewed::Inline {
+                    unread_divider = Some(m.clone());
                 }
                 self.shown_messages += 1;
             }
+
+            if let Some((id, rbox, row)) = receipts {
+                self.receipt_boxes.insert(id.clone(), rbox);
+                self.message_rows.insert(id, row);
+            }
+
+            if unread_divider.is_some() {
+                self.unread_divider = unread_divider;
+                if self.jump_to_unread {
+                    self.jump_to_unread = false;
+                    self.scroll_to_unread();
+                }
+            }
+        }
+    }
+
+    /// Replaces the content of a redacted message with the deleted message placeholder, or
+    /// removes the reaction if the redacted event is a reaction
+    pub fn redact_message(&mut self, roomid: String, evid: String) {
+        let mut redacted = None;
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            if let Some(m) = r.messages.iter_mut().find(|m| m.id.as_ref() == Some(&evid)) {
+                if !m.redacted {
+                    m.redact();
+                    redacted = Some(evid.clone());
+                }
+            }
+            if let Some(reacted) = r.remove_reaction(&evid) {
+                redacted = Some(reacted);
+            }
+        }
+
+        if let Some(evid) = redacted {
+            self.rebuild_message_row(roomid, evid);
+        }
+    }
+
+    /// Adds the new reactions to the room and updates the reacted messages
+    pub fn add_reactions(&mut self, roomid: String, reactions: Vec<(String, Reaction)>) {
+        let mut reacted: Vec<String> = vec![];
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            for (evid, reaction) in reactions {
+                r.add_reaction(&evid, reaction);
+                if !reacted.contains(&evid) {
+                    reacted.push(evid);
+                }
+            }
+        }
+
+        for evid in reacted {
+            self.rebuild_message_row(roomid.clone(), evid);
+        }
+    }
+
+    /// Replaces the content of the message edited by `edit` with the new content
+    pub fn edit_message(&mut self, edit: &Message) {
+        let evid = match edit.replace {
+            Some(ref evid) => evid.clone(),
+            None => return,
+        };
+
+        let mut edited = false;
+        if let Some(r) = self.rooms.get_mut(&edit.room) {
+            // only the sender can edit a message
+            let msg = r.messages.iter_mut()
+                .find(|m| m.id.as_ref() == Some(&evid) && m.sender == edit.sender);
+            if let Some(m) = msg {
+                if !m.redacted {
+                    m.apply_edit(edit);
+                    edited = true;
+                }
+            }
+        }
+
+        if edited {
+            self.rebuild_message_row(edit.room.clone(), evid);
+        }
+    }
+
+    /// Shows the decrypted content of messages that were received before their room key
+    pub fn decrypted_messages(&mut self, roomid: String, msgs: Vec<Message>) {
+        let mut decrypted = vec![];
+        for msg in msgs {
+            // an edit replaces the content of other message, it doesn't have a row
+            if msg.replace.is_some() {
+                self.edit_message(&msg);
+                continue;
+            }
+
+            if let Some(r) = self.rooms.get_mut(&roomid) {
+                if let Some(m) = r.messages.iter_mut().find(|m| m.id.is_some() && m.id == msg.id) {
+                    decrypted.push(msg.id.clone().unwrap_or_default());
+                    *m = msg;
+                }
+            }
+        }
+
+        for evid in decrypted {
+            self.rebuild_message_row(roomid.clone(), evid);
+        }
+    }
+
+    /// Builds again the row of a shown message, after its content changed
+    fn rebuild_message_row(&mut self, roomid: String, evid: String) {
+        if self.active_room != Some(roomid.clone()) {
+            return;
+        }
+
+        let old = match self.message_rows.get(&evid) {
+            Some(row) => row.clone(),
+            None => return,
+        };
+
+        let messages = self.ui.builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+
+        let mut row = None;
+        if let Some(r) = self.rooms.get(&roomid) {
+            if let Some(pos) = r.messages.iter().position(|m| m.id.as_ref() == Some(&evid)) {
+                let msg = &r.messages[pos];
+                let prev = match pos {
+                    0 => None,
+                    _ => r.messages.get(pos - 1),
+                };
+                let mb = widgets::MessageBox::new(r, msg, &self);
+                let w = match prev {
+                    Some(p) if self.should_group(msg, p) => mb.small_widget(),
+                    _ => mb.widget(),
+                };
+                row = Some((w, mb.receipts.clone()));
+            }
+        }
+
+        if let Some((w, rbox)) = row {
+            w.set_focus_on_click(false);
+            messages.insert(&w, old.get_index());
+            old.destroy();
+            self.receipt_boxes.insert(evid.clone(), rbox);
+            self.message_rows.insert(evid, w);
+        }
+    }
+
+    /// Adds the new read receipts to the room and moves the receipt avatars of the shown
+    /// messages
+    pub fn set_receipts(&mut self, roomid: String, receipts: Receipts) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.receipts.extend(receipts);
+        }
+
+        if self.active_room != Some(roomid.clone()) {
+            return;
+        }
+
+        if let Some(r) = self.rooms.get(&roomid) {
+            let uid = self.uid.clone().unwrap_or_default();
+            for (evid, rbox) in self.receipt_boxes.iter() {
+                widgets::fill_receipts(rbox, r, evid, &uid, self.backend.clone());
+            }
         }
     }
 
@@ -242,7 +411,9 @@ impl AppOp {
             .get_object("main_window")
             .expect("Can't find main_window in ui file.");
         if window.is_active() || force {
-            self.last_viewed_messages.insert(msg.room.clone(), msg.clone());
+            if let Some(r) = self.rooms.get_mut(&msg.room) {
+                r.fully_read = msg.id.clone();
+            }
             self.backend.send(BKCommand::MarkAsRead(msg.room.clone(),
                                                     msg.id.clone().unwrap_or_default())).unwrap();
         }
@@ -296,10 +467,13 @@ impl AppOp {
 
     pub fn send_message(&mut self, msg: String) {
         if msg.is_empty() {
-            // Not sending empty messages
+            // Not sending empty messages, and an empty edit cancels the edition
+            self.stop_editing();
             return;
         }
 
+        self.stop_typing();
+
         let room = self.active_room.clone();
         let now = Local::now();
 
@@ -316,6 +490,10 @@ impl AppOp {
             id: None,
             formatted_body: None,
             format: None,
+            redacted: false,
+            replace: None,
+            edited: false,
+            in_reply_to: None,
         };
 
         if msg.starts_with("/me ") {
@@ -348,10 +526,119 @@ impl AppOp {
         }
 
         m.id = Some(m.get_txn_id());
+
+        if let Some(evid) = self.stop_editing() {
+            m.replace = Some(evid.clone());
+            self.edit_message(&m);
+            self.backend.send(BKCommand::EditMsg(evid, m)).unwrap();
+            return;
+        }
+
+        m.in_reply_to = self.stop_replying().and_then(|p| p.id);
+
         self.add_tmp_room_message(m.clone());
         self.dequeue_message();
     }
 
+    /// Sets the message that the next message sent replies to
+    pub fn reply_to(&mut self, msg: Message) {
+        self.cancel_editing();
+
+        let sender = self.rooms.get(&msg.room)
+            .and_then(|r| r.members.get(&msg.sender))
+            .map(|m| m.get_alias())
+            .unwrap_or_else(|| msg.sender.clone());
+
+        let msg_entry: gtk::Entry = self.ui.builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+        msg_entry.set_placeholder_text(i18n_k("Reply to {name}", &[("name", &sender)]).as_str());
+        if let Some(style) = msg_entry.get_style_context() {
+            style.add_class("msg-entry-editing");
+        }
+        msg_entry.grab_focus();
+
+        self.replying_to = Some(msg);
+    }
+
+    /// Stops replying to a message, the replied message is returned
+    pub fn stop_replying(&mut self) -> Option<Message> {
+        let msg = self.replying_to.take();
+        if msg.is_some() {
+            let msg_entry: gtk::Entry = self.ui.builder
+                .get_object("msg_entry")
+                .expect("Couldn't find msg_entry in ui file.");
+            msg_entry.set_placeholder_text("");
+            if let Some(style) = msg_entry.get_style_context() {
+                style.remove_class("msg-entry-editing");
+            }
+        }
+
+        msg
+    }
+
+    pub fn scroll_to_message(&mut self, evid: String) {
+        if let Some(row) = self.message_rows.get(&evid).cloned() {
+            self.scroll_to_row(row);
+        }
+    }
+
+    /// Puts our last message of the active room in the message entry to edit it
+    pub fn edit_last_message(&mut self) {
+        self.stop_replying();
+
+        let uid = self.uid.clone().unwrap_or_default();
+        let last = self.active_room.as_ref()
+            .and_then(|roomid| self.rooms.get(roomid))
+            .and_then(|r| {
+                r.messages.iter().rev()
+                    .find(|m| m.sender == uid && m.id.is_some() && !m.redacted &&
+                              (m.mtype == "m.text" || m.mtype == "m.emote"))
+                    .cloned()
+            });
+        let msg = match last {
+            Some(m) => m,
+            None => return,
+        };
+
+        let text = match msg.mtype.as_ref() {
+            "m.emote" => format!("/me {}", msg.body),
+            _ => msg.body.clone(),
+        };
+
+        let msg_entry: gtk::Entry = self.ui.builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+        msg_entry.set_text(&text);
+        msg_entry.set_position(-1);
+        if let Some(style) = msg_entry.get_style_context() {
+            style.add_class("msg-entry-editing");
+        }
+
+        self.editing = msg.id;
+    }
+
+    /// Stops editing a message, the edited message id is returned
+    pub fn stop_editing(&mut self) -> Option<String> {
+        let msg_entry: gtk::Entry = self.ui.builder
+            .get_object("msg_entry")
+            .expect("Couldn't find msg_entry in ui file.");
+        if let Some(style) = msg_entry.get_style_context() {
+            style.remove_class("msg-entry-editing");
+        }
+
+        self.editing.take()
+    }
+
+    pub fn cancel_editing(&mut self) {
+        if self.stop_editing().is_some() {
+            let msg_entry: gtk::Entry = self.ui.builder
+                .get_object("msg_entry")
+                .expect("Couldn't find msg_entry in ui file.");
+            msg_entry.set_text("");
+        }
+    }
+
     pub fn attach_message(&mut self, file: String) -> Message {
         /* reenable autoscroll to jump to new message in history */
         self.autoscroll = true;
@@ -381,6 +668,10 @@ impl AppOp {
             id: None,
             formatted_body: None,
             format: None,
+            redacted: false,
+            replace: None,
+            edited: false,
+            in_reply_to: None,
         };
 
         m.id = Some(m.get_txn_id());
@@ -459,8 +750,8 @@ impl AppOp {
                     self.internal.send(command).unwrap();
                 }
                 self.internal.send(InternalCommand::LoadMoreNormal).unwrap();
-            } else if let Some(m) = r.messages.get(0) {
-                self.backend.send(BKCommand::GetMessageContext(m.clone())).unwrap();
+            } else {
+                self.backend.send(BKCommand::BackPaginate(r.id.clone())).unwrap();
             }
         }
     }
@@ -468,12 +759,29 @@ impl AppOp {
     pub fn load_more_normal(&mut self) {
         self.load_more_spn.stop();
         self.loading_more = false;
+
+        // we keep loading history until we reach the fully read marker, or give up after
+        // some pages, the marker can be an event that we don't know
+        if self.jump_to_unread && self.unread_divider.is_none() {
+            if self.unread_pages < globals::MAX_UNREAD_PAGES {
+                self.unread_pages += 1;
+                self.load_more_messages();
+            } else {
+                self.jump_to_unread = false;
+            }
+        }
     }
 
     pub fn show_room_messages(&mut self, newmsgs: Vec<Message>, init: bool) -> Option<()> {
         let mut msgs = vec![];
 
+        let mut edits = vec![];
+
         for msg in newmsgs.iter() {
+            if msg.replace.is_some() {
+                edits.push(msg);
+                continue;
+            }
             if let Some(r) = self.rooms.get_mut(&msg.room) {
                 if !r.messages.contains(msg) {
                     r.messages.push(msg.clone());
@@ -482,6 +790,16 @@ impl AppOp {
             }
         }
 
+        for edit in edits {
+            // the edited message can be in this batch, and its row isn't built yet
+            let target = msgs.iter_mut()
+                .find(|m| m.id.is_some() && m.id == edit.replace && m.sender == edit.sender);
+            if let Some(m) = target {
+                m.apply_edit(edit);
+            }
+            self.edit_message(edit);
+        }
+
         let mut prev = None;
         for msg in msgs.iter() {
             let mut should_notify = msg.body.contains(&self.username.clone()?) || {
@@ -526,7 +844,15 @@ impl AppOp {
     }
 
     pub fn show_room_messages_top(&mut self, msgs: Vec<Message>) {
+        // the backend can send messages that we already have if it didn't know the room history
+        let msgs: Vec<Message> = match self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
+            Some(r) => msgs.into_iter().filter(|m| !r.messages.contains(m)).collect(),
+            None => msgs,
+        };
+
         if msgs.is_empty() {
+            // this is the start of the room, the fully read marker isn't there
+            self.jump_to_unread = false;
             self.load_more_normal();
             return;
         }
@@ -553,4 +879,82 @@ impl AppOp {
         }
         self.internal.send(InternalCommand::LoadMoreNormal).unwrap();
     }
+
+    /// Shows the banner to jump to the first unread message if the room fully read marker isn't
+    /// the last message of the room
+    pub fn show_unread_banner(&self) {
+        let revealer: gtk::Revealer = self.ui.builder
+            .get_object("unread_revealer")
+            .expect("Can't find unread_revealer in ui file.");
+
+        let unread = match self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
+            Some(r) => match (r.fully_read.as_ref(), r.messages.last()) {
+                (Some(marker), Some(last)) => last.id.as_ref() != Some(marker),
+                _ => false,
+            },
+            None => false,
+        };
+
+        revealer.set_reveal_child(unread);
+    }
+
+    pub fn hide_unread_banner(&self) {
+        let revealer: gtk::Revealer = self.ui.builder
+            .get_object("unread_revealer")
+            .expect("Can't find unread_revealer in ui file.");
+        revealer.set_reveal_child(false);
+    }
+
+    /// Scrolls to the fully read marker, loading older messages until it's shown
+    pub fn jump_to_first_unread(&mut self) {
+        self.hide_unread_banner();
+
+        if self.unread_divider.is_some() {
+            self.scroll_to_unread();
+        } else {
+            self.jump_to_unread = true;
+            self.unread_pages = 0;
+            self.load_more_messages();
+        }
+    }
+
+    fn scroll_to_unread(&mut self) {
+        let row = match self.unread_divider {
+            Some(ref d) => d.clone(),
+            None => return,
+        };
+
+        self.scroll_to_row(row);
+    }
+
+    fn scroll_to_row(&mut self, row: gtk::ListBoxRow) {
+        let scroll: gtk::ScrolledWindow = self.ui.builder
+            .get_object("messages_scroll")
+            .expect("Can't find messages_scroll in ui file.");
+        let messages = self.ui.builder
+            .get_object::<gtk::ListBox>("message_list")
+            .expect("Can't find message_list in ui file.");
+
+        self.autoscroll = false;
+        // the new rows aren't allocated yet, so we wait a bit to get the position
+        gtk::timeout_add(100, move || {
+            if let Some((_, y)) = row.translate_coordinates(&messages, 0, 0) {
+                if let Some(adj) = scroll.get_vadjustment() {
+                    adj.set_value(y as f64);
+                }
+            }
+            gtk::Continue(false)
+        });
+    }
+
+    pub fn set_fully_read(&mut self, roomid: String, evid: String) {
+        if let Some(r) = self.rooms.get_mut(&roomid) {
+            r.fully_read = Some(evid);
+        }
+
+        // other device read the room, so the unread messages are already seen
+        if self.active_room == Some(roomid) {
+            self.hide_unread_banner();
+        }
+    }
 }
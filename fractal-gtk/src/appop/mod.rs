@@ -4,6 +4,7 @@ use i18n::i18n;
 
 use std::sync::mpsc::Sender;
 use std::collections::HashMap;
+use std::time::Instant;
 
 use gio::ApplicationExt;
 use self::gtk::prelude::*;
@@ -14,9 +15,11 @@ use backend;
 
 use types::Member;
 use types::Message;
+use types::PresenceList;
 use types::Room;
 use types::RoomList;
 use types::StickerGroup;
+use types::ServerInfo;
 
 use passwd::PasswordStorage;
 
@@ -44,6 +47,8 @@ mod invite;
 mod about;
 mod start_chat;
 mod stickers;
+mod typing;
+mod presence;
 
 pub use self::state::AppState;
 use self::message::TmpMsg;
@@ -64,7 +69,10 @@ pub struct AppOp {
     pub msg_queue: Vec<TmpMsg>,
     pub sending_message: bool,
     shown_messages: usize,
-    pub last_viewed_messages: HashMap<String, Message>,
+    pub unread_divider: Option<gtk::ListBoxRow>,
+    pub jump_to_unread: bool,
+    // the pages loaded looking for the fully read marker
+    unread_pages: usize,
 
     pub username: Option<String>,
     pub uid: Option<String>,
@@ -79,6 +87,24 @@ pub struct AppOp {
     pub load_more_spn: gtk::Spinner,
     pub more_members_btn: gtk::Button,
     pub unsent_messages: HashMap<String, (String, i32)>,
+    // room id -> users typing in the room
+    pub typing: HashMap<String, Vec<String>>,
+    // the room and time of the last typing notification we sent
+    pub typing_sent: Option<(String, Instant)>,
+    // event id -> read receipts box of the shown messages
+    pub receipt_boxes: HashMap<String, gtk::Box>,
+    // event id -> row of the shown messages
+    pub message_rows: HashMap<String, gtk::ListBoxRow>,
+    // the id of the message that we're editing in the message entry
+    pub editing: Option<String>,
+    // the message that we're replying to with the message entry
+    pub replying_to: Option<Message>,
+    // user id -> last known presence
+    pub presence: PresenceList,
+    pub status_msg: Option<String>,
+    // when the window lost the focus, and if we told the server we're away because of that
+    pub idle_since: Option<Instant>,
+    pub away: bool,
 
     pub highlighted_entry: Vec<String>,
     pub popover_position: Option<i32>,
@@ -90,6 +116,16 @@ pub struct AppOp {
     pub member_limit: usize,
 
     pub logged_in: bool,
+    // the server name or URL that was discovered in the login and what we know about it
+    pub login_server: Option<(String, ServerInfo)>,
+    // the server name whose discovery the login is waiting for
+    pub login_pending: Option<String>,
+    // if the user typed the server URL, it isn't replaced by the discovered one
+    pub login_server_custom: bool,
+    // the sid and the client secret of the email validation of the registration
+    pub register_sid: Option<(String, String)>,
+    // the username and the password of the registration, stored when it succeeds
+    pub register_pass: Option<(String, String)>,
     pub loading_more: bool,
 
     pub invitation_roomid: Option<String>,
@@ -131,12 +167,24 @@ impl AppOp {
             msg_queue: vec![],
             sending_message: false,
             shown_messages: 0,
-            last_viewed_messages: HashMap::new(),
+            unread_divider: None,
+            jump_to_unread: false,
+            unread_pages: 0,
             state: AppState::Login,
             roomlist: widgets::RoomList::new(None),
             since: None,
             member_limit: 50,
             unsent_messages: HashMap::new(),
+            typing: HashMap::new(),
+            typing_sent: None,
+            receipt_boxes: HashMap::new(),
+            message_rows: HashMap::new(),
+            editing: None,
+            replying_to: None,
+            presence: HashMap::new(),
+            status_msg: None,
+            idle_since: None,
+            away: false,
 
             highlighted_entry: vec![],
             popover_position: None,
@@ -144,6 +192,11 @@ impl AppOp {
             popover_closing: false,
 
             logged_in: false,
+            login_server: None,
+            login_pending: None,
+            login_server_custom: false,
+            register_sid: None,
+            register_pass: None,
             loading_more: false,
 
             md_enabled: false,
@@ -162,10 +215,6 @@ impl AppOp {
         self.set_state(AppState::Loading);
 
         if let Ok(data) = cache::load() {
-            let r: Vec<Room> = data.rooms.values().cloned().collect();
-            self.set_rooms(&r, None);
-            self.last_viewed_messages = data.last_viewed_messages;
-            self.since = Some(data.since);
             self.username = Some(data.username);
             self.uid = Some(data.uid);
         }
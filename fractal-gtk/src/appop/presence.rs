@@ -0,0 +1,98 @@
+use std::time::Instant;
+
+use appop::AppOp;
+use backend::BKCommand;
+use globals;
+
+use types::Member;
+use types::PresenceList;
+use types::PresenceState;
+
+
+impl AppOp {
+    pub fn set_presence(&mut self, presence: PresenceList) {
+        let uid = self.uid.clone().unwrap_or_default();
+        if let Some(p) = presence.get(&uid) {
+            self.status_msg = p.status_msg.clone();
+        }
+
+        let mut direct = vec![];
+        for r in self.rooms.values_mut() {
+            for (user, p) in presence.iter() {
+                if let Some(m) = r.members.get_mut(user) {
+                    m.presence = Some(p.clone());
+                    if r.direct && *user != uid {
+                        direct.push((r.id.clone(), user.clone(), p.clone()));
+                    }
+                }
+            }
+        }
+
+        for (roomid, user, p) in direct {
+            self.roomlist.set_room_presence(roomid, user, p);
+        }
+
+        let reload = match self.rooms.get(&self.active_room.clone().unwrap_or_default()) {
+            Some(r) => presence.keys().any(|u| r.members.contains_key(u)),
+            None => false,
+        };
+
+        self.presence.extend(presence);
+
+        if reload {
+            self.reload_members();
+        }
+    }
+
+    /// Sets the known presence to the members loaded after the presence event
+    pub fn fill_presence(&self, members: &mut Vec<Member>) {
+        for m in members.iter_mut() {
+            m.presence = self.presence.get(&m.uid).cloned();
+        }
+    }
+
+    pub fn update_status_msg(&mut self, msg: Option<String>) {
+        self.status_msg = match msg {
+            Some(ref m) if m.is_empty() => None,
+            m => m,
+        };
+        self.send_presence();
+    }
+
+    fn send_presence(&self) {
+        let state = match self.away {
+            true => PresenceState::Unavailable,
+            false => PresenceState::Online,
+        };
+        self.backend.send(BKCommand::SetPresence(state, self.status_msg.clone())).unwrap();
+    }
+
+    pub fn set_window_focus(&mut self, focus: bool) {
+        if focus {
+            self.idle_since = None;
+            if self.away {
+                self.away = false;
+                self.send_presence();
+            }
+        } else if self.idle_since.is_none() {
+            self.idle_since = Some(Instant::now());
+        }
+    }
+
+    /// Sets our presence to unavailable when the window has been without focus for a while
+    pub fn check_idle(&mut self) {
+        if !self.logged_in || self.away {
+            return;
+        }
+
+        let idle = match self.idle_since {
+            Some(ref since) => since.elapsed().as_secs() >= globals::IDLE_TIMEOUT,
+            None => false,
+        };
+
+        if idle {
+            self.away = true;
+            self.send_presence();
+        }
+    }
+}
@@ -90,9 +90,9 @@ impl AppOp {
         }
 
         for r in rooms.iter() {
-            if let None = r.name {
+            if r.name.is_none() && r.heroes.is_empty() {
                 // This will force the room name calculation for 1:1 rooms and other rooms with no
-                // name
+                // name, when the server doesn't send the room heroes
                 self.backend.send(BKCommand::GetRoomMembers(r.id.clone())).unwrap();
             }
 
@@ -155,6 +155,8 @@ impl AppOp {
     pub fn set_active_room(&mut self, room: &Room) {
         self.member_limit = 50;
         self.room_panel(RoomPanel::Loading);
+        self.cancel_editing();
+        self.stop_replying();
 
         let msg_entry: gtk::Entry = self.ui.builder
             .get_object("msg_entry")
@@ -168,7 +170,12 @@ impl AppOp {
             }
         }
 
+        self.stop_typing();
         self.active_room = Some(room.id.clone());
+        self.show_typing();
+        // this is done before marking the room as read to know where the user stopped reading
+        self.jump_to_unread = false;
+        self.show_unread_banner();
         self.clear_tmp_msgs();
         self.autoscroll = true;
 
@@ -203,6 +210,7 @@ impl AppOp {
         self.reload_members();
 
         self.set_room_topic_label(room.topic.clone());
+        self.set_room_encrypted_icon(room.encrypted);
 
         let name_label = self.ui.builder
             .get_object::<gtk::Label>("room_name")
@@ -332,8 +340,8 @@ impl AppOp {
     }
 
     pub fn cache_rooms(&self) {
-        // serializing rooms
-        if let Err(_) = cache::store(&self.rooms, self.last_viewed_messages.clone(), self.since.clone().unwrap_or_default(), self.username.clone().unwrap_or_default(), self.uid.clone().unwrap_or_default()) {
+        // the rooms are stored by the backend, we only need to save the app state
+        if let Err(_) = cache::store(self.username.clone().unwrap_or_default(), self.uid.clone().unwrap_or_default()) {
             println!("Error caching rooms");
         };
     }
@@ -639,6 +647,31 @@ impl AppOp {
         }
     }
 
+    pub fn room_encryption_change(&mut self, roomid: String) {
+        if !self.rooms.contains_key(&roomid) {
+            return;
+        }
+
+        {
+            let r = self.rooms.get_mut(&roomid).unwrap();
+            r.encrypted = true;
+        }
+
+        if roomid == self.active_room.clone().unwrap_or_default() {
+            self.set_room_encrypted_icon(true);
+        }
+
+        self.roomlist.set_room_encrypted(roomid);
+    }
+
+    /// Shows the lock next to the room name in the header for the encrypted rooms
+    pub fn set_room_encrypted_icon(&self, encrypted: bool) {
+        let icon = self.ui.builder
+            .get_object::<gtk::Image>("room_encrypted")
+            .expect("Can't find room_encrypted in ui file.");
+        icon.set_visible(encrypted);
+    }
+
     pub fn set_room_topic_label(&self, topic: Option<String>) {
         let t = self.ui.builder
             .get_object::<gtk::Label>("room_topic")
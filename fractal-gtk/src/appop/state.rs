@@ -9,6 +9,7 @@ use appop::room::RoomPanel;
 #[derive(Debug, Clone)]
 pub enum AppState {
     Login,
+    Register,
     Chat,
     Directory,
     Loading,
@@ -20,12 +21,18 @@ pub enum AppState {
 impl AppOp {
     pub fn set_state(&mut self, state: AppState) {
         self.state = state;
+        // only the single sign-on can be cancelled while loading
+        self.show_sso_cancel(false);
 
         let widget_name = match self.state {
             AppState::Login => {
                 self.clean_login();
                 "login"
             },
+            AppState::Register => {
+                self.clean_register();
+                "register"
+            },
             AppState::Chat => "chat",
             AppState::Directory => "directory",
             AppState::Loading => "loading",
@@ -41,6 +48,7 @@ impl AppOp {
         //setting headerbar
         let bar_name = match self.state {
             AppState::Login => "login",
+            AppState::Register => "login",
             AppState::Directory => "back",
             AppState::Loading => "login",
             AppState::AccountSettings => "account-settings",
@@ -56,6 +64,7 @@ impl AppOp {
         //set focus for views
         let widget_focus = match self.state {
             AppState::Login => "login_username",
+            AppState::Register => "register_username",
             AppState::Directory => "directory_search_entry",
             _ => "",
         };
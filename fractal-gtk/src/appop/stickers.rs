@@ -198,6 +198,10 @@ impl AppOp {
             thumb: Some(sticker.thumbnail.clone()),
             formatted_body: None,
             format: None,
+            redacted: false,
+            replace: None,
+            edited: false,
+            in_reply_to: None,
         };
 
         self.add_tmp_room_message(msg);
@@ -0,0 +1,83 @@
+extern crate gtk;
+
+use i18n::i18n_k;
+
+use self::gtk::prelude::*;
+
+use std::time::Instant;
+
+use appop::AppOp;
+use backend::BKCommand;
+use globals;
+
+
+impl AppOp {
+    /// Tells the server that the user is typing in the active room. The notification is sent
+    /// again only when the previous one is about to expire, not with every key press
+    pub fn send_typing(&mut self) {
+        let roomid = match self.active_room {
+            Some(ref r) => r.clone(),
+            None => return,
+        };
+
+        if let Some((ref r, ref sent)) = self.typing_sent {
+            if *r == roomid && sent.elapsed().as_secs() < globals::TYPING_INTERVAL {
+                return;
+            }
+        }
+
+        self.backend.send(BKCommand::SetTyping(roomid.clone(), true, globals::TYPING_TIMEOUT)).unwrap();
+        self.typing_sent = Some((roomid, Instant::now()));
+    }
+
+    /// Tells the server that the user isn't typing anymore, if we said it was
+    pub fn stop_typing(&mut self) {
+        if let Some((roomid, _)) = self.typing_sent.take() {
+            self.backend.send(BKCommand::SetTyping(roomid, false, 0)).unwrap();
+        }
+    }
+
+    pub fn set_typing(&mut self, roomid: String, users: Vec<String>) {
+        let uid = self.uid.clone().unwrap_or_default();
+        let users: Vec<String> = users.into_iter().filter(|u| *u != uid).collect();
+
+        if users.is_empty() {
+            self.typing.remove(&roomid);
+        } else {
+            self.typing.insert(roomid.clone(), users);
+        }
+
+        if self.active_room == Some(roomid) {
+            self.show_typing();
+        }
+    }
+
+    /// Shows who is typing in the active room under the message list
+    pub fn show_typing(&self) {
+        let revealer: gtk::Revealer = self.ui.builder
+            .get_object("typing_revealer")
+            .expect("Can't find typing_revealer in ui file.");
+        let label: gtk::Label = self.ui.builder
+            .get_object("typing_label")
+            .expect("Can't find typing_label in ui file.");
+
+        let roomid = self.active_room.clone().unwrap_or_default();
+        let users = self.typing.get(&roomid).cloned().unwrap_or_default();
+        let room = self.rooms.get(&roomid);
+        let names: Vec<String> = users.iter().map(|u| {
+            room.and_then(|r| r.members.get(u))
+                .map(|m| m.get_alias())
+                .unwrap_or(u.clone())
+        }).collect();
+
+        let text = match names.len() {
+            0 => String::new(),
+            1 => i18n_k("{user} is typing…", &[("user", &names[0])]),
+            2 => i18n_k("{user1} and {user2} are typing…", &[("user1", &names[0]), ("user2", &names[1])]),
+            _ => i18n_k("{user} and others are typing…", &[("user", &names[0])]),
+        };
+
+        label.set_text(&text);
+        revealer.set_reveal_child(!names.is_empty());
+    }
+}
@@ -1,50 +1,32 @@
 extern crate serde_json;
 
-use std::collections::HashMap;
 use std::fs::File;
 use std::fs::remove_dir_all;
 use std::io::prelude::*;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
 
-use types::RoomList;
 use error::Error;
+use passwd;
 
 use fractal_api::util::cache_path;
-use globals;
+use fractal_api::store::FileStore;
 
-use types::Message;
+use backend::Backend;
+use backend::BKResponse;
 
+/// Rooms, members and messages are stored by the backend store, this is only the app state
 #[derive(Serialize, Deserialize)]
 pub struct CacheData {
-    pub since: String,
-    pub rooms: RoomList,
-    pub last_viewed_messages: HashMap<String, Message>,
     pub username: String,
     pub uid: String,
 }
 
 
-pub fn store(
-    rooms: &RoomList,
-    last_viewed_messages: HashMap<String, Message>,
-    since: String,
-    username: String,
-    uid: String
-) -> Result<(), Error> {
-    let fname = cache_path("rooms.json")?;
-
-    let mut cacherooms = rooms.clone();
-    for r in cacherooms.values_mut() {
-        let skip = match r.messages.len() {
-            n if n > globals::CACHE_SIZE => n - globals::CACHE_SIZE,
-            _ => 0,
-        };
-        r.messages = r.messages.iter().skip(skip).cloned().collect();
-    }
+pub fn store(username: String, uid: String) -> Result<(), Error> {
+    let fname = cache_path("app.json")?;
 
     let data = CacheData {
-        since: since,
-        rooms: cacherooms,
-        last_viewed_messages: last_viewed_messages,
         username: username,
         uid: uid,
     };
@@ -56,7 +38,7 @@ pub fn store(
 }
 
 pub fn load() -> Result<CacheData, Error> {
-    let fname = cache_path("rooms.json")?;
+    let fname = cache_path("app.json")?;
 
     let mut file = File::open(fname)?;
     let mut serialized = String::new();
@@ -71,3 +53,18 @@ pub fn destroy() -> Result<(), Error> {
     let fname = cache_path("")?;
     remove_dir_all(fname).or_else(|_| Err(Error::CacheError))
 }
+
+/// Creates a backend that stores the rooms and the timelines in the cache directory, and the
+/// crypto data encrypted with the pickle key of the password storage
+pub fn backend(tx: Sender<BKResponse>) -> Backend {
+    let bk = Backend::new(tx);
+    let bk = match cache_path("store") {
+        Ok(path) => bk.with_store(Arc::new(FileStore::new(path))),
+        Err(_) => bk,
+    };
+
+    match passwd::pickle_key() {
+        Ok(key) => bk.with_pickle_key(key),
+        Err(_) => bk,
+    }
+}
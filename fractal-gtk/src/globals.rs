@@ -1,8 +1,16 @@
 pub static INITIAL_MESSAGES: usize = 40;
-pub static CACHE_SIZE: usize = 40;
 pub static MSG_ICON_SIZE: i32 = 40;
 pub static USERLIST_ICON_SIZE: i32 = 30;
+pub static RECEIPT_ICON_SIZE: i32 = 16;
+pub static MAX_RECEIPTS: usize = 5;
 pub static MINUTES_TO_SPLIT_MSGS: i64 = 30;
+/// Max number of pages of older messages loaded looking for the fully read marker
+pub static MAX_UNREAD_PAGES: usize = 10;
+pub static TYPING_TIMEOUT: i32 = 4000;
+pub static TYPING_INTERVAL: u64 = 3;
+pub static IDLE_TIMEOUT: u64 = 300;
+pub static IDLE_CHECK_INTERVAL: u32 = 30;
+pub static QUICK_REACTIONS: [&'static str; 6] = ["👍", "👎", "😄", "🎉", "😕", "❤️"];
 pub static APP_ID: &'static str = "org.gnome.Fractal";
 pub static DEFAULT_HOMESERVER: &'static str = "https://matrix.org";
 pub static DEFAULT_IDENTITYSERVER: &'static str = "https://vector.im";
@@ -1,8 +1,10 @@
+extern crate rand;
 extern crate secret_service;
 extern crate serde_json;
 
 use gio::{Settings, SettingsSchemaSource};
 use gio::SettingsExt;
+use self::rand::Rng;
 
 use std;
 
@@ -79,6 +81,68 @@ pub trait PasswordStorage {
 }
 
 
+/// The key that encrypts the crypto data of the backend store. It's created the first time and
+/// kept with the password
+pub fn pickle_key() -> Result<[u8; 32], Error> {
+    let stored = match pwd_conf() {
+        PWDConf::PlainText => plain_text::get_pickle_key(),
+        _ => ss_storage::get_pickle_key(),
+    };
+
+    if let Some(key) = stored.ok().and_then(|k| decode_key(&k)) {
+        return Ok(key);
+    }
+
+    let key: [u8; 32] = rand::thread_rng().gen();
+    let encoded = key.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    match pwd_conf() {
+        PWDConf::PlainText => plain_text::store_pickle_key(encoded)?,
+        _ => ss_storage::store_pickle_key(encoded)?,
+    };
+
+    Ok(key)
+}
+
+fn decode_key(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(key)
+}
+
+
+/// A rough estimation of the strength of a new password from 0 to 4, by its length and the
+/// kinds of characters that it uses
+pub fn strength(password: &str) -> u32 {
+    let len = password.chars().count();
+    let kinds = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ].iter().filter(|k| **k).count();
+
+    let score = match len {
+        0 => 0,
+        1..=7 => 1,
+        8..=11 => 2,
+        _ => 3,
+    };
+
+    match (score, kinds) {
+        (0, _) | (1, _) => score,
+        (_, 1) => score - 1,
+        (_, 2) => score,
+        _ => score + 1,
+    }
+}
+
+
 mod ss_storage {
     use super::Error;
 
@@ -154,6 +218,44 @@ mod ss_storage {
         Ok((token, uid))
     }
 
+    pub fn store_pickle_key(key: String) -> Result<(), Error> {
+        let ss = SecretService::new(EncryptionType::Dh)?;
+        let collection = ss.get_default_collection()?;
+        let label = "fractal-pickle-key";
+
+        // deleting previous items
+        delete_pass(label)?;
+
+        // create new item
+        collection.unlock()?;
+        collection.create_item(
+            label,               // label
+            vec![],              // properties
+            key.as_bytes(),      //secret
+            true,                // replace item with same attributes
+            "text/plain",        // secret content type
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_pickle_key() -> Result<String, Error> {
+        let ss = SecretService::new(EncryptionType::Dh)?;
+        let collection = ss.get_default_collection()?;
+        let allpass = collection.get_all_items()?;
+        let label = "fractal-pickle-key";
+
+        let p = allpass
+            .iter()
+            .find(|x| x.get_label().unwrap_or_default() == label)
+            .ok_or(Error::SecretServiceError)?;
+
+        p.unlock()?;
+        let secret = p.get_secret()?;
+
+        String::from_utf8(secret).or(Err(Error::SecretServiceError))
+    }
+
     pub fn store_pass(username: String, password: String, server: String, identity: String) -> Result<(), Error> {
         let ss = SecretService::new(EncryptionType::Dh)?;
         let collection = ss.get_default_collection()?;
@@ -285,6 +387,7 @@ mod plain_text {
         pub identity: String,
         pub password: Option<String>,
         pub token: Option<String>,
+        pub pickle_key: Option<String>,
     }
 
     fn get_file(name: &str) -> Result<String, Error> {
@@ -346,6 +449,17 @@ mod plain_text {
         Ok((data.token.unwrap_or_default(), data.username))
     }
 
+    pub fn store_pickle_key(key: String) -> Result<(), Error> {
+        let mut data = load().unwrap_or_default();
+        data.pickle_key = Some(key);
+        store(&data)?;
+        Ok(())
+    }
+
+    pub fn get_pickle_key() -> Result<String, Error> {
+        load()?.pickle_key.ok_or(Error::PlainTextError)
+    }
+
     pub fn store_pass(username: String, password: String, server: String, identity: String) -> Result<(), Error> {
         let mut data = load().unwrap_or_default();
         data.username = username;
@@ -361,3 +475,27 @@ mod plain_text {
         Ok((data.username, data.password.unwrap_or_default(), data.server, data.identity))
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_strength() {
+        assert_eq!(strength(""), 0);
+        assert_eq!(strength("Secret1!"), 3);
+        assert_eq!(strength("password"), 1);
+        assert_eq!(strength("correcthorse7"), 3);
+        assert_eq!(strength("Correct horse battery 7"), 4);
+        assert!(strength("pass") < strength("passwordpassword"));
+    }
+
+    #[test]
+    fn pickle_key_encoding() {
+        let key = decode_key(&"0f".repeat(32)).unwrap();
+        assert_eq!(key, [15u8; 32]);
+        assert!(decode_key("0f0f").is_none());
+        assert!(decode_key(&"zz".repeat(32)).is_none());
+    }
+}
@@ -9,6 +9,8 @@ use self::gdk_pixbuf::Pixbuf;
 use self::gdk_pixbuf::PixbufExt;
 use self::gdk::ContextExt;
 
+use types::PresenceState;
+
 
 pub type Avatar = gtk::Box;
 
@@ -186,3 +188,33 @@ pub fn admin_badge(kind: AdminColor, size: Option<i32>) -> gtk::DrawingArea {
 
     da
 }
+
+/// A dot to show over the avatar with the presence of the user, hidden if it isn't known
+pub fn presence_dot(presence: Option<PresenceState>) -> gtk::Box {
+    let dot = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+    dot.set_halign(gtk::Align::End);
+    dot.set_valign(gtk::Align::End);
+    dot.set_no_show_all(true);
+    if let Some(style) = dot.get_style_context() {
+        style.add_class("presence-dot");
+    }
+    set_presence(&dot, presence);
+
+    dot
+}
+
+pub fn set_presence(dot: &gtk::Box, presence: Option<PresenceState>) {
+    if let Some(style) = dot.get_style_context() {
+        style.remove_class("presence-online");
+        style.remove_class("presence-unavailable");
+        style.remove_class("presence-offline");
+        if let Some(p) = presence {
+            style.add_class(&format!("presence-{}", p.as_str()));
+        }
+    }
+
+    match presence {
+        Some(_) => dot.show(),
+        None => dot.hide(),
+    }
+}
@@ -74,21 +74,22 @@ impl<'a> MemberBox<'a> {
             v.pack_start(&uid, true, true, 0);
         }
 
+        let overlay = gtk::Overlay::new();
+        overlay.add(&avatar);
         match self.op.member_level(self.member) {
-            100 => {
-                let overlay = gtk::Overlay::new();
-                overlay.add(&avatar);
-                overlay.add_overlay(&widgets::admin_badge(widgets::AdminColor::Gold, None));
-                w.add(&overlay);
-            }
-            50 => {
-                let overlay = gtk::Overlay::new();
-                overlay.add(&avatar);
-                overlay.add_overlay(&widgets::admin_badge(widgets::AdminColor::Silver, None));
-                w.add(&overlay);
-            }
-            _ => {
-                w.add(&avatar);
+            100 => overlay.add_overlay(&widgets::admin_badge(widgets::AdminColor::Gold, None)),
+            50 => overlay.add_overlay(&widgets::admin_badge(widgets::AdminColor::Silver, None)),
+            _ => {}
+        }
+        let presence = self.member.presence.as_ref().map(|p| p.presence);
+        overlay.add_overlay(&widgets::presence_dot(presence));
+        w.add(&overlay);
+
+        if let Some(ref p) = self.member.presence {
+            if let Some(ref msg) = p.status_msg {
+                alias.push_str("\n");
+                alias.push_str(msg);
+                username.set_tooltip_text(&alias[..]);
             }
         }
 
@@ -2,8 +2,10 @@ extern crate gtk;
 extern crate chrono;
 extern crate pango;
 extern crate glib;
+extern crate gdk;
 
 use app::App;
+use app::InternalCommand;
 use i18n::i18n;
 
 use self::gtk::prelude::*;
@@ -37,6 +39,8 @@ pub struct MessageBox<'a> {
     op: &'a AppOp,
     username: gtk::Label,
     pub username_event_box: gtk::EventBox,
+    /// The avatars of the users that have read up to this message
+    pub receipts: gtk::Box,
 }
 
 impl<'a> MessageBox<'a> {
@@ -50,6 +54,7 @@ impl<'a> MessageBox<'a> {
             op: op,
             username: username,
             username_event_box: eb,
+            receipts: gtk::Box::new(gtk::Orientation::Horizontal, 0),
         }
     }
 
@@ -78,7 +83,7 @@ impl<'a> MessageBox<'a> {
         self.set_msg_styles(&row);
         row.set_selectable(false);
         row.set_margin_top(12);
-        row.add(&msg_widget);
+        row.add(&self.build_context_menu(&msg_widget));
         row.show_all();
 
         row
@@ -98,7 +103,7 @@ impl<'a> MessageBox<'a> {
         let row = gtk::ListBoxRow::new();
         self.set_msg_styles(&row);
         row.set_selectable(false);
-        row.add(&msg_widget);
+        row.add(&self.build_context_menu(&msg_widget));
         row.show_all();
 
         row
@@ -122,6 +127,10 @@ impl<'a> MessageBox<'a> {
         }
 
         let body = match msg.mtype.as_ref() {
+            _ if msg.redacted => self.build_room_msg_redacted(),
+            _ if msg.is_undecryptable() => self.build_room_msg_placeholder(&i18n("Unable to decrypt this message")),
+            // the fallback body of the unknown events is shown as a text message
+            _ if msg.is_unsupported() && msg.body.is_empty() => self.build_room_msg_placeholder(&i18n("Unsupported event")),
             "m.sticker" => self.build_room_msg_sticker(),
             "m.image" => self.build_room_msg_image(),
             "m.emote" => self.build_room_msg_emote(&msg),
@@ -130,8 +139,34 @@ impl<'a> MessageBox<'a> {
             _ => self.build_room_msg_body(&msg.body),
         };
 
+        if msg.edited && !msg.redacted {
+            body.add(&self.build_room_msg_edited());
+        }
+
+        if let Some(ref evid) = msg.in_reply_to {
+            if !msg.redacted {
+                content.pack_start(&self.build_room_msg_reply_quote(evid), false, false, 0);
+            }
+        }
+
         content.pack_start(&body, true, true, 0);
 
+        if !msg.redacted {
+            if let Some(reactions) = self.build_room_msg_reactions() {
+                content.pack_start(&reactions, false, false, 0);
+            }
+        }
+
+        self.receipts.set_halign(gtk::Align::End);
+        if let Some(style) = self.receipts.get_style_context() {
+            style.add_class("msg-receipts");
+        }
+        if let Some(ref id) = msg.id {
+            let uid = self.op.uid.clone().unwrap_or_default();
+            fill_receipts(&self.receipts, self.room, id, &uid, self.op.backend.clone());
+        }
+        content.pack_start(&self.receipts, false, false, 0);
+
         content
     }
 
@@ -468,6 +503,211 @@ impl<'a> MessageBox<'a> {
         info
     }
 
+    fn build_room_msg_redacted(&self) -> gtk::Box {
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let label = gtk::Label::new(i18n("Message deleted").as_str());
+        self.set_label_styles(&label);
+        label.set_selectable(false);
+        if let Some(style) = label.get_style_context() {
+            style.add_class("msg-redacted");
+        }
+
+        bx.add(&label);
+        bx
+    }
+
+    /// The text shown in place of the messages that we can't show
+    fn build_room_msg_placeholder(&self, text: &str) -> gtk::Box {
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+        let label = gtk::Label::new(text);
+        self.set_label_styles(&label);
+        label.set_selectable(false);
+        if let Some(style) = label.get_style_context() {
+            style.add_class("msg-placeholder");
+        }
+
+        bx.add(&label);
+        bx
+    }
+
+    fn build_room_msg_edited(&self) -> gtk::Label {
+        let label = gtk::Label::new(i18n("(edited)").as_str());
+        label.set_valign(gtk::Align::End);
+        label.set_margin_start(6);
+        if let Some(style) = label.get_style_context() {
+            style.add_class("msg-edited");
+        }
+
+        label
+    }
+
+    /// The reactions to the message, a pill for each key that toggles our reaction on click
+    fn build_room_msg_reactions(&self) -> Option<gtk::Box> {
+        let evid = self.msg.id.clone()?;
+        let keys = self.room.reaction_keys(&evid);
+        if keys.is_empty() {
+            return None;
+        }
+
+        let uid = self.op.uid.clone().unwrap_or_default();
+        let bx = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        bx.set_margin_top(3);
+
+        for (key, senders) in keys {
+            let own = self.room.own_reaction(&evid, &key, &uid);
+
+            let pill = gtk::Button::new_with_label(&format!("{} {}", key, senders.len()));
+            if let Some(style) = pill.get_style_context() {
+                style.add_class("reaction-pill");
+                if own.is_some() {
+                    style.add_class("reaction-own");
+                }
+            }
+
+            let names: Vec<String> = senders.iter()
+                .map(|s| match self.room.members.get(s) {
+                    Some(m) => m.get_alias(),
+                    None => s.clone(),
+                })
+                .collect();
+            pill.set_tooltip_text(names.join(", ").as_str());
+
+            let roomid = self.room.id.clone();
+            let backend = self.op.backend.clone();
+            pill.connect_clicked(clone!(evid => move |_| {
+                let cmd = match own {
+                    Some(ref id) => BKCommand::Redact(roomid.clone(), id.clone(), None),
+                    None => BKCommand::SendReaction(roomid.clone(), evid.clone(), key.clone()),
+                };
+                backend.send(cmd).unwrap();
+            }));
+
+            bx.pack_start(&pill, false, false, 0);
+        }
+
+        Some(bx)
+    }
+
+    /// A preview of the replied message, that scrolls to it on click
+    fn build_room_msg_reply_quote(&self, evid: &str) -> gtk::EventBox {
+        let eb = gtk::EventBox::new();
+        let bx = gtk::Box::new(gtk::Orientation::Vertical, 0);
+        if let Some(style) = bx.get_style_context() {
+            style.add_class("msg-reply-quote");
+        }
+
+        let parent = self.room.messages.iter().find(|m| m.id.as_ref().map(|id| id.as_str()) == Some(evid));
+        match parent {
+            Some(p) => {
+                let sender = match self.room.members.get(&p.sender) {
+                    Some(m) => m.get_alias(),
+                    None => p.sender.clone(),
+                };
+                let name = gtk::Label::new(sender.as_str());
+                name.set_halign(gtk::Align::Start);
+                if let Some(style) = name.get_style_context() {
+                    style.add_class("username");
+                }
+
+                let body = match p.mtype.as_ref() {
+                    _ if p.redacted => i18n("Message deleted"),
+                    _ if p.is_undecryptable() => i18n("Unable to decrypt this message"),
+                    _ if p.is_unsupported() && p.body.is_empty() => i18n("Unsupported event"),
+                    "m.image" | "m.sticker" => i18n("Image"),
+                    "m.video" | "m.audio" | "m.file" => p.body.clone(),
+                    _ => p.body.lines().next().unwrap_or_default().to_string(),
+                };
+                let text = gtk::Label::new(body.as_str());
+                text.set_halign(gtk::Align::Start);
+                text.set_ellipsize(pango::EllipsizeMode::End);
+
+                bx.pack_start(&name, false, false, 0);
+                bx.pack_start(&text, false, false, 0);
+
+                let internal = self.op.internal.clone();
+                let evid = evid.to_string();
+                eb.connect_button_press_event(move |_, _| {
+                    internal.send(InternalCommand::ScrollToMessage(evid.clone())).unwrap();
+                    glib::signal::Inhibit(true)
+                });
+            }
+            None => {
+                let text = gtk::Label::new(i18n("In reply to an older message").as_str());
+                text.set_halign(gtk::Align::Start);
+                bx.pack_start(&text, false, false, 0);
+            }
+        };
+
+        eb.add(&bx);
+        eb
+    }
+
+    /// Wraps the message in an event box that shows the message actions on right click
+    fn build_context_menu(&self, msg_widget: &gtk::Box) -> gtk::EventBox {
+        let eb = gtk::EventBox::new();
+        eb.add(msg_widget);
+
+        let msg = self.msg;
+        let evid = match msg.id {
+            Some(ref id) if !msg.redacted => id.clone(),
+            _ => return eb,
+        };
+        let own = msg.sender == self.op.uid.clone().unwrap_or_default();
+
+        let m = msg.clone();
+        let roomid = msg.room.clone();
+        let backend = self.op.backend.clone();
+        let internal = self.op.internal.clone();
+        eb.connect_button_press_event(move |eb, ev| {
+            if ev.get_button() != 3 {
+                return glib::signal::Inhibit(false);
+            }
+
+            let popover = gtk::Popover::new(eb);
+            let (x, y) = ev.get_position();
+            popover.set_pointing_to(&gdk::Rectangle { x: x as i32, y: y as i32, width: 1, height: 1 });
+
+            let vbox = gtk::Box::new(gtk::Orientation::Vertical, 0);
+
+            let quick = gtk::Box::new(gtk::Orientation::Horizontal, 0);
+            for key in globals::QUICK_REACTIONS.iter() {
+                let btn = gtk::Button::new_with_label(key);
+                btn.set_relief(gtk::ReliefStyle::None);
+                btn.connect_clicked(clone!(roomid, evid, backend, popover => move |_| {
+                    let cmd = BKCommand::SendReaction(roomid.clone(), evid.clone(), key.to_string());
+                    backend.send(cmd).unwrap();
+                    popover.hide();
+                }));
+                quick.pack_start(&btn, false, false, 0);
+            }
+            vbox.pack_start(&quick, false, false, 6);
+
+            let reply_btn = gtk::ModelButton::new();
+            reply_btn.set_label(&i18n("Reply"));
+            reply_btn.connect_clicked(clone!(m, internal => move |_| {
+                internal.send(InternalCommand::ReplyTo(m.clone())).unwrap();
+            }));
+            vbox.pack_start(&reply_btn, false, false, 6);
+
+            if own {
+                let delete_btn = gtk::ModelButton::new();
+                delete_btn.set_label(&i18n("Delete"));
+                delete_btn.connect_clicked(clone!(roomid, evid, backend => move |_| {
+                    backend.send(BKCommand::Redact(roomid.clone(), evid.clone(), None)).unwrap();
+                }));
+                vbox.pack_start(&delete_btn, false, false, 6);
+            }
+
+            vbox.show_all();
+            popover.add(&vbox);
+            popover.popup();
+
+            glib::signal::Inhibit(true)
+        });
+
+        eb
+    }
+
     fn build_room_msg_emote(&self, msg: &Message) -> gtk::Box {
         let bx = gtk::Box::new(gtk::Orientation::Horizontal, 0);
         let member = self.room.members.get(&msg.sender);
@@ -490,6 +730,49 @@ impl<'a> MessageBox<'a> {
     }
 }
 
+/// Shows in `container` the avatars of the users, apart from `uid`, whose last read receipt
+/// is for the event `evid`
+pub fn fill_receipts(container: &gtk::Box, room: &Room, evid: &str, uid: &str, backend: Sender<BKCommand>) {
+    for ch in container.get_children().iter() {
+        container.remove(ch);
+    }
+
+    let mut readers: Vec<(&String, &i64)> = room.receipts.iter()
+        .filter(|&(u, &(ref id, _))| u != uid && id == evid)
+        .map(|(u, &(_, ref ts))| (u, ts))
+        .collect();
+    // the last reader first
+    readers.sort_by_key(|&(_, ts)| -ts);
+
+    for &(reader, _) in readers.iter().take(globals::MAX_RECEIPTS) {
+        let avatar = widgets::Avatar::avatar_new(Some(globals::RECEIPT_ICON_SIZE));
+        let fname = api::util::cache_path(reader).unwrap_or(strn!(""));
+        if Path::new(&fname).is_file() {
+            avatar.circle(fname, Some(globals::RECEIPT_ICON_SIZE));
+        } else {
+            avatar.default(String::from("avatar-default-symbolic"), Some(globals::RECEIPT_ICON_SIZE));
+            get_member_info(backend.clone(), avatar.clone(), gtk::Label::new(""), reader.clone(), globals::RECEIPT_ICON_SIZE, 10);
+        }
+
+        let name = match room.members.get(reader) {
+            Some(m) => m.get_alias(),
+            None => reader.clone(),
+        };
+        avatar.set_tooltip_text(&name[..]);
+        container.pack_start(&avatar, false, false, 0);
+    }
+
+    if readers.len() > globals::MAX_RECEIPTS {
+        let more = gtk::Label::new(&format!("+{}", readers.len() - globals::MAX_RECEIPTS)[..]);
+        if let Some(style) = more.get_style_context() {
+            style.add_class("dim-label");
+        }
+        container.pack_start(&more, false, false, 3);
+    }
+
+    container.show_all();
+}
+
 fn highlight_username(label: gtk::Label, alias: &String, input: String) -> Option<pango::AttrList> {
     fn contains((start, end): (i32, i32), item: i32) -> bool {
         match start <= end {
@@ -11,6 +11,7 @@ pub mod image;
 mod inline_player;
 
 pub use self::message::MessageBox;
+pub use self::message::fill_receipts;
 pub use self::room::RoomBox;
 pub use self::member::MemberBox;
 pub use self::autocomplete::Autocomplete;
@@ -22,4 +23,6 @@ pub use self::avatar::Avatar;
 pub use self::avatar::AvatarExt;
 pub use self::avatar::admin_badge;
 pub use self::avatar::AdminColor;
+pub use self::avatar::presence_dot;
+pub use self::avatar::set_presence;
 pub use self::inline_player::AudioPlayerWidget;
@@ -17,6 +17,7 @@ use globals;
 use widgets::roomrow::RoomRow;
 use types::Room;
 use types::Message;
+use types::Presence;
 use std::sync::{Arc, Mutex, MutexGuard};
 
 use self::chrono::prelude::*;
@@ -241,6 +242,26 @@ impl RoomListGroup {
         self.edit_room(&room, move |rv| { rv.room.avatar = av.clone(); });
     }
 
+    pub fn set_room_encrypted(&mut self, room: String) {
+        if let Some(r) = self.rooms.get_mut(&room) {
+            r.set_encrypted();
+        }
+
+        self.edit_room(&room, move |rv| { rv.room.encrypted = true; });
+    }
+
+    pub fn set_room_presence(&mut self, room: String, uid: String, presence: Presence) {
+        if let Some(r) = self.rooms.get_mut(&room) {
+            r.set_presence(&uid, presence.clone());
+        }
+
+        self.edit_room(&room, move |rv| {
+            if let Some(m) = rv.room.members.get_mut(&uid) {
+                m.presence = Some(presence.clone());
+            }
+        });
+    }
+
     pub fn widget(&self) -> gtk::EventBox {
         let b = self.wbox.clone();
         if let Some(style) = b.get_style_context() {
@@ -516,6 +537,14 @@ impl RoomList {
         run_in_group!(self, &room, set_room_avatar, room, av);
     }
 
+    pub fn set_room_encrypted(&mut self, room: String) {
+        run_in_group!(self, &room, set_room_encrypted, room);
+    }
+
+    pub fn set_room_presence(&mut self, room: String, uid: String, presence: Presence) {
+        run_in_group!(self, &room, set_room_presence, room, uid, presence);
+    }
+
     pub fn set_room_notifications(&mut self, room: String, n: i32, h: i32) {
         run_in_group!(self, &room, set_room_notifications, room, n, h);
     }
@@ -10,10 +10,14 @@ use self::gtk::prelude::*;
 use fractal_api;
 use fractal_api::util::AvatarMode;
 use fractal_api::util::draw_identicon;
+use fractal_api::transport::ReqwestTransport;
 
 use types::Room;
+use types::Presence;
+use types::PresenceState;
 
 use util::glib_thread_prelude::*;
+use i18n::i18n;
 
 use widgets;
 use widgets::AvatarExt;
@@ -32,6 +36,8 @@ pub struct RoomRow {
     pub room: Room,
     pub icon: widgets::Avatar,
     pub direct: gtk::Image,
+    pub encrypted: gtk::Image,
+    pub presence: gtk::Box,
     pub text: gtk::Label,
     pub notifications: gtk::Label,
     pub widget: gtk::EventBox,
@@ -48,6 +54,16 @@ impl RoomRow {
             style.add_class("direct-chat");
         }
 
+        let encrypted = gtk::Image::new_from_icon_name("channel-secure-symbolic", 1);
+        encrypted.set_tooltip_text(i18n("Encrypted room").as_str());
+        encrypted.set_no_show_all(true);
+        encrypted.set_visible(room.encrypted);
+        if let Some(style) = encrypted.get_style_context() {
+            style.add_class("room-encrypted");
+        }
+
+        let presence = widgets::presence_dot(direct_presence(&room));
+
         let text = gtk::Label::new(name.clone().as_str());
         let baseu = url.clone();
         text.set_valign(gtk::Align::Start);
@@ -92,6 +108,8 @@ impl RoomRow {
             baseu,
             widget,
             direct,
+            encrypted,
+            presence,
         };
 
         rr.connect_dnd();
@@ -155,6 +173,18 @@ impl RoomRow {
         }
     }
 
+    pub fn set_encrypted(&mut self) {
+        self.room.encrypted = true;
+        self.encrypted.show();
+    }
+
+    pub fn set_presence(&mut self, uid: &str, presence: Presence) {
+        if let Some(m) = self.room.members.get_mut(uid) {
+            m.presence = Some(presence);
+        }
+        widgets::set_presence(&self.presence, direct_presence(&self.room));
+    }
+
     pub fn widget(&self) -> gtk::EventBox {
         let b = gtk::Box::new(gtk::Orientation::Horizontal, 5);
 
@@ -167,13 +197,21 @@ impl RoomRow {
             style.add_class("room-row");
         }
 
-        b.pack_start(&self.icon, false, false, 5);
+        if self.room.direct {
+            let overlay = gtk::Overlay::new();
+            overlay.add(&self.icon);
+            overlay.add_overlay(&self.presence);
+            b.pack_start(&overlay, false, false, 5);
+        } else {
+            b.pack_start(&self.icon, false, false, 5);
+        }
         if self.room.direct {
             b.pack_start(&self.direct, false, false, 0);
         }
         self.text.set_valign(gtk::Align::Center);
         self.notifications.set_valign(gtk::Align::Center);
         b.pack_start(&self.text, true, true, 0);
+        b.pack_start(&self.encrypted, false, false, 0);
         b.pack_start(&self.notifications, false, false, 5);
         self.widget.show_all();
 
@@ -215,6 +253,18 @@ impl RoomRow {
     }
 }
 
+/// The presence of the other user of a direct chat
+fn direct_presence(room: &Room) -> Option<PresenceState> {
+    if !room.direct {
+        return None;
+    }
+
+    room.heroes.first()
+        .and_then(|uid| room.members.get(uid))
+        .and_then(|m| m.presence.as_ref())
+        .map(|p| p.presence)
+}
+
 fn download_avatar(baseu: &Url,
                    rid: String,
                    name: String,
@@ -227,7 +277,7 @@ fn download_avatar(baseu: &Url,
         || {
             match avatar {
                 ref s if s.is_empty() => identicon!(&rid, name),
-                _ => fractal_api::util::dw_media(&url, &avatar, true, None, 40, 40),
+                _ => fractal_api::util::dw_media(&ReqwestTransport, &url, &avatar, true, None, 40, 40),
             }
         },
         |rc: Result<String, Error>| {
@@ -11,7 +11,6 @@ use error::Error;
 use backend::types::BKResponse;
 use backend::types::Backend;
 
-use util::json_q;
 
 use types::Room;
 use types::Protocol;
@@ -25,7 +24,7 @@ pub fn protocols(bk: &Backend) -> Result<(), Error> {
 
     let tx = bk.tx.clone();
     let s = bk.data.lock().unwrap().server_url.clone();
-    get!(&url,
+    get!(bk.transport(), &url,
         move |r: JsonValue| {
             let mut protocols: Vec<Protocol> = vec![];
 
@@ -94,7 +93,7 @@ pub fn room_search(bk: &Backend,
 
     let tx = bk.tx.clone();
     let data = bk.data.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
         move |r: JsonValue| {
             let next_branch = r["next_batch"].as_str().unwrap_or("");
             data.lock().unwrap().rooms_since = String::from(next_branch);
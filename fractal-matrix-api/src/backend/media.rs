@@ -15,8 +15,9 @@ use util::resolve_media_url;
 pub fn get_thumb_async(bk: &Backend, media: String, tx: Sender<String>) -> Result<(), Error> {
     let baseu = bk.get_base_url()?;
 
+    let tp = bk.transport();
     semaphore!(bk.limit_threads, {
-        match thumb!(&baseu, &media) {
+        match thumb!(&*tp, &baseu, &media) {
             Ok(fname) => {
                 tx.send(fname).unwrap();
             }
@@ -33,8 +34,9 @@ pub fn get_thumb_async(bk: &Backend, media: String, tx: Sender<String>) -> Resul
 pub fn get_media_async(bk: &Backend, media: String, tx: Sender<String>) -> Result<(), Error> {
     let baseu = bk.get_base_url()?;
 
+    let tp = bk.transport();
     semaphore!(bk.limit_threads, {
-        match media!(&baseu, &media) {
+        match media!(&*tp, &baseu, &media) {
             Ok(fname) => {
                 tx.send(fname).unwrap();
             }
@@ -51,9 +53,10 @@ pub fn get_media_async(bk: &Backend, media: String, tx: Sender<String>) -> Resul
 pub fn get_media(bk: &Backend, media: String) -> Result<(), Error> {
     let baseu = bk.get_base_url()?;
 
+    let tp = bk.transport();
     let tx = bk.tx.clone();
     thread::spawn(move || {
-        match media!(&baseu, &media) {
+        match media!(&*tp, &baseu, &media) {
             Ok(fname) => {
                 tx.send(BKResponse::Media(fname)).unwrap();
             }
@@ -84,15 +87,16 @@ pub fn get_media_url(bk: &Backend, media: String, tx: Sender<String>) -> Result<
 }
 
 #[cfg(feature = "gfx")]
-pub fn get_file_async(url: String, tx: Sender<String>) -> Result<(), Error> {
+pub fn get_file_async(bk: &Backend, url: String, tx: Sender<String>) -> Result<(), Error> {
     let fname;
     {
         let name = url.split("/").last().unwrap_or_default();
         fname = cache_dir_path("files", name)?.clone();
     }
 
+    let tp = bk.transport();
     thread::spawn(move || {
-        match download_file(&url, fname, None) {
+        match download_file(&*tp, &url, fname, None) {
             Ok(fname) => { tx.send(fname).unwrap(); }
             Err(_) => { tx.send(String::from("")).unwrap(); }
         };
@@ -11,8 +11,11 @@ use std::sync::mpsc::RecvError;
 use error::Error;
 
 use util::build_url;
-use util::json_q;
 use cache::CacheMap;
+use client::Client;
+use transport::Transport;
+use transport::ReqwestTransport;
+use store::Store;
 
 mod types;
 mod register;
@@ -22,6 +25,7 @@ mod sync;
 mod media;
 mod directory;
 mod stickers;
+mod uia;
 
 pub use self::types::BKResponse;
 pub use self::types::BKCommand;
@@ -34,40 +38,47 @@ pub use self::types::RoomType;
 
 impl Backend {
     pub fn new(tx: Sender<BKResponse>) -> Backend {
-        let data = BackendData {
-            user_id: String::from("Guest"),
-            access_token: String::from(""),
-            server_url: String::from("https://matrix.org"),
-            scalar_token: None,
-            scalar_url: String::from("https://scalar.vector.im"),
-            sticker_widget: None,
-            since: String::from(""),
-            rooms_since: String::from(""),
-            join_to_room: String::from(""),
-        };
+        Backend::with_transport(tx, Arc::new(ReqwestTransport))
+    }
+
+    /// Creates a backend that makes every request through `transport`, this can be used to
+    /// replace the network with a `MemoryTransport`
+    pub fn with_transport(tx: Sender<BKResponse>, transport: Arc<dyn Transport>) -> Backend {
+        let data = Arc::new(Mutex::new(BackendData::new()));
         Backend {
             tx: tx,
             internal_tx: None,
-            data: Arc::new(Mutex::new(data)),
+            client: Client::with_transport(data.clone(), transport),
+            data: data,
             user_info_cache: CacheMap::new().timeout(60*60),
             limit_threads: Arc::new((Mutex::new(0u8), Condvar::new())),
         }
     }
 
-    fn get_base_url(&self) -> Result<Url, Error> {
-        let s = self.data.lock().unwrap().server_url.clone();
-        let url = Url::parse(&s)?;
-        Ok(url)
+    /// Persists every sync to `store` and restores the rooms from it on the first sync
+    pub fn with_store(mut self, store: Arc<dyn Store>) -> Backend {
+        self.client.store = Some(store);
+        self
     }
 
-    fn url(&self, path: &str, params: Vec<(&str, String)>) -> Result<Url, Error> {
-        let base = self.get_base_url()?;
-        let tk = self.data.lock().unwrap().access_token.clone();
+    /// Sets the key that encrypts the crypto data in the store, the application should keep it
+    /// in a safe place like the secret service. Without it the olm account isn't stored and a
+    /// new device is needed on each restart
+    pub fn with_pickle_key(mut self, key: [u8; 32]) -> Backend {
+        self.client.pickle_key = Some(key);
+        self
+    }
 
-        let mut params2 = params.to_vec();
-        params2.push(("access_token", tk.clone()));
+    pub fn transport(&self) -> Arc<dyn Transport> {
+        self.client.transport.clone()
+    }
 
-        client_url!(&base, path, params2)
+    fn get_base_url(&self) -> Result<Url, Error> {
+        self.client.base_url()
+    }
+
+    fn url(&self, path: &str, params: Vec<(&str, String)>) -> Result<Url, Error> {
+        self.client.url(path, params)
     }
 
     fn get_scalar_token(&self) -> Result<String, Error> {
@@ -75,10 +86,10 @@ impl Backend {
         let uid = self.data.lock().unwrap().user_id.clone();
 
         let url = self.url(&format!("user/{}/openid/request_token", uid), vec![])?;
-        let js = json_q("post", &url, &json!({}), globals::TIMEOUT)?;
+        let js = self.transport().json_q("post", &url, &json!({}), globals::TIMEOUT)?;
 
         let vurl = Url::parse(&format!("{}/api/register", s))?;
-        let js = json_q("post", &vurl, &js, globals::TIMEOUT)?;
+        let js = self.transport().json_q("post", &vurl, &js, globals::TIMEOUT)?;
 
         match js["scalar_token"].as_str() {
             Some(st) => {
@@ -134,8 +145,27 @@ impl Backend {
             }
             Ok(BKCommand::Register(user, passwd, server)) => {
                 let r = register::register(self, user, passwd, server);
+                bkerror!(r, tx, BKResponse::RegisterError);
+            }
+            Ok(BKCommand::SsoLogin(server, cas)) => {
+                let r = register::sso_login(self, server, cas);
                 bkerror!(r, tx, BKResponse::LoginError);
             }
+            Ok(BKCommand::SsoCancel) => {
+                register::sso_cancel(self);
+            }
+            Ok(BKCommand::DiscoverServer(server)) => {
+                let r = register::discover(self, server);
+                bkerror!(r, tx, BKResponse::DiscoverServerError);
+            }
+            Ok(BKCommand::RegisterAvailable(user, server)) => {
+                let r = register::available(self, user, server);
+                bkerror!(r, tx, BKResponse::RegisterAvailableError);
+            }
+            Ok(BKCommand::RegisterEmailToken(identity, email, client_secret)) => {
+                let r = register::email_token(self, identity, email, client_secret);
+                bkerror!(r, tx, BKResponse::RegisterEmailTokenError);
+            }
             Ok(BKCommand::Guest(server)) => {
                 let r = register::guest(self, server);
                 bkerror!(r, tx, BKResponse::GuestLoginError);
@@ -179,12 +209,12 @@ impl Backend {
                 let r = user::delete_three_pid(self, medium, address);
                 bkerror!(r, tx, BKResponse::DeleteThreePIDError);
             }
-            Ok(BKCommand::ChangePassword(username, old_password, new_password)) => {
-                let r = user::change_password(self, username, old_password, new_password);
+            Ok(BKCommand::ChangePassword(old_password, new_password)) => {
+                let r = user::change_password(self, old_password, new_password);
                 bkerror!(r, tx, BKResponse::ChangePasswordError);
             }
-            Ok(BKCommand::AccountDestruction(username, password, flag)) => {
-                let r = user::account_destruction(self, username, password, flag);
+            Ok(BKCommand::AccountDestruction(password, flag)) => {
+                let r = user::account_destruction(self, password, flag);
                 bkerror!(r, tx, BKResponse::AccountDestructionError);
             }
             Ok(BKCommand::GetAvatar) => {
@@ -203,6 +233,30 @@ impl Backend {
                 let r = user::set_user_avatar(self, file);
                 bkerror!(r, tx, BKResponse::SetUserAvatarError);
             }
+            Ok(BKCommand::SetPresence(presence, status_msg)) => {
+                let r = user::set_presence(self, presence, status_msg);
+                bkerror!(r, tx, BKResponse::SetPresenceError);
+            }
+            Ok(BKCommand::UiaAuth(data)) => {
+                let r = uia::auth(self, data);
+                bkerror!(r, tx, BKResponse::UiaError);
+            }
+            Ok(BKCommand::UiaCancel) => {
+                let r = uia::cancel(self);
+                bkerror!(r, tx, BKResponse::UiaError);
+            }
+            Ok(BKCommand::GetDevices) => {
+                let r = user::get_devices(self);
+                bkerror!(r, tx, BKResponse::DevicesError);
+            }
+            Ok(BKCommand::RenameDevice(device, name)) => {
+                let r = user::rename_device(self, device, name);
+                bkerror!(r, tx, BKResponse::RenameDeviceError);
+            }
+            Ok(BKCommand::DeleteDevice(device, password)) => {
+                let r = user::delete_device(self, device, password);
+                bkerror!(r, tx, BKResponse::DeleteDeviceError);
+            }
             Ok(BKCommand::GetAvatarAsync(member, ctx)) => {
                 #[cfg(feature = "gfx")]
                 {
@@ -245,14 +299,18 @@ impl Backend {
                 let r = room::get_room_messages(self, room);
                 bkerror!(r, tx, BKResponse::RoomMessagesError);
             }
-            Ok(BKCommand::GetMessageContext(message)) => {
-                let r = room::get_message_context(self, message);
+            Ok(BKCommand::BackPaginate(roomid)) => {
+                let r = room::back_paginate(self, roomid);
                 bkerror!(r, tx, BKResponse::RoomMessagesError);
             }
             Ok(BKCommand::SendMsg(msg)) => {
                 let r = room::send_msg(self, msg);
                 bkerror!(r, tx, BKResponse::SendMsgError);
             }
+            Ok(BKCommand::EditMsg(evid, msg)) => {
+                let r = room::edit_msg(self, evid, msg);
+                bkerror!(r, tx, BKResponse::EditMsgError);
+            }
             Ok(BKCommand::SetRoom(room)) => {
                 let r = room::set_room(self, room);
                 bkerror!(r, tx, BKResponse::SetRoomError);
@@ -281,6 +339,18 @@ impl Backend {
                 let r = room::mark_as_read(self, roomid, evid);
                 bkerror!(r, tx, BKResponse::MarkAsReadError);
             }
+            Ok(BKCommand::SetTyping(roomid, typing, timeout)) => {
+                let r = room::set_typing(self, roomid, typing, timeout);
+                bkerror!(r, tx, BKResponse::SetTypingError);
+            }
+            Ok(BKCommand::Redact(roomid, evid, reason)) => {
+                let r = room::redact(self, roomid, evid, reason);
+                bkerror!(r, tx, BKResponse::RedactError);
+            }
+            Ok(BKCommand::SendReaction(roomid, evid, key)) => {
+                let r = room::send_reaction(self, roomid, evid, key);
+                bkerror!(r, tx, BKResponse::SendReactionError);
+            }
             Ok(BKCommand::SetRoomName(roomid, name)) => {
                 let r = room::set_room_name(self, roomid, name);
                 bkerror!(r, tx, BKResponse::SetRoomNameError);
@@ -383,7 +453,7 @@ impl Backend {
             Ok(BKCommand::GetFileAsync(url, ctx)) => {
                 #[cfg(feature = "gfx")]
                 {
-                    let r = media::get_file_async(url, ctx);
+                    let r = media::get_file_async(self, url, ctx);
                     bkerror!(r, tx, BKResponse::CommandError);
                 }
                 #[cfg(not(feature = "gfx"))]
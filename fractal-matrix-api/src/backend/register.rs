@@ -1,19 +1,20 @@
 extern crate url;
 extern crate serde_json;
-extern crate regex;
 
 use self::serde_json::Value as JsonValue;
-use self::regex::Regex;
 
+use std::sync::atomic::Ordering;
 use std::thread;
 use self::url::Url;
 
-use util::json_q;
 use globals;
 use error::Error;
 
 use backend::types::BKResponse;
 use backend::types::Backend;
+use backend::uia;
+use sso::SsoListener;
+use uia::UiaRequest;
 
 
 pub fn guest(bk: &Backend, server: String) -> Result<(), Error> {
@@ -24,7 +25,7 @@ pub fn guest(bk: &Backend, server: String) -> Result<(), Error> {
     let data = bk.data.clone();
     let tx = bk.tx.clone();
     let attrs = json!({});
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
           |r: JsonValue| {
         let uid = String::from(r["user_id"].as_str().unwrap_or(""));
         let tk = String::from(r["access_token"].as_str().unwrap_or(""));
@@ -39,121 +40,124 @@ pub fn guest(bk: &Backend, server: String) -> Result<(), Error> {
     Ok(())
 }
 
-fn build_login_attrs(user: String, password: String) -> Result<JsonValue, Error> {
-    let emailre = Regex::new(r"^([0-9a-zA-Z]([-\.\w]*[0-9a-zA-Z])+@([0-9a-zA-Z][-\w]*[0-9a-zA-Z]\.)+[a-zA-Z]{2,9})$")?;
-    let attrs;
-
-    // Email
-    if emailre.is_match(&user) {
-        attrs = json!({
-            "type": "m.login.password",
-            "password": password,
-            "initial_device_display_name": "Fractal",
-            "medium": "email",
-            "address": user.clone(),
-            "identifier": {
-                "type": "m.id.thirdparty",
-                "medium": "email",
-                "address": user.clone()
-            }
-        });
-    } else {
-        attrs = json!({
-            "type": "m.login.password",
-            "initial_device_display_name": "Fractal",
-            "user": user,
-            "password": password
-        });
-    }
+pub fn login(bk: &Backend, user: String, password: String, server: String) -> Result<(), Error> {
+    bk.client.set_server(server);
+
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.login(&user, &password) {
+            Ok((uid, tk)) => tx.send(BKResponse::Token(uid, tk)).unwrap(),
+            Err(err) => tx.send(BKResponse::LoginError(err)).unwrap(),
+        };
+    });
 
-    Ok(attrs)
+    Ok(())
 }
 
-pub fn login(bk: &Backend, user: String, password: String, server: String) -> Result<(), Error> {
-    let s = server.clone();
-    bk.data.lock().unwrap().server_url = s;
-    let url = bk.url("login", vec![])?;
+/// Sends the URL to login in the browser and waits for the login token in a loopback port
+pub fn sso_login(bk: &Backend, server: String, cas: bool) -> Result<(), Error> {
+    sso_cancel(bk);
+    bk.client.set_server(server);
 
-    let attrs = build_login_attrs(user, password)?;
-    let data = bk.data.clone();
+    let listener = SsoListener::bind()?;
+    let url = bk.client.sso_url(&listener.redirect_url()?, cas)?;
+    bk.data.lock().unwrap().sso_cancel = Some(listener.canceller());
+    bk.tx.send(BKResponse::SsoUrl(url.to_string())).unwrap();
 
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
-        |r: JsonValue| {
-            let uid = String::from(r["user_id"].as_str().unwrap_or(""));
-            let tk = String::from(r["access_token"].as_str().unwrap_or(""));
-
-            if uid.is_empty() || tk.is_empty() {
-                tx.send(BKResponse::LoginError(Error::BackendError)).unwrap();
-            } else {
-                data.lock().unwrap().user_id = uid.clone();
-                data.lock().unwrap().access_token = tk.clone();
-                data.lock().unwrap().since = String::new();
-                tx.send(BKResponse::Token(uid, tk)).unwrap();
+    thread::spawn(move || {
+        let token = match listener.wait_token() {
+            Ok(Some(token)) => token,
+            // nothing is sent when the login was cancelled
+            Ok(None) => return,
+            Err(err) => {
+                tx.send(BKResponse::LoginError(err)).unwrap();
+                return;
             }
-        },
-        |err| { tx.send(BKResponse::LoginError(err)).unwrap() }
-    );
+        };
+        drop(listener);
+
+        match client.login_token(&token) {
+            Ok((uid, tk)) => tx.send(BKResponse::Token(uid, tk)).unwrap(),
+            Err(err) => tx.send(BKResponse::LoginError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
+/// Stops the single sign-on that is waiting for the browser, if any
+pub fn sso_cancel(bk: &Backend) {
+    if let Some(cancel) = bk.data.lock().unwrap().sso_cancel.take() {
+        cancel.store(true, Ordering::SeqCst);
+    }
+}
+
 pub fn set_token(bk: &Backend, token: String, uid: String, server: String) -> Result<(), Error> {
-    let s = server.clone();
-    bk.data.lock().unwrap().server_url = s;
-    bk.data.lock().unwrap().access_token = token.clone();
-    bk.data.lock().unwrap().user_id = uid.clone();
-    bk.data.lock().unwrap().since = String::new();
+    bk.client.set_server(server);
+    bk.client.set_token(token.clone(), uid.clone());
     bk.tx.send(BKResponse::Token(uid, token)).unwrap();
 
     Ok(())
 }
 
 pub fn logout(bk: &Backend) -> Result<(), Error> {
-    let url = bk.url("logout", vec![])?;
-    let attrs = json!({});
-
-    let data = bk.data.clone();
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
-        |_| {
-            data.lock().unwrap().user_id = String::new();
-            data.lock().unwrap().access_token = String::new();
-            data.lock().unwrap().since = String::new();
-            tx.send(BKResponse::Logout).unwrap();
-        },
-        |err| { tx.send(BKResponse::LogoutError(err)).unwrap() }
-    );
+    thread::spawn(move || {
+        match client.logout() {
+            Ok(_) => tx.send(BKResponse::Logout).unwrap(),
+            Err(err) => tx.send(BKResponse::LogoutError(err)).unwrap(),
+        };
+    });
+
     Ok(())
 }
 
 pub fn register(bk: &Backend, user: String, password: String, server: String) -> Result<(), Error> {
-    let s = server.clone();
-    bk.data.lock().unwrap().server_url = s;
-    let url = bk.url("register", vec![("kind", strn!("user"))])?;
+    bk.client.set_server(server);
+    uia::start(bk, UiaRequest::Register(user, password), None)
+}
 
-    let attrs = json!({
-        "auth": {"type": "m.login.password"},
-        "username": user,
-        "bind_email": false,
-        "password": password
+pub fn discover(bk: &Backend, server: String) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.discover(&server) {
+            Ok(info) => tx.send(BKResponse::ServerDiscovered(server, info)).unwrap(),
+            Err(err) => tx.send(BKResponse::DiscoverServerError(err)).unwrap(),
+        };
     });
 
-    let data = bk.data.clone();
+    Ok(())
+}
+
+pub fn available(bk: &Backend, user: String, server: String) -> Result<(), Error> {
+    bk.client.set_server(server);
+
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
-        |r: JsonValue| {
-            println!("RESPONSE: {:#?}", r);
-            let uid = String::from(r["user_id"].as_str().unwrap_or(""));
-            let tk = String::from(r["access_token"].as_str().unwrap_or(""));
-
-            data.lock().unwrap().user_id = uid.clone();
-            data.lock().unwrap().access_token = tk.clone();
-            data.lock().unwrap().since = String::from("");
-            tx.send(BKResponse::Token(uid, tk)).unwrap();
-        },
-        |err| { tx.send(BKResponse::LoginError(err)).unwrap() }
-    );
+    thread::spawn(move || {
+        match client.username_available(&user) {
+            Ok(available) => tx.send(BKResponse::RegisterAvailable(user, available)).unwrap(),
+            Err(err) => tx.send(BKResponse::RegisterAvailableError(err)).unwrap(),
+        };
+    });
+
+    Ok(())
+}
+
+pub fn email_token(bk: &Backend, identity: String, email: String, client_secret: String) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.register_email_token(&identity, &email, &client_secret) {
+            Ok(sid) => tx.send(BKResponse::RegisterEmailToken(sid, client_secret)).unwrap(),
+            Err(err) => tx.send(BKResponse::RegisterEmailTokenError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
@@ -1,21 +1,14 @@
 extern crate serde_json;
-extern crate url;
-extern crate urlencoding;
 
 use std::fs::File;
 use std::io::prelude::*;
-use std::sync::mpsc::Sender;
-use self::url::Url;
 
 use globals;
 use std::thread;
 use error::Error;
 
-use util::json_q;
 #[cfg(feature = "gfx")] use util::dw_media;
-use util::get_initial_room_messages;
 use util::build_url;
-use util::put_media;
 use util;
 
 use backend::types::Backend;
@@ -27,6 +20,7 @@ use backend::room;
 use types::Room;
 use types::Member;
 use types::Message;
+use types::Reaction;
 
 use self::serde_json::Value as JsonValue;
 
@@ -46,7 +40,7 @@ pub fn get_room_detail(bk: &Backend, roomid: String, key: String) -> Result<(),
 
     let tx = bk.tx.clone();
     let keys = key.clone();
-    get!(&url,
+    get!(bk.transport(), &url,
         |r: JsonValue| {
             let mut value = String::from("");
             let k = keys.split('.').last().unwrap();
@@ -70,17 +64,18 @@ pub fn get_room_avatar(bk: &Backend, roomid: String) -> Result<(), Error> {
     let tk = bk.data.lock().unwrap().access_token.clone();
     let url = bk.url(&format!("rooms/{}/state/m.room.avatar", roomid), vec![])?;
 
+    let tp = bk.transport();
     let tx = bk.tx.clone();
-    get!(&url,
+    get!(bk.transport(), &url,
         |r: JsonValue| {
             let avatar;
 
             match r["url"].as_str() {
                 Some(u) => {
-                    avatar = thumb!(&baseu, u).unwrap_or_default();
+                    avatar = thumb!(&*tp, &baseu, u).unwrap_or_default();
                 },
                 None => {
-                    avatar = util::get_room_avatar(&baseu, &tk, &userid, &roomid)
+                    avatar = util::get_room_avatar(&*tp, &baseu, &tk, &userid, &roomid)
                         .unwrap_or(String::from(""));
                 }
             }
@@ -89,7 +84,7 @@ pub fn get_room_avatar(bk: &Backend, roomid: String) -> Result<(), Error> {
         |err: Error| {
             match err {
                 Error::MatrixError(ref js) if js["errcode"].as_str().unwrap_or("") == "M_NOT_FOUND" => {
-                    let avatar = util::get_room_avatar(&baseu, &tk, &userid, &roomid)
+                    let avatar = util::get_room_avatar(&*tp, &baseu, &tk, &userid, &roomid)
                         .unwrap_or(String::from(""));
                     tx.send(BKResponse::RoomAvatar(roomid, avatar)).unwrap();
                 },
@@ -104,43 +99,30 @@ pub fn get_room_avatar(bk: &Backend, roomid: String) -> Result<(), Error> {
 }
 
 pub fn get_room_members(bk: &Backend, roomid: String) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/joined_members", roomid), vec![])?;
-
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    get!(&url,
-        |r: JsonValue| {
-            let joined = r["joined"].as_object().unwrap();
-            let mut ms: Vec<Member> = vec![];
-            for memberid in joined.keys() {
-                let alias = &joined[memberid]["display_name"];
-                let avatar = &joined[memberid]["avatar_url"];
-
-                let m = Member {
-                    alias: match alias.as_str() { None => None, Some(a) => Some(strn!(a)) },
-                    avatar: match avatar.as_str() { None => None, Some(a) => Some(strn!(a)) },
-                    uid: memberid.to_string(),
-                };
-                ms.push(m);
-            }
-            tx.send(BKResponse::RoomMembers(roomid, ms)).unwrap();
-        },
-        |err| { tx.send(BKResponse::RoomMembersError(err)).unwrap() }
-    );
+    thread::spawn(move || {
+        match client.room_members(&roomid) {
+            Ok(ms) => tx.send(BKResponse::RoomMembers(roomid, ms)).unwrap(),
+            Err(err) => tx.send(BKResponse::RoomMembersError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
 pub fn get_room_messages(bk: &Backend, roomid: String) -> Result<(), Error> {
-    let baseu = bk.get_base_url()?;
-    let tk = bk.data.lock().unwrap().access_token.clone();
-
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
     thread::spawn(move || {
-        match get_initial_room_messages(&baseu, tk, roomid.clone(),
-                                        globals::PAGE_LIMIT as usize,
-                                        globals::PAGE_LIMIT, None) {
-            Ok((ms, _, _)) => {
-                tx.send(BKResponse::RoomMessagesInit(ms)).unwrap();
+        match client.load_room_timeline(&roomid) {
+            Ok(page) => {
+                tx.send(BKResponse::RoomSenders(roomid.clone(), page.members)).unwrap();
+                // the reactions go first, so they are known when the messages are shown
+                if !page.reactions.is_empty() {
+                    tx.send(BKResponse::Reactions(roomid, page.reactions)).unwrap();
+                }
+                tx.send(BKResponse::RoomMessagesInit(page.messages)).unwrap();
             }
             Err(err) => {
                 tx.send(BKResponse::RoomMessagesError(err)).unwrap();
@@ -151,160 +133,163 @@ pub fn get_room_messages(bk: &Backend, roomid: String) -> Result<(), Error> {
     Ok(())
 }
 
-fn parse_context(tx: Sender<BKResponse>, tk: String, baseu: Url, roomid: String, eid: String, limit: i32) -> Result<(), Error> {
-    let url = client_url!(&baseu, &format!("rooms/{}/context/{}", roomid, eid),
-        vec![("limit", format!("{}", limit)), ("access_token", tk.clone())])?;
-
-    get!(&url,
-        |r: JsonValue| {
-            let mut id: Option<String> = None;
-
-            let mut ms: Vec<Message> = vec![];
-            let array = r["events_before"].as_array();
-            for msg in array.unwrap().iter().rev() {
-                if let None = id {
-                    id = Some(msg["event_id"].as_str().unwrap_or("").to_string());
-                }
-
-                if !Message::supported_event(&&msg) {
-                    continue;
+pub fn back_paginate(bk: &Backend, roomid: String) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.back_paginate(&roomid) {
+            Ok(page) => {
+                tx.send(BKResponse::RoomSenders(roomid.clone(), page.members)).unwrap();
+                if !page.reactions.is_empty() {
+                    tx.send(BKResponse::Reactions(roomid.clone(), page.reactions)).unwrap();
                 }
-
-                let m = Message::parse_room_message(roomid.clone(), msg);
-                ms.push(m);
+                tx.send(BKResponse::RoomMessagesBackPaginated(roomid, page.messages, page.end)).unwrap();
             }
-
-            if ms.len() == 0 && id.is_some() {
-                // there's no messages so we'll try with a bigger context
-                if let Err(err) = parse_context(tx.clone(), tk, baseu, roomid, id.unwrap(), limit * 2) {
-                    tx.send(BKResponse::RoomMessagesError(err)).unwrap();
-                }
-            } else {
-                tx.send(BKResponse::RoomMessagesTo(ms)).unwrap();
+            Err(err) => {
+                tx.send(BKResponse::RoomMessagesError(err)).unwrap();
             }
-        },
-        |err| { tx.send(BKResponse::RoomMessagesError(err)).unwrap() }
-    );
+        }
+    });
 
     Ok(())
 }
 
-pub fn get_message_context(bk: &Backend, msg: Message) -> Result<(), Error> {
+pub fn send_msg(bk: &Backend, msg: Message) -> Result<(), Error> {
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    let baseu = bk.get_base_url()?;
-    let roomid = msg.room.clone();
-    let msgid = msg.id.unwrap_or_default();
-    let tk = bk.data.lock().unwrap().access_token.clone();
-
-    parse_context(tx, tk, baseu, roomid, msgid, globals::PAGE_LIMIT)?;
+    thread::spawn(move || {
+        let id = msg.id.clone().unwrap_or_default();
+        match client.send_message(&msg) {
+            Ok(evid) => tx.send(BKResponse::SentMsg(id, evid)).unwrap(),
+            Err(_) => tx.send(BKResponse::SendMsgError(Error::SendMsgError(id))).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
-pub fn send_msg(bk: &Backend, msg: Message) -> Result<(), Error> {
-    let roomid = msg.room.clone();
-
-    let id = msg.id.unwrap_or_default();
-    let url = bk.url(&format!("rooms/{}/send/m.room.message/{}", roomid, id), vec![])?;
-
-    let mut attrs = json!({
-        "body": msg.body.clone(),
-        "msgtype": msg.mtype.clone()
-    });
-
-    if let Some(u) = msg.url {
-        attrs["url"] = json!(u);
-    }
-
-    if let (Some(f), Some(f_b)) = (msg.format, msg.formatted_body) {
-        attrs["formatted_body"] = json!(f_b);
-        attrs["format"] = json!(f);
-    }
-
+pub fn edit_msg(bk: &Backend, evid: String, msg: Message) -> Result<(), Error> {
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    query!("put", &url, &attrs,
-        move |js: JsonValue| {
-            let evid = js["event_id"].as_str().unwrap_or_default();
-            tx.send(BKResponse::SentMsg(id, evid.to_string())).unwrap();
-        },
-        |_| { tx.send(BKResponse::SendMsgError(Error::SendMsgError(id))).unwrap(); }
-    );
+    thread::spawn(move || {
+        if let Err(err) = client.edit_message(&evid, &msg) {
+            tx.send(BKResponse::EditMsgError(err)).unwrap();
+        }
+    });
 
     Ok(())
 }
 
 pub fn join_room(bk: &Backend, roomid: String) -> Result<(), Error> {
-    let url = bk.url(&format!("join/{}", urlencoding::encode(&roomid)), vec![])?;
-
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
     let data = bk.data.clone();
-    post!(&url,
-        move |_: JsonValue| {
-            data.lock().unwrap().join_to_room = roomid.clone();
-            tx.send(BKResponse::JoinRoom).unwrap();
-        },
-        |err| { tx.send(BKResponse::JoinRoomError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        match client.join_room(&roomid) {
+            Ok(_) => {
+                data.lock().unwrap().join_to_room = roomid.clone();
+                tx.send(BKResponse::JoinRoom).unwrap();
+            }
+            Err(err) => tx.send(BKResponse::JoinRoomError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
 pub fn leave_room(bk: &Backend, roomid: String) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/leave", roomid), vec![])?;
-
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    post!(&url,
-        move |_: JsonValue| {
-            tx.send(BKResponse::LeaveRoom).unwrap();
-        },
-        |err| { tx.send(BKResponse::LeaveRoomError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        match client.leave_room(&roomid) {
+            Ok(_) => tx.send(BKResponse::LeaveRoom).unwrap(),
+            Err(err) => tx.send(BKResponse::LeaveRoomError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
 pub fn mark_as_read(bk: &Backend, roomid: String, eventid: String) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/receipt/m.read/{}", roomid, eventid), vec![])?;
-
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    let r = roomid.clone();
-    let e = eventid.clone();
-    post!(&url,
-        move |_: JsonValue| { tx.send(BKResponse::MarkedAsRead(r, e)).unwrap(); },
-        |err| { tx.send(BKResponse::MarkAsReadError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        match client.mark_as_read(&roomid, &eventid) {
+            Ok(_) => tx.send(BKResponse::MarkedAsRead(roomid, eventid)).unwrap(),
+            Err(err) => tx.send(BKResponse::MarkAsReadError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
-pub fn set_room_name(bk: &Backend, roomid: String, name: String) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/state/m.room.name", roomid), vec![])?;
-
-    let attrs = json!({
-        "name": name,
+/// Announces that the user is typing in the room for the next `timeout` milliseconds, or that
+/// the user stopped typing
+pub fn set_typing(bk: &Backend, roomid: String, typing: bool, timeout: i32) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        if let Err(err) = client.set_typing(&roomid, typing, timeout) {
+            tx.send(BKResponse::SetTypingError(err)).unwrap();
+        }
     });
 
+    Ok(())
+}
+
+/// Reacts to a message, the new reaction is sent back so it's shown before the next sync
+pub fn send_reaction(bk: &Backend, roomid: String, evid: String, key: String) -> Result<(), Error> {
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    query!("put", &url, &attrs,
-        |_| { tx.send(BKResponse::SetRoomName).unwrap(); },
-        |err| { tx.send(BKResponse::SetRoomNameError(err)).unwrap(); }
-    );
+    let uid = bk.data.lock().unwrap().user_id.clone();
+    thread::spawn(move || {
+        match client.send_reaction(&roomid, &evid, &key) {
+            Ok(id) => {
+                let reaction = Reaction { id: id, sender: uid, key: key };
+                tx.send(BKResponse::Reactions(roomid, vec![(evid, reaction)])).unwrap();
+            }
+            Err(err) => tx.send(BKResponse::SendReactionError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
-pub fn set_room_topic(bk: &Backend, roomid: String, topic: String) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/state/m.room.topic", roomid), vec![])?;
+pub fn redact(bk: &Backend, roomid: String, evid: String, reason: Option<String>) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.redact(&roomid, &evid, reason.as_ref().map(|r| r.as_str())) {
+            Ok(_) => tx.send(BKResponse::Redacted(roomid, evid)).unwrap(),
+            Err(err) => tx.send(BKResponse::RedactError(err)).unwrap(),
+        };
+    });
 
-    let attrs = json!({
-        "topic": topic,
+    Ok(())
+}
+
+pub fn set_room_name(bk: &Backend, roomid: String, name: String) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.set_room_name(&roomid, &name) {
+            Ok(_) => tx.send(BKResponse::SetRoomName).unwrap(),
+            Err(err) => tx.send(BKResponse::SetRoomNameError(err)).unwrap(),
+        };
     });
 
+    Ok(())
+}
+
+pub fn set_room_topic(bk: &Backend, roomid: String, topic: String) -> Result<(), Error> {
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    query!("put", &url, &attrs,
-        |_| { tx.send(BKResponse::SetRoomTopic).unwrap(); },
-        |err| { tx.send(BKResponse::SetRoomTopicError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        match client.set_room_topic(&roomid, &topic) {
+            Ok(_) => tx.send(BKResponse::SetRoomTopic).unwrap(),
+            Err(err) => tx.send(BKResponse::SetRoomTopicError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
@@ -320,17 +305,18 @@ pub fn set_room_avatar(bk: &Backend, roomid: String, avatar: String) -> Result<(
     let mut contents: Vec<u8> = vec![];
     file.read_to_end(&mut contents)?;
 
+    let tp = bk.transport();
     let tx = bk.tx.clone();
     thread::spawn(
         move || {
-            match put_media(mediaurl.as_str(), contents) {
+            match tp.put_media(mediaurl.as_str(), contents) {
                 Err(err) => {
                     tx.send(BKResponse::SetRoomAvatarError(err)).unwrap();
                 }
                 Ok(js) => {
                     let uri = js["content_uri"].as_str().unwrap_or("");
                     let attrs = json!({ "url": uri });
-                    match json_q("put", &roomurl, &attrs, 0) {
+                    match tp.json_q("put", &roomurl, &attrs, 0) {
                         Ok(_) => {
                             tx.send(BKResponse::SetRoomAvatar).unwrap();
                         },
@@ -363,11 +349,12 @@ pub fn attach_file(bk: &Backend, msg: Message) -> Result<(), Error> {
     let mediaurl = media_url!(&baseu, "upload", params)?;
 
     let mut m = msg.clone();
+    let tp = bk.transport();
     let tx = bk.tx.clone();
     let itx = bk.internal_tx.clone();
     thread::spawn(
         move || {
-            match put_media(mediaurl.as_str(), contents) {
+            match tp.put_media(mediaurl.as_str(), contents) {
                 Err(err) => {
                     tx.send(BKResponse::AttachFileError(err)).unwrap();
                 }
@@ -387,33 +374,15 @@ pub fn attach_file(bk: &Backend, msg: Message) -> Result<(), Error> {
 }
 
 pub fn new_room(bk: &Backend, name: String, privacy: RoomType, internal_id: String) -> Result<(), Error> {
-    let url = bk.url("createRoom", vec![])?;
-    let attrs = json!({
-        "invite": [],
-        "invite_3pid": [],
-        "name": &name,
-        "visibility": match privacy {
-            RoomType::Public => "public",
-            RoomType::Private => "private",
-        },
-        "topic": "",
-        "preset": match privacy {
-            RoomType::Public => "public_chat",
-            RoomType::Private => "private_chat",
-        },
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.create_room(&name, privacy) {
+            Ok(r) => tx.send(BKResponse::NewRoom(r, internal_id)).unwrap(),
+            Err(err) => tx.send(BKResponse::NewRoomError(err, internal_id)).unwrap(),
+        };
     });
 
-    let n = name.clone();
-    let tx = bk.tx.clone();
-    post!(&url, &attrs,
-        move |r: JsonValue| {
-            let id = strn!(r["room_id"].as_str().unwrap_or(""));
-            let name = n;
-            let r = Room::new(id, Some(name));
-            tx.send(BKResponse::NewRoom(r, internal_id)).unwrap();
-        },
-        |err| { tx.send(BKResponse::NewRoomError(err, internal_id)).unwrap(); }
-    );
     Ok(())
 }
 
@@ -431,8 +400,9 @@ pub fn direct_chat(bk: &Backend, user: Member, internal_id: String) -> Result<()
     let direct_url = bk.url(&format!("user/{}/account_data/m.direct", userid), vec![])?;
 
     let m = user.clone();
+    let tp = bk.transport();
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
         move |r: JsonValue| {
             let id = strn!(r["room_id"].as_str().unwrap_or(""));
             let mut r = Room::new(id.clone(), m.alias);
@@ -440,7 +410,7 @@ pub fn direct_chat(bk: &Backend, user: Member, internal_id: String) -> Result<()
             tx.send(BKResponse::NewRoom(r, internal_id)).unwrap();
 
             let attrs = json!({ m.uid.clone(): [id] });
-            match json_q("put", &direct_url, &attrs, 0) {
+            match tp.json_q("put", &direct_url, &attrs, 0) {
                 Ok(_js) => { }
                 Err(err) => { println!("Error {:?}", err); }
             };
@@ -481,10 +451,11 @@ pub fn make_search(bk: &Backend, roomid: String, term: String) -> Result<(), Err
         },
     });
 
+    let tp = bk.transport();
     let tx = bk.tx.clone();
 
     thread::spawn(move || {
-        match json_q("post", &url, &attrs, 0) {
+        match tp.json_q("post", &url, &attrs, 0) {
             Ok(js) => {
                 tx.send(BKResponse::SearchEnd).unwrap();
                 let res = &js["search_categories"]["room_events"]["results"];
@@ -513,7 +484,7 @@ pub fn add_to_fav(bk: &Backend, roomid: String, tofav: bool) -> Result<(), Error
 
     let tx = bk.tx.clone();
     let method = match tofav { true => "put", false => "delete" };
-    query!(method, &url, &attrs,
+    query!(bk.transport(), method, &url, &attrs,
         |_| { tx.send(BKResponse::AddedToFav(roomid.clone(), tofav)).unwrap(); },
         |err| { tx.send(BKResponse::AddToFavError(err)).unwrap(); }
     );
@@ -522,17 +493,13 @@ pub fn add_to_fav(bk: &Backend, roomid: String, tofav: bool) -> Result<(), Error
 }
 
 pub fn invite(bk: &Backend, roomid: String, userid: String) -> Result<(), Error> {
-    let url = bk.url(&format!("rooms/{}/invite", roomid), vec![])?;
-
-    let attrs = json!({
-        "user_id": userid,
-    });
-
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
-        |_| { },
-        |err| { tx.send(BKResponse::InviteError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        if let Err(err) = client.invite(&roomid, &userid) {
+            tx.send(BKResponse::InviteError(err)).unwrap();
+        }
+    });
 
     Ok(())
 }
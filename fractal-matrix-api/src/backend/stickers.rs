@@ -5,7 +5,6 @@ extern crate chrono;
 use self::chrono::prelude::*;
 
 use std::thread;
-use util::json_q;
 
 use globals;
 //use std::thread;
@@ -39,7 +38,7 @@ pub fn list(bk: &Backend) -> Result<(), Error> {
     let url = bk.vurl("widgets/assets", data)?;
 
     let tx = bk.tx.clone();
-    get!(&url,
+    get!(bk.transport(), &url,
         |r: JsonValue| {
             let mut stickers = vec![];
             for sticker_group in r["assets"].as_array().unwrap_or(&vec![]).iter() {
@@ -61,7 +60,7 @@ pub fn get_sticker_widget_id(bk: &Backend) -> Result<String, Error> {
     });
     let url = bk.vurl("widgets/request", vec![])?;
 
-    match json_q("post", &url, &data, globals::TIMEOUT) {
+    match bk.transport().json_q("post", &url, &data, globals::TIMEOUT) {
         Ok(r) => {
             let mut id = "".to_string();
             if let Some(i) = r["id"].as_str() {
@@ -106,7 +105,7 @@ pub fn send(bk: &Backend, roomid: String, sticker: &Sticker) -> Result<(), Error
     });
 
     let tx = bk.tx.clone();
-    query!("put", &url, &attrs,
+    query!(bk.transport(), "put", &url, &attrs,
         move |js: JsonValue| {
             let evid = js["event_id"].as_str().unwrap_or_default();
             tx.send(BKResponse::SentMsg(id, evid.to_string())).unwrap();
@@ -137,7 +136,7 @@ pub fn purchase(bk: &Backend, group: &StickerGroup) -> Result<(), Error> {
     let url = bk.vurl("widgets/purchase_asset", data)?;
     let tx = bk.tx.clone();
     let itx = bk.internal_tx.clone();
-    get!(&url,
+    get!(bk.transport(), &url,
         |_| {
             if let Some(t) = itx {
                 t.send(BKCommand::ListStickers).unwrap();
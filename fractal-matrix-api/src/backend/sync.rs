@@ -1,14 +1,37 @@
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+
 use globals;
 use std::{thread, time};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
 use error::Error;
-use util::json_q;
 use util::get_rooms_from_json;
-use util::get_rooms_timeline_from_json;
 use util::get_rooms_notifies_from_json;
 use util::parse_sync_events;
+use util::get_rooms_receipts_from_json;
+use util::get_fully_read;
+use util::fully_read_message;
+use util::parse_presence;
+use util::parse_reactions;
 use backend::types::BKResponse;
 use backend::types::Backend;
+use backend::types::BackendData;
+use client::Client;
 use types::Room;
+use types::Message;
+use types::EventContent;
+use types::SyncResponse;
+use types::Filter;
+use types::RoomFilter;
+use types::RoomEventFilter;
+use types::EventFilter;
+use store::Store;
+use store::store_sync;
+use store::load_rooms;
+use store::load_timeline;
 
 pub fn sync(bk: &Backend) -> Result<(), Error> {
     let tk = bk.data.lock().unwrap().access_token.clone();
@@ -16,55 +39,81 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
         return Err(Error::BackendError);
     }
 
-    let since = bk.data.lock().unwrap().since.clone();
+    let mut since = bk.data.lock().unwrap().since.clone();
     let userid = bk.data.lock().unwrap().user_id.clone();
+    let store = bk.client.store.clone();
 
-    let mut params: Vec<(&str, String)> = vec![];
-    params.push(("full_state", strn!("false")));
-
-    let timeout;
-
+    // Restoring the rooms from the store, so we only need an incremental sync
     if since.is_empty() {
-        let filter = format!("{{
-            \"room\": {{
-                \"state\": {{
-                    \"types\": [\"m.room.*\"],
-                    \"not_types\": [\"m.room.member\"]
-                }},
-                \"timeline\": {{
-                    \"types\": [\"m.room.message\", \"m.sticker\"],
-                    \"limit\": {}
-                }},
-                \"ephemeral\": {{ \"types\": [] }}
-            }},
-            \"presence\": {{ \"types\": [] }},
-            \"event_format\": \"client\",
-            \"event_fields\": [\"type\", \"content\", \"sender\", \"event_id\", \"age\", \"unsigned\"]
-        }}", globals::PAGE_LIMIT);
-
-        params.push(("filter", strn!(filter)));
-        params.push(("timeout", strn!("0")));
-        timeout = 0;
-    } else {
-        params.push(("since", since.clone()));
-        params.push(("timeout", strn!("30000")));
-        timeout = 30;
+        if let Some(ref store) = store {
+            if let Some(stored) = restore_from_store(bk, &**store, &userid) {
+                since = stored;
+            }
+        }
     }
 
-    let baseu = bk.get_base_url()?;
-    let url = bk.url("sync", params)?;
+    let timeout = match since.is_empty() {
+        true => 0,
+        false => 30,
+    };
 
+    let baseu = bk.get_base_url()?;
+    let tp = bk.transport();
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
     let data = bk.data.clone();
 
     let attrs = json!(null);
 
     thread::spawn(move || {
-        match json_q("get", &url, &attrs, timeout) {
-            Ok(r) => {
-                let next_batch = String::from(r["next_batch"].as_str().unwrap_or(""));
+        if let Err(err) = client.init_crypto() {
+            eprintln!("Error setting up the encryption: {:?}", err);
+        }
+
+        let query = client.filter_id(&sync_filter()).and_then(|filter_id| {
+            let mut params: Vec<(&str, String)> = vec![];
+            params.push(("full_state", strn!("false")));
+            params.push(("filter", filter_id));
+            params.push(("timeout", format!("{}", timeout * 1000)));
+            if !since.is_empty() {
+                params.push(("since", since.clone()));
+            }
+
+            let url = client.url("sync", params)?;
+            tp.json_q("get", &url, &attrs, timeout)
+        });
+
+        match query {
+            Ok(js) => {
+                let mut r: SyncResponse = match serde_json::from_value(js) {
+                    Ok(r) => r,
+                    Err(_) => {
+                        tx.send(BKResponse::SyncError(Error::BackendError)).unwrap();
+                        return;
+                    }
+                };
+                let next_batch = r.next_batch.clone();
+
+                // the room keys come before the messages, so they can be decrypted
+                if let Err(err) = client.sync_crypto(&mut r) {
+                    eprintln!("Error processing the encryption keys: {:?}", err);
+                }
+
+                if let Some(ref store) = store {
+                    if since.is_empty() {
+                        // a full sync replaces everything stored
+                        if let Err(err) = store.clear() {
+                            eprintln!("Error clearing the store: {:?}", err);
+                        }
+                    }
+                    if let Err(err) = store_sync(&**store, &userid, &r) {
+                        eprintln!("Error storing the sync: {:?}", err);
+                    }
+                }
+                // the events are stored encrypted, the plain text is only kept in memory
+                client.decrypt_sync(&mut r);
                 if since.is_empty() {
-                    let rooms = match get_rooms_from_json(&r, &userid, &baseu) {
+                    let rooms = match get_rooms_from_json(&*tp, &r, &userid, &baseu) {
                         Ok(rs) => rs,
                         Err(err) => {
                             tx.send(BKResponse::SyncError(err)).unwrap();
@@ -72,6 +121,20 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
                         }
                     };
 
+                    data.lock().unwrap().timelines.clear();
+                    data.lock().unwrap().gap_fills.clear();
+                    for room in rooms.iter() {
+                        if let Some(jr) = r.rooms.join.get(&room.id) {
+                            client.push_sync_timeline(&room.id, room.messages.clone(), &jr.timeline, &next_batch);
+                            // the edits of messages before this sync wait for the pagination
+                            let ms = Message::from_json_events_iter(room.id.clone(), jr.timeline.events.iter());
+                            let (_, edits) = Message::apply_edits(ms);
+                            for edit in edits.iter() {
+                                client.apply_edit(&room.id, edit.replace.as_ref().map(|e| e.as_str()).unwrap_or_default(), edit);
+                            }
+                        }
+                    }
+
                     let mut def: Option<Room> = None;
                     let jtr = data.lock().unwrap().join_to_room.clone();
                     if !jtr.is_empty() {
@@ -82,16 +145,63 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
                     tx.send(BKResponse::Rooms(rooms, def)).unwrap();
                 } else {
                     // New rooms
-                    match get_rooms_from_json(&r, &userid, &baseu) {
+                    match get_rooms_from_json(&*tp, &r, &userid, &baseu) {
                         Ok(rs) => tx.send(BKResponse::NewRooms(rs)).unwrap(),
                         Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
                     };
 
-                    // Message events
-                    match get_rooms_timeline_from_json(&baseu, &r, tk.clone(), since.clone()) {
-                        Ok(msgs) => tx.send(BKResponse::RoomMessages(msgs)).unwrap(),
-                        Err(err) => tx.send(BKResponse::RoomMessagesError(err)).unwrap(),
-                    };
+                    // Message events, filling the gap before limited timelines
+                    let mut msgs: Vec<Message> = vec![];
+                    let mut last_messages: HashMap<String, String> = HashMap::new();
+                    for (k, room) in r.rooms.join.iter() {
+                        // the read marker can be on an event after the last known message
+                        if !room.timeline.limited {
+                            let last = data.lock().unwrap().timelines.get(k)
+                                .and_then(|t| t.chunks.last())
+                                .and_then(|c| c.messages.last())
+                                .and_then(|m| m.id.clone());
+                            if let Some(last) = last {
+                                last_messages.insert(k.clone(), last);
+                            }
+                        }
+                        let ms = Message::from_json_events_iter(k.clone(), room.timeline.events.iter());
+                        let (edits, plain): (Vec<Message>, Vec<Message>) = ms.iter().cloned()
+                            .partition(|m| m.replace.is_some());
+                        let gap = client.push_sync_timeline(k, plain, &room.timeline, &next_batch);
+                        // the edits aren't kept as messages, the UI applies them too
+                        for edit in edits.iter() {
+                            client.apply_edit(k, edit.replace.as_ref().map(|e| e.as_str()).unwrap_or_default(), edit);
+                        }
+                        // the messages after a gap wait until it's filled, to keep them in order
+                        let (waiting, filling) = {
+                            let mut d = data.lock().unwrap();
+                            match d.gap_fills.get_mut(k) {
+                                Some(pending) => {
+                                    pending.extend(ms.iter().cloned());
+                                    (true, false)
+                                }
+                                None if gap => {
+                                    d.gap_fills.insert(k.clone(), ms.clone());
+                                    (true, true)
+                                }
+                                None => (false, false),
+                            }
+                        };
+                        if filling {
+                            fill_gap(&client, &tx, &data, k);
+                        }
+                        if !waiting {
+                            msgs.extend(ms);
+                        }
+
+                        let reactions = parse_reactions(k, room.timeline.events.iter());
+                        if !reactions.is_empty() {
+                            tx.send(BKResponse::Reactions(k.clone(), reactions)).unwrap();
+                        }
+                    }
+                    if !msgs.is_empty() {
+                        tx.send(BKResponse::RoomMessages(msgs)).unwrap();
+                    }
                     // Room notifications
                     match get_rooms_notifies_from_json(&r) {
                         Ok(notifies) => {
@@ -101,31 +211,62 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
                         },
                         Err(_) => {}
                     };
+                    // Typing notifications, only sent when the list of typing users changes
+                    for (k, room) in r.rooms.join.iter() {
+                        if let Some(ev) = room.ephemeral.find("m.typing") {
+                            let users = ev["content"]["user_ids"].as_array().unwrap_or(&vec![]).iter()
+                                .filter_map(|u| u.as_str())
+                                .map(|u| u.to_string())
+                                .collect();
+                            tx.send(BKResponse::Typing(k.clone(), users)).unwrap();
+                        }
+                    }
+                    // Read receipts
+                    for (k, receipts) in get_rooms_receipts_from_json(&r) {
+                        tx.send(BKResponse::Receipts(k, receipts)).unwrap();
+                    }
+                    // The read marker, moved from this or other devices
+                    for (k, room) in r.rooms.join.iter() {
+                        if let Some(evid) = get_fully_read(&room.account_data) {
+                            let last = last_messages.get(k).map(|m| m.as_str());
+                            let evid = fully_read_message(k, &evid, &room.timeline.events, last).unwrap_or(evid);
+                            tx.send(BKResponse::FullyRead(k.clone(), evid)).unwrap();
+                        }
+                    }
                     // Other events
                     match parse_sync_events(&r) {
                         Err(err) => tx.send(BKResponse::SyncError(err)).unwrap(),
                         Ok(events) => {
                             for ev in events {
-                                match ev.stype.as_ref() {
-                                    "m.room.name" => {
-                                        let name = strn!(ev.content["name"].as_str().unwrap_or(""));
-                                        tx.send(BKResponse::RoomName(ev.room.clone(), name)).unwrap();
+                                let room = ev.room.clone();
+                                match ev.content {
+                                    EventContent::Name(ref c) => {
+                                        tx.send(BKResponse::RoomName(room, c.name.clone())).unwrap();
                                     }
-                                    "m.room.topic" => {
-                                        let t = strn!(ev.content["topic"].as_str().unwrap_or(""));
-                                        tx.send(BKResponse::RoomTopic(ev.room.clone(), t)).unwrap();
+                                    EventContent::Topic(ref c) => {
+                                        tx.send(BKResponse::RoomTopic(room, c.topic.clone())).unwrap();
                                     }
-                                    "m.room.avatar" => {
-                                        tx.send(BKResponse::NewRoomAvatar(ev.room.clone())).unwrap();
+                                    EventContent::Avatar(_) => {
+                                        tx.send(BKResponse::NewRoomAvatar(room)).unwrap();
                                     }
-                                    "m.room.member" => {
-                                        tx.send(BKResponse::RoomMemberEvent(ev)).unwrap();
+                                    EventContent::Member(_) => {
+                                        tx.send(BKResponse::RoomMemberEvent(ev.clone())).unwrap();
                                     }
-                                    "m.sticker" => {
-                                        // This event is managed in the room list
+                                    EventContent::PowerLevels(ref c) => {
+                                        tx.send(BKResponse::RoomPowerLevels(room, c.users.clone())).unwrap();
+                                    }
+                                    EventContent::Encryption(_) => {
+                                        tx.send(BKResponse::RoomEncrypted(room)).unwrap();
+                                    }
+                                    EventContent::Redaction(ref c) => {
+                                        client.apply_redaction(&room, &c.redacts);
+                                        tx.send(BKResponse::Redacted(room, c.redacts.clone())).unwrap();
+                                    }
+                                    EventContent::Sticker(_) | EventContent::Reaction(_) => {
+                                        // These events are managed with the room messages
                                     }
                                     _ => {
-                                        println!("EVENT NOT MANAGED: {:?}", ev);
+                                        tx.send(BKResponse::RoomEvent(ev.clone())).unwrap();
                                     }
                                 }
                             }
@@ -133,8 +274,19 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
                     };
                 }
 
-                tx.send(BKResponse::Sync(next_batch.clone())).unwrap();
-                data.lock().unwrap().since = next_batch;
+                // The messages of previous syncs that the new room keys can decrypt
+                for (roomid, msgs) in client.late_decrypted() {
+                    tx.send(BKResponse::MessagesDecrypted(roomid, msgs)).unwrap();
+                }
+
+                // Presence, sent after the rooms so the members are already known
+                let presence = parse_presence(&r.presence.events);
+                if !presence.is_empty() {
+                    tx.send(BKResponse::Presence(presence)).unwrap();
+                }
+
+                data.lock().unwrap().since = next_batch.clone();
+                tx.send(BKResponse::Sync(next_batch)).unwrap();
             },
             Err(err) => {
                 // we wait if there's an error to avoid 100% CPU
@@ -150,7 +302,105 @@ pub fn sync(bk: &Backend) -> Result<(), Error> {
     Ok(())
 }
 
+/// The filter used in every sync, the timeline is limited to the last page of messages and the
+/// members are lazy loaded, so only the members that sent those messages come in the state
+pub fn sync_filter() -> Filter {
+    Filter::new()
+        .event_format("client")
+        .event_fields(&["type", "content", "sender", "event_id", "state_key",
+                        "origin_server_ts", "age", "unsigned", "redacts"])
+        .presence(EventFilter::new()
+            .types(&["m.presence"]))
+        .room(RoomFilter::new()
+            .state(RoomEventFilter::new()
+                .types(&["m.room.*"])
+                .lazy_load_members(true))
+            .timeline(RoomEventFilter::new()
+                .limit(globals::PAGE_LIMIT))
+            .ephemeral(RoomEventFilter::new()
+                .types(&["m.typing", "m.receipt"])))
+}
+
 pub fn force_sync(bk: &Backend) -> Result<(), Error> {
     bk.data.lock().unwrap().since = String::from("");
+    if let Some(ref store) = bk.client.store {
+        store.clear()?;
+    }
     sync(bk)
 }
+
+/// Sends the stored rooms for `userid` and returns the stored `since` token, or None if there's
+/// nothing stored for this user
+/// Fills the gap before the last sync of a room in its own thread, so the sync doesn't wait for
+/// it. The messages of the gap are sent with the newer ones that wait in `gap_fills`.
+fn fill_gap(client: &Client, tx: &Sender<BKResponse>, data: &Arc<Mutex<BackendData>>, roomid: &str) {
+    let client = client.clone();
+    let tx = tx.clone();
+    let data = data.clone();
+    let roomid = roomid.to_string();
+
+    thread::spawn(move || {
+        let mut msgs = vec![];
+        match client.fill_gap(&roomid) {
+            Ok(filled) => {
+                if !filled.members.is_empty() {
+                    tx.send(BKResponse::RoomSenders(roomid.clone(), filled.members)).unwrap();
+                }
+                if !filled.reactions.is_empty() {
+                    tx.send(BKResponse::Reactions(roomid.clone(), filled.reactions)).unwrap();
+                }
+                msgs.extend(filled.messages);
+            }
+            Err(err) => tx.send(BKResponse::RoomMessagesError(err)).unwrap(),
+        };
+
+        msgs.extend(data.lock().unwrap().gap_fills.remove(&roomid).unwrap_or_default());
+        if !msgs.is_empty() {
+            tx.send(BKResponse::RoomMessages(msgs)).unwrap();
+        }
+    });
+}
+
+fn restore_from_store(bk: &Backend, store: &dyn Store, userid: &str) -> Option<String> {
+    let session = match store.session() {
+        Ok(Some(session)) => session,
+        _ => return None,
+    };
+
+    if session.user_id != userid || session.since.is_empty() {
+        if let Err(err) = store.clear() {
+            eprintln!("Error clearing the store: {:?}", err);
+        }
+        return None;
+    }
+
+    // the stored events are encrypted, the room keys are in the stored crypto state
+    if let Err(err) = bk.client.init_crypto() {
+        eprintln!("Error setting up the encryption: {:?}", err);
+    }
+    let decrypt = |roomid: &str, evs: &mut Vec<JsonValue>| bk.client.decrypt_events(roomid, evs);
+
+    match load_rooms(store, userid, &decrypt) {
+        Ok(rooms) => {
+            // the timelines are restored too, so the next limited sync knows there's a gap
+            let mut timelines = HashMap::new();
+            for room in rooms.iter() {
+                match load_timeline(store, &room.id, &session.since, &decrypt) {
+                    Ok(t) => { timelines.insert(room.id.clone(), t); }
+                    Err(err) => eprintln!("Error loading the stored timeline: {:?}", err),
+                };
+            }
+            bk.data.lock().unwrap().timelines = timelines;
+
+            let jtr = bk.data.lock().unwrap().join_to_room.clone();
+            let def = rooms.iter().find(|x| !jtr.is_empty() && x.id == jtr).cloned();
+            bk.tx.send(BKResponse::Rooms(rooms, def)).unwrap();
+            bk.data.lock().unwrap().since = session.since.clone();
+            Some(session.since)
+        }
+        Err(err) => {
+            eprintln!("Error loading the stored rooms: {:?}", err);
+            None
+        }
+    }
+}
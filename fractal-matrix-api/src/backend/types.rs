@@ -1,27 +1,50 @@
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex, Condvar};
+use std::sync::atomic::AtomicBool;
 use std::sync::mpsc::Sender;
 
 use error::Error;
 
 use types::Message;
+use types::Device;
 use types::Member;
+use types::PresenceList;
+use types::PresenceState;
 use types::Protocol;
 use types::Room;
+use types::Receipts;
+use types::Reaction;
 use types::Event;
 use types::StickerGroup;
 use types::Sticker;
 use types::UserInfo;
+use types::ServerInfo;
+use types::Timeline;
+
+use uia::AuthData;
+use uia::PendingAuth;
+use uia::UiaState;
 
 use cache::CacheMap;
+use client::Client;
 use url::Url;
 
 #[derive(Debug)]
 pub enum BKCommand {
     Login(String, String, String),
+    // the server and if CAS should be used instead of SSO
+    SsoLogin(String, bool),
+    // stops waiting for the browser of the single sign-on
+    SsoCancel,
     SetToken(String, String, String),
     Logout,
-    #[allow(dead_code)]
     Register(String, String, String),
+    // a server name or the URL of a homeserver
+    DiscoverServer(String),
+    // the username to check and the server
+    RegisterAvailable(String, String),
+    // the identity server, the email and the client secret
+    RegisterEmailToken(String, String, String),
     #[allow(dead_code)]
     Guest(String),
     GetUsername,
@@ -32,15 +55,25 @@ pub enum BKCommand {
     SubmitPhoneToken(String, String, String, String),
     AddThreePID(String, String, String),
     DeleteThreePID(String, String),
-    ChangePassword(String, String, String),
-    AccountDestruction(String, String, bool),
+    // the old and the new password
+    ChangePassword(String, String),
+    // the password and if the account data should be erased
+    AccountDestruction(String, bool),
     GetAvatar,
     SetUserAvatar(String),
+    SetPresence(PresenceState, Option<String>),
+    // completes the next stage of the authentication in progress
+    UiaAuth(AuthData),
+    UiaCancel,
+    GetDevices,
+    RenameDevice(String, String),
+    // the id of the device and the user password
+    DeleteDevice(String, String),
     Sync,
     SyncForced,
     GetRoomMembers(String),
     GetRoomMessages(String),
-    GetMessageContext(Message),
+    BackPaginate(String),
     GetRoomAvatar(String),
     GetThumbAsync(String, Sender<String>),
     GetMediaAsync(String, Sender<String>),
@@ -50,12 +83,17 @@ pub enum BKCommand {
     GetMediaUrl(String, Sender<String>),
     GetUserInfoAsync(String, Sender<(String, String)>),
     SendMsg(Message),
+    // the id of the edited message and a message with the new content
+    EditMsg(String, Message),
     SetRoom(Room),
     ShutDown,
     DirectoryProtocols,
     DirectorySearch(String, String, String, bool),
     JoinRoom(String),
     MarkAsRead(String, String),
+    SetTyping(String, bool, i32),
+    Redact(String, String, Option<String>),
+    SendReaction(String, String, String),
     LeaveRoom(String),
     SetRoomName(String, String),
     SetRoomTopic(String, String),
@@ -78,7 +116,15 @@ pub enum BKCommand {
 pub enum BKResponse {
     ShutDown,
     Token(String, String),
+    // the URL to open in the browser to login with SSO
+    SsoUrl(String),
     Logout,
+    // the server name or URL that was discovered and its homeserver
+    ServerDiscovered(String, ServerInfo),
+    // the username and if it can be registered
+    RegisterAvailable(String, bool),
+    // the sid and the client secret of the email validation
+    RegisterEmailToken(String, String),
     Name(String),
     SetUserName(String),
     GetThreePID(Vec<UserInfo>),
@@ -91,6 +137,12 @@ pub enum BKResponse {
     DeleteThreePID,
     ChangePassword,
     AccountDestruction,
+    // the authentication in progress needs a stage that the user has to complete
+    UiaStage(UiaState),
+    // the devices of the account and the id of ours
+    Devices(Vec<Device>, String),
+    RenamedDevice(String, String),
+    DeletedDevice(String),
     Avatar(String),
     SetUserAvatar(String),
     Sync(String),
@@ -100,10 +152,27 @@ pub enum BKResponse {
     RoomAvatar(String, String),
     NewRoomAvatar(String),
     RoomMemberEvent(Event),
+    RoomPowerLevels(String, HashMap<String, i32>),
+    RoomEvent(Event),
     RoomMessages(Vec<Message>),
     RoomMessagesInit(Vec<Message>),
-    RoomMessagesTo(Vec<Message>),
+    RoomMessagesBackPaginated(String, Vec<Message>, Option<String>),
     RoomMembers(String, Vec<Member>),
+    // the lazy loaded members that sent some loaded messages, to add to the known members
+    RoomSenders(String, Vec<Member>),
+    Typing(String, Vec<String>),
+    // a message of a room was redacted, by us or by other user
+    Redacted(String, String),
+    // messages received before their room key, that could be decrypted now
+    MessagesDecrypted(String, Vec<Message>),
+    // new reactions to the messages of a room, as (reacted event id, reaction)
+    Reactions(String, Vec<(String, Reaction)>),
+    // the last presence of the users that changed it
+    Presence(PresenceList),
+    // the new read receipts of a room
+    Receipts(String, Receipts),
+    // the new position of our m.fully_read marker in a room
+    FullyRead(String, String),
     SentMsg(String, String),
     DirectoryProtocols(Vec<Protocol>),
     DirectorySearch(Vec<Room>),
@@ -115,6 +184,7 @@ pub enum BKResponse {
     SetRoomAvatar,
     RoomName(String, String),
     RoomTopic(String, String),
+    RoomEncrypted(String),
     Media(String),
     MediaUrl(Url),
     AttachedFile(Message),
@@ -138,7 +208,16 @@ pub enum BKResponse {
     AccountDestructionError(Error),
     AvatarError(Error),
     SetUserAvatarError(Error),
+    SetPresenceError(Error),
+    UiaError(Error),
+    DevicesError(Error),
+    RenameDeviceError(Error),
+    DeleteDeviceError(Error),
     LoginError(Error),
+    RegisterError(Error),
+    DiscoverServerError(Error),
+    RegisterAvailableError(Error),
+    RegisterEmailTokenError(Error),
     LogoutError(Error),
     GuestLoginError(Error),
     SyncError(Error),
@@ -147,11 +226,15 @@ pub enum BKResponse {
     RoomMessagesError(Error),
     RoomMembersError(Error),
     SendMsgError(Error),
+    EditMsgError(Error),
     SetRoomError(Error),
     CommandError(Error),
     DirectoryError(Error),
     JoinRoomError(Error),
     MarkAsReadError(Error),
+    SetTypingError(Error),
+    RedactError(Error),
+    SendReactionError(Error),
     LeaveRoomError(Error),
     SetRoomNameError(Error),
     SetRoomTopicError(Error),
@@ -177,6 +260,8 @@ pub enum RoomType {
 pub struct BackendData {
     pub user_id: String,
     pub access_token: String,
+    // the device of the session, empty when the session was restored with a token
+    pub device_id: String,
     pub server_url: String,
     pub scalar_token: Option<String>,
     pub scalar_url: String,
@@ -184,11 +269,47 @@ pub struct BackendData {
     pub since: String,
     pub rooms_since: String,
     pub join_to_room: String,
+    // the known history of each room, to paginate and to fill gaps
+    pub timelines: HashMap<String, Timeline>,
+    // the id of the uploaded sync filter, the filter is uploaded again when it's None
+    pub filter_id: Option<String>,
+    // the user-interactive authentication waiting for the next stage
+    pub uia: Option<PendingAuth>,
+    // cancels the user-interactive authentication whose request is in flight
+    pub uia_cancel: Option<Arc<AtomicBool>>,
+    // cancels the single sign-on that is waiting for the browser
+    pub sso_cancel: Option<Arc<AtomicBool>>,
+    // the rooms whose gap is being filled, with the newer messages that wait for the gap
+    pub gap_fills: HashMap<String, Vec<Message>>,
+}
+
+impl BackendData {
+    pub fn new() -> BackendData {
+        BackendData {
+            user_id: String::from("Guest"),
+            access_token: String::from(""),
+            device_id: String::new(),
+            server_url: String::from("https://matrix.org"),
+            scalar_token: None,
+            scalar_url: String::from("https://scalar.vector.im"),
+            sticker_widget: None,
+            since: String::from(""),
+            rooms_since: String::from(""),
+            join_to_room: String::from(""),
+            timelines: HashMap::new(),
+            filter_id: None,
+            uia: None,
+            uia_cancel: None,
+            sso_cancel: None,
+            gap_fills: HashMap::new(),
+        }
+    }
 }
 
 pub struct Backend {
     pub tx: Sender<BKResponse>,
     pub data: Arc<Mutex<BackendData>>,
+    pub client: Client,
     pub internal_tx: Option<Sender<BKCommand>>,
 
     // user info cache, uid -> (name, avatar)
@@ -202,6 +323,7 @@ impl Clone for Backend {
         Backend {
             tx: self.tx.clone(),
             data: self.data.clone(),
+            client: self.client.clone(),
             internal_tx: self.internal_tx.clone(),
             user_info_cache: self.user_info_cache.clone(),
             limit_threads: self.limit_threads.clone(),
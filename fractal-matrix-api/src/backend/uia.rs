@@ -0,0 +1,107 @@
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+
+use error::Error;
+use client::Client;
+use uia::AuthData;
+use uia::PendingAuth;
+use uia::UiaRequest;
+use uia::UiaResponse;
+use uia::UiaState;
+
+use backend::types::BKResponse;
+use backend::types::Backend;
+
+
+/// Starts the authentication of `request`, any previous authentication is dropped.
+///
+/// The user `password` completes the password stage if it's needed
+pub fn start(bk: &Backend, request: UiaRequest, password: Option<String>) -> Result<(), Error> {
+    cancel(bk)?;
+    let cancelled = Arc::new(AtomicBool::new(false));
+    bk.data.lock().unwrap().uia_cancel = Some(cancelled.clone());
+
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        run(&client, &tx, &cancelled, request, None, None, password);
+    });
+
+    Ok(())
+}
+
+/// Completes the next stage of the authentication in progress
+pub fn auth(bk: &Backend, data: AuthData) -> Result<(), Error> {
+    let (pending, cancelled) = {
+        let mut d = bk.data.lock().unwrap();
+        let cancelled = d.uia_cancel.clone().ok_or(Error::BackendError)?;
+        (d.uia.take().ok_or(Error::BackendError)?, cancelled)
+    };
+
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        run(&client, &tx, &cancelled, pending.request, Some(pending.state), Some(data), pending.password);
+    });
+
+    Ok(())
+}
+
+/// Drops the authentication in progress, the request in flight doesn't ask for more stages
+pub fn cancel(bk: &Backend) -> Result<(), Error> {
+    let mut data = bk.data.lock().unwrap();
+    data.uia = None;
+    if let Some(cancelled) = data.uia_cancel.take() {
+        cancelled.store(true, Ordering::SeqCst);
+    }
+    Ok(())
+}
+
+fn run(client: &Client, tx: &Sender<BKResponse>, cancelled: &AtomicBool, request: UiaRequest, state: Option<UiaState>, data: Option<AuthData>, password: Option<String>) {
+    let r = client.authenticate(&request, state.as_ref(), data.as_ref(), password.as_ref().map(|p| p.as_str()));
+    match r {
+        Ok(UiaResponse::Done(r)) => done(client, tx, request, r),
+        Ok(UiaResponse::Auth(state)) => {
+            // the flag is checked with the data locked, so a cancel can't happen in between
+            let mut d = client.data.lock().unwrap();
+            if cancelled.load(Ordering::SeqCst) {
+                return;
+            }
+            d.uia = Some(PendingAuth {
+                request: request,
+                state: state.clone(),
+                password: password,
+            });
+            tx.send(BKResponse::UiaStage(state)).unwrap();
+        }
+        Err(err) => error(tx, request, err),
+    };
+}
+
+/// Sends the response of the authenticated request
+fn done(client: &Client, tx: &Sender<BKResponse>, request: UiaRequest, r: JsonValue) {
+    match request {
+        UiaRequest::Register(..) => match client.registered(&r) {
+            Ok((uid, tk)) => tx.send(BKResponse::Token(uid, tk)).unwrap(),
+            Err(err) => tx.send(BKResponse::RegisterError(err)).unwrap(),
+        },
+        UiaRequest::ChangePassword(_) => tx.send(BKResponse::ChangePassword).unwrap(),
+        UiaRequest::Deactivate(_) => tx.send(BKResponse::AccountDestruction).unwrap(),
+        UiaRequest::DeleteDevice(device) => tx.send(BKResponse::DeletedDevice(device)).unwrap(),
+    };
+}
+
+fn error(tx: &Sender<BKResponse>, request: UiaRequest, err: Error) {
+    match request {
+        UiaRequest::Register(..) => tx.send(BKResponse::RegisterError(err)).unwrap(),
+        UiaRequest::ChangePassword(_) => tx.send(BKResponse::ChangePasswordError(err)).unwrap(),
+        UiaRequest::Deactivate(_) => tx.send(BKResponse::AccountDestructionError(err)).unwrap(),
+        UiaRequest::DeleteDevice(_) => tx.send(BKResponse::DeleteDeviceError(err)).unwrap(),
+    };
+}
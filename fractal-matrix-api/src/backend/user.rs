@@ -10,58 +10,95 @@ use std::thread;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
 use error::Error;
-use util::json_q;
 use util::build_url;
-use util::put_media;
 #[cfg(feature = "gfx")]
 use util::get_user_avatar;
 #[cfg(feature = "gfx")]
 use util::get_user_avatar_img;
 use backend::types::BKResponse;
 use backend::types::Backend;
+use backend::uia;
+use uia::UiaRequest;
 
 use types::Member;
+use types::PresenceState;
 use types::UserInfo;
 
 use self::serde_json::Value as JsonValue;
 
 
 pub fn get_username(bk: &Backend) -> Result<(), Error> {
-    let id = bk.data.lock().unwrap().user_id.clone();
-    let url = bk.url(&format!("profile/{}/displayname", id.clone()), vec![])?;
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    get!(&url,
-        |r: JsonValue| {
-            let name = String::from(r["displayname"].as_str().unwrap_or(&id));
-            tx.send(BKResponse::Name(name)).unwrap();
-        },
-        |err| { tx.send(BKResponse::UserNameError(err)).unwrap() }
-    );
+    thread::spawn(move || {
+        match client.get_username() {
+            Ok(name) => tx.send(BKResponse::Name(name)).unwrap(),
+            Err(err) => tx.send(BKResponse::UserNameError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
 pub fn set_username(bk: &Backend, name: String) -> Result<(), Error> {
-    let id = bk.data.lock().unwrap().user_id.clone();
-    let url = bk.url(&format!("profile/{}/displayname", id.clone()), vec![])?;
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.set_username(&name) {
+            Ok(_) => tx.send(BKResponse::SetUserName(name)).unwrap(),
+            Err(err) => tx.send(BKResponse::SetUserNameError(err)).unwrap(),
+        };
+    });
 
-    let attrs = json!({
-        "displayname": name,
+    Ok(())
+}
+
+pub fn set_presence(bk: &Backend, presence: PresenceState, status_msg: Option<String>) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        if let Err(err) = client.set_presence(presence, status_msg.as_ref().map(|s| s.as_str())) {
+            tx.send(BKResponse::SetPresenceError(err)).unwrap();
+        }
     });
 
+    Ok(())
+}
+
+pub fn get_devices(bk: &Backend) -> Result<(), Error> {
+    let client = bk.client.clone();
     let tx = bk.tx.clone();
-    query!("put", &url, &attrs,
-        |_| { tx.send(BKResponse::SetUserName(name)).unwrap(); },
-        |err| { tx.send(BKResponse::SetUserNameError(err)).unwrap(); }
-    );
+    thread::spawn(move || {
+        match client.devices() {
+            Ok((devices, current)) => tx.send(BKResponse::Devices(devices, current)).unwrap(),
+            Err(err) => tx.send(BKResponse::DevicesError(err)).unwrap(),
+        };
+    });
 
     Ok(())
 }
 
+pub fn rename_device(bk: &Backend, device: String, name: String) -> Result<(), Error> {
+    let client = bk.client.clone();
+    let tx = bk.tx.clone();
+    thread::spawn(move || {
+        match client.rename_device(&device, &name) {
+            Ok(_) => tx.send(BKResponse::RenamedDevice(device, name)).unwrap(),
+            Err(err) => tx.send(BKResponse::RenameDeviceError(err)).unwrap(),
+        };
+    });
+
+    Ok(())
+}
+
+pub fn delete_device(bk: &Backend, device: String, password: String) -> Result<(), Error> {
+    uia::start(bk, UiaRequest::DeleteDevice(device), Some(password))
+}
+
 pub fn get_threepid(bk: &Backend) -> Result<(), Error> {
     let url = bk.url(&format!("account/3pid"), vec![])?;
     let tx = bk.tx.clone();
-    get!(&url,
+    get!(bk.transport(), &url,
         |r: JsonValue| {
             let mut result: Vec<UserInfo> = vec![];
             if let Some(arr) = r["threepids"].as_array() {
@@ -110,7 +147,7 @@ pub fn get_email_token(bk: &Backend, identity: String, email: String, client_sec
     });
 
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
           |r: JsonValue| {
               let sid = String::from(r["sid"].as_str().unwrap_or(""));
               tx.send(BKResponse::GetTokenEmail(sid, client_secret)).unwrap();
@@ -140,7 +177,7 @@ pub fn get_phone_token(bk: &Backend, identity: String, phone: String, client_sec
     });
 
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
           |r: JsonValue| {
               let sid = String::from(r["sid"].as_str().unwrap_or(""));
               tx.send(BKResponse::GetTokenPhone(sid, client_secret)).unwrap();
@@ -170,7 +207,7 @@ pub fn add_threepid(bk: &Backend, identity: String, client_secret: String, sid:
     });
 
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
           |_r: JsonValue| {
               tx.send(BKResponse::AddThreePID(sid)).unwrap();
           },
@@ -191,7 +228,7 @@ pub fn submit_phone_token(bk: &Backend, url: String, client_secret: String, sid:
     let url = build_url(&Url::parse(&url)?, path, params)?;
 
     let tx = bk.tx.clone();
-    post!(&url,
+    post!(bk.transport(), &url,
           |r: JsonValue| {
               let result = if r["success"] == true {
                   Some(sid)
@@ -220,7 +257,7 @@ pub fn delete_three_pid(bk: &Backend, medium: String, address: String) -> Result
     });
 
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
           |_r: JsonValue| {
               tx.send(BKResponse::DeleteThreePID).unwrap();
           },
@@ -231,56 +268,12 @@ pub fn delete_three_pid(bk: &Backend, medium: String, address: String) -> Result
     Ok(())
 }
 
-pub fn change_password(bk: &Backend, username: String, old_password: String, new_password: String) -> Result<(), Error> {
-    let url = bk.url(&format!("account/password"), vec![])?;
-
-    let attrs = json!({
-        "new_password": new_password,
-        "auth": {
-            "type": "m.login.password",
-            "user": username,
-            "password": old_password,
-        }
-    });
-
-    let tx = bk.tx.clone();
-    post!(&url, &attrs,
-          |r: JsonValue| {
-              println!("{}", r);
-              tx.send(BKResponse::ChangePassword).unwrap();
-          },
-          |err| {
-              tx.send(BKResponse::ChangePasswordError(err)).unwrap();
-          }
-         );
-
-    Ok(())
+pub fn change_password(bk: &Backend, old_password: String, new_password: String) -> Result<(), Error> {
+    uia::start(bk, UiaRequest::ChangePassword(new_password), Some(old_password))
 }
 
-pub fn account_destruction(bk: &Backend, username: String, password: String, flag: bool) -> Result<(), Error> {
-    let url = bk.url(&format!("account/deactivate"), vec![])?;
-
-    let attrs = json!({
-        "erase": flag,
-        "auth": {
-            "type": "m.login.password",
-            "user": username,
-            "password": password,
-        }
-    });
-
-    let tx = bk.tx.clone();
-    post!(&url, &attrs,
-          |r: JsonValue| {
-              println!("{}", r);
-              tx.send(BKResponse::AccountDestruction).unwrap();
-          },
-          |err| {
-              tx.send(BKResponse::AccountDestructionError(err)).unwrap();
-          }
-         );
-
-    Ok(())
+pub fn account_destruction(bk: &Backend, password: String, flag: bool) -> Result<(), Error> {
+    uia::start(bk, UiaRequest::Deactivate(flag), Some(password))
 }
 
 #[cfg(feature = "gfx")]
@@ -288,8 +281,9 @@ pub fn get_avatar(bk: &Backend) -> Result<(), Error> {
     let baseu = bk.get_base_url()?;
     let userid = bk.data.lock().unwrap().user_id.clone();
 
+    let tp = bk.transport();
     let tx = bk.tx.clone();
-    thread::spawn(move || match get_user_avatar(&baseu, &userid) {
+    thread::spawn(move || match get_user_avatar(&*tp, &baseu, &userid) {
         Ok((_, fname)) => {
             tx.send(BKResponse::Avatar(fname)).unwrap();
         }
@@ -325,9 +319,10 @@ pub fn get_user_info_async(bk: &mut Backend,
 
     #[cfg(feature = "gfx")]
     {
+        let tp = bk.transport();
         semaphore!(bk.limit_threads, {
             let i0 = info.lock();
-            match get_user_avatar(&baseu, &u) {
+            match get_user_avatar(&*tp, &baseu, &u) {
                 Ok(info) => {
                     tx.send(info.clone()).unwrap();
                     let mut i = i0.unwrap();
@@ -361,8 +356,9 @@ pub fn get_avatar_async(bk: &Backend, member: Option<Member>, tx: Sender<String>
     let alias = m.get_alias();
     let avatar = m.avatar.clone();
 
+    let tp = bk.transport();
     semaphore!(bk.limit_threads, {
-        match get_user_avatar_img(&baseu, uid,
+        match get_user_avatar_img(&*tp, &baseu, uid,
                                   alias,
                                   avatar.unwrap_or_default()) {
             Ok(fname) => { tx.send(fname.clone()).unwrap(); }
@@ -385,17 +381,18 @@ pub fn set_user_avatar(bk: &Backend, avatar: String) -> Result<(), Error> {
     let mut contents: Vec<u8> = vec![];
     file.read_to_end(&mut contents)?;
 
+    let tp = bk.transport();
     let tx = bk.tx.clone();
     thread::spawn(
         move || {
-            match put_media(mediaurl.as_str(), contents) {
+            match tp.put_media(mediaurl.as_str(), contents) {
                 Err(err) => {
                     tx.send(BKResponse::SetUserAvatarError(err)).unwrap();
                 }
                 Ok(js) => {
                     let uri = js["content_uri"].as_str().unwrap_or("");
                     let attrs = json!({ "avatar_url": uri });
-                    match json_q("put", &url, &attrs, 0) {
+                    match tp.json_q("put", &url, &attrs, 0) {
                         Ok(_) => {
                             tx.send(BKResponse::SetUserAvatar(avatar)).unwrap();
                         },
@@ -418,7 +415,7 @@ pub fn search(bk: &Backend, term: String) -> Result<(), Error> {
     });
 
     let tx = bk.tx.clone();
-    post!(&url, &attrs,
+    post!(bk.transport(), &url, &attrs,
         |js: JsonValue| {
             let mut users: Vec<Member> = vec![];
             if let Some(arr) = js["results"].as_array() {
@@ -0,0 +1,1223 @@
+extern crate serde_json;
+extern crate url;
+extern crate urlencoding;
+extern crate regex;
+extern crate md5;
+extern crate chrono;
+
+use self::serde_json::Value as JsonValue;
+use self::url::Url;
+use self::regex::Regex;
+use self::chrono::prelude::*;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use globals;
+use error::Error;
+
+use util::build_url;
+use util::parse_room_member;
+use util::parse_reactions;
+use transport::Transport;
+use transport::ReqwestTransport;
+use store::Store;
+use crypto::Crypto;
+use crypto::DeviceKeys;
+use uia;
+use uia::AuthData;
+use uia::UiaRequest;
+use uia::UiaResponse;
+use uia::UiaState;
+
+use backend::BackendData;
+use backend::RoomType;
+
+use types::Device;
+use types::ServerInfo;
+use types::Member;
+use types::PresenceState;
+use types::Message;
+use types::Room;
+use types::Timeline;
+use types::SyncTimeline;
+use types::MessagesPage;
+use types::Filter;
+use types::RoomEventFilter;
+use types::SyncResponse;
+
+/// Synchronous access to the matrix client-server API.
+///
+/// Every method blocks until the homeserver answers and returns the result directly, so it can
+/// be used from tools and tests without the `BKCommand` channel. The `Backend` holds one of these
+/// and forwards the results as `BKResponse`s.
+#[derive(Clone)]
+pub struct Client {
+    pub data: Arc<Mutex<BackendData>>,
+    pub transport: Arc<dyn Transport>,
+    pub store: Option<Arc<dyn Store>>,
+    // the encryption state of the device, None until the keys are set up
+    pub crypto: Arc<Mutex<Option<Crypto>>>,
+    // the key of the stored crypto data, without it the crypto data isn't stored
+    pub pickle_key: Option<[u8; 32]>,
+}
+
+impl Client {
+    /// Creates a new client that talks to the homeserver at `server_url`
+    pub fn new(server_url: &str) -> Client {
+        let mut data = BackendData::new();
+        data.server_url = server_url.to_string();
+        Client::from_data(Arc::new(Mutex::new(data)))
+    }
+
+    /// Creates a client that shares the session data with other clients or a `Backend`
+    pub fn from_data(data: Arc<Mutex<BackendData>>) -> Client {
+        Client::with_transport(data, Arc::new(ReqwestTransport))
+    }
+
+    /// Creates a client that makes every request through `transport`
+    pub fn with_transport(data: Arc<Mutex<BackendData>>, transport: Arc<dyn Transport>) -> Client {
+        Client {
+            data: data,
+            transport: transport,
+            store: None,
+            crypto: Arc::new(Mutex::new(None)),
+            pickle_key: None,
+        }
+    }
+
+    pub fn user_id(&self) -> String {
+        self.data.lock().unwrap().user_id.clone()
+    }
+
+    pub fn access_token(&self) -> String {
+        self.data.lock().unwrap().access_token.clone()
+    }
+
+    pub fn device_id(&self) -> String {
+        self.data.lock().unwrap().device_id.clone()
+    }
+
+    pub fn set_server(&self, server_url: String) {
+        self.data.lock().unwrap().server_url = server_url;
+    }
+
+    pub fn set_token(&self, token: String, uid: String) {
+        let mut data = self.data.lock().unwrap();
+        data.access_token = token;
+        data.user_id = uid;
+        data.since = String::new();
+        data.timelines.clear();
+        data.filter_id = None;
+        data.device_id = String::new();
+        *self.crypto.lock().unwrap() = None;
+    }
+
+    pub fn base_url(&self) -> Result<Url, Error> {
+        let s = self.data.lock().unwrap().server_url.clone();
+        let url = Url::parse(&s)?;
+        Ok(url)
+    }
+
+    /// Builds a client API url for `path` adding the `access_token` to the query params
+    pub fn url(&self, path: &str, params: Vec<(&str, String)>) -> Result<Url, Error> {
+        let base = self.base_url()?;
+        let tk = self.access_token();
+
+        let mut params2 = params.to_vec();
+        params2.push(("access_token", tk.clone()));
+
+        client_url!(&base, path, params2)
+    }
+
+    // Session
+
+    /// Logs in with user and password and stores the session in the client data.
+    ///
+    /// Returns the pair (user_id, access_token)
+    pub fn login(&self, user: &str, password: &str) -> Result<(String, String), Error> {
+        let attrs = build_login_attrs(user, password)?;
+        self.login_with(&attrs)
+    }
+
+    /// Logs in with the `loginToken` given by the single sign-on
+    pub fn login_token(&self, token: &str) -> Result<(String, String), Error> {
+        self.login_with(&build_token_login_attrs(token))
+    }
+
+    /// The URL to start the single sign-on in the browser, that redirects to `redirect` with
+    /// the login token. The CAS endpoint is used if `cas` is true
+    pub fn sso_url(&self, redirect: &str, cas: bool) -> Result<Url, Error> {
+        let path = match cas {
+            true => "login/cas/redirect",
+            false => "login/sso/redirect",
+        };
+
+        client_url!(&self.base_url()?, path, vec![("redirectUrl", redirect.to_string())])
+    }
+
+    fn login_with(&self, attrs: &JsonValue) -> Result<(String, String), Error> {
+        let url = self.url("login", vec![])?;
+
+        let r = self.transport.json_q("post", &url, attrs, globals::TIMEOUT)?;
+        let uid = String::from(r["user_id"].as_str().unwrap_or(""));
+        let tk = String::from(r["access_token"].as_str().unwrap_or(""));
+
+        if uid.is_empty() || tk.is_empty() {
+            return Err(Error::BackendError);
+        }
+
+        self.set_token(tk.clone(), uid.clone());
+        self.data.lock().unwrap().device_id = String::from(r["device_id"].as_str().unwrap_or(""));
+        Ok((uid, tk))
+    }
+
+    pub fn logout(&self) -> Result<(), Error> {
+        let url = self.url("logout", vec![])?;
+        self.transport.json_q("post", &url, &json!({}), globals::TIMEOUT)?;
+
+        self.set_token(String::new(), String::new());
+        if let Some(ref store) = self.store {
+            store.clear()?;
+            store.set_crypto(None)?;
+        }
+        Ok(())
+    }
+
+    // User
+
+    pub fn get_username(&self) -> Result<String, Error> {
+        let id = self.user_id();
+        let url = self.url(&format!("profile/{}/displayname", id), vec![])?;
+
+        let r = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+        Ok(String::from(r["displayname"].as_str().unwrap_or(&id)))
+    }
+
+    pub fn set_username(&self, name: &str) -> Result<(), Error> {
+        let id = self.user_id();
+        let url = self.url(&format!("profile/{}/displayname", id), vec![])?;
+        let attrs = json!({
+            "displayname": name,
+        });
+
+        self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Sets our presence, with an optional status message
+    pub fn set_presence(&self, presence: PresenceState, status_msg: Option<&str>) -> Result<(), Error> {
+        let id = self.user_id();
+        let url = self.url(&format!("presence/{}/status", id), vec![])?;
+        let mut attrs = json!({
+            "presence": presence.as_str(),
+        });
+        if let Some(msg) = status_msg {
+            attrs["status_msg"] = json!(msg);
+        }
+
+        self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    // Devices
+
+    /// The devices of the account and the id of the current one.
+    ///
+    /// The current device is asked to the server when the session was restored with a token,
+    /// and it's empty if the server doesn't know it
+    pub fn devices(&self) -> Result<(Vec<Device>, String), Error> {
+        let url = self.url("devices", vec![])?;
+        let r = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+        let devices: Vec<Device> = serde_json::from_value(r["devices"].clone())
+            .or(Err(Error::BackendError))?;
+
+        let mut current = self.device_id();
+        if current.is_empty() {
+            let url = self.url("account/whoami", vec![])?;
+            if let Ok(r) = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT) {
+                current = String::from(r["device_id"].as_str().unwrap_or(""));
+            }
+        }
+
+        Ok((devices, current))
+    }
+
+    pub fn rename_device(&self, device: &str, name: &str) -> Result<(), Error> {
+        let url = self.url(&format!("devices/{}", device), vec![])?;
+        let attrs = json!({
+            "display_name": name,
+        });
+
+        self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    // Discovery
+
+    /// Finds the homeserver of `server`, a server name like `example.org` or the URL of a
+    /// homeserver. The server name is resolved with its `.well-known` file, or used as the
+    /// homeserver without it. The homeserver is validated with its supported versions and its
+    /// login types are fetched
+    pub fn discover(&self, server: &str) -> Result<ServerInfo, Error> {
+        let mut info = ServerInfo::default();
+
+        if server.contains("://") {
+            info.homeserver = server.trim_right_matches('/').to_string();
+        } else {
+            let url = Url::parse(&format!("https://{}/.well-known/matrix/client", server))?;
+            match self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT) {
+                Ok(r) => {
+                    let base_url = |js: &JsonValue| {
+                        js["base_url"].as_str().map(|u| u.trim_right_matches('/').to_string())
+                    };
+                    info.homeserver = base_url(&r["m.homeserver"]).ok_or(Error::BackendError)?;
+                    info.identity_server = base_url(&r["m.identity_server"]);
+                }
+                Err(_) => info.homeserver = format!("https://{}", server),
+            };
+        }
+
+        let url = Url::parse(&format!("{}/_matrix/client/versions", info.homeserver))?;
+        let r = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+        info.versions = serde_json::from_value(r["versions"].clone())
+            .or(Err(Error::BackendError))?;
+
+        let url = Url::parse(&format!("{}/_matrix/client/r0/login", info.homeserver))?;
+        let r = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+        info.login_types = r["flows"].as_array().unwrap_or(&vec![]).iter()
+            .filter_map(|f| f["type"].as_str())
+            .map(|t| t.to_string())
+            .collect();
+
+        Ok(info)
+    }
+
+    // Registration
+
+    /// Checks if `username` can be registered, false if it's taken. Invalid usernames are
+    /// returned as errors
+    pub fn username_available(&self, username: &str) -> Result<bool, Error> {
+        let url = self.url("register/available", vec![("username", username.to_string())])?;
+
+        match self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT) {
+            Ok(r) => Ok(r["available"].as_bool().unwrap_or(false)),
+            Err(Error::MatrixError(ref js)) if js["errcode"] == "M_USER_IN_USE" => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Asks the identity server to send a validation link to `email`, for the email stage of
+    /// the registration. Returns the sid of the validation
+    pub fn register_email_token(&self, identity: &str, email: &str, client_secret: &str) -> Result<String, Error> {
+        let url = self.url("register/email/requestToken", vec![])?;
+        let attrs = json!({
+            "id_server": uia::id_server(identity),
+            "client_secret": client_secret,
+            "email": email,
+            "send_attempt": 1,
+        });
+
+        let r = self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+        match r["sid"].as_str() {
+            Some(sid) => Ok(sid.to_string()),
+            None => Err(Error::BackendError),
+        }
+    }
+
+    /// Logs in with the session created by a registration
+    pub fn registered(&self, r: &JsonValue) -> Result<(String, String), Error> {
+        let uid = String::from(r["user_id"].as_str().unwrap_or(""));
+        let tk = String::from(r["access_token"].as_str().unwrap_or(""));
+
+        if uid.is_empty() || tk.is_empty() {
+            return Err(Error::BackendError);
+        }
+
+        self.set_token(tk.clone(), uid.clone());
+        self.data.lock().unwrap().device_id = String::from(r["device_id"].as_str().unwrap_or(""));
+        Ok((uid, tk))
+    }
+
+    // User-interactive auth
+
+    /// Makes a request that needs user-interactive auth with an optional `auth` dict
+    pub fn uia_request(&self, request: &UiaRequest, auth: Option<JsonValue>) -> Result<UiaResponse, Error> {
+        let url = self.url(&request.path(), request.params())?;
+        let attrs = request.body(auth);
+
+        match self.transport.json_q(request.method(), &url, &attrs, globals::TIMEOUT) {
+            Ok(r) => Ok(UiaResponse::Done(r)),
+            Err(Error::MatrixError(js)) => match UiaState::from_json(&js) {
+                Some(state) => Ok(UiaResponse::Auth(state)),
+                None => Err(Error::MatrixError(js)),
+            },
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Starts or continues the authentication of `request`.
+    ///
+    /// Without `state` the request is made without auth to start a new session, otherwise
+    /// `data` completes the next stage. The dummy stage and the password stage, when the user
+    /// `password` is known, are completed without asking, once each, the other stages or a
+    /// repeated one are returned to the caller. A wrong `password` fails the request with the
+    /// server error
+    pub fn authenticate(&self, request: &UiaRequest, state: Option<&UiaState>, data: Option<&AuthData>, password: Option<&str>) -> Result<UiaResponse, Error> {
+        let auth = match (state, data) {
+            (Some(s), Some(d)) => Some(d.to_json(s.session.as_ref().map(|s| s.as_str()))),
+            _ => None,
+        };
+        let mut r = self.uia_request(request, auth)?;
+        // the stages completed here, so a server that asks for them again doesn't loop
+        let mut done: Vec<String> = vec![];
+
+        loop {
+            let state = match r {
+                UiaResponse::Done(_) => return Ok(r),
+                UiaResponse::Auth(ref state) => state.clone(),
+            };
+
+            let stage = match state.next_stage() {
+                Some(s) if !done.contains(&s) => s,
+                _ => return Ok(r),
+            };
+            let auto = match password {
+                Some(p) if stage == uia::PASSWORD => AuthData::Password(self.user_id(), p.to_string()),
+                _ if stage == uia::DUMMY => AuthData::Dummy,
+                _ => return Ok(r),
+            };
+
+            let session = state.session.as_ref().map(|s| s.as_str());
+            r = self.uia_request(request, Some(auto.to_json(session)))?;
+
+            if let (&AuthData::Password(..), &UiaResponse::Auth(ref state)) = (&auto, &r) {
+                if state.errcode.is_some() {
+                    return Err(Error::MatrixError(json!({
+                        "errcode": state.errcode,
+                        "error": state.error,
+                    })));
+                }
+            }
+            done.push(stage);
+        }
+    }
+
+    // Sync
+
+    /// Uploads a filter for the current user and returns its id
+    pub fn upload_filter(&self, filter: &Filter) -> Result<String, Error> {
+        let id = self.user_id();
+        let url = self.url(&format!("user/{}/filter", id), vec![])?;
+
+        let r = self.transport.json_q("post", &url, &filter.to_json(), globals::TIMEOUT)?;
+        match r["filter_id"].as_str() {
+            Some(fid) => Ok(fid.to_string()),
+            None => Err(Error::BackendError),
+        }
+    }
+
+    /// The id of the sync filter, `filter` is uploaded only the first time and the returned id
+    /// is reused until the session changes
+    pub fn filter_id(&self, filter: &Filter) -> Result<String, Error> {
+        if let Some(fid) = self.data.lock().unwrap().filter_id.clone() {
+            return Ok(fid);
+        }
+
+        let fid = self.upload_filter(filter)?;
+        self.data.lock().unwrap().filter_id = Some(fid.clone());
+        Ok(fid)
+    }
+
+    // Encryption
+
+    /// Loads the crypto data of this device from the store, or creates a new olm account and
+    /// uploads its keys.
+    ///
+    /// The device id comes from the login, or from the stored crypto data when the session is
+    /// restored with a token. Without a device id the encryption isn't available.
+    pub fn init_crypto(&self) -> Result<(), Error> {
+        if self.crypto.lock().unwrap().is_some() {
+            return Ok(());
+        }
+
+        let (userid, device_id) = {
+            let data = self.data.lock().unwrap();
+            (data.user_id.clone(), data.device_id.clone())
+        };
+        let stored = match (self.store.as_ref(), self.pickle_key.as_ref()) {
+            (Some(store), Some(key)) => store.crypto()?.map(|s| (s, key)),
+            _ => None,
+        };
+
+        let crypto = match stored {
+            Some((ref s, key)) if s.user_id == userid && (device_id.is_empty() || s.device_id == device_id) => {
+                Crypto::from_store(s, key)?
+            }
+            _ if !device_id.is_empty() => Crypto::new(&userid, &device_id),
+            _ => return Ok(()),
+        };
+        let uploaded = crypto.keys_uploaded();
+
+        self.data.lock().unwrap().device_id = crypto.device_id.clone();
+        *self.crypto.lock().unwrap() = Some(crypto);
+
+        if !uploaded {
+            self.upload_keys(0)?;
+        }
+        self.save_crypto()
+    }
+
+    /// Uploads the device keys, if they aren't uploaded yet, and the one time keys needed to
+    /// have half of the max number of keys in the server, that has `count` keys
+    pub fn upload_keys(&self, count: usize) -> Result<(), Error> {
+        let attrs = match self.crypto.lock().unwrap().as_mut() {
+            Some(crypto) => {
+                let mut attrs = json!({ "one_time_keys": crypto.one_time_keys(count)? });
+                if !crypto.keys_uploaded() {
+                    attrs["device_keys"] = crypto.device_keys()?;
+                }
+                attrs
+            }
+            None => return Ok(()),
+        };
+
+        let url = self.url("keys/upload", vec![])?;
+        self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+
+        if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+            crypto.mark_keys_as_published();
+        }
+        self.save_crypto()
+    }
+
+    /// Writes the crypto data to the store, it must be called after every change so the olm
+    /// and megolm ratchets are never reused
+    fn save_crypto(&self) -> Result<(), Error> {
+        let (store, key) = match (self.store.as_ref(), self.pickle_key.as_ref()) {
+            (Some(store), Some(key)) => (store.clone(), key),
+            _ => return Ok(()),
+        };
+
+        let stored = match self.crypto.lock().unwrap().as_ref() {
+            Some(crypto) => crypto.to_store(key)?,
+            None => return Ok(()),
+        };
+        store.set_crypto(Some(&stored))
+    }
+
+    /// Processes the encryption data of a sync: the room keys sent to this device, the rooms
+    /// with encryption enabled, the device list changes and the one time keys count.
+    ///
+    /// The encrypted events of the joined rooms timelines are replaced with the decrypted ones
+    pub fn sync_crypto(&self, r: &mut SyncResponse) -> Result<(), Error> {
+        let otk_count = match self.crypto.lock().unwrap().as_mut() {
+            Some(crypto) => {
+                for ev in r.to_device.events.iter().filter(|ev| ev["type"] == "m.room.encrypted") {
+                    let added = match crypto.decrypt_to_device(ev) {
+                        Ok(ref payload) if payload["type"] == "m.room_key" => crypto.add_room_key(payload),
+                        Ok(_) => Ok(()),
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = added {
+                        eprintln!("Error decrypting a to-device event from {}: {:?}", ev["sender"], err);
+                    }
+                }
+
+                for userid in r.device_lists.changed.iter() {
+                    crypto.mark_outdated(userid);
+                }
+                for userid in r.device_lists.left.iter() {
+                    crypto.forget_user(userid);
+                }
+
+                for (roomid, room) in r.rooms.join.iter_mut() {
+                    if room.state.events.iter().chain(room.timeline.events.iter()).any(|ev| ev["type"] == "m.room.encryption") {
+                        crypto.set_room_encrypted(roomid);
+                    }
+
+                    // the next messages shouldn't be readable by the members that left
+                    let left = room.timeline.events.iter().any(|ev| {
+                        ev["type"] == "m.room.member" &&
+                        ev["content"]["membership"] != "join" &&
+                        ev["content"]["membership"] != "invite"
+                    });
+                    if left {
+                        crypto.discard_outbound_session(roomid);
+                    }
+                    crypto.update_room_members(roomid, &room.state.events);
+                    crypto.update_room_members(roomid, &room.timeline.events);
+                }
+
+                match r.device_one_time_keys_count.get("signed_curve25519") {
+                    Some(&count) if crypto.needs_one_time_keys(count as usize) => Some(count as usize),
+                    _ => None,
+                }
+            }
+            None => return Ok(()),
+        };
+
+        if let Some(count) = otk_count {
+            self.upload_keys(count)?;
+        }
+        self.save_crypto()
+    }
+
+    /// Decrypts the timeline events of a sync, after the sync is stored with the events
+    /// encrypted
+    pub fn decrypt_sync(&self, r: &mut SyncResponse) {
+        let encrypted = r.rooms.join.values()
+            .any(|room| room.timeline.events.iter().any(|ev| ev["type"] == "m.room.encrypted"));
+        if !encrypted {
+            return;
+        }
+
+        if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+            for (roomid, room) in r.rooms.join.iter_mut() {
+                crypto.decrypt_events(roomid, &mut room.timeline.events);
+            }
+        }
+        if let Err(err) = self.save_crypto() {
+            eprintln!("Error saving the encryption state: {:?}", err);
+        }
+    }
+
+    /// Replaces the encrypted events of a room with the decrypted ones
+    pub fn decrypt_events(&self, roomid: &str, events: &mut Vec<JsonValue>) {
+        if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+            crypto.decrypt_events(roomid, events);
+        }
+    }
+
+    /// The events that were decrypted when their room key arrived, after they were received.
+    ///
+    /// They're replaced in the known timelines and returned as messages by room. The store keeps
+    /// them encrypted, they're decrypted again when they're loaded
+    pub fn late_decrypted(&self) -> Vec<(String, Vec<Message>)> {
+        let events = match self.crypto.lock().unwrap().as_mut() {
+            Some(crypto) => crypto.take_late_decrypted(),
+            None => return vec![],
+        };
+
+        let mut rooms: HashMap<String, Vec<JsonValue>> = HashMap::new();
+        for (roomid, ev) in events {
+            rooms.entry(roomid).or_insert(vec![]).push(ev);
+        }
+
+        let mut decrypted = vec![];
+        for (roomid, evs) in rooms {
+            let msgs = Message::from_json_events_iter(roomid.clone(), evs.iter());
+            if let Some(t) = self.data.lock().unwrap().timelines.get_mut(&roomid) {
+                for m in msgs.iter() {
+                    t.replace(m);
+                }
+            }
+            decrypted.push((roomid, msgs));
+        }
+
+        decrypted
+    }
+
+    /// Downloads the device keys of `users`, only the devices signed by themselves are kept
+    pub fn query_keys(&self, users: &[String]) -> Result<(), Error> {
+        let mut device_keys = json!({});
+        for u in users {
+            device_keys[u.as_str()] = json!([]);
+        }
+
+        let url = self.url("keys/query", vec![])?;
+        let attrs = json!({ "device_keys": device_keys, "timeout": 10000 });
+        let r = self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+
+        if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+            for u in users {
+                let devices: Vec<DeviceKeys> = r["device_keys"][u.as_str()].as_object()
+                    .map(|devices| {
+                        devices.iter()
+                            .filter_map(|(id, keys)| DeviceKeys::parse(keys).filter(|d| d.user_id == *u && d.device_id == *id))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                crypto.set_devices(u, devices);
+            }
+        }
+        self.save_crypto()
+    }
+
+    /// Claims a one time key of each device and creates an olm session with it, the devices
+    /// without one time keys are ignored
+    pub fn claim_keys(&self, devices: &[DeviceKeys]) -> Result<(), Error> {
+        let mut one_time_keys = json!({});
+        for d in devices {
+            one_time_keys[d.user_id.as_str()][d.device_id.as_str()] = json!("signed_curve25519");
+        }
+
+        let url = self.url("keys/claim", vec![])?;
+        let attrs = json!({ "one_time_keys": one_time_keys, "timeout": 10000 });
+        let r = self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+
+        if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+            for d in devices {
+                let otk = r["one_time_keys"][d.user_id.as_str()][d.device_id.as_str()].as_object()
+                    .and_then(|keys| keys.iter().find(|&(k, _)| k.starts_with("signed_curve25519:")))
+                    .map(|(_, v)| v.clone());
+                if let Some(otk) = otk {
+                    if let Err(err) = crypto.create_session(d, &otk) {
+                        eprintln!("Error creating an olm session with {} {}: {:?}", d.user_id, d.device_id, err);
+                    }
+                }
+            }
+        }
+        self.save_crypto()
+    }
+
+    /// Sends a to-device event, `messages` has the content for each device by user id
+    pub fn send_to_device(&self, evtype: &str, messages: &JsonValue) -> Result<(), Error> {
+        let seed = format!("{}{}{}", evtype, messages, Local::now().to_string());
+        let txn = format!("{:x}", md5::compute(seed.as_bytes()));
+        let url = self.url(&format!("sendToDevice/{}/{}", evtype, txn), vec![])?;
+
+        self.transport.json_q("put", &url, &json!({ "messages": messages }), globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Shares the key of our megolm session in an encrypted room with the devices of the
+    /// members that don't have it yet.
+    ///
+    /// The members are only requested the first time, then they're updated with the syncs, so
+    /// nothing is sent if the members and their devices didn't change
+    pub fn share_room_key(&self, roomid: &str) -> Result<(), Error> {
+        let known = self.crypto.lock().unwrap().as_ref().and_then(|c| c.room_members(roomid));
+        let members: Vec<String> = match known {
+            Some(members) => members,
+            None => {
+                let members: Vec<String> = self.room_members(roomid)?.into_iter().map(|m| m.uid).collect();
+                if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+                    crypto.set_room_members(roomid, &members);
+                }
+                members
+            }
+        };
+
+        let query = match self.crypto.lock().unwrap().as_ref() {
+            Some(crypto) => crypto.users_to_query(&members),
+            None => return Err(Error::CryptoError),
+        };
+        if !query.is_empty() {
+            self.query_keys(&query)?;
+        }
+
+        let (devices, without_session) = match self.crypto.lock().unwrap().as_mut() {
+            Some(crypto) => {
+                let devices = crypto.room_key_receivers(roomid, &members);
+                let without_session: Vec<DeviceKeys> = devices.iter()
+                    .filter(|d| !crypto.has_session(&d.curve25519))
+                    .cloned()
+                    .collect();
+                (devices, without_session)
+            }
+            None => return Err(Error::CryptoError),
+        };
+        if devices.is_empty() {
+            return Ok(());
+        }
+        if !without_session.is_empty() {
+            self.claim_keys(&without_session)?;
+        }
+
+        let mut messages = json!({});
+        let mut shared = vec![];
+        if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+            for d in devices {
+                if let Some(content) = crypto.encrypt_room_key(roomid, &d)? {
+                    messages[d.user_id.as_str()][d.device_id.as_str()] = content;
+                    shared.push(d);
+                }
+            }
+        }
+        // the olm sessions advanced even if the keys aren't sent
+        self.save_crypto()?;
+
+        if !shared.is_empty() {
+            self.send_to_device("m.room.encrypted", &messages)?;
+            if let Some(crypto) = self.crypto.lock().unwrap().as_mut() {
+                crypto.mark_room_key_shared(roomid, &shared);
+            }
+        }
+        self.save_crypto()
+    }
+
+    // Rooms
+
+    /// Sends a `m.room.message` event using the message id as transaction id.
+    ///
+    /// Returns the event id assigned by the server
+    pub fn send_message(&self, msg: &Message) -> Result<String, Error> {
+        let id = msg.id.clone().unwrap_or_default();
+
+        let mut attrs = json!({
+            "body": msg.body.clone(),
+            "msgtype": msg.mtype.clone()
+        });
+
+        if let Some(ref u) = msg.url {
+            attrs["url"] = json!(u);
+        }
+
+        if let (&Some(ref f), &Some(ref f_b)) = (&msg.format, &msg.formatted_body) {
+            attrs["formatted_body"] = json!(f_b);
+            attrs["format"] = json!(f);
+        }
+
+        if let Some(ref evid) = msg.in_reply_to {
+            attrs["m.relates_to"] = json!({ "m.in_reply_to": { "event_id": evid } });
+            // without the parent message the reply is sent without the quote
+            if let Ok(parent) = self.room_event(&msg.room, evid) {
+                let (body, formatted) = msg.reply_fallback(&parent);
+                attrs["body"] = json!(body);
+                attrs["formatted_body"] = json!(formatted);
+                attrs["format"] = json!("org.matrix.custom.html");
+            }
+        }
+
+        self.send_room_event(&msg.room, "m.room.message", &attrs, &id)
+    }
+
+    /// Sends a room event, encrypted if the room has encryption enabled, with `txn` as
+    /// transaction id.
+    ///
+    /// Returns the event id assigned by the server
+    pub fn send_room_event(&self, roomid: &str, evtype: &str, content: &JsonValue, txn: &str) -> Result<String, Error> {
+        let encrypted = match self.crypto.lock().unwrap().as_ref() {
+            Some(crypto) => crypto.is_room_encrypted(roomid),
+            None => false,
+        };
+
+        let (evtype, content) = match encrypted {
+            false => (evtype.to_string(), content.clone()),
+            true => {
+                self.share_room_key(roomid)?;
+                let encrypted = match self.crypto.lock().unwrap().as_mut() {
+                    Some(crypto) => crypto.encrypt_room_event(roomid, evtype, content)?,
+                    None => return Err(Error::CryptoError),
+                };
+                self.save_crypto()?;
+                (strn!("m.room.encrypted"), encrypted)
+            }
+        };
+
+        let url = self.url(&format!("rooms/{}/send/{}/{}", roomid, evtype, txn), vec![])?;
+        let js = self.transport.json_q("put", &url, &content, globals::TIMEOUT)?;
+        Ok(String::from(js["event_id"].as_str().unwrap_or_default()))
+    }
+
+    /// Gets a message of a room, from the known timeline or from the server
+    pub fn room_event(&self, roomid: &str, evid: &str) -> Result<Message, Error> {
+        let known = self.data.lock().unwrap().timelines.get(roomid)
+            .and_then(|t| t.find(evid));
+        if let Some(msg) = known {
+            return Ok(msg);
+        }
+
+        let url = self.url(&format!("rooms/{}/event/{}", roomid, evid), vec![])?;
+        let mut evs = vec![self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?];
+        self.decrypt_events(roomid, &mut evs);
+        Ok(Message::parse_room_message(roomid.to_string(), &evs[0]))
+    }
+
+    /// Sends a new version of the message `evid`.
+    ///
+    /// `msg` has the room and the new content, and its id is used as transaction id
+    pub fn edit_message(&self, evid: &str, msg: &Message) -> Result<String, Error> {
+        let id = msg.id.clone().unwrap_or_default();
+
+        let mut content = json!({
+            "body": msg.body.clone(),
+            "msgtype": msg.mtype.clone()
+        });
+        if let (&Some(ref f), &Some(ref f_b)) = (&msg.format, &msg.formatted_body) {
+            content["formatted_body"] = json!(f_b);
+            content["format"] = json!(f);
+        }
+
+        // clients without edit support show the fallback body as a new message
+        let mut attrs = json!({
+            "body": format!("* {}", msg.body),
+            "msgtype": msg.mtype.clone(),
+            "m.new_content": content,
+            "m.relates_to": {
+                "rel_type": "m.replace",
+                "event_id": evid,
+            },
+        });
+        if let (&Some(ref f), &Some(ref f_b)) = (&msg.format, &msg.formatted_body) {
+            attrs["formatted_body"] = json!(format!("* {}", f_b));
+            attrs["format"] = json!(f);
+        }
+
+        let edit_id = self.send_room_event(&msg.room, "m.room.message", &attrs, &id)?;
+        self.apply_edit(&msg.room, evid, msg);
+
+        Ok(edit_id)
+    }
+
+    /// Replaces the content of an edited message in the room timeline
+    pub fn apply_edit(&self, roomid: &str, evid: &str, edit: &Message) -> bool {
+        match self.data.lock().unwrap().timelines.get_mut(roomid) {
+            Some(t) => t.edit(evid, edit),
+            None => false,
+        }
+    }
+
+    /// Reacts to the message `evid` with `key`, returns the id of the reaction event
+    pub fn send_reaction(&self, roomid: &str, evid: &str, key: &str) -> Result<String, Error> {
+        let seed = format!("{}{}{}{}", roomid, evid, key, Local::now().to_string());
+        let txn = format!("{:x}", md5::compute(seed.as_bytes()));
+
+        let attrs = json!({
+            "m.relates_to": {
+                "rel_type": "m.annotation",
+                "event_id": evid,
+                "key": key,
+            }
+        });
+
+        self.send_room_event(roomid, "m.reaction", &attrs, &txn)
+    }
+
+    /// Redacts an event of a room and removes the message content from the known timeline.
+    ///
+    /// Returns the event id of the redaction
+    pub fn redact(&self, roomid: &str, evid: &str, reason: Option<&str>) -> Result<String, Error> {
+        let seed = format!("{}{}{}", roomid, evid, Local::now().to_string());
+        let txn = format!("{:x}", md5::compute(seed.as_bytes()));
+        let url = self.url(&format!("rooms/{}/redact/{}/{}", roomid, evid, txn), vec![])?;
+
+        let mut attrs = json!({});
+        if let Some(r) = reason {
+            attrs["reason"] = json!(r);
+        }
+
+        let js = self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        self.apply_redaction(roomid, evid);
+
+        Ok(String::from(js["event_id"].as_str().unwrap_or_default()))
+    }
+
+    /// Removes the content of a redacted message from the room timeline
+    pub fn apply_redaction(&self, roomid: &str, evid: &str) -> bool {
+        match self.data.lock().unwrap().timelines.get_mut(roomid) {
+            Some(t) => t.redact(evid),
+            None => false,
+        }
+    }
+
+    /// Gets up to `limit` events before the `from` pagination token, or from the end of the room
+    /// timeline if `from` is None, without going further than the `to` token.
+    ///
+    /// The members are lazy loaded, so the page only has the members that sent its messages.
+    /// The page `end` is None when there're no more events to load
+    pub fn room_messages(&self, roomid: &str, from: Option<String>, to: Option<String>, limit: i32) -> Result<MessagesPage, Error> {
+        let filter = RoomEventFilter::new().lazy_load_members(true);
+        let mut params = vec![
+            ("dir", strn!("b")),
+            ("limit", format!("{}", limit)),
+            ("filter", serde_json::to_string(&filter)?),
+        ];
+
+        if let Some(f) = from {
+            params.push(("from", f));
+        }
+        if let Some(ref t) = to {
+            params.push(("to", t.clone()));
+        }
+
+        let url = self.url(&format!("rooms/{}/messages", roomid), params)?;
+        let r = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+
+        let mut evs = r["chunk"].as_array().cloned().ok_or(Error::BackendError)?;
+        // the events are stored encrypted
+        let received = evs.clone();
+        self.decrypt_events(roomid, &mut evs);
+        // the pages can be shorter than `limit`, so the start of the room, or the `to` token, is
+        // reached when there's no `end`, it's the same token or there're no events
+        let end = match r["end"].as_str() {
+            Some(e) if !evs.is_empty() && r["start"] != e && to.as_ref().map(|t| t != e).unwrap_or(true) => {
+                Some(e.to_string())
+            }
+            _ => None,
+        };
+
+        let members = r["state"].as_array().unwrap_or(&vec![]).iter()
+            .filter(|ev| ev["type"] == "m.room.member")
+            .filter_map(parse_room_member)
+            .collect();
+
+        let (messages, edits) = Message::apply_edits(Message::from_json_events_iter(roomid.to_string(), evs.iter().rev()));
+        Ok(MessagesPage {
+            messages: messages,
+            end: end,
+            members: members,
+            reactions: parse_reactions(roomid, evs.iter().rev()),
+            events: received.into_iter().rev().collect(),
+            edits: edits,
+        })
+    }
+
+    /// Loads the last messages of a room, making at most `MAX_PAGES` requests to get
+    /// `PAGE_LIMIT` messages, and restarts the room timeline with them
+    pub fn load_room_timeline(&self, roomid: &str) -> Result<MessagesPage, Error> {
+        let mut page = MessagesPage::default();
+
+        for _ in 0..globals::MAX_PAGES {
+            let mut p = self.room_messages(roomid, page.end.clone(), None, globals::PAGE_LIMIT)?;
+            // the edits of the newer pages can target the messages of this one
+            page.edits = Message::apply_edits_to(page.edits, &mut p.messages);
+            page.edits.extend(p.edits);
+            let mut messages = p.messages;
+            messages.extend(page.messages);
+            page.messages = messages;
+            page.members.extend(p.members);
+            let mut reactions = p.reactions;
+            reactions.extend(page.reactions);
+            page.reactions = reactions;
+            page.end = p.end;
+
+            if page.end.is_none() || page.messages.len() >= globals::PAGE_LIMIT as usize {
+                break;
+            }
+        }
+
+        let mut data = self.data.lock().unwrap();
+        let since = match data.since.is_empty() {
+            true => None,
+            false => Some(data.since.clone()),
+        };
+        let timeline = data.timelines.entry(roomid.to_string())
+            .or_insert(Timeline::new(roomid));
+        timeline.apply_pending_edits(&mut page);
+        timeline.reset(page.clone(), since);
+
+        Ok(page)
+    }
+
+    /// Loads the messages before the first known message of a room, using the token stored in
+    /// the room timeline, or the last messages if there's no timeline for the room.
+    ///
+    /// The page `end` is the token to continue, that is None when the start of the room is
+    /// reached
+    pub fn back_paginate(&self, roomid: &str) -> Result<MessagesPage, Error> {
+        let from = match self.data.lock().unwrap().timelines.get(roomid) {
+            Some(t) if t.start_reached => return Ok(MessagesPage::default()),
+            Some(t) => t.back_token(),
+            None => None,
+        };
+
+        let mut page = self.room_messages(roomid, from, None, globals::PAGE_LIMIT)?;
+
+        let mut data = self.data.lock().unwrap();
+        let timeline = data.timelines.entry(roomid.to_string())
+            .or_insert(Timeline::new(roomid));
+        timeline.apply_pending_edits(&mut page);
+        timeline.prepend(page.clone());
+
+        Ok(page)
+    }
+
+    /// Adds the messages of a sync to the room timeline.
+    ///
+    /// Returns true if the sync was limited and there's a gap before the new messages
+    pub fn push_sync_timeline(&self, roomid: &str, messages: Vec<Message>, timeline: &SyncTimeline, next_batch: &str) -> bool {
+        let mut data = self.data.lock().unwrap();
+        data.timelines.entry(roomid.to_string())
+            .or_insert(Timeline::new(roomid))
+            .push_sync(messages, timeline.prev_batch.clone(), timeline.limited, next_batch)
+    }
+
+    /// Fills the gap before the last sync of a room, making at most `MAX_PAGES` requests.
+    ///
+    /// Returns the new messages in chronological order. If the gap is bigger, or a newer sync
+    /// adds another gap meanwhile, the rest stays in the timeline and it's not loaded.
+    pub fn fill_gap(&self, roomid: &str) -> Result<MessagesPage, Error> {
+        let mut filled = MessagesPage::default();
+
+        for _ in 0..globals::MAX_PAGES {
+            let gap = match self.data.lock().unwrap().timelines.get(roomid).and_then(|t| t.last_gap()) {
+                Some(gap) => gap,
+                None => break,
+            };
+
+            let mut page = self.room_messages(roomid, Some(gap.from.clone()), Some(gap.to.clone()), globals::PAGE_LIMIT)?;
+            {
+                // the gap is filled in its own thread, a new sync can change the timeline meanwhile
+                let mut data = self.data.lock().unwrap();
+                match data.timelines.get_mut(roomid) {
+                    Some(t) if t.last_gap().as_ref() == Some(&gap) => {
+                        t.apply_pending_edits(&mut page);
+                        t.fill_last_gap(page.clone());
+                    }
+                    _ => break,
+                }
+            }
+            let mut messages = page.messages;
+            messages.extend(filled.messages);
+            filled.messages = messages;
+            filled.members.extend(page.members);
+            let mut reactions = page.reactions;
+            reactions.extend(filled.reactions);
+            filled.reactions = reactions;
+
+            if let Some(ref store) = self.store {
+                if let Err(err) = store.fill_last_gap(roomid, &page.events, page.end) {
+                    eprintln!("Error storing the messages of a gap: {:?}", err);
+                }
+            }
+        }
+
+        Ok(filled)
+    }
+
+    /// Gets the joined members of a room at the point of the last sync, so the list matches
+    /// the members lazy loaded with the room timeline
+    pub fn room_members(&self, roomid: &str) -> Result<Vec<Member>, Error> {
+        let mut params = vec![("membership", strn!("join"))];
+        let since = self.data.lock().unwrap().since.clone();
+        if !since.is_empty() {
+            params.push(("at", since));
+        }
+
+        let url = self.url(&format!("rooms/{}/members", roomid), params)?;
+        let r = self.transport.json_q("get", &url, &json!(null), globals::TIMEOUT)?;
+
+        let chunk = r["chunk"].as_array().ok_or(Error::BackendError)?;
+        Ok(chunk.iter().filter_map(parse_room_member).collect())
+    }
+
+    pub fn join_room(&self, roomid: &str) -> Result<(), Error> {
+        let url = self.url(&format!("join/{}", urlencoding::encode(roomid)), vec![])?;
+        self.transport.json_q("post", &url, &json!(null), globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn leave_room(&self, roomid: &str) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/leave", roomid), vec![])?;
+        self.transport.json_q("post", &url, &json!(null), globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    /// Moves our `m.fully_read` marker and our read receipt to the event `eventid`
+    pub fn mark_as_read(&self, roomid: &str, eventid: &str) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/read_markers", roomid), vec![])?;
+        let attrs = json!({
+            "m.fully_read": eventid,
+            "m.read": eventid,
+        });
+
+        self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn set_typing(&self, roomid: &str, typing: bool, timeout: i32) -> Result<(), Error> {
+        let userid = self.user_id();
+        let url = self.url(&format!("rooms/{}/typing/{}", roomid, userid), vec![])?;
+        let mut attrs = json!({
+            "typing": typing,
+        });
+        if typing {
+            attrs["timeout"] = json!(timeout);
+        }
+
+        self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn set_room_name(&self, roomid: &str, name: &str) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/state/m.room.name", roomid), vec![])?;
+        let attrs = json!({
+            "name": name,
+        });
+
+        self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn set_room_topic(&self, roomid: &str, topic: &str) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/state/m.room.topic", roomid), vec![])?;
+        let attrs = json!({
+            "topic": topic,
+        });
+
+        self.transport.json_q("put", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn invite(&self, roomid: &str, userid: &str) -> Result<(), Error> {
+        let url = self.url(&format!("rooms/{}/invite", roomid), vec![])?;
+        let attrs = json!({
+            "user_id": userid,
+        });
+
+        self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+        Ok(())
+    }
+
+    pub fn create_room(&self, name: &str, privacy: RoomType) -> Result<Room, Error> {
+        let url = self.url("createRoom", vec![])?;
+        let attrs = json!({
+            "invite": [],
+            "invite_3pid": [],
+            "name": name,
+            "visibility": match privacy {
+                RoomType::Public => "public",
+                RoomType::Private => "private",
+            },
+            "topic": "",
+            "preset": match privacy {
+                RoomType::Public => "public_chat",
+                RoomType::Private => "private_chat",
+            },
+        });
+
+        let r = self.transport.json_q("post", &url, &attrs, globals::TIMEOUT)?;
+        let id = strn!(r["room_id"].as_str().unwrap_or(""));
+        Ok(Room::new(id, Some(name.to_string())))
+    }
+}
+
+pub fn build_login_attrs(user: &str, password: &str) -> Result<JsonValue, Error> {
+    let emailre = Regex::new(r"^([0-9a-zA-Z]([-\.\w]*[0-9a-zA-Z])+@([0-9a-zA-Z][-\w]*[0-9a-zA-Z]\.)+[a-zA-Z]{2,9})$")?;
+    let attrs;
+
+    // Email
+    if emailre.is_match(user) {
+        attrs = json!({
+            "type": "m.login.password",
+            "password": password,
+            "initial_device_display_name": "Fractal",
+            "medium": "email",
+            "address": user,
+            "identifier": {
+                "type": "m.id.thirdparty",
+                "medium": "email",
+                "address": user
+            }
+        });
+    } else {
+        attrs = json!({
+            "type": "m.login.password",
+            "initial_device_display_name": "Fractal",
+            "user": user,
+            "password": password
+        });
+    }
+
+    Ok(attrs)
+}
+
+pub fn build_token_login_attrs(token: &str) -> JsonValue {
+    json!({
+        "type": "m.login.token",
+        "initial_device_display_name": "Fractal",
+        "token": token,
+    })
+}
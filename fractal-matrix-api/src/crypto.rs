@@ -0,0 +1,841 @@
+extern crate serde_json;
+extern crate vodozemac;
+
+use self::serde_json::Value as JsonValue;
+use self::vodozemac::olm::Account;
+use self::vodozemac::olm::AccountPickle;
+use self::vodozemac::olm::OlmMessage;
+use self::vodozemac::olm::Session;
+use self::vodozemac::olm::SessionPickle;
+use self::vodozemac::olm::SessionConfig as OlmConfig;
+use self::vodozemac::megolm::GroupSession;
+use self::vodozemac::megolm::GroupSessionPickle;
+use self::vodozemac::megolm::InboundGroupSession;
+use self::vodozemac::megolm::InboundGroupSessionPickle;
+use self::vodozemac::megolm::MegolmMessage;
+use self::vodozemac::megolm::SessionConfig as MegolmConfig;
+use self::vodozemac::megolm::SessionKey;
+use self::vodozemac::Curve25519PublicKey;
+use self::vodozemac::Ed25519PublicKey;
+use self::vodozemac::Ed25519Signature;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use error::Error;
+
+pub const OLM_ALGORITHM: &'static str = "m.olm.v1.curve25519-aes-sha2";
+pub const MEGOLM_ALGORITHM: &'static str = "m.megolm.v1.aes-sha2";
+
+/// Number of messages encrypted with a megolm session before a new one is created
+const ROTATION_MSGS: u32 = 100;
+
+/// The identity keys of a device of other user, or of other device of ours
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceKeys {
+    pub user_id: String,
+    pub device_id: String,
+    pub curve25519: String,
+    pub ed25519: String,
+}
+
+impl DeviceKeys {
+    /// Parses the keys of a device from a /keys/query response, returns None if they aren't
+    /// signed by the device
+    pub fn parse(keys: &JsonValue) -> Option<DeviceKeys> {
+        let user_id = keys["user_id"].as_str()?;
+        let device_id = keys["device_id"].as_str()?;
+        let curve = keys["keys"][format!("curve25519:{}", device_id).as_str()].as_str()?;
+        let ed = keys["keys"][format!("ed25519:{}", device_id).as_str()].as_str()?;
+
+        if verify_signature(keys, user_id, device_id, ed).is_err() {
+            return None;
+        }
+
+        Some(DeviceKeys {
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            curve25519: curve.to_string(),
+            ed25519: ed.to_string(),
+        })
+    }
+}
+
+/// The signed content of a json object, that is its canonical json without the `signatures`
+/// and `unsigned` keys
+pub fn canonical_json(value: &JsonValue) -> Result<String, Error> {
+    let mut v = value.clone();
+    if let Some(obj) = v.as_object_mut() {
+        obj.remove("signatures");
+        obj.remove("unsigned");
+    }
+
+    // the json objects are sorted maps, so the keys are already in lexicographic order
+    Ok(serde_json::to_string(&v)?)
+}
+
+/// Checks the signature of a json object made by the device `device_id` of `user_id`, that
+/// has the `ed25519` key
+pub fn verify_signature(value: &JsonValue, user_id: &str, device_id: &str, ed25519: &str) -> Result<(), Error> {
+    let keyid = format!("ed25519:{}", device_id);
+    let signature = value["signatures"][user_id][keyid.as_str()].as_str().ok_or(Error::CryptoError)?;
+
+    let key = Ed25519PublicKey::from_base64(ed25519)?;
+    let signature = Ed25519Signature::from_base64(signature)?;
+    key.verify(canonical_json(value)?.as_bytes(), &signature)?;
+
+    Ok(())
+}
+
+/// A megolm session stored with the room and the curve25519 key of the device that created it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredGroupSession {
+    pub room_id: String,
+    pub sender_key: String,
+    pub pickle: String,
+}
+
+/// Our megolm session in a room, with the devices that have its key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredOutboundSession {
+    pub room_id: String,
+    pub pickle: String,
+    pub shared_with: Vec<(String, String)>,
+}
+
+/// The persisted state of `Crypto`.
+///
+/// The olm account and the sessions are stored as vodozemac pickles encrypted with a pickle key,
+/// that the application keeps out of the store, like the access token.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CryptoStore {
+    pub user_id: String,
+    pub device_id: String,
+    pub account: String,
+    pub keys_uploaded: bool,
+    // olm sessions as (curve25519 key of the other device, pickle)
+    pub sessions: Vec<(String, String)>,
+    pub inbound: Vec<StoredGroupSession>,
+    pub outbound: Vec<StoredOutboundSession>,
+    pub devices: Vec<DeviceKeys>,
+    pub outdated: Vec<String>,
+    pub encrypted_rooms: Vec<String>,
+    // the events that wait for their room key, as (session id, room id, event)
+    #[serde(default)]
+    pub undecrypted: Vec<(String, String, JsonValue)>,
+    // the joined members of the encrypted rooms, as (room id, members)
+    #[serde(default)]
+    pub room_members: Vec<(String, Vec<String>)>,
+    // the decrypted megolm messages, as (session id, message index, event id)
+    #[serde(default)]
+    pub message_indexes: Vec<(String, u32, String)>,
+}
+
+struct OutboundSession {
+    session: GroupSession,
+    // the (user id, device id) of the devices that received the session key
+    shared_with: HashSet<(String, String)>,
+}
+
+/// The end-to-end encryption state of this device.
+///
+/// It has the olm account with our identity keys, the olm sessions with other devices, used to
+/// share the megolm room keys, and the megolm sessions used to encrypt and decrypt the room
+/// events. This struct doesn't make requests, the `Client` sends and receives the keys.
+pub struct Crypto {
+    pub user_id: String,
+    pub device_id: String,
+    account: Account,
+    keys_uploaded: bool,
+    // olm sessions by the curve25519 key of the other device, the newest last
+    sessions: HashMap<String, Vec<Session>>,
+    // megolm sessions to decrypt, by (room id, sender curve25519 key, session id)
+    inbound: HashMap<(String, String, String), InboundGroupSession>,
+    // our megolm session in each room
+    outbound: HashMap<String, OutboundSession>,
+    // the known devices of each user
+    devices: HashMap<String, Vec<DeviceKeys>>,
+    // users whose device list changed since it was downloaded
+    outdated: HashSet<String>,
+    encrypted_rooms: HashSet<String>,
+    // the joined members of the encrypted rooms, that receive our room keys. They're loaded
+    // once and then updated with the member events of each sync
+    room_members: HashMap<String, HashSet<String>>,
+    // the encrypted events of unknown megolm sessions, as (room id, event) by session id, they
+    // are decrypted when the room key arrives
+    undecrypted: HashMap<String, Vec<(String, JsonValue)>>,
+    // the events decrypted after their room key arrived, as (room id, event)
+    late_decrypted: Vec<(String, JsonValue)>,
+    // the event id of each decrypted megolm message by (session id, message index), a message
+    // index that comes again with other event id is a replayed ciphertext
+    message_indexes: HashMap<(String, u32), String>,
+}
+
+impl Crypto {
+    /// Creates a new olm account for the device `device_id`
+    pub fn new(user_id: &str, device_id: &str) -> Crypto {
+        Crypto {
+            user_id: user_id.to_string(),
+            device_id: device_id.to_string(),
+            account: Account::new(),
+            keys_uploaded: false,
+            sessions: HashMap::new(),
+            inbound: HashMap::new(),
+            outbound: HashMap::new(),
+            devices: HashMap::new(),
+            outdated: HashSet::new(),
+            encrypted_rooms: HashSet::new(),
+            room_members: HashMap::new(),
+            undecrypted: HashMap::new(),
+            late_decrypted: vec![],
+            message_indexes: HashMap::new(),
+        }
+    }
+
+    /// Restores the state from the store, the pickles are decrypted with `pickle_key`
+    pub fn from_store(stored: &CryptoStore, pickle_key: &[u8; 32]) -> Result<Crypto, Error> {
+        let mut crypto = Crypto::new(&stored.user_id, &stored.device_id);
+        crypto.account = Account::from_pickle(AccountPickle::from_encrypted(&stored.account, pickle_key)?);
+        crypto.keys_uploaded = stored.keys_uploaded;
+
+        for &(ref key, ref pickle) in stored.sessions.iter() {
+            let session = Session::from_pickle(SessionPickle::from_encrypted(pickle, pickle_key)?);
+            crypto.sessions.entry(key.clone()).or_insert(vec![]).push(session);
+        }
+
+        for s in stored.inbound.iter() {
+            let pickle = InboundGroupSessionPickle::from_encrypted(&s.pickle, pickle_key)?;
+            let session = InboundGroupSession::from_pickle(pickle);
+            let id = (s.room_id.clone(), s.sender_key.clone(), session.session_id());
+            crypto.inbound.insert(id, session);
+        }
+
+        for s in stored.outbound.iter() {
+            crypto.outbound.insert(s.room_id.clone(), OutboundSession {
+                session: GroupSession::from_pickle(GroupSessionPickle::from_encrypted(&s.pickle, pickle_key)?),
+                shared_with: s.shared_with.iter().cloned().collect(),
+            });
+        }
+
+        for d in stored.devices.iter() {
+            crypto.devices.entry(d.user_id.clone()).or_insert(vec![]).push(d.clone());
+        }
+        crypto.outdated = stored.outdated.iter().cloned().collect();
+        crypto.encrypted_rooms = stored.encrypted_rooms.iter().cloned().collect();
+        for &(ref roomid, ref members) in stored.room_members.iter() {
+            crypto.room_members.insert(roomid.clone(), members.iter().cloned().collect());
+        }
+        for &(ref session_id, ref roomid, ref ev) in stored.undecrypted.iter() {
+            crypto.undecrypted.entry(session_id.clone()).or_insert(vec![]).push((roomid.clone(), ev.clone()));
+        }
+        for &(ref session_id, index, ref evid) in stored.message_indexes.iter() {
+            crypto.message_indexes.insert((session_id.clone(), index), evid.clone());
+        }
+
+        Ok(crypto)
+    }
+
+    /// The state to persist, with the pickles encrypted with `pickle_key`
+    pub fn to_store(&self, pickle_key: &[u8; 32]) -> Result<CryptoStore, Error> {
+        let mut stored = CryptoStore {
+            user_id: self.user_id.clone(),
+            device_id: self.device_id.clone(),
+            account: self.account.pickle().encrypt(pickle_key),
+            keys_uploaded: self.keys_uploaded,
+            devices: self.devices.values().flat_map(|ds| ds.iter().cloned()).collect(),
+            outdated: self.outdated.iter().cloned().collect(),
+            encrypted_rooms: self.encrypted_rooms.iter().cloned().collect(),
+            room_members: self.room_members.iter()
+                .map(|(roomid, members)| (roomid.clone(), members.iter().cloned().collect()))
+                .collect(),
+            message_indexes: self.message_indexes.iter()
+                .map(|(&(ref session_id, index), evid)| (session_id.clone(), index, evid.clone()))
+                .collect(),
+            ..Default::default()
+        };
+
+        for (key, sessions) in self.sessions.iter() {
+            for session in sessions.iter() {
+                stored.sessions.push((key.clone(), session.pickle().encrypt(pickle_key)));
+            }
+        }
+
+        for (&(ref roomid, ref sender_key, _), session) in self.inbound.iter() {
+            stored.inbound.push(StoredGroupSession {
+                room_id: roomid.clone(),
+                sender_key: sender_key.clone(),
+                pickle: session.pickle().encrypt(pickle_key),
+            });
+        }
+
+        for (session_id, events) in self.undecrypted.iter() {
+            for &(ref roomid, ref ev) in events.iter() {
+                stored.undecrypted.push((session_id.clone(), roomid.clone(), ev.clone()));
+            }
+        }
+
+        for (roomid, out) in self.outbound.iter() {
+            stored.outbound.push(StoredOutboundSession {
+                room_id: roomid.clone(),
+                pickle: out.session.pickle().encrypt(pickle_key),
+                shared_with: out.shared_with.iter().cloned().collect(),
+            });
+        }
+
+        Ok(stored)
+    }
+
+    pub fn curve25519_key(&self) -> String {
+        self.account.curve25519_key().to_base64()
+    }
+
+    pub fn ed25519_key(&self) -> String {
+        self.account.ed25519_key().to_base64()
+    }
+
+    /// Adds the signature of this device to the `signatures` of a json object
+    pub fn sign_json(&self, value: &mut JsonValue) -> Result<(), Error> {
+        let signature = self.account.sign(canonical_json(value)?.as_bytes());
+        let keyid = format!("ed25519:{}", self.device_id);
+        value["signatures"][self.user_id.as_str()][keyid.as_str()] = json!(signature.to_base64());
+        Ok(())
+    }
+
+    // Keys upload
+
+    pub fn keys_uploaded(&self) -> bool {
+        self.keys_uploaded
+    }
+
+    /// The signed identity keys of this device, to upload them to the server
+    pub fn device_keys(&self) -> Result<JsonValue, Error> {
+        let mut keys = json!({
+            "user_id": self.user_id,
+            "device_id": self.device_id,
+            "algorithms": [OLM_ALGORITHM, MEGOLM_ALGORITHM],
+            "keys": {},
+        });
+        keys["keys"][format!("curve25519:{}", self.device_id).as_str()] = json!(self.curve25519_key());
+        keys["keys"][format!("ed25519:{}", self.device_id).as_str()] = json!(self.ed25519_key());
+
+        self.sign_json(&mut keys)?;
+        Ok(keys)
+    }
+
+    /// Returns true if the server has less than half of the one time keys that we can keep,
+    /// `count` is the number of keys that the server has
+    pub fn needs_one_time_keys(&self, count: usize) -> bool {
+        count < self.account.max_number_of_one_time_keys() / 2
+    }
+
+    /// Generates the one time keys needed to have half of the max number of keys in the server,
+    /// where there're `count` keys, and returns all the signed keys that aren't uploaded yet
+    pub fn one_time_keys(&mut self, count: usize) -> Result<JsonValue, Error> {
+        let pending = self.account.one_time_keys().len();
+        let wanted = self.account.max_number_of_one_time_keys() / 2;
+        if count + pending < wanted {
+            self.account.generate_one_time_keys(wanted - count - pending);
+        }
+
+        let mut keys = json!({});
+        for (id, key) in self.account.one_time_keys() {
+            let mut k = json!({ "key": key.to_base64() });
+            self.sign_json(&mut k)?;
+            keys[format!("signed_curve25519:{}", id.to_base64()).as_str()] = k;
+        }
+
+        Ok(keys)
+    }
+
+    /// Marks the device keys and the generated one time keys as uploaded
+    pub fn mark_keys_as_published(&mut self) {
+        self.account.mark_keys_as_published();
+        self.keys_uploaded = true;
+    }
+
+    // Devices
+
+    pub fn set_room_encrypted(&mut self, roomid: &str) {
+        self.encrypted_rooms.insert(roomid.to_string());
+    }
+
+    pub fn is_room_encrypted(&self, roomid: &str) -> bool {
+        self.encrypted_rooms.contains(roomid)
+    }
+
+    /// The known joined members of an encrypted room, None if they weren't loaded yet
+    pub fn room_members(&self, roomid: &str) -> Option<Vec<String>> {
+        self.room_members.get(roomid).map(|members| members.iter().cloned().collect())
+    }
+
+    pub fn set_room_members(&mut self, roomid: &str, members: &[String]) {
+        self.room_members.insert(roomid.to_string(), members.iter().cloned().collect());
+    }
+
+    /// Updates the known members of a room with its new `m.room.member` events
+    pub fn update_room_members(&mut self, roomid: &str, events: &[JsonValue]) {
+        let members = match self.room_members.get_mut(roomid) {
+            Some(members) => members,
+            None => return,
+        };
+
+        for ev in events.iter().filter(|ev| ev["type"] == "m.room.member") {
+            let userid = ev["state_key"].as_str().unwrap_or_default().to_string();
+            match ev["content"]["membership"].as_str() {
+                Some("join") => members.insert(userid),
+                _ => members.remove(&userid),
+            };
+        }
+    }
+
+    pub fn mark_outdated(&mut self, userid: &str) {
+        self.outdated.insert(userid.to_string());
+    }
+
+    /// Forgets the devices of a user that doesn't share any encrypted room with us anymore
+    pub fn forget_user(&mut self, userid: &str) {
+        self.devices.remove(userid);
+        self.outdated.remove(userid);
+    }
+
+    /// The users in `users` whose device list is unknown or outdated
+    pub fn users_to_query(&self, users: &[String]) -> Vec<String> {
+        users.iter()
+            .filter(|u| !self.devices.contains_key(*u) || self.outdated.contains(*u))
+            .cloned()
+            .collect()
+    }
+
+    /// Replaces the known devices of a user with the downloaded ones.
+    ///
+    /// A device that was known with other keys is ignored, and the room keys shared with a
+    /// removed device are replaced, so it can't decrypt the new messages.
+    pub fn set_devices(&mut self, userid: &str, devices: Vec<DeviceKeys>) {
+        let known = self.devices.remove(userid).unwrap_or_default();
+
+        let devices: Vec<DeviceKeys> = devices.into_iter()
+            .map(|d| match known.iter().find(|k| k.device_id == d.device_id) {
+                Some(k) if *k != d => {
+                    eprintln!("The keys of the device {} of {} changed, ignoring them", d.device_id, userid);
+                    k.clone()
+                }
+                _ => d,
+            })
+            .collect();
+
+        let removed: Vec<String> = known.iter()
+            .filter(|k| !devices.iter().any(|d| d.device_id == k.device_id))
+            .map(|k| k.device_id.clone())
+            .collect();
+        if !removed.is_empty() {
+            self.outbound.retain(|_, out| {
+                !out.shared_with.iter().any(|&(ref u, ref d)| u == userid && removed.contains(d))
+            });
+        }
+
+        self.devices.insert(userid.to_string(), devices);
+        self.outdated.remove(userid);
+    }
+
+    /// Returns true if there's an olm session with the device that has the `curve25519` key
+    pub fn has_session(&self, curve25519: &str) -> bool {
+        self.sessions.get(curve25519).map(|s| !s.is_empty()).unwrap_or(false)
+    }
+
+    /// Creates an olm session with a device, using a one time key claimed for it
+    pub fn create_session(&mut self, device: &DeviceKeys, otk: &JsonValue) -> Result<(), Error> {
+        verify_signature(otk, &device.user_id, &device.device_id, &device.ed25519)?;
+
+        let identity_key = Curve25519PublicKey::from_base64(&device.curve25519)?;
+        let one_time_key = Curve25519PublicKey::from_base64(otk["key"].as_str().unwrap_or_default())?;
+        let session = self.account.create_outbound_session(OlmConfig::version_1(), identity_key, one_time_key);
+
+        self.sessions.entry(device.curve25519.clone()).or_insert(vec![]).push(session);
+        Ok(())
+    }
+
+    // Room keys
+
+    /// Our megolm session in the room, a new one is created if there isn't one or if it was
+    /// used for too many messages
+    fn outbound_session(&mut self, roomid: &str) -> &mut OutboundSession {
+        let expired = self.outbound.get(roomid)
+            .map(|out| out.session.message_index() >= ROTATION_MSGS)
+            .unwrap_or(true);
+
+        if expired {
+            let session = GroupSession::new(MegolmConfig::version_1());
+            // our own messages are decrypted with an inbound copy of the session
+            let inbound = InboundGroupSession::new(&session.session_key(), MegolmConfig::version_1());
+            let id = (roomid.to_string(), self.curve25519_key(), session.session_id());
+            self.inbound.insert(id, inbound);
+
+            self.outbound.insert(roomid.to_string(), OutboundSession {
+                session: session,
+                shared_with: HashSet::new(),
+            });
+        }
+
+        self.outbound.get_mut(roomid).unwrap()
+    }
+
+    /// Drops our megolm session in the room, so the next message uses a new one that isn't
+    /// shared with the members that left
+    pub fn discard_outbound_session(&mut self, roomid: &str) {
+        self.outbound.remove(roomid);
+    }
+
+    /// The devices of `members` that don't have the key of our megolm session in the room
+    pub fn room_key_receivers(&mut self, roomid: &str, members: &[String]) -> Vec<DeviceKeys> {
+        let devices: Vec<DeviceKeys> = members.iter()
+            .filter_map(|m| self.devices.get(m))
+            .flat_map(|ds| ds.iter().cloned())
+            .filter(|d| d.user_id != self.user_id || d.device_id != self.device_id)
+            .collect();
+
+        let out = self.outbound_session(roomid);
+        devices.into_iter()
+            .filter(|d| !out.shared_with.contains(&(d.user_id.clone(), d.device_id.clone())))
+            .collect()
+    }
+
+    /// Encrypts the key of our megolm session in the room for a device, returns the content of
+    /// the `m.room.encrypted` to-device event or None if there's no olm session with the device
+    pub fn encrypt_room_key(&mut self, roomid: &str, device: &DeviceKeys) -> Result<Option<JsonValue>, Error> {
+        let content = {
+            let out = self.outbound_session(roomid);
+            json!({
+                "algorithm": MEGOLM_ALGORITHM,
+                "room_id": roomid,
+                "session_id": out.session.session_id(),
+                "session_key": out.session.session_key().to_base64(),
+            })
+        };
+
+        self.encrypt_olm(device, "m.room_key", content)
+    }
+
+    /// Remembers that the devices received the key of our megolm session in the room
+    pub fn mark_room_key_shared(&mut self, roomid: &str, devices: &[DeviceKeys]) {
+        if let Some(out) = self.outbound.get_mut(roomid) {
+            for d in devices {
+                out.shared_with.insert((d.user_id.clone(), d.device_id.clone()));
+            }
+        }
+    }
+
+    fn encrypt_olm(&mut self, device: &DeviceKeys, evtype: &str, content: JsonValue) -> Result<Option<JsonValue>, Error> {
+        let payload = json!({
+            "sender": self.user_id,
+            "sender_device": self.device_id,
+            "keys": { "ed25519": self.ed25519_key() },
+            "recipient": device.user_id,
+            "recipient_keys": { "ed25519": device.ed25519 },
+            "type": evtype,
+            "content": content,
+        });
+        let sender_key = self.curve25519_key();
+
+        let session = match self.sessions.get_mut(&device.curve25519).and_then(|s| s.last_mut()) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let (msgtype, body) = session.encrypt(serde_json::to_string(&payload)?).to_parts();
+
+        let mut ciphertext = json!({});
+        ciphertext[device.curve25519.as_str()] = json!({
+            "type": msgtype,
+            "body": vodozemac::base64_encode(body),
+        });
+
+        Ok(Some(json!({
+            "algorithm": OLM_ALGORITHM,
+            "sender_key": sender_key,
+            "ciphertext": ciphertext,
+        })))
+    }
+
+    // Decryption
+
+    /// Decrypts an olm `m.room.encrypted` to-device event and returns the decrypted event, with
+    /// the curve25519 key of the device that sent it as `sender_key`
+    pub fn decrypt_to_device(&mut self, ev: &JsonValue) -> Result<JsonValue, Error> {
+        let content = &ev["content"];
+        if content["algorithm"] != OLM_ALGORITHM {
+            return Err(Error::CryptoError);
+        }
+
+        let sender_key = content["sender_key"].as_str().ok_or(Error::CryptoError)?;
+        let ciphertext = &content["ciphertext"][self.curve25519_key().as_str()];
+        let msgtype = ciphertext["type"].as_u64().ok_or(Error::CryptoError)?;
+        let body = vodozemac::base64_decode(ciphertext["body"].as_str().unwrap_or_default())?;
+        let message = OlmMessage::from_parts(msgtype as usize, &body)?;
+
+        let plaintext = self.decrypt_olm(sender_key, &message)?;
+        let mut payload: JsonValue = serde_json::from_slice(&plaintext)?;
+
+        // the payload must be for this device, and from the user that sent the event
+        if payload["recipient"] != self.user_id ||
+           payload["recipient_keys"]["ed25519"] != self.ed25519_key() ||
+           payload["sender"] != ev["sender"] {
+            return Err(Error::CryptoError);
+        }
+
+        payload["sender_key"] = json!(sender_key);
+        Ok(payload)
+    }
+
+    fn decrypt_olm(&mut self, sender_key: &str, message: &OlmMessage) -> Result<Vec<u8>, Error> {
+        if let Some(sessions) = self.sessions.get_mut(sender_key) {
+            for session in sessions.iter_mut().rev() {
+                // a pre-key message can only be decrypted by the session that it created
+                if let OlmMessage::PreKey(ref m) = *message {
+                    if m.session_id() != session.session_id() {
+                        continue;
+                    }
+                }
+                if let Ok(plaintext) = session.decrypt(message) {
+                    return Ok(plaintext);
+                }
+            }
+        }
+
+        match *message {
+            OlmMessage::PreKey(ref m) => {
+                let key = Curve25519PublicKey::from_base64(sender_key)?;
+                let created = self.account.create_inbound_session(key, m)?;
+                self.sessions.entry(sender_key.to_string()).or_insert(vec![]).push(created.session);
+                Ok(created.plaintext)
+            }
+            OlmMessage::Normal(_) => Err(Error::CryptoError),
+        }
+    }
+
+    /// Adds the megolm session of a decrypted `m.room_key` event.
+    ///
+    /// The events received before that were waiting for this session are decrypted, they can
+    /// be taken with `take_late_decrypted`
+    pub fn add_room_key(&mut self, payload: &JsonValue) -> Result<(), Error> {
+        let content = &payload["content"];
+        if payload["type"] != "m.room_key" || content["algorithm"] != MEGOLM_ALGORITHM {
+            return Err(Error::CryptoError);
+        }
+
+        let roomid = content["room_id"].as_str().ok_or(Error::CryptoError)?;
+        let sender_key = payload["sender_key"].as_str().ok_or(Error::CryptoError)?;
+        let key = SessionKey::from_base64(content["session_key"].as_str().unwrap_or_default())?;
+        let session = InboundGroupSession::new(&key, MegolmConfig::version_1());
+        if content["session_id"] != session.session_id() {
+            return Err(Error::CryptoError);
+        }
+
+        // a known session is kept, a copy received later could decrypt less messages
+        let session_id = session.session_id();
+        let id = (roomid.to_string(), sender_key.to_string(), session_id.clone());
+        self.inbound.entry(id).or_insert(session);
+
+        let waiting = self.undecrypted.remove(&session_id).unwrap_or_default();
+        for (roomid, ev) in waiting {
+            match self.decrypt_room_event(&roomid, &ev) {
+                Ok(clear) => self.late_decrypted.push((roomid, clear)),
+                // other sender or room with the same session id, keep waiting
+                Err(_) => self.undecrypted.entry(session_id.clone()).or_insert(vec![]).push((roomid, ev)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The events decrypted after their room key arrived, as (room id, event), since the last
+    /// call
+    pub fn take_late_decrypted(&mut self) -> Vec<(String, JsonValue)> {
+        self.late_decrypted.drain(..).collect()
+    }
+
+    /// Decrypts a megolm `m.room.encrypted` room event and returns the event with the decrypted
+    /// type and content.
+    ///
+    /// A ciphertext that was decrypted before with other event id is rejected as a replay
+    pub fn decrypt_room_event(&mut self, roomid: &str, ev: &JsonValue) -> Result<JsonValue, Error> {
+        let content = &ev["content"];
+        if content["algorithm"] != MEGOLM_ALGORITHM {
+            return Err(Error::CryptoError);
+        }
+
+        let id = (
+            roomid.to_string(),
+            content["sender_key"].as_str().unwrap_or_default().to_string(),
+            content["session_id"].as_str().unwrap_or_default().to_string(),
+        );
+        let session = self.inbound.get_mut(&id).ok_or(Error::CryptoError)?;
+        let message = MegolmMessage::from_base64(content["ciphertext"].as_str().unwrap_or_default())?;
+        let decrypted = session.decrypt(&message)?;
+
+        let payload: JsonValue = serde_json::from_slice(&decrypted.plaintext)?;
+        if payload["room_id"] != roomid {
+            return Err(Error::CryptoError);
+        }
+
+        if let Some(evid) = ev["event_id"].as_str() {
+            let index = (id.2, decrypted.message_index);
+            if self.message_indexes.get(&index).map(|known| known != evid).unwrap_or(false) {
+                return Err(Error::CryptoError);
+            }
+            self.message_indexes.insert(index, evid.to_string());
+        }
+
+        let mut clear = ev.clone();
+        clear["type"] = payload["type"].clone();
+        clear["content"] = payload["content"].clone();
+        Ok(clear)
+    }
+
+    /// Replaces the encrypted events of a room with the decrypted ones, the events that can't
+    /// be decrypted are kept as they are.
+    ///
+    /// The events of unknown sessions are remembered, to decrypt them when the room key arrives
+    pub fn decrypt_events(&mut self, roomid: &str, events: &mut Vec<JsonValue>) {
+        for ev in events.iter_mut() {
+            if ev["type"] != "m.room.encrypted" {
+                continue;
+            }
+            if let Ok(clear) = self.decrypt_room_event(roomid, ev) {
+                *ev = clear;
+                continue;
+            }
+
+            let session_id = match ev["content"]["session_id"].as_str() {
+                Some(id) if !self.inbound.keys().any(|&(_, _, ref s)| s == id) => id.to_string(),
+                _ => continue,
+            };
+            let waiting = self.undecrypted.entry(session_id).or_insert(vec![]);
+            if ev["event_id"].is_string() && !waiting.iter().any(|&(_, ref w)| w["event_id"] == ev["event_id"]) {
+                waiting.push((roomid.to_string(), ev.clone()));
+            }
+        }
+    }
+
+    /// Encrypts a room event with our megolm session in the room and returns the content of
+    /// the `m.room.encrypted` event.
+    ///
+    /// The room key must be shared before, with `room_key_receivers` and `encrypt_room_key`
+    pub fn encrypt_room_event(&mut self, roomid: &str, evtype: &str, content: &JsonValue) -> Result<JsonValue, Error> {
+        let payload = json!({
+            "type": evtype,
+            "content": content,
+            "room_id": roomid,
+        });
+        let sender_key = self.curve25519_key();
+        let device_id = self.device_id.clone();
+
+        let out = self.outbound.get_mut(roomid).ok_or(Error::CryptoError)?;
+        let message = out.session.encrypt(serde_json::to_string(&payload)?);
+
+        let mut encrypted = json!({
+            "algorithm": MEGOLM_ALGORITHM,
+            "sender_key": sender_key,
+            "ciphertext": message.to_base64(),
+            "session_id": out.session.session_id(),
+            "device_id": device_id,
+        });
+        // the relations stay visible to the server, so it can aggregate them
+        if !content["m.relates_to"].is_null() {
+            encrypted["m.relates_to"] = content["m.relates_to"].clone();
+        }
+
+        Ok(encrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(c: &Crypto) -> DeviceKeys {
+        DeviceKeys::parse(&c.device_keys().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn device_keys_are_signed() {
+        let alice = Crypto::new("@alice:localhost", "ALICE");
+        let mut keys = alice.device_keys().unwrap();
+        assert_eq!(device(&alice).curve25519, alice.curve25519_key());
+
+        keys["device_id"] = json!("OTHER");
+        assert!(DeviceKeys::parse(&keys).is_none());
+    }
+
+    #[test]
+    fn room_key_exchange() {
+        let roomid = "!room:localhost";
+        let mut alice = Crypto::new("@alice:localhost", "ALICE");
+        let mut bob = Crypto::new("@bob:localhost", "BOB");
+        bob.set_room_encrypted(roomid);
+
+        let otks = alice.one_time_keys(0).unwrap();
+        alice.mark_keys_as_published();
+        let otk = otks.as_object().unwrap().values().next().unwrap().clone();
+
+        let alice_device = device(&alice);
+        bob.set_devices("@alice:localhost", vec![alice_device.clone()]);
+        bob.create_session(&alice_device, &otk).unwrap();
+
+        let receivers = bob.room_key_receivers(roomid, &[strn!("@alice:localhost"), strn!("@bob:localhost")]);
+        assert_eq!(receivers, vec![alice_device.clone()]);
+        let content = bob.encrypt_room_key(roomid, &alice_device).unwrap().unwrap();
+        bob.mark_room_key_shared(roomid, &receivers);
+        assert!(bob.room_key_receivers(roomid, &[strn!("@alice:localhost")]).is_empty());
+
+        let encrypted = bob.encrypt_room_event(roomid, "m.room.message", &json!({ "msgtype": "m.text", "body": "hi" })).unwrap();
+        assert!(encrypted["body"].is_null());
+
+        // a message that arrives before the room key waits for it, even across a restart
+        let mut early = vec![json!({
+            "type": "m.room.encrypted",
+            "sender": "@bob:localhost",
+            "event_id": "$0",
+            "content": encrypted,
+        })];
+        alice.decrypt_events(roomid, &mut early);
+        assert_eq!(early[0]["type"], "m.room.encrypted");
+
+        // alice gets the room key and the message, after restoring her state from the store
+        let key = [7u8; 32];
+        let stored = alice.to_store(&key).unwrap();
+        assert!(Crypto::from_store(&stored, &[8u8; 32]).is_err());
+        let mut alice = Crypto::from_store(&stored, &key).unwrap();
+        let payload = alice.decrypt_to_device(&json!({
+            "type": "m.room.encrypted",
+            "sender": "@bob:localhost",
+            "content": content,
+        })).unwrap();
+        alice.add_room_key(&payload).unwrap();
+
+        let late = alice.take_late_decrypted();
+        assert_eq!(late.len(), 1);
+        assert_eq!(late[0].0, roomid);
+        assert_eq!(late[0].1["event_id"], "$0");
+        assert_eq!(late[0].1["content"]["body"], "hi");
+        assert!(alice.take_late_decrypted().is_empty());
+
+        // the same ciphertext with other event id is a replay, also after a restart
+        let mut alice = Crypto::from_store(&alice.to_store(&key).unwrap(), &key).unwrap();
+        let mut events = vec![json!({
+            "type": "m.room.encrypted",
+            "sender": "@bob:localhost",
+            "event_id": "$1",
+            "content": encrypted,
+        })];
+        alice.decrypt_events(roomid, &mut events);
+        assert_eq!(events[0]["type"], "m.room.encrypted");
+        assert!(alice.take_late_decrypted().is_empty());
+
+        // but the same event can be decrypted again, like when it's paginated
+        let clear = alice.decrypt_room_event(roomid, &json!({ "event_id": "$0", "content": encrypted })).unwrap();
+        assert_eq!(clear["type"], "m.room.message");
+        assert_eq!(clear["content"]["body"], "hi");
+
+        // other room can't use the key
+        assert!(alice.decrypt_room_event("!other:localhost", &json!({ "content": encrypted })).is_err());
+    }
+}
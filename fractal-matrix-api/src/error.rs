@@ -4,6 +4,7 @@ extern crate regex;
 extern crate reqwest;
 #[cfg(feature = "gfx")] extern crate glib;
 extern crate serde_json;
+extern crate vodozemac;
 
 use std::io;
 use std::time::SystemTimeError;
@@ -18,6 +19,7 @@ pub enum Error {
     ReqwestError(reqwest::Error),
     MatrixError(JsonValue),
     SendMsgError(String),
+    CryptoError,
 }
 
 impl From<reqwest::Error> for Error {
@@ -34,5 +36,15 @@ derror!(regex::Error, Error::BackendError);
 #[cfg(feature = "gfx")] derror!(glib::Error, Error::BackendError);
 derror!(SystemTimeError, Error::BackendError);
 
+derror!(vodozemac::KeyError, Error::CryptoError);
+derror!(vodozemac::SignatureError, Error::CryptoError);
+derror!(vodozemac::Base64DecodeError, Error::CryptoError);
+derror!(vodozemac::DecodeError, Error::CryptoError);
+derror!(vodozemac::PickleError, Error::CryptoError);
+derror!(vodozemac::olm::DecryptionError, Error::CryptoError);
+derror!(vodozemac::olm::SessionCreationError, Error::CryptoError);
+derror!(vodozemac::megolm::DecryptionError, Error::CryptoError);
+derror!(vodozemac::megolm::SessionKeyDecodeError, Error::CryptoError);
+
 derror!(OsString, Error::CacheError);
 derror!(serde_json::Error, Error::CacheError);
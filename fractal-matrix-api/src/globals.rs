@@ -1,3 +1,9 @@
 pub static TIMEOUT: u64 = 80;
 pub static PAGE_LIMIT: i32 = 40;
 pub static ROOM_DIRECTORY_LIMIT: i32 = 20;
+/// Max number of /messages requests made to fill a gap or to load a room history at once
+pub static MAX_PAGES: usize = 3;
+/// Max number of timeline events stored for each room before the older ones are dropped
+pub static STORED_EVENTS: usize = 1000;
+/// Seconds to wait for the browser to finish the single sign-on
+pub static SSO_TIMEOUT: u64 = 300;
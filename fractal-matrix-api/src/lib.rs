@@ -16,6 +16,12 @@ mod model;
 pub mod types;
 pub mod cache;
 pub mod backend;
+pub mod client;
+pub mod transport;
+pub mod store;
+pub mod crypto;
+pub mod uia;
+pub mod sso;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,12 @@
+/// A device (a session) of the user, as listed by the `/devices` endpoint
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Device {
+    pub device_id: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub last_seen_ip: Option<String>,
+    /// The last time the device was used, in milliseconds since the epoch
+    #[serde(default)]
+    pub last_seen_ts: Option<i64>,
+}
@@ -1,14 +1,20 @@
 extern crate serde_json;
 
 use self::serde_json::Value as JsonValue;
+use std::collections::HashMap;
 
-#[derive(Debug, Clone)]
+/// A room event with its content already parsed.
+///
+/// Events that we don't know or that have an invalid content are kept as
+/// `EventContent::Unknown` with the raw json so nothing is lost.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Event {
     pub sender: String,
-    pub stype: String,
     pub room: String,
     pub id: String,
-    pub content: JsonValue,
+    pub state_key: Option<String>,
+    pub origin_server_ts: i64,
+    pub content: EventContent,
 }
 
 impl PartialEq for Event {
@@ -16,3 +22,324 @@ impl PartialEq for Event {
         self.id == other.id
     }
 }
+
+impl Event {
+    /// Parses a matrix.org event json
+    ///
+    /// # Arguments
+    ///
+    /// * `roomid` - The room where this event was sent
+    /// * `ev` - The event as Json
+    pub fn from_json(roomid: &str, ev: &JsonValue) -> Event {
+        let stype = ev["type"].as_str().unwrap_or_default();
+
+        Event {
+            sender: strn!(ev["sender"].as_str().unwrap_or_default()),
+            room: strn!(roomid),
+            id: strn!(ev["event_id"].as_str().unwrap_or_default()),
+            state_key: ev["state_key"].as_str().map(|s| strn!(s)),
+            origin_server_ts: ev["origin_server_ts"].as_i64().unwrap_or_default(),
+            content: EventContent::from_json(stype, ev),
+        }
+    }
+
+    pub fn stype(&self) -> &str {
+        self.content.stype()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EventContent {
+    Name(NameContent),
+    Topic(TopicContent),
+    Avatar(AvatarContent),
+    Member(MemberContent),
+    PowerLevels(PowerLevelsContent),
+    JoinRules(JoinRulesContent),
+    HistoryVisibility(HistoryVisibilityContent),
+    CanonicalAlias(CanonicalAliasContent),
+    Redaction(RedactionContent),
+    Message(MessageContent),
+    Sticker(StickerContent),
+    Reaction(ReactionContent),
+    Encrypted(EncryptedContent),
+    Encryption(EncryptionContent),
+    /// Any other event type, with the type and the raw content
+    Unknown(String, JsonValue),
+}
+
+impl EventContent {
+    /// Builds the typed content for an event of type `stype`
+    ///
+    /// `ev` is the whole event because some fields, like the `redacts` of a redaction, live
+    /// outside the content.
+    pub fn from_json(stype: &str, ev: &JsonValue) -> EventContent {
+        let c = ev["content"].clone();
+
+        let parsed = match stype {
+            "m.room.name" => serde_json::from_value(c.clone()).map(EventContent::Name),
+            "m.room.topic" => serde_json::from_value(c.clone()).map(EventContent::Topic),
+            "m.room.avatar" => serde_json::from_value(c.clone()).map(EventContent::Avatar),
+            "m.room.member" => serde_json::from_value(c.clone()).map(EventContent::Member),
+            "m.room.power_levels" => serde_json::from_value(c.clone()).map(EventContent::PowerLevels),
+            "m.room.join_rules" => serde_json::from_value(c.clone()).map(EventContent::JoinRules),
+            "m.room.history_visibility" => serde_json::from_value(c.clone()).map(EventContent::HistoryVisibility),
+            "m.room.canonical_alias" => serde_json::from_value(c.clone()).map(EventContent::CanonicalAlias),
+            "m.room.redaction" => {
+                let mut c = c.clone();
+                if c.is_object() && !ev["redacts"].is_null() {
+                    c["redacts"] = ev["redacts"].clone();
+                }
+                serde_json::from_value(c).map(EventContent::Redaction)
+            }
+            "m.room.message" => serde_json::from_value(c.clone()).map(EventContent::Message),
+            "m.sticker" => serde_json::from_value(c.clone()).map(EventContent::Sticker),
+            "m.reaction" => serde_json::from_value(c.clone()).map(EventContent::Reaction),
+            "m.room.encrypted" => serde_json::from_value(c.clone()).map(EventContent::Encrypted),
+            "m.room.encryption" => serde_json::from_value(c.clone()).map(EventContent::Encryption),
+            _ => Ok(EventContent::Unknown(strn!(stype), c.clone())),
+        };
+
+        parsed.unwrap_or(EventContent::Unknown(strn!(stype), c))
+    }
+
+    /// The matrix event type for this content
+    pub fn stype(&self) -> &str {
+        match *self {
+            EventContent::Name(_) => "m.room.name",
+            EventContent::Topic(_) => "m.room.topic",
+            EventContent::Avatar(_) => "m.room.avatar",
+            EventContent::Member(_) => "m.room.member",
+            EventContent::PowerLevels(_) => "m.room.power_levels",
+            EventContent::JoinRules(_) => "m.room.join_rules",
+            EventContent::HistoryVisibility(_) => "m.room.history_visibility",
+            EventContent::CanonicalAlias(_) => "m.room.canonical_alias",
+            EventContent::Redaction(_) => "m.room.redaction",
+            EventContent::Message(_) => "m.room.message",
+            EventContent::Sticker(_) => "m.sticker",
+            EventContent::Reaction(_) => "m.reaction",
+            EventContent::Encrypted(_) => "m.room.encrypted",
+            EventContent::Encryption(_) => "m.room.encryption",
+            EventContent::Unknown(ref stype, _) => stype.as_str(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NameContent {
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopicContent {
+    #[serde(default)]
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AvatarContent {
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Membership {
+    Join,
+    Leave,
+    Invite,
+    Ban,
+    Knock,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MemberContent {
+    pub membership: Membership,
+    pub displayname: Option<String>,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowerLevelsContent {
+    #[serde(default)]
+    pub users: HashMap<String, i32>,
+    #[serde(default)]
+    pub users_default: i32,
+    #[serde(default)]
+    pub events: HashMap<String, i32>,
+    #[serde(default)]
+    pub events_default: i32,
+    #[serde(default = "default_power_level")]
+    pub state_default: i32,
+    #[serde(default = "default_power_level")]
+    pub ban: i32,
+    #[serde(default = "default_power_level")]
+    pub kick: i32,
+    #[serde(default = "default_power_level")]
+    pub redact: i32,
+    #[serde(default = "default_power_level")]
+    pub invite: i32,
+}
+
+fn default_power_level() -> i32 {
+    50
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JoinRulesContent {
+    pub join_rule: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryVisibilityContent {
+    pub history_visibility: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanonicalAliasContent {
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedactionContent {
+    pub redacts: String,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageContent {
+    pub msgtype: String,
+    #[serde(default)]
+    pub body: String,
+    pub format: Option<String>,
+    pub formatted_body: Option<String>,
+    pub url: Option<String>,
+    pub info: Option<JsonValue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StickerContent {
+    #[serde(default)]
+    pub body: String,
+    pub url: String,
+    pub info: Option<JsonValue>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionContent {
+    #[serde(rename = "m.relates_to")]
+    pub relates_to: AnnotationContent,
+}
+
+/// The relation of a reaction with the event that it annotates
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationContent {
+    pub rel_type: String,
+    pub event_id: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedContent {
+    pub algorithm: String,
+    pub ciphertext: JsonValue,
+    pub sender_key: Option<String>,
+    pub device_id: Option<String>,
+    pub session_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptionContent {
+    pub algorithm: String,
+    pub rotation_period_ms: Option<u64>,
+    pub rotation_period_msgs: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_member_event() {
+        let ev = json!({
+            "type": "m.room.member",
+            "sender": "@alice:localhost",
+            "event_id": "$1:localhost",
+            "state_key": "@alice:localhost",
+            "origin_server_ts": 1,
+            "content": { "membership": "join", "displayname": "Alice" },
+        });
+
+        let e = Event::from_json("!room:localhost", &ev);
+        assert_eq!(e.id, "$1:localhost");
+        assert_eq!(e.stype(), "m.room.member");
+        match e.content {
+            EventContent::Member(ref c) => {
+                assert_eq!(c.membership, Membership::Join);
+                assert_eq!(c.displayname, Some(strn!("Alice")));
+            }
+            ref c => panic!("unexpected content {:?}", c),
+        }
+    }
+
+    #[test]
+    fn parse_unknown_and_invalid_events() {
+        let ev = json!({ "type": "org.example.custom", "content": { "a": 1 } });
+        match Event::from_json("!room:localhost", &ev).content {
+            EventContent::Unknown(ref t, ref c) => {
+                assert_eq!(t, "org.example.custom");
+                assert_eq!(c["a"], 1);
+            }
+            ref c => panic!("unexpected content {:?}", c),
+        }
+
+        let ev = json!({ "type": "m.room.member", "content": { "membership": 3 } });
+        let e = Event::from_json("!room:localhost", &ev);
+        assert_eq!(e.stype(), "m.room.member");
+        match e.content {
+            EventContent::Unknown(..) => {}
+            ref c => panic!("unexpected content {:?}", c),
+        }
+    }
+
+    #[test]
+    fn parse_reaction() {
+        let ev = json!({
+            "type": "m.reaction",
+            "content": {
+                "m.relates_to": { "rel_type": "m.annotation", "event_id": "$1:localhost", "key": "👍" },
+            },
+        });
+        match Event::from_json("!room:localhost", &ev).content {
+            EventContent::Reaction(ref c) => {
+                assert_eq!(c.relates_to.rel_type, "m.annotation");
+                assert_eq!(c.relates_to.event_id, "$1:localhost");
+                assert_eq!(c.relates_to.key, "👍");
+            }
+            ref c => panic!("unexpected content {:?}", c),
+        }
+
+        // the content of a redacted reaction is empty
+        let ev = json!({ "type": "m.reaction", "content": {} });
+        match Event::from_json("!room:localhost", &ev).content {
+            EventContent::Unknown(..) => {}
+            ref c => panic!("unexpected content {:?}", c),
+        }
+    }
+
+    #[test]
+    fn parse_redaction() {
+        let ev = json!({
+            "type": "m.room.redaction",
+            "redacts": "$2:localhost",
+            "content": { "reason": "spam" },
+        });
+        match Event::from_json("!room:localhost", &ev).content {
+            EventContent::Redaction(ref c) => {
+                assert_eq!(c.redacts, "$2:localhost");
+                assert_eq!(c.reason, Some(strn!("spam")));
+            }
+            ref c => panic!("unexpected content {:?}", c),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+
+/// A filter for the events of the whole account (presence and account data)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<String>>,
+}
+
+/// A filter for the events of each room (state, timeline, ephemeral and account data)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomEventFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_types: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_senders: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contains_url: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lazy_load_members: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_redundant_members: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RoomFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_rooms: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_leave: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<RoomEventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<RoomEventFilter>,
+}
+
+/// A sync filter, uploaded to the server with `POST /user/{userId}/filter`.
+///
+/// It's built chaining the section methods:
+///
+/// ```
+/// # use fractal_matrix_api::types::{Filter, RoomFilter, RoomEventFilter};
+/// let filter = Filter::new()
+///     .room(RoomFilter::new()
+///         .timeline(RoomEventFilter::new().limit(40)));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Filter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_fields: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence: Option<EventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<EventFilter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomFilter>,
+}
+
+fn strings(v: &[&str]) -> Option<Vec<String>> {
+    Some(v.iter().map(|s| s.to_string()).collect())
+}
+
+impl EventFilter {
+    pub fn new() -> EventFilter {
+        Default::default()
+    }
+
+    /// A filter that doesn't match any event
+    pub fn none() -> EventFilter {
+        EventFilter::new().types(&[])
+    }
+
+    pub fn limit(mut self, limit: i32) -> EventFilter {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn types(mut self, types: &[&str]) -> EventFilter {
+        self.types = strings(types);
+        self
+    }
+
+    pub fn not_types(mut self, types: &[&str]) -> EventFilter {
+        self.not_types = strings(types);
+        self
+    }
+}
+
+impl RoomEventFilter {
+    pub fn new() -> RoomEventFilter {
+        Default::default()
+    }
+
+    /// A filter that doesn't match any event
+    pub fn none() -> RoomEventFilter {
+        RoomEventFilter::new().types(&[])
+    }
+
+    pub fn limit(mut self, limit: i32) -> RoomEventFilter {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn types(mut self, types: &[&str]) -> RoomEventFilter {
+        self.types = strings(types);
+        self
+    }
+
+    pub fn not_types(mut self, types: &[&str]) -> RoomEventFilter {
+        self.not_types = strings(types);
+        self
+    }
+
+    pub fn lazy_load_members(mut self, lazy: bool) -> RoomEventFilter {
+        self.lazy_load_members = Some(lazy);
+        self
+    }
+}
+
+impl RoomFilter {
+    pub fn new() -> RoomFilter {
+        Default::default()
+    }
+
+    pub fn include_leave(mut self, include: bool) -> RoomFilter {
+        self.include_leave = Some(include);
+        self
+    }
+
+    pub fn state(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.state = Some(filter);
+        self
+    }
+
+    pub fn timeline(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.timeline = Some(filter);
+        self
+    }
+
+    pub fn ephemeral(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.ephemeral = Some(filter);
+        self
+    }
+
+    pub fn account_data(mut self, filter: RoomEventFilter) -> RoomFilter {
+        self.account_data = Some(filter);
+        self
+    }
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Default::default()
+    }
+
+    pub fn event_fields(mut self, fields: &[&str]) -> Filter {
+        self.event_fields = strings(fields);
+        self
+    }
+
+    pub fn event_format(mut self, format: &str) -> Filter {
+        self.event_format = Some(format.to_string());
+        self
+    }
+
+    pub fn presence(mut self, filter: EventFilter) -> Filter {
+        self.presence = Some(filter);
+        self
+    }
+
+    pub fn account_data(mut self, filter: EventFilter) -> Filter {
+        self.account_data = Some(filter);
+        self
+    }
+
+    pub fn room(mut self, filter: RoomFilter) -> Filter {
+        self.room = Some(filter);
+        self
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        serde_json::to_value(self).unwrap_or(json!({}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_set_fields_are_serialized() {
+        let filter = Filter::new()
+            .event_format("client")
+            .presence(EventFilter::none())
+            .room(RoomFilter::new()
+                .state(RoomEventFilter::new().lazy_load_members(true))
+                .timeline(RoomEventFilter::new().limit(40)));
+
+        assert_eq!(filter.to_json(), json!({
+            "event_format": "client",
+            "presence": { "types": [] },
+            "room": {
+                "state": { "lazy_load_members": true },
+                "timeline": { "limit": 40 },
+            },
+        }));
+    }
+}
@@ -1,10 +1,46 @@
 use std::collections::HashMap;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceState {
+    Online,
+    Unavailable,
+    Offline,
+}
+
+impl Default for PresenceState {
+    fn default() -> PresenceState {
+        PresenceState::Offline
+    }
+}
+
+impl PresenceState {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PresenceState::Online => "online",
+            PresenceState::Unavailable => "unavailable",
+            PresenceState::Offline => "offline",
+        }
+    }
+}
+
+/// The content of a `m.presence` event
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Presence {
+    pub presence: PresenceState,
+    pub last_active_ago: Option<i64>,
+    pub currently_active: Option<bool>,
+    pub status_msg: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Member {
     pub alias: Option<String>,
     pub uid: String,
     pub avatar: Option<String>,
+    #[serde(default)]
+    pub presence: Option<Presence>,
 }
 
 impl Clone for Member {
@@ -13,6 +49,7 @@ impl Clone for Member {
             alias: self.alias.clone(),
             uid: self.uid.clone(),
             avatar: self.avatar.clone(),
+            presence: self.presence.clone(),
         }
     }
 }
@@ -32,5 +69,8 @@ impl PartialEq for Member {
     }
 }
 
+// hashmap userid -> Presence
+pub type PresenceList = HashMap<String, Presence>;
+
 // hashmap userid -> Member
 pub type MemberList = HashMap<String, Member>;
@@ -7,6 +7,9 @@ use std::cmp::Ordering;
 use self::serde_json::Value as JsonValue;
 use self::time::Duration;
 
+/// The mtype of the messages that map events of unknown types
+pub const UNSUPPORTED_MTYPE: &'static str = "m.unsupported";
+
 #[derive(Debug)]
 #[derive(Serialize, Deserialize)]
 pub struct Message {
@@ -20,6 +23,16 @@ pub struct Message {
     pub id: Option<String>,
     pub formatted_body: Option<String>,
     pub format: Option<String>,
+    #[serde(default)]
+    pub redacted: bool,
+    /// The event id of the message that this one edits
+    #[serde(default)]
+    pub replace: Option<String>,
+    #[serde(default)]
+    pub edited: bool,
+    /// The event id of the message that this one replies to
+    #[serde(default)]
+    pub in_reply_to: Option<String>,
 }
 
 impl Clone for Message {
@@ -35,6 +48,10 @@ impl Clone for Message {
             id: self.id.clone(),
             formatted_body: self.formatted_body.clone(),
             format: self.format.clone(),
+            redacted: self.redacted,
+            replace: self.replace.clone(),
+            edited: self.edited,
+            in_reply_to: self.in_reply_to.clone(),
         }
     }
 }
@@ -52,6 +69,10 @@ impl Default for Message {
             id: None,
             formatted_body: None,
             format: None,
+            redacted: false,
+            replace: None,
+            edited: false,
+            in_reply_to: None,
         }
     }
 }
@@ -89,26 +110,63 @@ impl Message {
 
     /// List all supported types. By default a message map a m.room.message event, but there's
     /// other events that we want to show in the message history so we map other event types to our
-    /// Message struct, like stickers
-    pub fn types() -> [&'static str; 2] {
+    /// Message struct, like stickers or the encrypted messages that we can't decrypt
+    pub fn types() -> [&'static str; 3] {
         [
             "m.room.message",
             "m.sticker",
+            "m.room.encrypted",
+        ]
+    }
+
+    /// Message events that aren't shown in the history, because they change other messages or
+    /// they aren't messages, like the calls or the verification of devices. The types that end
+    /// with a dot are prefixes
+    pub fn hidden_types() -> [&'static str; 4] {
+        [
+            "m.room.redaction",
+            "m.reaction",
+            "m.call.",
+            "m.key.verification.",
         ]
     }
 
     /// Helper function to use in iterator filter of a matrix.org json response to filter supported
-    /// events
+    /// events.
+    ///
+    /// The message events of unknown types are accepted too and shown as unsupported if they have
+    /// a fallback `body`, but not the state events, that aren't part of the message history
     pub fn supported_event(ev: &&JsonValue) -> bool {
         let type_ = ev["type"].as_str().unwrap_or_default();
 
-        for t in Message::types().iter() {
-            if t == &type_ {
-                return true;
-            }
+        if type_.is_empty() || !ev["state_key"].is_null() {
+            return false;
+        }
+
+        let hidden = Message::hidden_types().iter()
+            .any(|t| type_ == *t || (t.ends_with('.') && type_.starts_with(t)));
+        if hidden {
+            return false;
         }
 
-        false
+        // the relation of an encrypted event is in clear, so we can tell apart the reactions and
+        // the edits, that would be shown as new messages
+        if type_ == "m.room.encrypted" {
+            let rel_type = ev["content"]["m.relates_to"]["rel_type"].as_str().unwrap_or_default();
+            return rel_type != "m.annotation" && rel_type != "m.replace";
+        }
+
+        Message::types().contains(&type_) || ev["content"]["body"].is_string()
+    }
+
+    /// The message is an encrypted event that we couldn't decrypt
+    pub fn is_undecryptable(&self) -> bool {
+        self.mtype == "m.room.encrypted"
+    }
+
+    /// The message is an event of a type that we don't know how to show
+    pub fn is_unsupported(&self) -> bool {
+        self.mtype == UNSUPPORTED_MTYPE
     }
 
     /// Parses a matrix.org event and return a Message object
@@ -124,12 +182,18 @@ impl Message {
             age = msg["unsigned"]["age"].as_i64().unwrap_or(0);
         }
 
+        // the age is only valid when the event is received, so stored events use the timestamp.
+        // Both come from the server, an invalid timestamp is ignored
+        let date = msg["origin_server_ts"].as_i64()
+            .and_then(|ts| Local.timestamp_opt(ts / 1000, ((ts % 1000) * 1_000_000) as u32).single())
+            .unwrap_or_else(|| Message::age_to_datetime(age));
+
         let id = msg["event_id"].as_str().unwrap_or("");
         let type_ = msg["type"].as_str().unwrap_or("");
 
         let mut message = Message {
             sender: sender.to_string(),
-            date: Message::age_to_datetime(age),
+            date: date,
             room: roomid.clone(),
             id: Some(id.to_string()),
             mtype: type_.to_string(),
@@ -138,13 +202,38 @@ impl Message {
             thumb: None,
             formatted_body: None,
             format: None,
+            redacted: false,
+            replace: None,
+            edited: false,
+            in_reply_to: None,
         };
 
+        // the server removes the content of redacted events
+        if msg["unsigned"]["redacted_because"].is_object() {
+            message.redacted = true;
+            return message;
+        }
+
         let c = &msg["content"];
         match type_ {
-            "m.room.message" => Message::parse_m_room_message(&mut message, c),
+            "m.room.message" => {
+                Message::parse_m_room_message(&mut message, c);
+                // edits have the new content apart, the body is only a fallback
+                if c["m.relates_to"]["rel_type"] == "m.replace" && c["m.new_content"].is_object() {
+                    message.replace = c["m.relates_to"]["event_id"].as_str().map(|s| s.to_string());
+                    Message::parse_m_room_message(&mut message, &c["m.new_content"]);
+                }
+                if let Some(evid) = c["m.relates_to"]["m.in_reply_to"]["event_id"].as_str() {
+                    message.in_reply_to = Some(evid.to_string());
+                    message.strip_reply_fallback();
+                }
+            }
             "m.sticker" => Message::parse_m_sticker(&mut message, c),
-            _ => {}
+            "m.room.encrypted" => {}
+            _ => {
+                message.mtype = UNSUPPORTED_MTYPE.to_string();
+                message.body = c["body"].as_str().unwrap_or_default().to_string();
+            }
         };
 
         message
@@ -190,6 +279,122 @@ impl Message {
         msg.thumb = Some(t);
     }
 
+    /// Removes the quote of the replied message that is added for the clients without reply
+    /// support
+    fn strip_reply_fallback(&mut self) {
+        let body = self.body.clone();
+        let lines: Vec<&str> = body.split('\n').collect();
+        let quoted = lines.iter().take_while(|l| l.starts_with('>')).count();
+        if quoted > 0 {
+            let mut rest = &lines[quoted..];
+            if rest.first() == Some(&"") {
+                rest = &rest[1..];
+            }
+            self.body = rest.join("\n");
+        }
+
+        if let Some(ref mut f) = self.formatted_body {
+            if let (Some(start), Some(end)) = (f.find("<mx-reply>"), f.find("</mx-reply>")) {
+                if start < end {
+                    let stripped = format!("{}{}", &f[..start], &f[end + "</mx-reply>".len()..]);
+                    *f = stripped;
+                }
+            }
+        }
+    }
+
+    /// The body and the html body of a reply to `parent`, with the quote of the parent message
+    /// for the clients without reply support
+    pub fn reply_fallback(&self, parent: &Message) -> (String, String) {
+        let parent_body = match parent.mtype.as_ref() {
+            "m.image" => "sent an image.".to_string(),
+            "m.video" => "sent a video.".to_string(),
+            "m.audio" => "sent an audio file.".to_string(),
+            "m.file" => "sent a file.".to_string(),
+            "m.emote" => format!("* {}", parent.body),
+            _ => parent.body.clone(),
+        };
+
+        let mut body = String::new();
+        for (i, line) in parent_body.split('\n').enumerate() {
+            match i {
+                0 => body.push_str(&format!("> <{}> {}\n", parent.sender, line)),
+                _ => body.push_str(&format!("> {}\n", line)),
+            };
+        }
+        body.push_str("\n");
+        body.push_str(&self.body);
+
+        let parent_html = match (&parent.format, &parent.formatted_body) {
+            (&Some(ref f), &Some(ref f_b)) if f == "org.matrix.custom.html" => f_b.clone(),
+            _ => html_escape(&parent_body).replace('\n', "<br />"),
+        };
+        let html = match (&self.format, &self.formatted_body) {
+            (&Some(ref f), &Some(ref f_b)) if f == "org.matrix.custom.html" => f_b.clone(),
+            _ => html_escape(&self.body).replace('\n', "<br />"),
+        };
+        let formatted = format!("<mx-reply><blockquote>\
+                                 <a href=\"https://matrix.to/#/{room}/{evid}\">In reply to</a> \
+                                 <a href=\"https://matrix.to/#/{sender}\">{sender}</a>\
+                                 <br />{parent}</blockquote></mx-reply>{html}",
+                                room = parent.room,
+                                evid = parent.id.clone().unwrap_or_default(),
+                                sender = parent.sender,
+                                parent = parent_html,
+                                html = html);
+
+        (body, formatted)
+    }
+
+    /// Removes the content of the message, as the server does when the event is redacted
+    pub fn redact(&mut self) {
+        self.body = String::new();
+        self.formatted_body = None;
+        self.format = None;
+        self.url = None;
+        self.thumb = None;
+        self.redacted = true;
+    }
+
+    /// Replaces the content of the message with the content of an edit
+    pub fn apply_edit(&mut self, edit: &Message) {
+        self.mtype = edit.mtype.clone();
+        self.body = edit.body.clone();
+        self.formatted_body = edit.formatted_body.clone();
+        self.format = edit.format.clone();
+        self.url = edit.url.clone();
+        self.thumb = edit.thumb.clone();
+        self.edited = true;
+    }
+
+    /// Applies the edits of a list of messages to the edited messages and removes them.
+    ///
+    /// Returns the messages and the edits of messages that aren't in the list, that have to be
+    /// applied when the edited messages are loaded
+    pub fn apply_edits(msgs: Vec<Message>) -> (Vec<Message>, Vec<Message>) {
+        let (edits, mut msgs): (Vec<Message>, Vec<Message>) = msgs.into_iter()
+            .partition(|m| m.replace.is_some());
+
+        let pending = Message::apply_edits_to(edits, &mut msgs);
+        (msgs, pending)
+    }
+
+    /// Applies `edits` to the edited messages in `msgs`, returns the edits that weren't applied
+    pub fn apply_edits_to(edits: Vec<Message>, msgs: &mut [Message]) -> Vec<Message> {
+        let mut pending = vec![];
+
+        for edit in edits {
+            let target = msgs.iter_mut()
+                .find(|m| m.id.is_some() && m.id == edit.replace && m.sender == edit.sender);
+            match target {
+                Some(m) => m.apply_edit(&edit),
+                None => pending.push(edit),
+            };
+        }
+
+        pending
+    }
+
     /// Create a vec of Message from a json event list
     ///
     /// * `roomid` - The messages room id
@@ -210,6 +415,98 @@ impl Message {
     fn age_to_datetime(age: i64) -> DateTime<Local> {
         let now = Local::now();
         let diff = Duration::seconds(age / 1000);
-        now - diff
+        now.checked_sub_signed(diff).unwrap_or(now)
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_fallbacks_are_stripped() {
+        let ev = json!({
+            "type": "m.room.message",
+            "sender": "@bob:localhost",
+            "event_id": "$2",
+            "content": {
+                "msgtype": "m.text",
+                "body": "> <@alice:localhost> first line\n> second line\n\nthe reply",
+                "format": "org.matrix.custom.html",
+                "formatted_body": "<mx-reply><blockquote>quote</blockquote></mx-reply><b>the reply</b>",
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$1" } },
+            },
+        });
+
+        let msg = Message::parse_room_message("!room".to_string(), &ev);
+        assert_eq!(msg.in_reply_to, Some("$1".to_string()));
+        assert_eq!(msg.body, "the reply");
+        assert_eq!(msg.formatted_body, Some("<b>the reply</b>".to_string()));
+    }
+
+    #[test]
+    fn invalid_timestamps() {
+        for ts in [-1i64, -1500, i64::max_value(), i64::min_value()].iter() {
+            let ev = json!({
+                "type": "m.room.message",
+                "event_id": "$1",
+                "origin_server_ts": ts,
+                "unsigned": { "age": i64::max_value() },
+                "content": { "msgtype": "m.text", "body": "hi" },
+            });
+            let msg = Message::parse_room_message("!room".to_string(), &ev);
+            assert_eq!(msg.body, "hi");
+        }
+    }
+
+    #[test]
+    fn unknown_events() {
+        let evs = vec![
+            json!({"type": "m.room.encrypted", "event_id": "$1", "content": {"algorithm": "m.megolm.v1.aes-sha2"}}),
+            json!({"type": "m.room.encrypted", "event_id": "$2",
+                   "content": {"m.relates_to": {"rel_type": "m.annotation", "event_id": "$1"}}}),
+            json!({"type": "org.example.poll", "event_id": "$3", "content": {"body": "Lunch?"}}),
+            json!({"type": "m.room.topic", "event_id": "$4", "state_key": "", "content": {}}),
+            json!({"type": "m.reaction", "event_id": "$5", "content": {}}),
+            json!({"type": "m.call.invite", "event_id": "$6", "content": {"call_id": "1"}}),
+            json!({"type": "m.key.verification.start", "event_id": "$7", "content": {"body": "verify"}}),
+            json!({"type": "org.example.state", "event_id": "$8", "content": {"value": 1}}),
+        ];
+
+        let msgs = Message::from_json_events_iter("!room".to_string(), evs.iter());
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].is_undecryptable());
+        assert!(msgs[1].is_unsupported());
+        assert_eq!(msgs[1].body, "Lunch?");
+    }
+
+    #[test]
+    fn reply_fallback() {
+        let parent = Message {
+            sender: "@alice:localhost".to_string(),
+            room: "!room".to_string(),
+            id: Some("$1".to_string()),
+            body: "first <line>\nsecond line".to_string(),
+            ..Default::default()
+        };
+        let reply = Message {
+            body: "the reply".to_string(),
+            in_reply_to: Some("$1".to_string()),
+            ..Default::default()
+        };
+
+        let (body, formatted) = reply.reply_fallback(&parent);
+        assert_eq!(body, "> <@alice:localhost> first <line>\n> second line\n\nthe reply");
+        assert!(formatted.starts_with("<mx-reply><blockquote><a href=\"https://matrix.to/#/!room/$1\">"));
+        assert!(formatted.contains("first &lt;line&gt;<br />second line</blockquote></mx-reply>"));
+        assert!(formatted.ends_with("</mx-reply>the reply"));
     }
 }
@@ -5,3 +5,8 @@ pub mod event;
 pub mod message;
 pub mod stickers;
 pub mod userinfo;
+pub mod sync;
+pub mod timeline;
+pub mod filter;
+pub mod device;
+pub mod server;
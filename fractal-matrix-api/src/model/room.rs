@@ -3,6 +3,21 @@ use model::message::Message;
 use model::member::MemberList;
 use model::member::Member;
 
+/// The read receipts of a room, user id -> (event id, timestamp)
+pub type Receipts = HashMap<String, (String, i64)>;
+
+/// A reaction of a user to a message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reaction {
+    /// The id of the reaction event, needed to redact it
+    pub id: String,
+    pub sender: String,
+    pub key: String,
+}
+
+/// The reactions to the messages of a room, event id -> reactions to that event
+pub type Reactions = HashMap<String, Vec<Reaction>>;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Room {
     pub id: String,
@@ -13,7 +28,13 @@ pub struct Room {
     pub guest_can_join: bool,
     pub world_readable: bool,
     pub n_members: i32,
+    pub n_invited_members: i32,
+    /// The members used to calculate the room name, when the members are lazy loaded
+    pub heroes: Vec<String>,
+    /// The known members, only the senders of the loaded messages until the whole list is
+    /// loaded and `members_loaded` is true
     pub members: MemberList,
+    pub members_loaded: bool,
     pub notifications: i32,
     pub highlight: i32,
     pub messages: Vec<Message>,
@@ -26,6 +47,16 @@ pub struct Room {
     /// Hashmap with the room users power levels
     /// the key will be the userid and the value will be the level
     pub power_levels: HashMap<String, i32>,
+
+    /// The last message read by each user
+    pub receipts: Receipts,
+    /// The event id of our `m.fully_read` marker, the messages after it are unread
+    pub fully_read: Option<String>,
+    #[serde(default)]
+    pub reactions: Reactions,
+    /// The room has end-to-end encryption enabled
+    #[serde(default)]
+    pub encrypted: bool,
 }
 
 impl Room {
@@ -39,18 +70,70 @@ impl Room {
             guest_can_join: true,
             world_readable: true,
             n_members: 0,
+            n_invited_members: 0,
+            heroes: vec![],
             notifications: 0,
             highlight: 0,
             messages: vec![],
             members: HashMap::new(),
+            members_loaded: false,
             fav: false,
             left: false,
             inv: false,
             direct: false,
             inv_sender: None,
             power_levels: HashMap::new(),
+            receipts: HashMap::new(),
+            fully_read: None,
+            reactions: HashMap::new(),
+            encrypted: false,
         }
     }
+
+    /// Adds a reaction to the message `evid`, ignoring the reactions that we already know
+    pub fn add_reaction(&mut self, evid: &str, reaction: Reaction) {
+        let reactions = self.reactions.entry(evid.to_string()).or_insert(vec![]);
+        if !reactions.iter().any(|r| r.id == reaction.id) {
+            reactions.push(reaction);
+        }
+    }
+
+    /// Removes a redacted reaction, returns the id of the message that it was reacting to
+    pub fn remove_reaction(&mut self, id: &str) -> Option<String> {
+        for (evid, reactions) in self.reactions.iter_mut() {
+            if let Some(pos) = reactions.iter().position(|r| r.id == id) {
+                reactions.remove(pos);
+                return Some(evid.clone());
+            }
+        }
+
+        None
+    }
+
+    /// The reactions to the message `evid` grouped by key, with the senders of each key, in the
+    /// order that the keys were first used
+    pub fn reaction_keys(&self, evid: &str) -> Vec<(String, Vec<String>)> {
+        let mut keys: Vec<(String, Vec<String>)> = vec![];
+        for r in self.reactions.get(evid).map(|rs| rs.as_slice()).unwrap_or(&[]) {
+            match keys.iter().position(|&(ref k, _)| k == &r.key) {
+                Some(i) => {
+                    if !keys[i].1.contains(&r.sender) {
+                        keys[i].1.push(r.sender.clone());
+                    }
+                }
+                None => keys.push((r.key.clone(), vec![r.sender.clone()])),
+            };
+        }
+
+        keys
+    }
+
+    /// The id of the reaction of `uid` to the message `evid` with `key`
+    pub fn own_reaction(&self, evid: &str, key: &str, uid: &str) -> Option<String> {
+        self.reactions.get(evid)?.iter()
+            .find(|r| r.key == key && r.sender == uid)
+            .map(|r| r.id.clone())
+    }
 }
 
 impl Clone for Room {
@@ -64,16 +147,23 @@ impl Clone for Room {
             guest_can_join: self.guest_can_join,
             world_readable: self.world_readable,
             n_members: self.n_members,
+            n_invited_members: self.n_invited_members,
+            heroes: self.heroes.clone(),
             notifications: self.notifications,
             highlight: self.highlight,
             messages: self.messages.iter().cloned().collect(),
             members: self.members.clone(),
+            members_loaded: self.members_loaded,
             fav: self.fav,
             left: self.left,
             inv: self.inv,
             direct: self.direct,
             inv_sender: self.inv_sender.clone(),
             power_levels: self.power_levels.clone(),
+            receipts: self.receipts.clone(),
+            fully_read: self.fully_read.clone(),
+            reactions: self.reactions.clone(),
+            encrypted: self.encrypted,
         }
     }
 }
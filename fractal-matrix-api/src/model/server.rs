@@ -0,0 +1,51 @@
+/// What we know about a homeserver after its discovery
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    /// The base URL of the client-server API
+    pub homeserver: String,
+    pub identity_server: Option<String>,
+    /// The versions of the client-server spec that the server supports
+    pub versions: Vec<String>,
+    /// The types of login that the server offers, like `m.login.password` or `m.login.sso`
+    pub login_types: Vec<String>,
+}
+
+impl ServerInfo {
+    pub fn supports_password(&self) -> bool {
+        self.login_types.iter().any(|t| t == "m.login.password")
+    }
+
+    /// If the user can login with the browser, with SSO or CAS
+    pub fn supports_sso(&self) -> bool {
+        self.login_types.iter().any(|t| t == "m.login.sso" || t == "m.login.cas")
+    }
+
+    /// If the login in the browser has to be done with CAS, for servers without SSO
+    pub fn uses_cas(&self) -> bool {
+        !self.login_types.iter().any(|t| t == "m.login.sso") &&
+            self.login_types.iter().any(|t| t == "m.login.cas")
+    }
+
+    /// If the password can't be used to login, only the browser
+    pub fn sso_only(&self) -> bool {
+        self.supports_sso() && !self.supports_password()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_types() {
+        let mut info = ServerInfo::default();
+        info.login_types = vec![strn!("m.login.sso"), strn!("m.login.token")];
+        assert!(info.sso_only() && !info.uses_cas());
+
+        info.login_types = vec![strn!("m.login.cas")];
+        assert!(info.sso_only() && info.uses_cas());
+
+        info.login_types.push(strn!("m.login.password"));
+        assert!(info.supports_sso() && !info.sso_only());
+    }
+}
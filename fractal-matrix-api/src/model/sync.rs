@@ -0,0 +1,104 @@
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+use std::collections::HashMap;
+
+/// The body of a /sync response.
+///
+/// The response is deserialized once and the rooms, messages, notifications and events are
+/// derived from these structs. The events are kept as json because each one is parsed later to
+/// a `Message` or an `Event` depending on its type.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    pub rooms: Rooms,
+    pub presence: Events,
+    pub account_data: Events,
+    pub to_device: Events,
+    pub device_lists: DeviceLists,
+    // the number of one time keys of this device that the server has, by algorithm
+    pub device_one_time_keys_count: HashMap<String, u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Rooms {
+    pub join: HashMap<String, JoinedRoom>,
+    pub leave: HashMap<String, LeftRoom>,
+    pub invite: HashMap<String, InvitedRoom>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct JoinedRoom {
+    pub state: Events,
+    pub timeline: Timeline,
+    pub ephemeral: Events,
+    pub account_data: Events,
+    pub unread_notifications: UnreadNotifications,
+    pub summary: RoomSummary,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LeftRoom {
+    pub state: Events,
+    pub timeline: Timeline,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct InvitedRoom {
+    pub invite_state: Events,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Timeline {
+    pub events: Vec<JsonValue>,
+    pub limited: bool,
+    pub prev_batch: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct Events {
+    pub events: Vec<JsonValue>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct UnreadNotifications {
+    pub highlight_count: i32,
+    pub notification_count: i32,
+}
+
+/// The users whose devices changed since the last sync, or that don't share an encrypted room
+/// with us anymore
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeviceLists {
+    pub changed: Vec<String>,
+    pub left: Vec<String>,
+}
+
+/// The room summary sent when the members are lazy loaded, the fields are only present when
+/// they change
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RoomSummary {
+    #[serde(rename = "m.heroes")]
+    pub heroes: Option<Vec<String>>,
+    #[serde(rename = "m.joined_member_count")]
+    pub joined_member_count: Option<i32>,
+    #[serde(rename = "m.invited_member_count")]
+    pub invited_member_count: Option<i32>,
+}
+
+impl Events {
+    /// Returns the first event of type `t`
+    pub fn find(&self, t: &str) -> Option<&JsonValue> {
+        self.events.iter().find(|x| x["type"] == t)
+    }
+}
@@ -0,0 +1,290 @@
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+
+use types::Message;
+use types::Member;
+use types::Reaction;
+
+/// A piece of the room history without holes
+#[derive(Debug, Clone, Default)]
+pub struct TimelineChunk {
+    pub messages: Vec<Message>,
+    /// Token to paginate backwards from the first event of this chunk
+    pub prev_batch: Option<String>,
+    /// Token to paginate forwards from the last event of this chunk
+    pub next_batch: Option<String>,
+}
+
+/// The events missing between two chunks.
+///
+/// It can be filled paginating backwards from `from` until `to`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gap {
+    pub from: String,
+    pub to: String,
+}
+
+/// A page of events returned by /rooms/{id}/messages
+#[derive(Debug, Clone, Default)]
+pub struct MessagesPage {
+    /// The supported messages in chronological order
+    pub messages: Vec<Message>,
+    /// Token to continue the pagination, None if there're no more events in that direction
+    pub end: Option<String>,
+    /// The members that sent these messages, when the members are lazy loaded
+    pub members: Vec<Member>,
+    /// The reactions in this page, as (reacted event id, reaction)
+    pub reactions: Vec<(String, Reaction)>,
+    /// The events of the page in chronological order, as received, to store them
+    pub events: Vec<JsonValue>,
+    /// The edits of messages that aren't in this page
+    pub edits: Vec<Message>,
+}
+
+/// The known history of a room.
+///
+/// The history is a list of chunks, oldest first, with a gap between each pair of consecutive
+/// chunks. A limited sync adds a new chunk after a gap, that can be filled later paginating
+/// backwards, and older messages are added to the first chunk until the start of the room is
+/// reached.
+#[derive(Debug, Clone, Default)]
+pub struct Timeline {
+    pub room: String,
+    pub chunks: Vec<TimelineChunk>,
+    pub start_reached: bool,
+    /// Edits of messages that aren't loaded yet, applied when the messages are paginated
+    pub pending_edits: Vec<Message>,
+}
+
+impl Timeline {
+    pub fn new(room: &str) -> Timeline {
+        Timeline {
+            room: room.to_string(),
+            chunks: vec![],
+            start_reached: false,
+            pending_edits: vec![],
+        }
+    }
+
+    /// Adds the messages of a sync response at the end of the timeline.
+    ///
+    /// Returns true if the sync was limited and there's a gap before the new messages
+    pub fn push_sync(&mut self, messages: Vec<Message>, prev_batch: Option<String>, limited: bool, next_batch: &str) -> bool {
+        let gap = limited && !self.chunks.is_empty();
+
+        if gap || self.chunks.is_empty() {
+            self.chunks.push(TimelineChunk {
+                messages: messages,
+                prev_batch: prev_batch,
+                next_batch: Some(next_batch.to_string()),
+            });
+        } else if let Some(last) = self.chunks.last_mut() {
+            last.messages.extend(messages);
+            last.next_batch = Some(next_batch.to_string());
+        }
+
+        gap
+    }
+
+    /// Replaces the whole timeline with the last page of the room history
+    pub fn reset(&mut self, page: MessagesPage, next_batch: Option<String>) {
+        self.start_reached = page.end.is_none();
+        self.chunks = vec![TimelineChunk {
+            messages: page.messages,
+            prev_batch: page.end,
+            next_batch: next_batch,
+        }];
+    }
+
+    /// The gap before the newest chunk, if there's one
+    pub fn last_gap(&self) -> Option<Gap> {
+        let n = self.chunks.len();
+        if n < 2 {
+            return None;
+        }
+
+        match (&self.chunks[n - 1].prev_batch, &self.chunks[n - 2].next_batch) {
+            (&Some(ref from), &Some(ref to)) => Some(Gap { from: from.clone(), to: to.clone() }),
+            _ => None,
+        }
+    }
+
+    /// Adds the messages of a backwards pagination inside the last gap.
+    ///
+    /// When the page `end` is None the gap is closed and the two chunks are merged.
+    pub fn fill_last_gap(&mut self, page: MessagesPage) {
+        let n = self.chunks.len();
+        if n < 2 {
+            return;
+        }
+
+        {
+            let last = &mut self.chunks[n - 1];
+            let mut messages = page.messages;
+            messages.extend(last.messages.drain(..));
+            last.messages = messages;
+            last.prev_batch = page.end.clone();
+        }
+
+        if page.end.is_none() {
+            let last = self.chunks.pop().unwrap_or_default();
+            if let Some(prev) = self.chunks.last_mut() {
+                prev.messages.extend(last.messages);
+                prev.next_batch = last.next_batch;
+            }
+        }
+    }
+
+    /// Token to load the messages before the first known one, None if the start of the room was
+    /// reached
+    pub fn back_token(&self) -> Option<String> {
+        if self.start_reached {
+            return None;
+        }
+
+        self.chunks.first().and_then(|c| c.prev_batch.clone())
+    }
+
+    /// Adds the messages of a backwards pagination at the start of the timeline
+    pub fn prepend(&mut self, page: MessagesPage) {
+        if self.chunks.is_empty() {
+            self.chunks.push(TimelineChunk::default());
+        }
+
+        let first = &mut self.chunks[0];
+        let mut messages = page.messages;
+        messages.extend(first.messages.drain(..));
+        first.messages = messages;
+        first.prev_batch = page.end.clone();
+
+        self.start_reached = page.end.is_none();
+    }
+
+    /// A known message of the timeline
+    pub fn find(&self, evid: &str) -> Option<Message> {
+        self.chunks.iter()
+            .flat_map(|c| c.messages.iter())
+            .find(|m| m.id.as_ref().map(|id| id.as_str()) == Some(evid))
+            .cloned()
+    }
+
+    /// Removes the content of a known message, returns false if the message isn't in the timeline
+    pub fn redact(&mut self, evid: &str) -> bool {
+        for chunk in self.chunks.iter_mut() {
+            if let Some(m) = chunk.messages.iter_mut().find(|m| m.id.as_ref().map(|id| id.as_str()) == Some(evid)) {
+                m.redact();
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Applies an edit to a known message, returns false if the message isn't in the timeline,
+    /// then the edit is kept until the message is loaded
+    pub fn edit(&mut self, evid: &str, edit: &Message) -> bool {
+        for chunk in self.chunks.iter_mut() {
+            let msg = chunk.messages.iter_mut()
+                .find(|m| m.id.as_ref().map(|id| id.as_str()) == Some(evid) && m.sender == edit.sender);
+            if let Some(m) = msg {
+                m.apply_edit(edit);
+                return true;
+            }
+        }
+
+        self.pending_edits.push(edit.clone());
+        false
+    }
+
+    /// Applies the pending edits to the messages of a page loaded before adding it to the
+    /// timeline, and keeps the edits of the page that target older messages
+    pub fn apply_pending_edits(&mut self, page: &mut MessagesPage) {
+        let edits = self.pending_edits.drain(..).collect();
+        self.pending_edits = Message::apply_edits_to(edits, &mut page.messages);
+        self.pending_edits.extend(page.edits.drain(..));
+    }
+
+    /// Replaces a known message with a new version of the same event, like an encrypted message
+    /// that was decrypted later. Returns false if the message isn't in the timeline
+    pub fn replace(&mut self, msg: &Message) -> bool {
+        for chunk in self.chunks.iter_mut() {
+            if let Some(m) = chunk.messages.iter_mut().find(|m| m.id.is_some() && m.id == msg.id) {
+                *m = msg.clone();
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// All the known messages, oldest first
+    pub fn messages(&self) -> Vec<Message> {
+        self.chunks.iter().flat_map(|c| c.messages.iter().cloned()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(body: &str) -> Message {
+        Message {
+            body: body.to_string(),
+            id: Some(body.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn page(bodies: &[&str], end: Option<&str>) -> MessagesPage {
+        MessagesPage {
+            messages: bodies.iter().map(|b| msg(b)).collect(),
+            end: end.map(|e| e.to_string()),
+            members: vec![],
+            reactions: vec![],
+            events: vec![],
+            edits: vec![],
+        }
+    }
+
+    fn bodies(t: &Timeline) -> Vec<String> {
+        t.messages().iter().map(|m| m.body.clone()).collect()
+    }
+
+    #[test]
+    fn limited_sync_creates_a_gap() {
+        let mut t = Timeline::new("!room:localhost");
+        assert!(!t.push_sync(vec![msg("a")], Some(strn!("p1")), true, "s1"));
+        assert!(!t.push_sync(vec![msg("b")], Some(strn!("p2")), false, "s2"));
+        assert_eq!(t.chunks.len(), 1);
+        assert_eq!(t.last_gap(), None);
+
+        assert!(t.push_sync(vec![msg("e")], Some(strn!("p3")), true, "s3"));
+        assert_eq!(t.last_gap(), Some(Gap { from: strn!("p3"), to: strn!("s2") }));
+
+        t.fill_last_gap(page(&["d"], Some("p4")));
+        assert_eq!(t.last_gap(), Some(Gap { from: strn!("p4"), to: strn!("s2") }));
+        assert_eq!(bodies(&t), vec!["a", "b", "d", "e"]);
+
+        t.fill_last_gap(page(&["c"], None));
+        assert_eq!(t.chunks.len(), 1);
+        assert_eq!(t.last_gap(), None);
+        assert_eq!(bodies(&t), vec!["a", "b", "c", "d", "e"]);
+        assert_eq!(t.chunks[0].next_batch, Some(strn!("s3")));
+    }
+
+    #[test]
+    fn back_pagination_until_the_start() {
+        let mut t = Timeline::new("!room:localhost");
+        t.push_sync(vec![msg("c")], Some(strn!("p1")), true, "s1");
+        assert_eq!(t.back_token(), Some(strn!("p1")));
+
+        t.prepend(page(&["a", "b"], Some("p0")));
+        assert_eq!(t.back_token(), Some(strn!("p0")));
+        assert_eq!(bodies(&t), vec!["a", "b", "c"]);
+
+        t.prepend(page(&[], None));
+        assert!(t.start_reached);
+        assert_eq!(t.back_token(), None);
+    }
+}
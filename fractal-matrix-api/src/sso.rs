@@ -0,0 +1,163 @@
+//! Single sign-on.
+//!
+//! The SSO (and CAS) login is done in the browser: the homeserver redirects it to the identity
+//! provider and, once the user is authenticated, to the `redirectUrl` that we gave with a
+//! `loginToken` query param. We listen on a loopback port to receive that redirect and the
+//! token is exchanged for an access token with a `m.login.token` login.
+//!
+//! https://matrix.org/docs/spec/client_server/r0.4.0.html#sso-client-login
+
+extern crate url;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use self::url::Url;
+
+use error::Error;
+use globals;
+
+const RESPONSE: &'static str = "HTTP/1.1 200 OK\r\n\
+    Content-Type: text/html; charset=utf-8\r\n\
+    Connection: close\r\n\r\n\
+    <html><body><p>You're logged in, you can close this window and go back to Fractal.</p></body></html>";
+
+const NOT_FOUND: &'static str = "HTTP/1.1 404 Not Found\r\nConnection: close\r\n\r\n";
+
+/// Seconds that a connection can take to send its request or to read the response
+const STREAM_TIMEOUT: u64 = 10;
+
+/// A listener on a random loopback port that waits for the redirect with the login token
+pub struct SsoListener {
+    listener: TcpListener,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl SsoListener {
+    pub fn bind() -> Result<SsoListener, Error> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        Ok(SsoListener { listener: listener, cancelled: Arc::new(AtomicBool::new(false)) })
+    }
+
+    /// A flag to stop `wait_token` from another thread, the listener is dropped with the
+    /// waiting thread so the port is closed
+    pub fn canceller(&self) -> Arc<AtomicBool> {
+        self.cancelled.clone()
+    }
+
+    /// The URL that the homeserver has to redirect the browser to
+    pub fn redirect_url(&self) -> Result<String, Error> {
+        let port = self.listener.local_addr()?.port();
+        Ok(format!("http://127.0.0.1:{}/", port))
+    }
+
+    /// Waits for the browser and returns the login token, or None if it's cancelled. Other
+    /// requests, like the favicon, and broken connections are ignored. Fails if the login
+    /// isn't done after `globals::SSO_TIMEOUT` seconds
+    pub fn wait_token(&self) -> Result<Option<String>, Error> {
+        let deadline = Instant::now() + Duration::from_secs(globals::SSO_TIMEOUT);
+
+        while Instant::now() < deadline {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+
+            match self.listener.accept() {
+                Ok((stream, _)) => match handle(stream) {
+                    Ok(Some(token)) => return Ok(Some(token)),
+                    Ok(None) => {}
+                    Err(err) => eprintln!("Error reading the single sign-on request: {:?}", err),
+                },
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            };
+        }
+
+        Err(Error::BackendError)
+    }
+}
+
+/// Answers a request of the browser, returning the login token if it has one
+fn handle(mut stream: TcpStream) -> Result<Option<String>, Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(STREAM_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(STREAM_TIMEOUT)))?;
+
+    let mut line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut line)?;
+
+    // the headers are read until the empty line, the request has no body
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    // GET /?loginToken=xxx HTTP/1.1
+    let token = line.split_whitespace().nth(1)
+        .and_then(|path| Url::parse("http://127.0.0.1").ok()?.join(path).ok())
+        .and_then(|url| {
+            url.query_pairs()
+                .find(|&(ref k, _)| k == "loginToken")
+                .map(|(_, v)| v.into_owned())
+        });
+
+    match token {
+        Some(_) => stream.write_all(RESPONSE.as_bytes())?,
+        None => stream.write_all(NOT_FOUND.as_bytes())?,
+    };
+
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn receive_token() {
+        let listener = SsoListener::bind().unwrap();
+        let url = listener.redirect_url().unwrap();
+        let addr = url.trim_left_matches("http://").trim_right_matches('/').to_string();
+
+        let browser = thread::spawn(move || {
+            let mut responses = vec![];
+            for path in ["/favicon.ico", "/?loginToken=abc%2B123"].iter() {
+                let mut stream = TcpStream::connect(&addr).unwrap();
+                write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                responses.push(response);
+            }
+            responses
+        });
+
+        assert_eq!(listener.wait_token().unwrap(), Some(strn!("abc+123")));
+        let responses = browser.join().unwrap();
+        assert!(responses[0].starts_with("HTTP/1.1 404"));
+        assert!(responses[1].starts_with("HTTP/1.1 200"));
+    }
+
+    #[test]
+    fn broken_connection_and_cancel() {
+        let listener = SsoListener::bind().unwrap();
+        let url = listener.redirect_url().unwrap();
+        let addr = url.trim_left_matches("http://").trim_right_matches('/').to_string();
+        let cancel = listener.canceller();
+
+        let browser = thread::spawn(move || {
+            // a connection closed before sending the request doesn't stop the wait
+            drop(TcpStream::connect(&addr).unwrap());
+            thread::sleep(Duration::from_millis(300));
+            cancel.store(true, Ordering::SeqCst);
+        });
+
+        assert_eq!(listener.wait_token().unwrap(), None);
+        browser.join().unwrap();
+    }
+}
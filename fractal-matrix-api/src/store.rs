@@ -0,0 +1,784 @@
+extern crate serde;
+extern crate serde_json;
+extern crate md5;
+
+use self::serde::Serialize;
+use self::serde::de::DeserializeOwned;
+use self::serde_json::Value as JsonValue;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use error::Error;
+use globals;
+
+use util::calculate_room_name;
+use util::evc;
+use util::get_admins;
+use util::parse_room_member;
+use util::parse_receipts;
+use util::get_fully_read;
+use util::fully_read_message;
+use util::parse_reactions;
+
+use types::Message;
+use types::Room;
+use types::Timeline;
+use types::Receipts;
+use types::Reaction;
+use types::SyncResponse;
+
+use crypto::CryptoStore;
+
+/// The session that the stored data belongs to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredSession {
+    pub user_id: String,
+    pub since: String,
+}
+
+/// The persisted state of a room.
+///
+/// The state events are stored by type and state_key so each sync only needs to replace the
+/// events that changed, and the `Room` is rebuilt from them when it's loaded.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoredRoom {
+    pub id: String,
+    pub inv: bool,
+    pub state: HashMap<String, HashMap<String, JsonValue>>,
+    pub notifications: i32,
+    pub highlight: i32,
+    pub fav: bool,
+    pub direct: bool,
+    #[serde(default)]
+    pub heroes: Vec<String>,
+    #[serde(default)]
+    pub joined_members: Option<i32>,
+    #[serde(default)]
+    pub invited_members: Option<i32>,
+    #[serde(default)]
+    pub receipts: Receipts,
+    #[serde(default)]
+    pub fully_read: Option<String>,
+}
+
+/// A batch of timeline events as received in one sync.
+///
+/// A limited chunk has a gap before it, from its `prev_batch` to the `next_batch` of the
+/// previous chunk, until it's filled
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Chunk {
+    pub prev_batch: Option<String>,
+    pub limited: bool,
+    pub events: Vec<JsonValue>,
+    /// The `next_batch` of the sync, to paginate forwards from the last event
+    #[serde(default)]
+    pub next_batch: Option<String>,
+}
+
+/// Storage for the client state, so it can be restored without a full initial sync.
+///
+/// The `Backend` writes every sync response to its store, if it has one, and loads the rooms
+/// from it when it starts syncing again.
+pub trait Store: Send + Sync {
+    fn session(&self) -> Result<Option<StoredSession>, Error>;
+    fn set_session(&self, session: &StoredSession) -> Result<(), Error>;
+
+    fn room_ids(&self) -> Result<Vec<String>, Error>;
+    fn load_room(&self, roomid: &str) -> Result<Option<StoredRoom>, Error>;
+    fn save_room(&self, room: &StoredRoom) -> Result<(), Error>;
+    fn remove_room(&self, roomid: &str) -> Result<(), Error>;
+
+    /// Adds a chunk of events at the end of the stored room timeline. The oldest chunks are
+    /// dropped when the room has more than `STORED_EVENTS` events
+    fn append_chunk(&self, roomid: &str, chunk: &Chunk) -> Result<(), Error>;
+    /// Returns the stored timeline chunks of a room, oldest first
+    fn chunks(&self, roomid: &str) -> Result<Vec<Chunk>, Error>;
+    /// Adds the events of a backwards pagination inside the gap of the last limited chunk. The
+    /// gap is closed if `end` is None, otherwise `end` is the token to continue filling it
+    fn fill_last_gap(&self, roomid: &str, events: &[JsonValue], end: Option<String>) -> Result<(), Error>;
+
+    /// The keys and sessions of this device
+    fn crypto(&self) -> Result<Option<CryptoStore>, Error>;
+    fn set_crypto(&self, crypto: Option<&CryptoStore>) -> Result<(), Error>;
+
+    /// Removes the session and the rooms from the store. The crypto data is kept, it belongs
+    /// to the device and it can't be downloaded again with a full sync
+    fn clear(&self) -> Result<(), Error>;
+}
+
+impl StoredRoom {
+    pub fn new(id: &str) -> StoredRoom {
+        StoredRoom {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Replaces the stored state with the state events in `events`, ignoring non state events
+    pub fn apply_state(&mut self, events: &[JsonValue]) {
+        for ev in events {
+            let t = ev["type"].as_str();
+            let k = ev["state_key"].as_str();
+            if let (Some(t), Some(k)) = (t, k) {
+                self.state.entry(t.to_string())
+                    .or_insert(HashMap::new())
+                    .insert(k.to_string(), ev.clone());
+            }
+        }
+    }
+
+    pub fn state_events(&self) -> Vec<JsonValue> {
+        self.state.values()
+            .flat_map(|evs| evs.values())
+            .cloned()
+            .collect()
+    }
+
+    /// Builds the `Room` for this stored state with the `messages` as timeline
+    pub fn to_room(&self, userid: &str, messages: Vec<Message>) -> Result<Room, Error> {
+        let stevents = self.state_events();
+        let name = calculate_room_name(&stevents, userid, &self.heroes)?;
+        let mut r = Room::new(self.id.clone(), name);
+
+        r.avatar = Some(evc(&stevents, "m.room.avatar", "url"));
+        r.alias = Some(evc(&stevents, "m.room.canonical_alias", "alias"));
+        r.topic = Some(evc(&stevents, "m.room.topic", "topic"));
+        r.encrypted = self.state.contains_key("m.room.encryption");
+        r.inv = self.inv;
+        r.direct = self.direct;
+        r.fav = self.fav;
+        r.notifications = self.notifications;
+        r.highlight = self.highlight;
+        r.power_levels = get_admins(&stevents);
+        r.messages = messages;
+        r.heroes = self.heroes.clone();
+        r.receipts = self.receipts.clone();
+        r.fully_read = self.fully_read.clone();
+
+        if !self.inv {
+            for ev in stevents.iter().filter(|x| x["type"] == "m.room.member") {
+                if let Some(m) = parse_room_member(ev) {
+                    r.members.insert(m.uid.clone(), m);
+                }
+            }
+        }
+        r.n_members = self.joined_members.unwrap_or(r.members.len() as i32);
+        r.n_invited_members = self.invited_members.unwrap_or_default();
+
+        Ok(r)
+    }
+}
+
+/// Writes the changes from a sync response to the store and updates the session `since`
+pub fn store_sync(store: &dyn Store, userid: &str, r: &SyncResponse) -> Result<(), Error> {
+    let direct: Option<Vec<String>> = r.account_data.events.iter()
+        .find(|x| x["type"] == "m.direct")
+        .and_then(|ev| ev["content"].as_object())
+        .map(|content| {
+            content.values()
+                .filter_map(|rooms| rooms.as_array())
+                .flat_map(|rooms| rooms.iter())
+                .filter_map(|room| room.as_str())
+                .map(|room| room.to_string())
+                .collect()
+        });
+
+    for (k, room) in r.rooms.join.iter() {
+        let mut sr = store.load_room(k)?.unwrap_or(StoredRoom::new(k));
+
+        sr.inv = false;
+        sr.apply_state(&room.state.events);
+        sr.apply_state(&room.timeline.events);
+        sr.notifications = room.unread_notifications.notification_count;
+        sr.highlight = room.unread_notifications.highlight_count;
+
+        sr.receipts.extend(parse_receipts(&room.ephemeral.events));
+        if let Some(evid) = get_fully_read(&room.account_data) {
+            sr.fully_read = Some(evid);
+        }
+
+        // the summary fields are only sent when they change
+        if let Some(ref heroes) = room.summary.heroes {
+            sr.heroes = heroes.clone();
+        }
+        if room.summary.joined_member_count.is_some() {
+            sr.joined_members = room.summary.joined_member_count;
+        }
+        if room.summary.invited_member_count.is_some() {
+            sr.invited_members = room.summary.invited_member_count;
+        }
+
+        if let Some(tag) = room.account_data.events.iter().find(|x| x["type"] == "m.tag") {
+            sr.fav = tag["content"]["tags"]["m.favourite"].is_object();
+        }
+
+        store.save_room(&sr)?;
+
+        if !room.timeline.events.is_empty() {
+            store.append_chunk(k, &Chunk {
+                prev_batch: room.timeline.prev_batch.clone(),
+                limited: room.timeline.limited,
+                events: room.timeline.events.clone(),
+                next_batch: Some(r.next_batch.clone()),
+            })?;
+        }
+    }
+
+    for (k, room) in r.rooms.invite.iter() {
+        let mut sr = StoredRoom::new(k);
+        sr.inv = true;
+        sr.apply_state(&room.invite_state.events);
+        store.save_room(&sr)?;
+    }
+
+    for k in r.rooms.leave.keys() {
+        store.remove_room(k)?;
+    }
+
+    if let Some(direct) = direct {
+        for roomid in store.room_ids()? {
+            if let Some(mut sr) = store.load_room(&roomid)? {
+                let d = direct.contains(&roomid);
+                if sr.direct != d {
+                    sr.direct = d;
+                    store.save_room(&sr)?;
+                }
+            }
+        }
+    }
+
+    store.set_session(&StoredSession {
+        user_id: userid.to_string(),
+        since: r.next_batch.clone(),
+    })
+}
+
+/// Rebuilds all the stored rooms, with the last `PAGE_LIMIT` messages of their timeline.
+///
+/// The events are stored as received, so the encrypted ones are decrypted with `decrypt`
+pub fn load_rooms(store: &dyn Store, userid: &str, decrypt: &dyn Fn(&str, &mut Vec<JsonValue>)) -> Result<Vec<Room>, Error> {
+    let mut rooms = vec![];
+
+    for roomid in store.room_ids()? {
+        let sr = match store.load_room(&roomid)? {
+            Some(sr) => sr,
+            None => continue,
+        };
+
+        let mut messages = vec![];
+        let mut reactions = vec![];
+        let mut redacted = vec![];
+        let mut events = vec![];
+        for chunk in store.chunks(&roomid)?.iter_mut().rev() {
+            decrypt(&roomid, &mut chunk.events);
+            // the events are stored as received, so the redactions are applied when loading
+            redacted.extend(chunk.events.iter()
+                .filter(|ev| ev["type"] == "m.room.redaction")
+                .filter_map(|ev| ev["redacts"].as_str())
+                .map(|id| id.to_string()));
+
+            let mut ms = Message::from_json_events_iter(roomid.clone(), chunk.events.iter());
+            for m in ms.iter_mut() {
+                if m.id.as_ref().map(|id| redacted.contains(id)).unwrap_or(false) {
+                    m.redact();
+                }
+            }
+            ms.extend(messages);
+            messages = ms;
+
+            let mut rs: Vec<(String, Reaction)> = parse_reactions(&roomid, chunk.events.iter())
+                .into_iter()
+                .filter(|&(_, ref r)| !redacted.contains(&r.id))
+                .collect();
+            rs.extend(reactions);
+            reactions = rs;
+
+            let mut evs = chunk.events.clone();
+            evs.extend(events);
+            events = evs;
+
+            if messages.len() >= globals::PAGE_LIMIT as usize {
+                break;
+            }
+        }
+
+        let (messages, _) = Message::apply_edits(messages);
+        let skip = match messages.len() {
+            n if n > globals::PAGE_LIMIT as usize => n - globals::PAGE_LIMIT as usize,
+            _ => 0,
+        };
+        let messages = messages.into_iter().skip(skip).collect();
+
+        let mut room = sr.to_room(userid, messages)?;
+        // the marker can be on an event that isn't shown
+        room.fully_read = room.fully_read.map(|evid| fully_read_message(&roomid, &evid, &events, None).unwrap_or(evid));
+        for (evid, reaction) in reactions {
+            room.add_reaction(&evid, reaction);
+        }
+        rooms.push(room);
+    }
+
+    Ok(rooms)
+}
+
+/// Rebuilds the timeline of a stored room, with the last chunks that have `PAGE_LIMIT`
+/// messages, so the gaps between them are known like before the restart.
+///
+/// `since` is the stored sync token, where the timeline ends if it doesn't have stored events,
+/// and the encrypted events are decrypted with `decrypt`
+pub fn load_timeline(store: &dyn Store, roomid: &str, since: &str, decrypt: &dyn Fn(&str, &mut Vec<JsonValue>)) -> Result<Timeline, Error> {
+    let mut chunks = store.chunks(roomid)?;
+    let start = tail_start(roomid, &chunks);
+
+    let mut timeline = Timeline::new(roomid);
+    let mut redacted = vec![];
+    for chunk in chunks[start..].iter_mut() {
+        decrypt(roomid, &mut chunk.events);
+        let ms = Message::from_json_events_iter(roomid.to_string(), chunk.events.iter());
+        let (edits, plain): (Vec<Message>, Vec<Message>) = ms.into_iter().partition(|m| m.replace.is_some());
+
+        let next_batch = chunk.next_batch.clone().unwrap_or_default();
+        timeline.push_sync(plain, chunk.prev_batch.clone(), chunk.limited, &next_batch);
+        for edit in edits.iter() {
+            timeline.edit(edit.replace.as_ref().map(|e| e.as_str()).unwrap_or_default(), edit);
+        }
+
+        redacted.extend(chunk.events.iter()
+            .filter(|ev| ev["type"] == "m.room.redaction")
+            .filter_map(|ev| ev["redacts"].as_str())
+            .map(|id| id.to_string()));
+    }
+    for evid in redacted {
+        timeline.redact(&evid);
+    }
+
+    if timeline.chunks.is_empty() {
+        timeline.push_sync(vec![], Some(since.to_string()), false, since);
+    }
+
+    Ok(timeline)
+}
+
+/// The index of the first of the last chunks that have `PAGE_LIMIT` messages, the ones needed
+/// to load the room
+fn tail_start(roomid: &str, chunks: &[Chunk]) -> usize {
+    let mut start = chunks.len();
+    let mut count = 0;
+    while start > 0 && count < globals::PAGE_LIMIT as usize {
+        start -= 1;
+        count += Message::from_json_events_iter(roomid.to_string(), chunks[start].events.iter()).len();
+    }
+    start
+}
+
+/// Drops the oldest chunks when there're more than `STORED_EVENTS` events, keeping the ones
+/// needed to load the room. Returns false if nothing was dropped
+fn compact_chunks(roomid: &str, chunks: &mut Vec<Chunk>) -> bool {
+    let count: usize = chunks.iter().map(|c| c.events.len()).sum();
+    if count <= globals::STORED_EVENTS {
+        return false;
+    }
+
+    let start = tail_start(roomid, chunks);
+    chunks.drain(..start);
+    start > 0
+}
+
+/// Adds `events` at the start of the last limited chunk, see `Store::fill_last_gap`. Returns
+/// false if there's no limited chunk
+fn fill_chunks_gap(chunks: &mut Vec<Chunk>, events: &[JsonValue], end: Option<String>) -> bool {
+    match chunks.iter_mut().rev().find(|c| c.limited) {
+        Some(chunk) => {
+            let mut evs = events.to_vec();
+            evs.extend(chunk.events.drain(..));
+            chunk.events = evs;
+            chunk.limited = end.is_some();
+            chunk.prev_batch = end;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Store that keeps everything in memory, useful for tests and short lived clients
+pub struct MemoryStore {
+    session: Mutex<Option<StoredSession>>,
+    rooms: Mutex<HashMap<String, StoredRoom>>,
+    chunks: Mutex<HashMap<String, Vec<Chunk>>>,
+    crypto: Mutex<Option<CryptoStore>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            session: Mutex::new(None),
+            rooms: Mutex::new(HashMap::new()),
+            chunks: Mutex::new(HashMap::new()),
+            crypto: Mutex::new(None),
+        }
+    }
+}
+
+impl Store for MemoryStore {
+    fn session(&self) -> Result<Option<StoredSession>, Error> {
+        Ok(self.session.lock().unwrap().clone())
+    }
+
+    fn set_session(&self, session: &StoredSession) -> Result<(), Error> {
+        *self.session.lock().unwrap() = Some(session.clone());
+        Ok(())
+    }
+
+    fn room_ids(&self) -> Result<Vec<String>, Error> {
+        Ok(self.rooms.lock().unwrap().keys().cloned().collect())
+    }
+
+    fn load_room(&self, roomid: &str) -> Result<Option<StoredRoom>, Error> {
+        Ok(self.rooms.lock().unwrap().get(roomid).cloned())
+    }
+
+    fn save_room(&self, room: &StoredRoom) -> Result<(), Error> {
+        self.rooms.lock().unwrap().insert(room.id.clone(), room.clone());
+        Ok(())
+    }
+
+    fn remove_room(&self, roomid: &str) -> Result<(), Error> {
+        self.rooms.lock().unwrap().remove(roomid);
+        self.chunks.lock().unwrap().remove(roomid);
+        Ok(())
+    }
+
+    fn append_chunk(&self, roomid: &str, chunk: &Chunk) -> Result<(), Error> {
+        let mut chunks = self.chunks.lock().unwrap();
+        let chunks = chunks.entry(roomid.to_string()).or_insert(vec![]);
+        chunks.push(chunk.clone());
+        compact_chunks(roomid, chunks);
+        Ok(())
+    }
+
+    fn chunks(&self, roomid: &str) -> Result<Vec<Chunk>, Error> {
+        Ok(self.chunks.lock().unwrap().get(roomid).cloned().unwrap_or_default())
+    }
+
+    fn fill_last_gap(&self, roomid: &str, events: &[JsonValue], end: Option<String>) -> Result<(), Error> {
+        if let Some(chunks) = self.chunks.lock().unwrap().get_mut(roomid) {
+            fill_chunks_gap(chunks, events, end);
+        }
+        Ok(())
+    }
+
+    fn crypto(&self) -> Result<Option<CryptoStore>, Error> {
+        Ok(self.crypto.lock().unwrap().clone())
+    }
+
+    fn set_crypto(&self, crypto: Option<&CryptoStore>) -> Result<(), Error> {
+        *self.crypto.lock().unwrap() = crypto.cloned();
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        *self.session.lock().unwrap() = None;
+        self.rooms.lock().unwrap().clear();
+        self.chunks.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+/// Store that writes the data as json files in a directory.
+///
+/// Each room has a `<hash>.json` file with its state and a `<hash>.timeline` file where the
+/// timeline chunks are appended, one json per line, so a sync doesn't rewrite the history until
+/// it has more than `STORED_EVENTS` events and the oldest chunks are dropped.
+/// The session and the crypto data are in `session.json` and `crypto.json`, the olm pickles of
+/// the crypto data are encrypted by the `Client` with its pickle key.
+pub struct FileStore {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> FileStore {
+        FileStore {
+            path: path.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn rooms_dir(&self) -> Result<PathBuf, Error> {
+        let mut path = self.path.clone();
+        path.push("rooms");
+        if !path.exists() {
+            fs::create_dir_all(&path)?;
+        }
+        Ok(path)
+    }
+
+    fn room_path(&self, roomid: &str, ext: &str) -> Result<PathBuf, Error> {
+        let mut path = self.rooms_dir()?;
+        path.push(format!("{:x}.{}", md5::compute(roomid.as_bytes()), ext));
+        Ok(path)
+    }
+
+    fn file_path(&self, name: &str) -> Result<PathBuf, Error> {
+        if !self.path.exists() {
+            fs::create_dir_all(&self.path)?;
+        }
+        let mut path = self.path.clone();
+        path.push(name);
+        Ok(path)
+    }
+
+    fn read_json<T: DeserializeOwned>(path: &PathBuf) -> Result<Option<T>, Error> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut serialized = String::new();
+        File::open(path)?.read_to_string(&mut serialized)?;
+        Ok(Some(serde_json::from_str(&serialized)?))
+    }
+
+    fn read_chunks(path: &PathBuf) -> Result<Vec<Chunk>, Error> {
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut chunks = vec![];
+        for line in BufReader::new(File::open(path)?).lines() {
+            // a line can be incomplete if the app was closed while writing it
+            if let Ok(chunk) = serde_json::from_str(&line?) {
+                chunks.push(chunk);
+            }
+        }
+
+        Ok(chunks)
+    }
+
+    /// Writes the whole timeline file again, when some old events changed
+    fn write_chunks(path: &PathBuf, chunks: &[Chunk]) -> Result<(), Error> {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            for chunk in chunks.iter() {
+                writeln!(file, "{}", serde_json::to_string(chunk)?)?;
+            }
+        }
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Writes to a temporary file first so a crash never leaves a half written file
+    fn write_json<T: Serialize>(path: &PathBuf, data: &T) -> Result<(), Error> {
+        let serialized = serde_json::to_string(data)?;
+        let tmp = path.with_extension("tmp");
+        File::create(&tmp)?.write_all(serialized.as_bytes())?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn session(&self) -> Result<Option<StoredSession>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        FileStore::read_json(&self.file_path("session.json")?)
+    }
+
+    fn set_session(&self, session: &StoredSession) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        FileStore::write_json(&self.file_path("session.json")?, session)
+    }
+
+    fn room_ids(&self) -> Result<Vec<String>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        let mut ids = vec![];
+
+        for entry in fs::read_dir(self.rooms_dir()?)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                if let Some(room) = FileStore::read_json::<StoredRoom>(&path)? {
+                    ids.push(room.id);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+
+    fn load_room(&self, roomid: &str) -> Result<Option<StoredRoom>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        FileStore::read_json(&self.room_path(roomid, "json")?)
+    }
+
+    fn save_room(&self, room: &StoredRoom) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        FileStore::write_json(&self.room_path(&room.id, "json")?, room)
+    }
+
+    fn remove_room(&self, roomid: &str) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        for ext in ["json", "timeline"].iter() {
+            let path = self.room_path(roomid, ext)?;
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append_chunk(&self, roomid: &str, chunk: &Chunk) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let path = self.room_path(roomid, "timeline")?;
+        {
+            let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+            writeln!(file, "{}", serde_json::to_string(chunk)?)?;
+        }
+
+        // the file only keeps the last events, the older ones are paginated from the server
+        let mut chunks = FileStore::read_chunks(&path)?;
+        if compact_chunks(roomid, &mut chunks) {
+            FileStore::write_chunks(&path, &chunks)?;
+        }
+        Ok(())
+    }
+
+    fn chunks(&self, roomid: &str) -> Result<Vec<Chunk>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        FileStore::read_chunks(&self.room_path(roomid, "timeline")?)
+    }
+
+    fn fill_last_gap(&self, roomid: &str, events: &[JsonValue], end: Option<String>) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let path = self.room_path(roomid, "timeline")?;
+        let mut chunks = FileStore::read_chunks(&path)?;
+
+        if !fill_chunks_gap(&mut chunks, events, end) {
+            return Ok(());
+        }
+
+        FileStore::write_chunks(&path, &chunks)
+    }
+
+    fn crypto(&self) -> Result<Option<CryptoStore>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        FileStore::read_json(&self.file_path("crypto.json")?)
+    }
+
+    fn set_crypto(&self, crypto: Option<&CryptoStore>) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let path = self.file_path("crypto.json")?;
+        match crypto {
+            Some(c) => FileStore::write_json(&path, c),
+            None if path.exists() => Ok(fs::remove_file(path)?),
+            None => Ok(()),
+        }
+    }
+
+    fn clear(&self) -> Result<(), Error> {
+        let _guard = self.lock.lock().unwrap();
+        let mut rooms = self.path.clone();
+        rooms.push("rooms");
+        if rooms.exists() {
+            fs::remove_dir_all(&rooms)?;
+        }
+
+        let session = self.file_path("session.json")?;
+        if session.exists() {
+            fs::remove_file(session)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// A store in a new directory for each test run, so parallel runs don't share it
+    fn temp_store(name: &str) -> (FileStore, PathBuf) {
+        let mut path = env::temp_dir();
+        path.push(format!("fractal-matrix-api-{}-{}", name, process::id()));
+        (FileStore::new(path.clone()), path)
+    }
+
+    #[test]
+    fn file_store_roundtrip() {
+        let (store, path) = temp_store("store-test");
+
+        let mut room = StoredRoom::new("!room:localhost");
+        room.apply_state(&[
+            json!({"type": "m.room.name", "state_key": "", "content": {"name": "Old"}}),
+            json!({"type": "m.room.name", "state_key": "", "content": {"name": "New"}}),
+            json!({"type": "m.room.message", "content": {"body": "not state"}}),
+        ]);
+        store.save_room(&room).unwrap();
+        store.append_chunk("!room:localhost", &Chunk {
+            prev_batch: Some(strn!("t1")),
+            limited: false,
+            events: vec![json!({"type": "m.room.message", "event_id": "$1", "content": {"msgtype": "m.text", "body": "hi"}})],
+            next_batch: Some(strn!("s1")),
+        }).unwrap();
+        store.set_session(&StoredSession { user_id: strn!("@alice:localhost"), since: strn!("s1") }).unwrap();
+
+        assert_eq!(store.room_ids().unwrap(), vec![strn!("!room:localhost")]);
+        assert_eq!(store.session().unwrap().unwrap().since, "s1");
+        assert_eq!(store.chunks("!room:localhost").unwrap().len(), 1);
+
+        let rooms = load_rooms(&store, "@alice:localhost", &|_, _| {}).unwrap();
+        assert_eq!(rooms[0].name, Some(strn!("New")));
+        assert_eq!(rooms[0].messages[0].body, "hi");
+
+        // the events are loaded through the decryption
+        let decrypt = |_: &str, evs: &mut Vec<JsonValue>| evs[0]["content"]["body"] = json!("decrypted");
+        let rooms = load_rooms(&store, "@alice:localhost", &decrypt).unwrap();
+        assert_eq!(rooms[0].messages[0].body, "decrypted");
+        assert_eq!(store.chunks("!room:localhost").unwrap()[0].events[0]["content"]["body"], "hi");
+
+        store.set_crypto(Some(&CryptoStore { device_id: strn!("DEVICE"), ..Default::default() })).unwrap();
+        store.clear().unwrap();
+        assert!(store.session().unwrap().is_none());
+        assert!(store.room_ids().unwrap().is_empty());
+        assert_eq!(store.crypto().unwrap().unwrap().device_id, "DEVICE");
+
+        store.set_crypto(None).unwrap();
+        assert!(store.crypto().unwrap().is_none());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn file_store_keeps_the_last_events() {
+        let (store, path) = temp_store("compact-test");
+
+        for i in 0..30 {
+            let events = (0..40).map(|j| json!({
+                "type": "m.room.message",
+                "event_id": format!("${}-{}", i, j),
+                "content": {"msgtype": "m.text", "body": format!("message {}", i * 40 + j)},
+            })).collect();
+            store.append_chunk("!room:localhost", &Chunk {
+                prev_batch: Some(format!("t{}", i)),
+                limited: false,
+                events: events,
+                next_batch: Some(format!("s{}", i + 1)),
+            }).unwrap();
+        }
+
+        // the 1000 events limit is passed with the chunk 25, only the last one is kept
+        let chunks = store.chunks("!room:localhost").unwrap();
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks[0].prev_batch, Some(strn!("t25")));
+
+        let timeline = load_timeline(&store, "!room:localhost", "s30", &|_, _| {}).unwrap();
+        let last = timeline.chunks.last().unwrap().messages.last().unwrap().body.clone();
+        assert_eq!(last, "message 1199");
+        fs::remove_dir_all(path).unwrap();
+    }
+}
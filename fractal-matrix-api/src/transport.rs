@@ -0,0 +1,258 @@
+extern crate reqwest;
+extern crate serde_json;
+extern crate url;
+extern crate mime;
+extern crate tree_magic;
+
+use self::serde_json::Value as JsonValue;
+use self::url::Url;
+use self::reqwest::header::CONTENT_TYPE;
+use self::mime::Mime;
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use error::Error;
+
+/// The HTTP layer used to talk with the homeserver.
+///
+/// Every request made by the `Client` and the `Backend` goes through one of these, so the
+/// network can be replaced with `MemoryTransport` to run the backend offline.
+pub trait Transport: Send + Sync {
+    /// Makes a JSON request to the homeserver API and returns the JSON response.
+    ///
+    /// Non successful responses or responses with an `errcode` are returned as
+    /// `Error::MatrixError`
+    fn json_q(&self, method: &str, url: &Url, attrs: &JsonValue, timeout: u64) -> Result<JsonValue, Error>;
+
+    /// Downloads the raw content of `url`
+    fn get_media(&self, url: &str) -> Result<Vec<u8>, Error>;
+
+    /// Uploads `file` to the media repository `url` and returns the JSON response
+    fn put_media(&self, url: &str, file: Vec<u8>) -> Result<JsonValue, Error>;
+}
+
+/// Default transport, every request is made with reqwest
+pub struct ReqwestTransport;
+
+impl Transport for ReqwestTransport {
+    fn json_q(&self, method: &str, url: &Url, attrs: &JsonValue, timeout: u64) -> Result<JsonValue, Error> {
+        let mut clientb = reqwest::ClientBuilder::new();
+        let client = match timeout {
+            0 => clientb.timeout(None).build()?,
+            n => clientb.timeout(StdDuration::from_secs(n)).build()?
+        };
+
+        let mut conn = match method {
+            "post" => client.post(url.as_str()),
+            "put" => client.put(url.as_str()),
+            "delete" => client.delete(url.as_str()),
+            _ => client.get(url.as_str()),
+        };
+
+        if !attrs.is_null() {
+            conn = conn.json(attrs);
+        }
+
+        let mut res = conn.send()?;
+
+        if !res.status().is_success() {
+            return match res.json() {
+                Ok(js) => Err(Error::MatrixError(js)),
+                Err(err) => Err(Error::ReqwestError(err))
+            }
+        }
+
+        let json: Result<JsonValue, reqwest::Error> = res.json();
+        match json {
+            Ok(js) => {
+                let js2 = js.clone();
+                if let Some(error) = js.as_object() {
+                    if error.contains_key("errcode") {
+                        println!("ERROR: {:#?}", js2);
+                        return Err(Error::MatrixError(js2));
+                    }
+                }
+                Ok(js)
+            }
+            Err(_) => Err(Error::BackendError),
+        }
+    }
+
+    fn get_media(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let client = reqwest::Client::new();
+        let conn = client.get(url);
+        let mut res = conn.send()?;
+
+        let mut buffer = Vec::new();
+        res.read_to_end(&mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    fn put_media(&self, url: &str, file: Vec<u8>) -> Result<JsonValue, Error> {
+        let client = reqwest::Client::new();
+        let conn = client.post(url);
+        let mime: Mime = (&tree_magic::from_u8(&file)).parse().unwrap();
+
+        let conn = conn
+            .body(file)
+            .header(CONTENT_TYPE, mime.to_string());
+
+        let mut res = conn.send()?;
+
+        match res.json() {
+            Ok(js) => Ok(js),
+            Err(_) => Err(Error::BackendError),
+        }
+    }
+}
+
+/// A request received by a `MemoryTransport`
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub url: Url,
+    pub body: JsonValue,
+}
+
+/// Transport that never touches the network and replays canned responses.
+///
+/// Responses are registered by method and url path, ignoring the query string. When several
+/// responses are registered for the same request they are returned in order and the last one
+/// is repeated for any further request. Every request is recorded so tests can check what the
+/// backend sent.
+pub struct MemoryTransport {
+    responses: Mutex<HashMap<(String, String), VecDeque<Result<JsonValue, JsonValue>>>>,
+    media: Mutex<HashMap<String, Vec<u8>>>,
+    requests: Mutex<Vec<Request>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport {
+            responses: Mutex::new(HashMap::new()),
+            media: Mutex::new(HashMap::new()),
+            requests: Mutex::new(vec![]),
+        }
+    }
+
+    /// Queues `response` as the answer to the next `method` request to `path`
+    pub fn respond(&self, method: &str, path: &str, response: JsonValue) {
+        self.push(method, path, Ok(response));
+    }
+
+    /// Queues a matrix error, like `{"errcode": "M_FORBIDDEN"}`, as the answer to the next
+    /// `method` request to `path`
+    pub fn respond_error(&self, method: &str, path: &str, error: JsonValue) {
+        self.push(method, path, Err(error));
+    }
+
+    /// Sets the content returned when downloading `path`
+    pub fn set_media(&self, path: &str, content: Vec<u8>) {
+        self.media.lock().unwrap().insert(path.to_string(), content);
+    }
+
+    /// Returns all the requests made until now
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn push(&self, method: &str, path: &str, response: Result<JsonValue, JsonValue>) {
+        let key = (method.to_string(), path.to_string());
+        let mut responses = self.responses.lock().unwrap();
+        responses.entry(key).or_insert(VecDeque::new()).push_back(response);
+    }
+
+    fn record(&self, method: &str, url: &Url, body: &JsonValue) {
+        self.requests.lock().unwrap().push(Request {
+            method: method.to_string(),
+            url: url.clone(),
+            body: body.clone(),
+        });
+    }
+
+    fn next_response(&self, method: &str, url: &Url) -> Result<JsonValue, Error> {
+        let key = (method.to_string(), url.path().to_string());
+        let mut responses = self.responses.lock().unwrap();
+
+        let response = match responses.get_mut(&key) {
+            Some(queue) => {
+                if queue.len() > 1 {
+                    queue.pop_front()
+                } else {
+                    queue.front().cloned()
+                }
+            }
+            None => None,
+        };
+
+        match response {
+            Some(Ok(js)) => Ok(js),
+            Some(Err(js)) => Err(Error::MatrixError(js)),
+            None => Err(Error::MatrixError(json!({
+                "errcode": "M_UNRECOGNIZED",
+                "error": format!("No response for {} {}", method, url.path()),
+            }))),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn json_q(&self, method: &str, url: &Url, attrs: &JsonValue, _timeout: u64) -> Result<JsonValue, Error> {
+        self.record(method, url, attrs);
+        self.next_response(method, url)
+    }
+
+    fn get_media(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let url = Url::parse(url)?;
+        self.record("get", &url, &JsonValue::Null);
+
+        match self.media.lock().unwrap().get(url.path()) {
+            Some(content) => Ok(content.clone()),
+            None => Err(Error::BackendError),
+        }
+    }
+
+    fn put_media(&self, url: &str, file: Vec<u8>) -> Result<JsonValue, Error> {
+        let url = Url::parse(url)?;
+        self.record("post", &url, &json!({ "size": file.len() }));
+        self.next_response("post", &url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_transport_replays_in_order() {
+        let tp = MemoryTransport::new();
+        tp.respond("get", "/_matrix/client/r0/sync", json!({"next_batch": "s1"}));
+        tp.respond("get", "/_matrix/client/r0/sync", json!({"next_batch": "s2"}));
+
+        let url = Url::parse("https://example.org/_matrix/client/r0/sync?since=s0").unwrap();
+        let first = tp.json_q("get", &url, &JsonValue::Null, 0).unwrap();
+        let second = tp.json_q("get", &url, &JsonValue::Null, 0).unwrap();
+        let third = tp.json_q("get", &url, &JsonValue::Null, 0).unwrap();
+
+        assert_eq!(first["next_batch"], "s1");
+        assert_eq!(second["next_batch"], "s2");
+        assert_eq!(third["next_batch"], "s2");
+        assert_eq!(tp.requests().len(), 3);
+    }
+
+    #[test]
+    fn memory_transport_unknown_request() {
+        let tp = MemoryTransport::new();
+        let url = Url::parse("https://example.org/_matrix/client/r0/login").unwrap();
+
+        match tp.json_q("post", &url, &json!({}), 0) {
+            Err(Error::MatrixError(js)) => assert_eq!(js["errcode"], "M_UNRECOGNIZED"),
+            r => panic!("unexpected response {:?}", r),
+        }
+    }
+}
@@ -1,10 +1,34 @@
 pub use model::event::Event;
+pub use model::event::EventContent;
+pub use model::event::Membership;
 pub use model::room::Room;
 pub use model::room::RoomList;
+pub use model::room::Receipts;
+pub use model::room::Reaction;
+pub use model::room::Reactions;
 pub use model::protocol::Protocol;
 pub use model::message::Message;
 pub use model::member::Member;
 pub use model::member::MemberList;
+pub use model::member::Presence;
+pub use model::member::PresenceState;
+pub use model::member::PresenceList;
 pub use model::stickers::Sticker;
 pub use model::stickers::StickerGroup;
 pub use model::userinfo::UserInfo;
+pub use model::sync::SyncResponse;
+pub use model::sync::JoinedRoom;
+pub use model::sync::Timeline as SyncTimeline;
+pub use model::timeline::Timeline;
+pub use model::timeline::TimelineChunk;
+pub use model::timeline::Gap;
+pub use model::timeline::MessagesPage;
+pub use model::filter::Filter;
+pub use model::filter::RoomFilter;
+pub use model::filter::RoomEventFilter;
+pub use model::filter::EventFilter;
+pub use model::sync::RoomSummary;
+pub use model::sync::Events as SyncEvents;
+pub use model::sync::DeviceLists;
+pub use model::device::Device;
+pub use model::server::ServerInfo;
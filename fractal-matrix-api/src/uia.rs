@@ -0,0 +1,361 @@
+//! User-interactive authentication.
+//!
+//! Some endpoints, like registration, password changes or deleting devices, answer the first
+//! request with a 401 and a list of flows. Each flow is a list of stages that the client has
+//! to complete, one request per stage, sending the `session` id of the first answer. The
+//! server returns the same list with the `completed` stages after each step, until the original
+//! request succeeds.
+//!
+//! https://matrix.org/docs/spec/client_server/r0.4.0.html#user-interactive-authentication-api
+
+extern crate serde_json;
+
+use self::serde_json::Value as JsonValue;
+use std::collections::HashMap;
+
+pub const PASSWORD: &'static str = "m.login.password";
+pub const EMAIL: &'static str = "m.login.email.identity";
+pub const RECAPTCHA: &'static str = "m.login.recaptcha";
+pub const TERMS: &'static str = "m.login.terms";
+pub const DUMMY: &'static str = "m.login.dummy";
+
+/// The stages that we know how to complete
+pub fn supported_stages() -> [&'static str; 5] {
+    [PASSWORD, EMAIL, RECAPTCHA, TERMS, DUMMY]
+}
+
+/// The identity server without the scheme, as the server expects it
+pub fn id_server(url: &str) -> &str {
+    url.splitn(2, "://").last().unwrap_or(url)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuthFlow {
+    #[serde(default)]
+    pub stages: Vec<String>,
+}
+
+/// The state of an authentication session, as returned by the server in each 401
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UiaState {
+    #[serde(default)]
+    pub flows: Vec<AuthFlow>,
+    /// The parameters of each stage, like the recaptcha public key or the terms to accept
+    #[serde(default)]
+    pub params: HashMap<String, JsonValue>,
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default)]
+    pub completed: Vec<String>,
+    /// The error of the last completed stage, like a wrong password
+    #[serde(default)]
+    pub errcode: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl UiaState {
+    /// Parses the body of a 401 answer, None if it isn't an authentication request
+    pub fn from_json(js: &JsonValue) -> Option<UiaState> {
+        if !js["flows"].is_array() {
+            return None;
+        }
+
+        serde_json::from_value(js.clone()).ok()
+    }
+
+    /// The stages that can be done now, the next stage of each flow that starts with the
+    /// completed ones. The flows with only supported stages and less stages go first
+    pub fn next_stages(&self) -> Vec<String> {
+        let supported = supported_stages();
+        let mut flows: Vec<&AuthFlow> = self.flows.iter()
+            .filter(|f| f.stages.len() > self.completed.len() && f.stages.starts_with(&self.completed))
+            .collect();
+        flows.sort_by_key(|f| {
+            let unsupported = f.stages.iter().filter(|s| !supported.contains(&s.as_str())).count();
+            (unsupported, f.stages.len())
+        });
+
+        let mut stages: Vec<String> = vec![];
+        for f in flows {
+            let s = &f.stages[self.completed.len()];
+            if !stages.contains(s) {
+                stages.push(s.clone());
+            }
+        }
+
+        stages
+    }
+
+    /// The stage that should be done now
+    pub fn next_stage(&self) -> Option<String> {
+        self.next_stages().into_iter().next()
+    }
+
+    /// The public key to show the recaptcha
+    pub fn recaptcha_public_key(&self) -> Option<String> {
+        self.params.get(RECAPTCHA)
+            .and_then(|p| p["public_key"].as_str())
+            .map(|k| k.to_string())
+    }
+
+    /// The policies to accept in the terms stage, as (name, url) in the `lang` language when
+    /// there is a translation, in english or in the first language otherwise
+    pub fn terms_policies(&self, lang: &str) -> Vec<(String, String)> {
+        let mut policies = vec![];
+        let ps = self.params.get(TERMS)
+            .and_then(|p| p["policies"].as_object().cloned())
+            .unwrap_or_default();
+
+        for (_, policy) in ps {
+            let langs = match policy.as_object() {
+                Some(l) => l.clone(),
+                None => continue,
+            };
+            let tr = langs.get(lang)
+                .or(langs.get("en"))
+                .or(langs.values().find(|v| v.is_object()));
+            if let Some(tr) = tr {
+                let name = tr["name"].as_str().unwrap_or_default().to_string();
+                let url = tr["url"].as_str().unwrap_or_default().to_string();
+                policies.push((name, url));
+            }
+        }
+
+        policies
+    }
+}
+
+/// The data to complete one stage
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthData {
+    /// The user id and the password
+    Password(String, String),
+    /// The sid and client secret of a validated email, and the identity server
+    Email(String, String, String),
+    /// The response of the recaptcha
+    Recaptcha(String),
+    Terms,
+    Dummy,
+}
+
+impl AuthData {
+    pub fn stage(&self) -> &'static str {
+        match *self {
+            AuthData::Password(..) => PASSWORD,
+            AuthData::Email(..) => EMAIL,
+            AuthData::Recaptcha(_) => RECAPTCHA,
+            AuthData::Terms => TERMS,
+            AuthData::Dummy => DUMMY,
+        }
+    }
+
+    /// The `auth` dict for the request
+    pub fn to_json(&self, session: Option<&str>) -> JsonValue {
+        let mut auth = match *self {
+            AuthData::Password(ref user, ref password) => json!({
+                "identifier": {
+                    "type": "m.id.user",
+                    "user": user,
+                },
+                "user": user,
+                "password": password,
+            }),
+            AuthData::Email(ref sid, ref client_secret, ref server) => {
+                let creds = json!({
+                    "sid": sid,
+                    "client_secret": client_secret,
+                    "id_server": id_server(server),
+                });
+                json!({
+                    "threepid_creds": creds.clone(),
+                    "threepidCreds": creds,
+                })
+            }
+            AuthData::Recaptcha(ref response) => json!({ "response": response }),
+            AuthData::Terms | AuthData::Dummy => json!({}),
+        };
+
+        auth["type"] = json!(self.stage());
+        if let Some(s) = session {
+            auth["session"] = json!(s);
+        }
+
+        auth
+    }
+}
+
+/// A request that needs user-interactive authentication
+#[derive(Clone, PartialEq)]
+pub enum UiaRequest {
+    /// The username and the password of the new account
+    Register(String, String),
+    /// The new password
+    ChangePassword(String),
+    /// Deactivates the account, erasing the data if true
+    Deactivate(bool),
+    /// The id of the device to delete
+    DeleteDevice(String),
+}
+
+impl UiaRequest {
+    pub fn method(&self) -> &'static str {
+        match *self {
+            UiaRequest::DeleteDevice(_) => "delete",
+            _ => "post",
+        }
+    }
+
+    pub fn path(&self) -> String {
+        match *self {
+            UiaRequest::Register(..) => strn!("register"),
+            UiaRequest::ChangePassword(_) => strn!("account/password"),
+            UiaRequest::Deactivate(_) => strn!("account/deactivate"),
+            UiaRequest::DeleteDevice(ref d) => format!("devices/{}", d),
+        }
+    }
+
+    pub fn params(&self) -> Vec<(&'static str, String)> {
+        match *self {
+            UiaRequest::Register(..) => vec![("kind", strn!("user"))],
+            _ => vec![],
+        }
+    }
+
+    /// The body of the request with the `auth` dict, if any
+    pub fn body(&self, auth: Option<JsonValue>) -> JsonValue {
+        let mut body = match *self {
+            UiaRequest::Register(ref username, ref password) => json!({
+                "username": username,
+                "password": password,
+                "bind_email": false,
+                "initial_device_display_name": "Fractal",
+            }),
+            UiaRequest::ChangePassword(ref password) => json!({ "new_password": password }),
+            UiaRequest::Deactivate(erase) => json!({ "erase": erase }),
+            UiaRequest::DeleteDevice(_) => json!({}),
+        };
+
+        if let Some(a) = auth {
+            body["auth"] = a;
+        }
+
+        body
+    }
+}
+
+// the passwords aren't printed
+impl ::std::fmt::Debug for UiaRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        match *self {
+            UiaRequest::Register(ref username, _) => write!(f, "Register({:?})", username),
+            UiaRequest::ChangePassword(_) => write!(f, "ChangePassword"),
+            UiaRequest::Deactivate(erase) => write!(f, "Deactivate({:?})", erase),
+            UiaRequest::DeleteDevice(ref d) => write!(f, "DeleteDevice({:?})", d),
+        }
+    }
+}
+
+/// The answer to a request with user-interactive authentication
+#[derive(Debug, Clone, PartialEq)]
+pub enum UiaResponse {
+    /// The request succeeded with this response
+    Done(JsonValue),
+    /// More stages are needed
+    Auth(UiaState),
+}
+
+/// An authentication in progress, waiting for the user to complete a stage
+#[derive(Clone)]
+pub struct PendingAuth {
+    pub request: UiaRequest,
+    pub state: UiaState,
+    /// The password of the user, to complete the password stage without asking again
+    pub password: Option<String>,
+}
+
+// the password isn't printed
+impl ::std::fmt::Debug for PendingAuth {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "PendingAuth {{ request: {:?}, state: {:?}, password: {} }}",
+               self.request, self.state, if self.password.is_some() { "Some(..)" } else { "None" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_stages() {
+        let js = json!({
+            "flows": [
+                { "stages": ["m.login.recaptcha", "m.login.terms", "m.login.dummy"] },
+                { "stages": ["m.login.email.identity", "org.example.sms"] },
+                { "stages": ["m.login.email.identity"] },
+            ],
+            "params": {
+                "m.login.recaptcha": { "public_key": "6Le31_kSAAAAAK-54VKccKamtr-MFA_3WS1d_fGV" },
+            },
+            "session": "xxxxxx",
+        });
+
+        let mut state = UiaState::from_json(&js).unwrap();
+        assert_eq!(state.session, Some(strn!("xxxxxx")));
+        assert_eq!(state.next_stages(), vec![strn!(EMAIL), strn!(RECAPTCHA)]);
+        assert_eq!(state.recaptcha_public_key(), Some(strn!("6Le31_kSAAAAAK-54VKccKamtr-MFA_3WS1d_fGV")));
+
+        state.completed = vec![strn!(RECAPTCHA)];
+        assert_eq!(state.next_stage(), Some(strn!(TERMS)));
+
+        assert!(UiaState::from_json(&json!({ "errcode": "M_FORBIDDEN" })).is_none());
+    }
+
+    #[test]
+    fn terms_policies() {
+        let js = json!({
+            "flows": [{ "stages": ["m.login.terms"] }],
+            "params": {
+                "m.login.terms": {
+                    "policies": {
+                        "privacy_policy": {
+                            "version": "1.0",
+                            "en": { "name": "Privacy Policy", "url": "https://example.org/privacy-en" },
+                            "fr": { "name": "Politique de confidentialité", "url": "https://example.org/privacy-fr" },
+                        },
+                    },
+                },
+            },
+        });
+
+        let state = UiaState::from_json(&js).unwrap();
+        assert_eq!(state.terms_policies("fr"), vec![(strn!("Politique de confidentialité"), strn!("https://example.org/privacy-fr"))]);
+        assert_eq!(state.terms_policies("de"), vec![(strn!("Privacy Policy"), strn!("https://example.org/privacy-en"))]);
+    }
+
+    #[test]
+    fn auth_data() {
+        let auth = AuthData::Password(strn!("@alice:localhost"), strn!("secret")).to_json(Some("xxxxxx"));
+        assert_eq!(auth["type"], "m.login.password");
+        assert_eq!(auth["identifier"]["user"], "@alice:localhost");
+        assert_eq!(auth["session"], "xxxxxx");
+
+        let auth = AuthData::Email(strn!("sid"), strn!("secret"), strn!("https://vector.im")).to_json(None);
+        assert_eq!(auth["threepid_creds"]["id_server"], "vector.im");
+
+        let body = UiaRequest::Deactivate(true).body(Some(AuthData::Dummy.to_json(None)));
+        assert_eq!(body, json!({ "erase": true, "auth": { "type": "m.login.dummy" } }));
+    }
+
+    #[test]
+    fn passwords_not_printed() {
+        let pending = PendingAuth {
+            request: UiaRequest::Register(strn!("alice"), strn!("secret")),
+            state: UiaState::default(),
+            password: Some(strn!("secret")),
+        };
+        let printed = format!("{:?}", pending);
+        assert!(printed.contains("alice"));
+        assert!(!printed.contains("secret"));
+    }
+}
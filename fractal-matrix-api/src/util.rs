@@ -1,6 +1,5 @@
 #[cfg(feature = "gfx")] extern crate glib;
 extern crate url;
-extern crate reqwest;
 extern crate regex;
 extern crate serde_json;
 #[cfg(feature = "gfx")] extern crate cairo;
@@ -8,8 +7,6 @@ extern crate serde_json;
 #[cfg(feature = "gfx")] extern crate pangocairo;
 #[cfg(feature = "gfx")] extern crate gdk;
 #[cfg(feature = "gfx")] extern crate gdk_pixbuf;
-extern crate mime;
-extern crate tree_magic;
 extern crate unicode_segmentation;
 
 use self::unicode_segmentation::UnicodeSegmentation;
@@ -25,7 +22,6 @@ use self::regex::Regex;
 use self::serde_json::Value as JsonValue;
 
 use self::url::Url;
-use std::io::Read;
 use std::path::Path;
 use std::path::PathBuf;
 use std::collections::HashMap;
@@ -38,16 +34,21 @@ use std::collections::HashSet;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
-use std::time::Duration as StdDuration;
 
 use error::Error;
+use transport::Transport;
 use types::Message;
 use types::Room;
+use types::Receipts;
 use types::Event;
+use types::EventContent;
+use types::Reaction;
 use types::Member;
+use types::Presence;
+use types::PresenceList;
+use types::SyncResponse;
+use types::SyncEvents;
 
-use self::reqwest::header::CONTENT_TYPE;
-use self::mime::Mime;
 
 use globals;
 
@@ -165,35 +166,36 @@ macro_rules! bkerror {
 
 #[macro_export]
 macro_rules! get {
-    ($url: expr, $attrs: expr, $okcb: expr, $errcb: expr, $timeout: expr) => {
-        query!("get", $url, $attrs, $okcb, $errcb, $timeout)
+    ($tp: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr, $timeout: expr) => {
+        query!($tp, "get", $url, $attrs, $okcb, $errcb, $timeout)
     };
-    ($url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
-        query!("get", $url, $attrs, $okcb, $errcb)
+    ($tp: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        query!($tp, "get", $url, $attrs, $okcb, $errcb)
     };
-    ($url: expr, $okcb: expr, $errcb: expr) => {
-        query!("get", $url, $okcb, $errcb)
+    ($tp: expr, $url: expr, $okcb: expr, $errcb: expr) => {
+        query!($tp, "get", $url, $okcb, $errcb)
     };
 }
 
 #[macro_export]
 macro_rules! post {
-    ($url: expr, $attrs: expr, $okcb: expr, $errcb: expr, $timeout: expr) => {
-        query!("post", $url, $attrs, $okcb, $errcb, $timeout)
+    ($tp: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr, $timeout: expr) => {
+        query!($tp, "post", $url, $attrs, $okcb, $errcb, $timeout)
     };
-    ($url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
-        query!("post", $url, $attrs, $okcb, $errcb)
+    ($tp: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        query!($tp, "post", $url, $attrs, $okcb, $errcb)
     };
-    ($url: expr, $okcb: expr, $errcb: expr) => {
-        query!("post", $url, $okcb, $errcb)
+    ($tp: expr, $url: expr, $okcb: expr, $errcb: expr) => {
+        query!($tp, "post", $url, $okcb, $errcb)
     };
 }
 
 #[macro_export]
 macro_rules! query {
-    ($method: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr, $timeout: expr) => {
+    ($tp: expr, $method: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr, $timeout: expr) => {
+        let transport = $tp;
         thread::spawn(move || {
-            let js = json_q($method, $url, $attrs, $timeout);
+            let js = transport.json_q($method, $url, $attrs, $timeout);
 
             match js {
                 Ok(r) => {
@@ -205,12 +207,12 @@ macro_rules! query {
             }
         });
     };
-    ($method: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
-        query!($method, $url, $attrs, $okcb, $errcb, globals::TIMEOUT);
+    ($tp: expr, $method: expr, $url: expr, $attrs: expr, $okcb: expr, $errcb: expr) => {
+        query!($tp, $method, $url, $attrs, $okcb, $errcb, globals::TIMEOUT);
     };
-    ($method: expr, $url: expr, $okcb: expr, $errcb: expr) => {
+    ($tp: expr, $method: expr, $url: expr, $okcb: expr, $errcb: expr) => {
         let attrs = json!(null);
-        query!($method, $url, &attrs, $okcb, $errcb)
+        query!($tp, $method, $url, &attrs, $okcb, $errcb)
     };
 }
 
@@ -218,100 +220,87 @@ macro_rules! query {
 #[allow(unused_macros)]
 #[macro_export]
 macro_rules! media {
-    ($base: expr, $url: expr, $dest: expr) => {
-        dw_media($base, $url, false, $dest, 0, 0)
+    ($tp: expr, $base: expr, $url: expr, $dest: expr) => {
+        dw_media($tp, $base, $url, false, $dest, 0, 0)
     };
-    ($base: expr, $url: expr) => {
-        dw_media($base, $url, false, None, 0, 0)
+    ($tp: expr, $base: expr, $url: expr) => {
+        dw_media($tp, $base, $url, false, None, 0, 0)
     };
 }
 
 #[cfg(feature = "gfx")]
 #[macro_export]
 macro_rules! thumb {
-    ($base: expr, $url: expr) => {
-        dw_media($base, $url, true, None, 64, 64)
+    ($tp: expr, $base: expr, $url: expr) => {
+        dw_media($tp, $base, $url, true, None, 64, 64)
     };
-    ($base: expr, $url: expr, $size: expr) => {
-        dw_media($base, $url, true, None, $size, $size)
+    ($tp: expr, $base: expr, $url: expr, $size: expr) => {
+        dw_media($tp, $base, $url, true, None, $size, $size)
     };
-    ($base: expr, $url: expr, $w: expr, $h: expr) => {
-        dw_media($base, $url, true, None, $w, $h)
+    ($tp: expr, $base: expr, $url: expr, $w: expr, $h: expr) => {
+        dw_media($tp, $base, $url, true, None, $w, $h)
     };
 }
 
-pub fn evc(events: &JsonValue, t: &str, field: &str) -> String {
-    if let Some(arr) = events.as_array() {
-        return match arr.iter().find(|x| x["type"] == t) {
-            Some(js) => String::from(js["content"][field].as_str().unwrap_or("")),
-            None => String::new(),
-        };
+pub fn evc(events: &[JsonValue], t: &str, field: &str) -> String {
+    match events.iter().find(|x| x["type"] == t) {
+        Some(js) => String::from(js["content"][field].as_str().unwrap_or("")),
+        None => String::new(),
     }
-
-    String::new()
 }
 
-pub fn get_rooms_from_json(r: &JsonValue, userid: &str, baseu: &Url) -> Result<Vec<Room>, Error> {
-    let rooms = &r["rooms"];
-
-    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
-    let leave = rooms["leave"].as_object().ok_or(Error::BackendError)?;
-    let invite = rooms["invite"].as_object().ok_or(Error::BackendError)?;
-    let global_account = &r["account_data"]["events"].as_array();
+pub fn get_rooms_from_json(tp: &dyn Transport, r: &SyncResponse, userid: &str, baseu: &Url) -> Result<Vec<Room>, Error> {
+    let join = &r.rooms.join;
+    let leave = &r.rooms.leave;
+    let invite = &r.rooms.invite;
 
     // getting the list of direct rooms
     let mut direct: HashSet<String> = HashSet::new();
-    match global_account.unwrap_or(&vec![]).iter().find(|x| x["type"] == "m.direct") {
-        Some(js) => {
-            if let Some(content) = js["content"].as_object() {
-                for i in content.keys() {
-                    for room in content[i].as_array().unwrap_or(&vec![]) {
-                        if let Some(roomid) = room.as_str() {
-                            direct.insert(roomid.to_string());
-                        }
+    if let Some(js) = r.account_data.find("m.direct") {
+        if let Some(content) = js["content"].as_object() {
+            for i in content.keys() {
+                for room in content[i].as_array().unwrap_or(&vec![]) {
+                    if let Some(roomid) = room.as_str() {
+                        direct.insert(roomid.to_string());
                     }
                 }
             }
-        },
-        None => {}
-    };
+        }
+    }
 
     let mut rooms: Vec<Room> = vec![];
-    for k in join.keys() {
-        let room = join.get(k).ok_or(Error::BackendError)?;
-        let stevents = &room["state"]["events"];
-        let timeline = &room["timeline"];
-        let dataevs = &room["account_data"]["events"];
-        let name = calculate_room_name(stevents, userid)?;
+    for (k, room) in join.iter() {
+        let stevents = &room.state.events;
+        let heroes = room.summary.heroes.clone().unwrap_or_default();
+        let name = calculate_room_name(stevents, userid, &heroes)?;
         let mut r = Room::new(k.clone(), name);
 
         r.avatar = Some(evc(stevents, "m.room.avatar", "url"));
         r.alias = Some(evc(stevents, "m.room.canonical_alias", "alias"));
         r.topic = Some(evc(stevents, "m.room.topic", "topic"));
+        r.encrypted = stevents.iter().chain(room.timeline.events.iter())
+            .any(|x| x["type"] == "m.room.encryption");
         r.direct = direct.contains(k);
-        r.notifications = room["unread_notifications"]["notification_count"]
-            .as_i64()
-            .unwrap_or(0) as i32;
-        r.highlight = room["unread_notifications"]["highlight_count"]
-            .as_i64()
-            .unwrap_or(0) as i32;
-
-        for ev in dataevs.as_array() {
-            for tag in ev.iter().filter(|x| x["type"] == "m.tag") {
-                if let Some(_) = tag["content"]["tags"]["m.favourite"].as_object() {
-                    r.fav = true;
-                }
+        r.notifications = room.unread_notifications.notification_count;
+        r.highlight = room.unread_notifications.highlight_count;
+
+        for tag in room.account_data.events.iter().filter(|x| x["type"] == "m.tag") {
+            if let Some(_) = tag["content"]["tags"]["m.favourite"].as_object() {
+                r.fav = true;
             }
         }
+        r.fully_read = get_fully_read(&room.account_data).map(|evid| {
+            fully_read_message(k, &evid, &room.timeline.events, None).unwrap_or(evid)
+        });
 
-        if let Some(evs) = timeline["events"].as_array() {
-            let ms = Message::from_json_events_iter(k.clone(), evs.iter());
-            r.messages.extend(ms);
+        let ms = Message::from_json_events_iter(k.clone(), room.timeline.events.iter());
+        // the edits of older messages are kept in the room timeline by the sync
+        r.messages.extend(Message::apply_edits(ms).0);
+        for (evid, reaction) in parse_reactions(k, room.timeline.events.iter()) {
+            r.add_reaction(&evid, reaction);
         }
 
-        let mevents = stevents.as_array().unwrap()
-            .iter()
-            .filter(|x| x["type"] == "m.room.member");
+        let mevents = stevents.iter().filter(|x| x["type"] == "m.room.member");
 
         for ev in mevents {
             let member = parse_room_member(ev);
@@ -320,8 +309,14 @@ pub fn get_rooms_from_json(r: &JsonValue, userid: &str, baseu: &Url) -> Result<V
             }
         }
 
+        // with lazy loading the members are only the senders, the summary has the real counts
+        r.heroes = heroes;
+        r.n_members = room.summary.joined_member_count.unwrap_or(r.members.len() as i32);
+        r.n_invited_members = room.summary.invited_member_count.unwrap_or_default();
+
         // power levels info
         r.power_levels = get_admins(stevents);
+        r.receipts = parse_receipts(&room.ephemeral.events);
 
         rooms.push(r);
     }
@@ -334,32 +329,31 @@ pub fn get_rooms_from_json(r: &JsonValue, userid: &str, baseu: &Url) -> Result<V
     }
 
     // invitations
-    for k in invite.keys() {
-        let room = invite.get(k).ok_or(Error::BackendError)?;
-        let stevents = &room["invite_state"]["events"];
-        let name = calculate_room_name(stevents, userid)?;
+    for (k, room) in invite.iter() {
+        let stevents = &room.invite_state.events;
+        let name = calculate_room_name(stevents, userid, &[])?;
         let mut r = Room::new(k.clone(), name);
         r.inv = true;
 
         r.avatar = Some(evc(stevents, "m.room.avatar", "url"));
         r.alias = Some(evc(stevents, "m.room.canonical_alias", "alias"));
         r.topic = Some(evc(stevents, "m.room.topic", "topic"));
+        r.encrypted = stevents.iter().any(|x| x["type"] == "m.room.encryption");
         r.direct = direct.contains(k);
 
         #[cfg(feature = "gfx")]
         {
-            if let Some(arr) = stevents.as_array() {
-                if let Some(ev) = arr.iter()
-                                    .find(|x| x["membership"] == "invite" && x["state_key"] == userid) {
-                    if let Ok((alias, avatar)) = get_user_avatar(baseu, ev["sender"].as_str().unwrap_or_default()) {
-                        r.inv_sender = Some(
-                            Member {
-                                alias: Some(alias),
-                                avatar: Some(avatar),
-                                uid: strn!(userid),
-                            }
-                        );
-                    }
+            if let Some(ev) = stevents.iter()
+                                .find(|x| x["membership"] == "invite" && x["state_key"] == userid) {
+                if let Ok((alias, avatar)) = get_user_avatar(tp, baseu, ev["sender"].as_str().unwrap_or_default()) {
+                    r.inv_sender = Some(
+                        Member {
+                            alias: Some(alias),
+                            avatar: Some(avatar),
+                            uid: strn!(userid),
+                            presence: None,
+                        }
+                    );
                 }
             }
         }
@@ -370,10 +364,10 @@ pub fn get_rooms_from_json(r: &JsonValue, userid: &str, baseu: &Url) -> Result<V
     Ok(rooms)
 }
 
-pub fn get_admins(stevents: &JsonValue) -> HashMap<String, i32> {
+pub fn get_admins(stevents: &[JsonValue]) -> HashMap<String, i32> {
     let mut admins = HashMap::new();
 
-    let plevents = stevents.as_array().unwrap()
+    let plevents = stevents
         .iter()
         .filter(|x| x["type"] == "m.room.power_levels");
 
@@ -389,123 +383,121 @@ pub fn get_admins(stevents: &JsonValue) -> HashMap<String, i32> {
     admins
 }
 
-pub fn get_rooms_timeline_from_json(baseu: &Url,
-                                    r: &JsonValue,
-                                    tk: String,
-                                    prev_batch: String)
-                                    -> Result<Vec<Message>, Error> {
-    let rooms = &r["rooms"];
-    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
-
-    let mut msgs: Vec<Message> = vec![];
-    for k in join.keys() {
-        let room = join.get(k).ok_or(Error::BackendError)?;
-
-        if let (Some(true), Some(pb)) = (room["timeline"]["limited"].as_bool(),
-                                         room["timeline"]["prev_batch"].as_str()) {
-            let pbs = pb.to_string();
-            let fill_the_gap = fill_room_gap(baseu,
-                                             tk.clone(),
-                                             k.clone(),
-                                             prev_batch.clone(),
-                                             pbs.clone())?;
-            for m in fill_the_gap {
-                msgs.push(m);
-            }
-        }
-
-        let timeline = room["timeline"]["events"].as_array();
-        if timeline.is_none() {
-            continue;
-        }
+pub fn get_rooms_notifies_from_json(r: &SyncResponse) -> Result<Vec<(String, i32, i32)>, Error> {
+    let mut out: Vec<(String, i32, i32)> = vec![];
+    for (k, room) in r.rooms.join.iter() {
+        let n = room.unread_notifications.notification_count;
+        let h = room.unread_notifications.highlight_count;
 
-        let events = timeline.unwrap().iter();
-        let ms = Message::from_json_events_iter(k.clone(), events);
-        msgs.extend(ms);
+        out.push((k.clone(), n, h));
     }
 
-    Ok(msgs)
+    Ok(out)
 }
 
-pub fn get_rooms_notifies_from_json(r: &JsonValue) -> Result<Vec<(String, i32, i32)>, Error> {
-    let rooms = &r["rooms"];
-    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
-
-    let mut out: Vec<(String, i32, i32)> = vec![];
-    for k in join.keys() {
-        let room = join.get(k).ok_or(Error::BackendError)?;
-        let n = room["unread_notifications"]["notification_count"]
-            .as_i64()
-            .unwrap_or(0) as i32;
-        let h = room["unread_notifications"]["highlight_count"]
-            .as_i64()
-            .unwrap_or(0) as i32;
+/// Gets the event id of the `m.fully_read` marker from the room account data
+pub fn get_fully_read(account_data: &SyncEvents) -> Option<String> {
+    account_data.find("m.fully_read")
+        .and_then(|ev| ev["content"]["event_id"].as_str())
+        .map(|evid| evid.to_string())
+}
 
-        out.push((k.clone(), n, h));
+/// Gets the message shown for the `m.fully_read` marker `evid`, the last message at or before
+/// it in `events`, because the marker can point to an event that isn't shown, like a reaction.
+/// `before` is the message right before `events`, if it's known.
+///
+/// Returns None if the marker isn't in `events` or there's no message before it
+pub fn fully_read_message(roomid: &str, evid: &str, events: &[JsonValue], before: Option<&str>) -> Option<String> {
+    let pos = events.iter().position(|ev| ev["event_id"] == evid)?;
+
+    Message::from_json_events_iter(roomid.to_string(), events[..pos + 1].iter())
+        .into_iter()
+        .filter(|m| m.replace.is_none())
+        .last()
+        .and_then(|m| m.id)
+        .or(before.map(|b| b.to_string()))
+}
+
+/// Gets the read receipts of the `m.receipt` ephemeral events
+pub fn parse_receipts(events: &[JsonValue]) -> Receipts {
+    let mut receipts = HashMap::new();
+
+    for ev in events.iter().filter(|x| x["type"] == "m.receipt") {
+        if let Some(content) = ev["content"].as_object() {
+            for (evid, receipt) in content.iter() {
+                if let Some(users) = receipt["m.read"].as_object() {
+                    for (uid, r) in users.iter() {
+                        let ts = r["ts"].as_i64().unwrap_or_default();
+                        receipts.insert(uid.clone(), (evid.clone(), ts));
+                    }
+                }
+            }
+        }
     }
 
-    Ok(out)
+    receipts
 }
 
-pub fn parse_sync_events(r: &JsonValue) -> Result<Vec<Event>, Error> {
-    let rooms = &r["rooms"];
-    let join = rooms["join"].as_object().ok_or(Error::BackendError)?;
+/// Gets the last presence of each user from the `m.presence` events
+pub fn parse_presence(events: &[JsonValue]) -> PresenceList {
+    let mut presence = HashMap::new();
 
-    let mut evs: Vec<Event> = vec![];
-    for k in join.keys() {
-        let room = join.get(k).ok_or(Error::BackendError)?;
-        let timeline = room["timeline"]["events"].as_array();
-        if timeline.is_none() {
-            return Ok(evs);
+    for ev in events.iter().filter(|x| x["type"] == "m.presence") {
+        let sender = match ev["sender"].as_str() {
+            Some(s) => s,
+            None => continue,
+        };
+        if let Ok(p) = serde_json::from_value::<Presence>(ev["content"].clone()) {
+            presence.insert(sender.to_string(), p);
         }
+    }
 
-        let events = timeline.unwrap()
+    presence
+}
+
+/// Gets the new read receipts for each room
+pub fn get_rooms_receipts_from_json(r: &SyncResponse) -> Vec<(String, Receipts)> {
+    r.rooms.join.iter()
+        .map(|(k, room)| (k.clone(), parse_receipts(&room.ephemeral.events)))
+        .filter(|&(_, ref receipts)| !receipts.is_empty())
+        .collect()
+}
+
+/// The reactions in a list of events, as (reacted event id, reaction)
+pub fn parse_reactions<'a, I>(roomid: &str, events: I) -> Vec<(String, Reaction)>
+    where I: Iterator<Item=&'a JsonValue> {
+    events
+        .filter(|ev| ev["type"] == "m.reaction")
+        .map(|ev| Event::from_json(roomid, ev))
+        .filter_map(|ev| match ev.content {
+            EventContent::Reaction(ref c) if c.relates_to.rel_type == "m.annotation" => {
+                let reaction = Reaction {
+                    id: ev.id.clone(),
+                    sender: ev.sender.clone(),
+                    key: c.relates_to.key.clone(),
+                };
+                Some((c.relates_to.event_id.clone(), reaction))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn parse_sync_events(r: &SyncResponse) -> Result<Vec<Event>, Error> {
+    let mut evs: Vec<Event> = vec![];
+    for (k, room) in r.rooms.join.iter() {
+        let events = room.timeline.events
             .iter()
             .filter(|x| x["type"] != "m.room.message");
 
         for ev in events {
-            //println!("ev: {:#?}", ev);
-            evs.push(Event {
-                room: k.clone(),
-                sender: strn!(ev["sender"].as_str().unwrap_or("")),
-                content: ev["content"].clone(),
-                stype: strn!(ev["type"].as_str().unwrap_or("")),
-                id: strn!(ev["id"].as_str().unwrap_or("")),
-            });
+            evs.push(Event::from_json(k, ev));
         }
     }
 
     Ok(evs)
 }
 
-pub fn get_media(url: &str) -> Result<Vec<u8>, Error> {
-    let client = reqwest::Client::new();
-    let mut conn = client.get(url);
-    let mut res = conn.send()?;
-
-    let mut buffer = Vec::new();
-    res.read_to_end(&mut buffer)?;
-
-    Ok(buffer)
-}
-
-pub fn put_media(url: &str, file: Vec<u8>) -> Result<JsonValue, Error> {
-    let client = reqwest::Client::new();
-    let mut conn = client.post(url);
-    let mime: Mime = (&tree_magic::from_u8(&file)).parse().unwrap();
-
-    let conn = conn
-        .body(file)
-        .header(CONTENT_TYPE, mime.to_string());
-
-    let mut res = conn.send()?;
-
-    match res.json() {
-        Ok(js) => Ok(js),
-        Err(_) => Err(Error::BackendError),
-    }
-}
-
 pub fn resolve_media_url(
     base: &Url,
     url: &str,
@@ -534,7 +526,8 @@ pub fn resolve_media_url(
 }
 
 #[cfg(feature = "gfx")]
-pub fn dw_media(base: &Url,
+pub fn dw_media(tp: &dyn Transport,
+                base: &Url,
                 url: &str,
                 thumb: bool,
                 dest: Option<&str>,
@@ -566,10 +559,10 @@ pub fn dw_media(base: &Url,
         Some(d) => String::from(d),
     };
 
-    download_file(url.as_str(), fname, dest)
+    download_file(tp, url.as_str(), fname, dest)
 }
 
-pub fn download_file(url: &str, fname: String, dest: Option<&str>) -> Result<String, Error> {
+pub fn download_file(tp: &dyn Transport, url: &str, fname: String, dest: Option<&str>) -> Result<String, Error> {
     let pathname = fname.clone();
     let p = Path::new(&pathname);
     if p.is_file() {
@@ -585,65 +578,18 @@ pub fn download_file(url: &str, fname: String, dest: Option<&str>) -> Result<Str
     }
 
     let mut file = File::create(&fname)?;
-    let buffer = get_media(url)?;
+    let buffer = tp.get_media(url)?;
     file.write_all(&buffer)?;
 
     Ok(fname)
 }
 
-pub fn json_q(method: &str, url: &Url, attrs: &JsonValue, timeout: u64) -> Result<JsonValue, Error> {
-    let mut clientb = reqwest::ClientBuilder::new();
-    let client = match timeout {
-        0 => clientb.timeout(None).build()?,
-        n => clientb.timeout(StdDuration::from_secs(n)).build()?
-    };
-
-    let mut conn = match method {
-        "post" => client.post(url.as_str()),
-        "put" => client.put(url.as_str()),
-        "delete" => client.delete(url.as_str()),
-        _ => client.get(url.as_str()),
-    };
-
-    if !attrs.is_null() {
-        conn = conn.json(attrs);
-    }
-
-    let mut res = conn.send()?;
-
-    //let mut content = String::new();
-    //res.read_to_string(&mut content);
-    //cb(content);
-
-    if !res.status().is_success() {
-        return match res.json() {
-            Ok(js) => Err(Error::MatrixError(js)),
-            Err(err) => Err(Error::ReqwestError(err))
-        }
-    }
-
-    let json: Result<JsonValue, reqwest::Error> = res.json();
-    match json {
-        Ok(js) => {
-            let js2 = js.clone();
-            if let Some(error) = js.as_object() {
-                if error.contains_key("errcode") {
-                    println!("ERROR: {:#?}", js2);
-                    return Err(Error::MatrixError(js2));
-                }
-            }
-            Ok(js)
-        }
-        Err(_) => Err(Error::BackendError),
-    }
-}
-
 #[cfg(feature = "gfx")]
-pub fn get_user_avatar(baseu: &Url, userid: &str) -> Result<(String, String), Error> {
+pub fn get_user_avatar(tp: &dyn Transport, baseu: &Url, userid: &str) -> Result<(String, String), Error> {
     let url = client_url!(baseu, &format!("profile/{}", userid), vec![])?;
     let attrs = json!(null);
 
-    match json_q("get", &url, &attrs, globals::TIMEOUT) {
+    match tp.json_q("get", &url, &attrs, globals::TIMEOUT) {
         Ok(js) => {
             let name = match js["displayname"].as_str() {
                 Some(n) if n.is_empty() => userid.to_string(),
@@ -654,7 +600,7 @@ pub fn get_user_avatar(baseu: &Url, userid: &str) -> Result<(String, String), Er
             match js["avatar_url"].as_str() {
                 Some(url) => {
                     let dest = cache_path(userid)?;
-                    let img = dw_media(baseu, &url, true, Some(&dest), 64, 64)?;
+                    let img = dw_media(tp, baseu, &url, true, Some(&dest), 64, 64)?;
                     Ok((name.clone(), img))
                 },
                 None => Ok((name.clone(), identicon!(userid, name)?)),
@@ -664,17 +610,17 @@ pub fn get_user_avatar(baseu: &Url, userid: &str) -> Result<(String, String), Er
     }
 }
 
-pub fn get_room_st(base: &Url, tk: &str, roomid: &str) -> Result<JsonValue, Error> {
+pub fn get_room_st(tp: &dyn Transport, base: &Url, tk: &str, roomid: &str) -> Result<JsonValue, Error> {
     let url = client_url!(base, &format!("rooms/{}/state", roomid), vec![("access_token", strn!(tk))])?;
 
     let attrs = json!(null);
-    let st = json_q("get", &url, &attrs, globals::TIMEOUT)?;
+    let st = tp.json_q("get", &url, &attrs, globals::TIMEOUT)?;
     Ok(st)
 }
 
 #[cfg(feature = "gfx")]
-pub fn get_room_avatar(base: &Url, tk: &str, userid: &str, roomid: &str) -> Result<String, Error> {
-    let st = get_room_st(base, tk, roomid)?;
+pub fn get_room_avatar(tp: &dyn Transport, base: &Url, tk: &str, userid: &str, roomid: &str) -> Result<String, Error> {
+    let st = get_room_st(tp, base, tk, roomid)?;
     let events = st.as_array().ok_or(Error::BackendError)?;
 
     // we look for members that aren't me
@@ -691,12 +637,12 @@ pub fn get_room_avatar(base: &Url, tk: &str, userid: &str, roomid: &str) -> Resu
     };
 
     let mut fname = match members.count() {
-        1 => thumb!(&base, m1).unwrap_or_default(),
+        1 => thumb!(tp, &base, m1).unwrap_or_default(),
         _ => String::new(),
     };
 
     if fname.is_empty() {
-        let roomname = match calculate_room_name(&st, userid)?{
+        let roomname = match calculate_room_name(events, userid, &[])?{
             Some(ref name) => { name.clone() },
             None => { "X".to_string() },
         };
@@ -792,10 +738,14 @@ pub fn draw_identicon(fname: &str, name: String, mode: AvatarMode) -> Result<Str
     Ok(fname)
 }
 
-pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<Option<String>, Error> {
+/// Calculates the room name from the `m.room.name` or `m.room.canonical_alias` state events,
+/// or from the room members if there's none of them.
+///
+/// When the members are lazy loaded only the `heroes` of the room summary are known, so they're
+/// used instead of the member events
+pub fn calculate_room_name(events: &[JsonValue], userid: &str, heroes: &[String]) -> Result<Option<String>, Error> {
 
     // looking for "m.room.name" event
-    let events = roomst.as_array().ok_or(Error::BackendError)?;
     if let Some(name) = events.iter().find(|x| x["type"] == "m.room.name") {
         if let Some(name) = name["content"]["name"].as_str() {
             if !name.to_string().is_empty() {
@@ -811,6 +761,25 @@ pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<Option<St
         }
     }
 
+    // the heroes of the room summary, in their order, with the name of their member event if
+    // it was loaded
+    if !heroes.is_empty() {
+        let alias = |uid: &String| {
+            events.iter()
+                .find(|x| x["type"] == "m.room.member" && x["state_key"] == uid.as_str())
+                .and_then(|m| m["content"]["displayname"].as_str())
+                .unwrap_or(uid.as_str())
+                .to_string()
+        };
+
+        let name = match heroes.len() {
+            1 => alias(&heroes[0]),
+            2 => format!("{} and {}", alias(&heroes[0]), alias(&heroes[1])),
+            _ => format!("{} and Others", alias(&heroes[0])),
+        };
+        return Ok(Some(name));
+    }
+
     // we look for members that aren't me
     let filter = |x: &&JsonValue| {
         (x["type"] == "m.room.member" &&
@@ -854,108 +823,6 @@ pub fn calculate_room_name(roomst: &JsonValue, userid: &str) -> Result<Option<St
     Ok(Some(name))
 }
 
-/// Recursive function that tries to get at least @get Messages for the room.
-///
-/// The @limit is the first "limit" param in the GET request.
-/// The @end param is used as "from" param in the GET request, so we'll get
-/// messages before that.
-pub fn get_initial_room_messages(baseu: &Url,
-                                 tk: String,
-                                 roomid: String,
-                                 get: usize,
-                                 limit: i32,
-                                 end: Option<String>)
-                                 -> Result<(Vec<Message>, String, String), Error> {
-
-    let mut ms: Vec<Message> = vec![];
-    let mut nstart;
-    let mut nend;
-
-    let mut params = vec![
-        ("dir", strn!("b")),
-        ("limit", format!("{}", limit)),
-        ("access_token", tk.clone()),
-    ];
-
-    match end {
-        Some(ref e) => { params.push(("from", e.clone())) }
-        None => {}
-    };
-
-    let path = format!("rooms/{}/messages", roomid);
-    let url = client_url!(baseu, &path, params)?;
-
-    let r = json_q("get", &url, &json!(null), globals::TIMEOUT)?;
-    nend = String::from(r["end"].as_str().unwrap_or(""));
-    nstart = String::from(r["start"].as_str().unwrap_or(""));
-
-    let array = r["chunk"].as_array();
-    if array.is_none() || array.unwrap().len() == 0 {
-        return Ok((ms, nstart, nend));
-    }
-
-    let evs = array.unwrap().iter().rev();
-    let msgs = Message::from_json_events_iter(roomid.clone(), evs);
-    ms.extend(msgs);
-
-    if ms.len() < get {
-        let (more, s, e) =
-            get_initial_room_messages(baseu, tk, roomid, get, limit * 2, Some(nend))?;
-        nstart = s;
-        nend = e;
-        for m in more.iter().rev() {
-            ms.insert(0, m.clone());
-        }
-    }
-
-    Ok((ms, nstart, nend))
-}
-
-/// Recursive function that tries to get all messages in a room from a batch id to a batch id,
-/// following the response pagination
-pub fn fill_room_gap(baseu: &Url,
-                     tk: String,
-                     roomid: String,
-                     from: String,
-                     to: String)
-                     -> Result<Vec<Message>, Error> {
-
-    let mut ms: Vec<Message> = vec![];
-    let nend;
-
-    let mut params = vec![
-        ("dir", strn!("f")),
-        ("limit", format!("{}", globals::PAGE_LIMIT)),
-        ("access_token", tk.clone()),
-    ];
-
-    params.push(("from", from.clone()));
-    params.push(("to", to.clone()));
-
-    let path = format!("rooms/{}/messages", roomid);
-    let url = client_url!(baseu, &path, params)?;
-
-    let r = json_q("get", &url, &json!(null), globals::TIMEOUT)?;
-    nend = String::from(r["end"].as_str().unwrap_or(""));
-
-    let array = r["chunk"].as_array();
-    if array.is_none() || array.unwrap().len() == 0 {
-        return Ok(ms);
-    }
-
-    let evs = array.unwrap().iter();
-    let mevents = Message::from_json_events_iter(roomid.clone(), evs);
-    ms.extend(mevents);
-
-    // loading more until no more messages
-    let more = fill_room_gap(baseu, tk, roomid, nend, to)?;
-    for m in more.iter() {
-        ms.insert(0, m.clone());
-    }
-
-    Ok(ms)
-}
-
 pub fn build_url(base: &Url, path: &str, params: Vec<(&str, String)>) -> Result<Url, Error> {
     let mut url = base.join(path)?;
 
@@ -973,6 +840,18 @@ pub fn build_url(base: &Url, path: &str, params: Vec<(&str, String)>) -> Result<
     Ok(url)
 }
 
+/// The server name of a user id like `@alice:example.org`, None if it isn't a user id
+pub fn user_server_name(uid: &str) -> Option<String> {
+    if !uid.starts_with('@') {
+        return None;
+    }
+
+    match uid.splitn(2, ':').nth(1) {
+        Some(name) if !name.is_empty() => Some(name.to_string()),
+        _ => None,
+    }
+}
+
 #[cfg(feature = "gfx")]
 pub fn circle_image(fname: String) -> Result<String, Error> {
     use std::f64::consts::PI;
@@ -1033,13 +912,13 @@ pub fn cache_dir_path(dir: &str, name: &str) -> Result<String, Error> {
 }
 
 #[cfg(feature = "gfx")]
-pub fn get_user_avatar_img(baseu: &Url, userid: String, alias: String, avatar: String) -> Result<String, Error> {
+pub fn get_user_avatar_img(tp: &dyn Transport, baseu: &Url, userid: String, alias: String, avatar: String) -> Result<String, Error> {
     if avatar.is_empty() {
         return identicon!(&userid, alias);
     }
 
     let dest = cache_path(&userid)?;
-    let img = dw_media(baseu, &avatar, true, Some(&dest), 64, 64)?;
+    let img = dw_media(tp, baseu, &avatar, true, Some(&dest), 64, 64)?;
     Ok(img)
 }
 
@@ -1066,5 +945,39 @@ pub fn parse_room_member(msg: &JsonValue) -> Option<Member> {
         uid: strn!(sender),
         alias: displayname,
         avatar: avatar_url,
+        presence: None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn server_names() {
+        assert_eq!(user_server_name("@alice:example.org"), Some(strn!("example.org")));
+        assert_eq!(user_server_name("@alice:localhost:8008"), Some(strn!("localhost:8008")));
+        assert_eq!(user_server_name("alice"), None);
+        assert_eq!(user_server_name("@alice:"), None);
+    }
+
+    #[test]
+    fn fully_read_on_hidden_events() {
+        let events = vec![
+            json!({"type": "m.room.message", "event_id": "$1", "content": {"msgtype": "m.text", "body": "hi"}}),
+            json!({"type": "m.reaction", "event_id": "$2", "content": {}}),
+            json!({"type": "m.room.message", "event_id": "$3", "content": {"msgtype": "m.text", "body": "* hey",
+                   "m.new_content": {"msgtype": "m.text", "body": "hey"},
+                   "m.relates_to": {"rel_type": "m.replace", "event_id": "$1"}}}),
+            json!({"type": "m.room.message", "event_id": "$4", "content": {"msgtype": "m.text", "body": "bye"}}),
+        ];
+
+        assert_eq!(fully_read_message("!room", "$1", &events, None), Some(strn!("$1")));
+        assert_eq!(fully_read_message("!room", "$2", &events, None), Some(strn!("$1")));
+        assert_eq!(fully_read_message("!room", "$3", &events, None), Some(strn!("$1")));
+        assert_eq!(fully_read_message("!room", "$4", &events, None), Some(strn!("$4")));
+        assert_eq!(fully_read_message("!room", "$5", &events, Some("$0")), None);
+        assert_eq!(fully_read_message("!room", "$2", &events[1..], None), None);
+        assert_eq!(fully_read_message("!room", "$2", &events[1..], Some("$0")), Some(strn!("$0")));
+    }
+}
@@ -0,0 +1,1322 @@
+#[macro_use]
+extern crate fractal_matrix_api;
+#[macro_use]
+extern crate serde_json;
+extern crate url;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use serde_json::Value as JsonValue;
+
+use fractal_matrix_api::backend::{Backend, BKCommand, BKResponse, RoomType};
+use fractal_matrix_api::types::Message;
+use fractal_matrix_api::types::PresenceState;
+use fractal_matrix_api::types::ServerInfo;
+use fractal_matrix_api::store::MemoryStore;
+use fractal_matrix_api::store::Store;
+use fractal_matrix_api::uia;
+use fractal_matrix_api::uia::AuthData;
+
+use common::MockHomeserver;
+use common::wait_for;
+use common::SERVER;
+
+use url::Url;
+
+fn backend(hs: &Arc<MockHomeserver>) -> (Sender<BKCommand>, Receiver<BKResponse>) {
+    let (tx, rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
+    let bk = Backend::with_transport(tx, hs.clone());
+    (bk.run(), rx)
+}
+
+fn backend_with_store(hs: &Arc<MockHomeserver>, store: &Arc<MemoryStore>) -> (Sender<BKCommand>, Receiver<BKResponse>) {
+    let (tx, rx): (Sender<BKResponse>, Receiver<BKResponse>) = channel();
+    let bk = Backend::with_transport(tx, hs.clone())
+        .with_store(store.clone())
+        .with_pickle_key([1; 32]);
+    (bk.run(), rx)
+}
+
+fn login(cmd: &Sender<BKCommand>, rx: &Receiver<BKResponse>, user: &str, password: &str) -> String {
+    cmd.send(BKCommand::Login(user.to_string(), password.to_string(), SERVER.to_string())).unwrap();
+    match wait_for(rx, |r| match *r { BKResponse::Token(..) | BKResponse::LoginError(_) => true, _ => false }) {
+        BKResponse::Token(uid, _) => uid,
+        r => panic!("Login failed: {:?}", r),
+    }
+}
+
+#[test]
+fn login_and_logout() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let (cmd, rx) = backend(&hs);
+
+    assert_eq!(login(&cmd, &rx, "alice", "secret"), uid);
+
+    cmd.send(BKCommand::Logout).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Logout => true, _ => false });
+}
+
+#[test]
+fn login_with_wrong_password() {
+    let hs = MockHomeserver::new();
+    hs.add_user("alice", "secret");
+    let (cmd, rx) = backend(&hs);
+
+    cmd.send(BKCommand::Login(strn!("alice"), strn!("wrong"), strn!(SERVER))).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Token(..) | BKResponse::LoginError(_) => true, _ => false }) {
+        BKResponse::LoginError(_) => {}
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn initial_sync_lists_joined_rooms() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    hs.join(&roomid, &bob);
+    hs.send_text(&roomid, &bob, "hello alice");
+    hs.create_room(&bob, "Not joined");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) | BKResponse::SyncError(_) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            assert_eq!(rooms.len(), 1);
+            assert_eq!(rooms[0].id, roomid);
+            assert_eq!(rooms[0].name, Some(strn!("Test room")));
+            assert_eq!(rooms[0].messages.len(), 1);
+            assert_eq!(rooms[0].messages[0].body, "hello alice");
+            assert_eq!(rooms[0].messages[0].sender, bob);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+}
+
+#[test]
+fn incremental_sync_returns_new_messages() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    hs.join(&roomid, &bob);
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    hs.send_text(&roomid, &bob, "new message");
+    hs.send_event(&roomid, &bob, "m.room.topic", Some(""), json!({ "topic": "New topic" }));
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(_) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            assert_eq!(msgs.len(), 1);
+            assert_eq!(msgs[0].body, "new message");
+            assert_eq!(msgs[0].room, roomid);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    match wait_for(&rx, |r| match *r { BKResponse::RoomTopic(..) => true, _ => false }) {
+        BKResponse::RoomTopic(room, topic) => {
+            assert_eq!(room, roomid);
+            assert_eq!(topic, "New topic");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn send_message() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    let msg = Message {
+        sender: uid.clone(),
+        room: roomid.clone(),
+        body: strn!("hello world"),
+        id: Some(strn!("txn1")),
+        ..Default::default()
+    };
+    cmd.send(BKCommand::SendMsg(msg)).unwrap();
+
+    match wait_for(&rx, |r| match *r { BKResponse::SentMsg(..) | BKResponse::SendMsgError(_) => true, _ => false }) {
+        BKResponse::SentMsg(txid, evid) => {
+            assert_eq!(txid, "txn1");
+            let events = hs.events(&roomid);
+            let last = events.last().unwrap();
+            assert_eq!(last["event_id"], evid);
+            assert_eq!(last["content"]["body"], "hello world");
+            assert_eq!(last["content"]["msgtype"], "m.text");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn room_messages_are_paginated_backwards() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    for i in 0..60 {
+        hs.send_text(&roomid, &uid, &format!("message {}", i));
+    }
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::GetRoomMessages(roomid.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessagesInit(_) | BKResponse::RoomMessagesError(_) => true, _ => false }) {
+        BKResponse::RoomMessagesInit(msgs) => {
+            assert!(msgs.len() >= 40);
+            assert_eq!(msgs.last().unwrap().body, "message 59");
+            let bodies: Vec<&str> = msgs.iter().map(|m| m.body.as_str()).collect();
+            let mut sorted = bodies.clone();
+            sorted.sort_by_key(|b| b[8..].parse::<i32>().unwrap());
+            assert_eq!(bodies, sorted);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn create_and_join_rooms() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let bobs_room = hs.create_room(&bob, "Bob's room");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::NewRoom(strn!("New room"), RoomType::Private, strn!("internal"))).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::NewRoom(..) | BKResponse::NewRoomError(..) => true, _ => false }) {
+        BKResponse::NewRoom(room, internal) => {
+            assert_eq!(internal, "internal");
+            assert_eq!(room.name, Some(strn!("New room")));
+            assert!(hs.room_ids().contains(&room.id));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::JoinRoom(bobs_room.clone())).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::JoinRoom => true, _ => false });
+
+    cmd.send(BKCommand::GetRoomMembers(bobs_room.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMembers(..) => true, _ => false }) {
+        BKResponse::RoomMembers(room, members) => {
+            assert_eq!(room, bobs_room);
+            assert_eq!(members.len(), 2);
+            assert!(members.iter().any(|m| m.uid == uid));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn attach_file_uploads_media() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    let mut path = ::std::env::temp_dir();
+    path.push("fractal-matrix-api-attach-test.txt");
+    ::std::fs::write(&path, b"file content").unwrap();
+
+    let msg = Message {
+        sender: uid.clone(),
+        room: roomid.clone(),
+        mtype: strn!("m.file"),
+        body: strn!("file.txt"),
+        url: Some(path.to_string_lossy().to_string()),
+        id: Some(strn!("txn-file")),
+        ..Default::default()
+    };
+    cmd.send(BKCommand::AttachFile(msg)).unwrap();
+
+    match wait_for(&rx, |r| match *r { BKResponse::AttachedFile(_) | BKResponse::AttachFileError(_) => true, _ => false }) {
+        BKResponse::AttachedFile(m) => assert!(m.url.unwrap().starts_with("mxc://localhost/")),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    wait_for(&rx, |r| match *r { BKResponse::SentMsg(..) => true, _ => false });
+
+    let events = hs.events(&roomid);
+    assert_eq!(events.last().unwrap()["content"]["msgtype"], "m.file");
+}
+
+#[test]
+fn rooms_are_restored_from_the_store() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Stored room");
+    hs.send_text(&roomid, &uid, "stored message");
+
+    let store = Arc::new(MemoryStore::new());
+    {
+        let (cmd, rx) = backend_with_store(&hs, &store);
+        login(&cmd, &rx, "alice", "secret");
+        cmd.send(BKCommand::Sync).unwrap();
+        wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+    }
+
+    let session = store.session().unwrap().unwrap();
+    assert_eq!(session.user_id, uid);
+    assert!(!session.since.is_empty());
+
+    hs.send_text(&roomid, &uid, "new message");
+
+    let (cmd, rx) = backend_with_store(&hs, &store);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            assert_eq!(rooms.len(), 1);
+            assert_eq!(rooms[0].name, Some(strn!("Stored room")));
+            assert_eq!(rooms[0].messages.last().unwrap().body, "stored message");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // the next sync only brings what happened since the stored token
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(_) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            assert_eq!(msgs.len(), 1);
+            assert_eq!(msgs[0].body, "new message");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn redacted_messages() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    let first = hs.send_text(&roomid, &uid, "first");
+    hs.send_text(&roomid, &uid, "second");
+
+    let store = Arc::new(MemoryStore::new());
+    {
+        let (cmd, rx) = backend_with_store(&hs, &store);
+        login(&cmd, &rx, "alice", "secret");
+        cmd.send(BKCommand::Sync).unwrap();
+        wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+        cmd.send(BKCommand::Redact(roomid.clone(), first.clone(), Some(strn!("typo")))).unwrap();
+        match wait_for(&rx, |r| match *r { BKResponse::Redacted(..) | BKResponse::RedactError(_) => true, _ => false }) {
+            BKResponse::Redacted(room, evid) => {
+                assert_eq!(room, roomid);
+                assert_eq!(evid, first);
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+
+        let redaction = hs.events(&roomid).into_iter().find(|ev| ev["type"] == "m.room.redaction").unwrap();
+        assert_eq!(redaction["redacts"], json!(first));
+        assert_eq!(redaction["content"]["reason"], json!("typo"));
+
+        // the redaction comes back in the next sync, to other devices too
+        cmd.send(BKCommand::Sync).unwrap();
+        match wait_for(&rx, |r| match *r { BKResponse::Redacted(..) => true, _ => false }) {
+            BKResponse::Redacted(_, evid) => assert_eq!(evid, first),
+            r => panic!("Unexpected response {:?}", r),
+        }
+        wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+    }
+
+    // the stored timeline has the original event, but it's loaded redacted
+    let (cmd, rx) = backend_with_store(&hs, &store);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            let msgs = &rooms[0].messages;
+            let m = msgs.iter().find(|m| m.id == Some(first.clone())).unwrap();
+            assert!(m.redacted);
+            assert!(m.body.is_empty());
+            assert_eq!(msgs.last().unwrap().body, "second");
+            assert!(!msgs.last().unwrap().redacted);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn edited_messages() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    let first = hs.send_text(&roomid, &uid, "helo");
+    hs.send_text(&roomid, &uid, "second");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    let edit = Message {
+        sender: uid.clone(),
+        room: roomid.clone(),
+        body: strn!("hello"),
+        id: Some(strn!("txn1")),
+        ..Default::default()
+    };
+    cmd.send(BKCommand::EditMsg(first.clone(), edit)).unwrap();
+
+    // the edit comes back in the next sync pointing to the original message
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(_) | BKResponse::EditMsgError(_) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            let m = msgs.last().unwrap();
+            assert_eq!(m.replace, Some(first.clone()));
+            assert_eq!(m.body, "hello");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let ev = hs.events(&roomid).into_iter().last().unwrap();
+    assert_eq!(ev["content"]["body"], json!("* hello"));
+    assert_eq!(ev["content"]["m.new_content"]["body"], json!("hello"));
+    assert_eq!(ev["content"]["m.relates_to"]["rel_type"], json!("m.replace"));
+
+    // an initial sync has the edit applied to the original message
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            let msgs = &rooms[0].messages;
+            assert!(msgs.iter().all(|m| m.replace.is_none()));
+            let m = msgs.iter().find(|m| m.id == Some(first.clone())).unwrap();
+            assert_eq!(m.body, "hello");
+            assert!(m.edited);
+            assert_eq!(msgs.last().unwrap().body, "second");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn replies() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    let parent = hs.send_text(&roomid, &uid, "how are you?");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    let msg = Message {
+        sender: uid.clone(),
+        room: roomid.clone(),
+        body: strn!("fine"),
+        id: Some(strn!("txn1")),
+        in_reply_to: Some(parent.clone()),
+        ..Default::default()
+    };
+    cmd.send(BKCommand::SendMsg(msg)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::SentMsg(..) => true, _ => false });
+
+    // the parent isn't in a synced timeline, so it's requested to build the quote
+    let ev = hs.events(&roomid).into_iter().last().unwrap();
+    assert_eq!(ev["content"]["m.relates_to"]["m.in_reply_to"]["event_id"], json!(parent));
+    assert_eq!(ev["content"]["body"], json!(format!("> <{}> how are you?\n\nfine", uid)));
+    assert_eq!(ev["content"]["format"], json!("org.matrix.custom.html"));
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            let m = rooms[0].messages.last().unwrap();
+            assert_eq!(m.in_reply_to, Some(parent.clone()));
+            assert_eq!(m.body, "fine");
+            assert_eq!(m.formatted_body, Some(strn!("fine")));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn reactions() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    hs.join(&roomid, &bob);
+    let msg = hs.send_text(&roomid, &uid, "hello");
+    hs.send_event(&roomid, &bob, "m.reaction", None, json!({
+        "m.relates_to": { "rel_type": "m.annotation", "event_id": msg, "key": "👍" }
+    }));
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            assert_eq!(rooms[0].reaction_keys(&msg), vec![(strn!("👍"), vec![bob.clone()])]);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    cmd.send(BKCommand::SendReaction(roomid.clone(), msg.clone(), strn!("👍"))).unwrap();
+    let own = match wait_for(&rx, |r| match *r { BKResponse::Reactions(..) | BKResponse::SendReactionError(_) => true, _ => false }) {
+        BKResponse::Reactions(room, reactions) => {
+            assert_eq!(room, roomid);
+            assert_eq!(reactions[0].0, msg);
+            assert_eq!(reactions[0].1.sender, uid);
+            reactions[0].1.id.clone()
+        }
+        r => panic!("Unexpected response {:?}", r),
+    };
+
+    let ev = hs.events(&roomid).into_iter().last().unwrap();
+    assert_eq!(ev["type"], json!("m.reaction"));
+    assert_eq!(ev["content"]["m.relates_to"]["key"], json!("👍"));
+
+    // the reaction comes back in the next sync
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Reactions(..) => true, _ => false }) {
+        BKResponse::Reactions(_, reactions) => assert_eq!(reactions[0].1.id, own),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    // removing our reaction
+    cmd.send(BKCommand::Redact(roomid.clone(), own.clone(), None)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Redacted(..) => true, _ => false });
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            assert_eq!(rooms[0].reaction_keys(&msg), vec![(strn!("👍"), vec![bob.clone()])]);
+            assert_eq!(rooms[0].own_reaction(&msg, "👍", &uid), None);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn limited_sync_fills_the_gap() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    hs.set_timeline_limit(5);
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    for i in 0..20 {
+        hs.send_text(&roomid, &uid, &format!("message {}", i));
+    }
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(_) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            let bodies: Vec<String> = msgs.iter().map(|m| m.body.clone()).collect();
+            let expected: Vec<String> = (0..20).map(|i| format!("message {}", i)).collect();
+            assert_eq!(bodies, expected);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn restored_timeline_fills_the_gap() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    hs.set_timeline_limit(5);
+
+    let store = Arc::new(MemoryStore::new());
+    {
+        let (cmd, rx) = backend_with_store(&hs, &store);
+        login(&cmd, &rx, "alice", "secret");
+        cmd.send(BKCommand::Sync).unwrap();
+        wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+    }
+
+    for i in 0..20 {
+        hs.send_text(&roomid, &uid, &format!("message {}", i));
+    }
+
+    // the first sync after the restart is limited, the messages before it are loaded
+    let (cmd, rx) = backend_with_store(&hs, &store);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(_) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            let bodies: Vec<String> = msgs.iter().map(|m| m.body.clone()).collect();
+            let expected: Vec<String> = (0..20).map(|i| format!("message {}", i)).collect();
+            assert_eq!(bodies, expected);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // and stored, so the gap is closed in the store too
+    let chunks = store.chunks(&roomid).unwrap();
+    assert!(!chunks.last().unwrap().limited);
+    let stored = chunks.iter()
+        .flat_map(|c| c.events.iter())
+        .filter(|ev| ev["type"] == "m.room.message")
+        .count();
+    assert_eq!(stored, 20);
+}
+
+#[test]
+fn back_paginate_until_the_start_of_the_room() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    for i in 0..100 {
+        hs.send_text(&roomid, &uid, &format!("message {}", i));
+    }
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::GetRoomMessages(roomid.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessagesInit(_) => true, _ => false }) {
+        BKResponse::RoomMessagesInit(msgs) => assert_eq!(msgs[0].body, "message 60"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    let back = |cmd: &Sender<BKCommand>| {
+        cmd.send(BKCommand::BackPaginate(roomid.clone())).unwrap();
+        match wait_for(&rx, |r| match *r { BKResponse::RoomMessagesBackPaginated(..) => true, _ => false }) {
+            BKResponse::RoomMessagesBackPaginated(room, msgs, token) => {
+                assert_eq!(room, roomid);
+                (msgs, token)
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    };
+
+    let (msgs, token) = back(&cmd);
+    assert_eq!(msgs.len(), 40);
+    assert_eq!(msgs[0].body, "message 20");
+    assert_eq!(msgs[39].body, "message 59");
+    assert!(token.is_some());
+
+    // a short page isn't the start of the room, the next one is empty
+    let (msgs, token) = back(&cmd);
+    assert_eq!(msgs.len(), 20);
+    assert_eq!(msgs[0].body, "message 0");
+    assert!(token.is_some());
+
+    let (msgs, token) = back(&cmd);
+    assert!(msgs.is_empty());
+    assert!(token.is_none());
+
+    let (msgs, token) = back(&cmd);
+    assert!(msgs.is_empty());
+    assert!(token.is_none());
+    assert_eq!(hs.count_requests("get", "/messages"), 4);
+}
+
+#[test]
+fn edits_of_older_messages_are_applied_when_they_are_paginated() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    let mut edited = String::new();
+    for i in 0..100 {
+        let evid = hs.send_text(&roomid, &uid, &format!("message {}", i));
+        if i == 10 {
+            edited = evid;
+        }
+    }
+    hs.send_event(&roomid, &uid, "m.room.message", None, json!({
+        "msgtype": "m.text",
+        "body": "* edited 10",
+        "m.new_content": { "msgtype": "m.text", "body": "edited 10" },
+        "m.relates_to": { "rel_type": "m.replace", "event_id": edited },
+    }));
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    // the edit is in the first page and the edited message two pages later
+    cmd.send(BKCommand::GetRoomMessages(roomid.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessagesInit(_) => true, _ => false }) {
+        BKResponse::RoomMessagesInit(msgs) => {
+            assert_eq!(msgs[0].body, "message 21");
+            assert!(msgs.iter().all(|m| m.replace.is_none()));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::BackPaginate(roomid.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessagesBackPaginated(..) => true, _ => false }) {
+        BKResponse::RoomMessagesBackPaginated(_, msgs, _) => {
+            assert_eq!(msgs[0].body, "message 0");
+            let m = msgs.iter().find(|m| m.id == Some(edited.clone())).unwrap();
+            assert_eq!(m.body, "edited 10");
+            assert!(m.edited);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn sync_filter_is_uploaded_once() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    hs.create_room(&uid, "Test room");
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    for _ in 0..2 {
+        cmd.send(BKCommand::Sync).unwrap();
+        match wait_for(&rx, |r| match *r { BKResponse::Sync(_) | BKResponse::SyncError(_) => true, _ => false }) {
+            BKResponse::Sync(_) => {}
+            r => panic!("Unexpected response {:?}", r),
+        }
+    }
+
+    let filters = hs.filters();
+    assert_eq!(filters.len(), 1);
+    assert_eq!(filters[0]["room"]["timeline"]["limit"], json!(40));
+}
+
+#[test]
+fn members_are_lazy_loaded() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "");
+    let mut others = vec![];
+    for i in 0..10 {
+        let other = hs.add_user(&format!("user{}", i), "secret");
+        hs.join(&roomid, &other);
+        others.push(other);
+    }
+    hs.send_text(&roomid, &others[3], "hello");
+    // the heroes aren't in the order of their member events, that aren't sent
+    hs.set_heroes(&roomid, &[&others[7], &others[2]]);
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) | BKResponse::SyncError(_) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            let room = rooms.iter().find(|r| r.id == roomid).expect("room not found");
+            assert_eq!(room.n_members, 11);
+            assert_eq!(room.heroes, vec![others[7].clone(), others[2].clone()]);
+            assert!(room.members.contains_key(&others[3]));
+            assert!(!room.members.contains_key(&others[7]));
+            assert!(!room.members.contains_key(&others[2]));
+            assert_eq!(room.name, Some(format!("{} and {}", others[7], others[2])));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::GetRoomMembers(roomid.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMembers(..) => true, _ => false }) {
+        BKResponse::RoomMembers(_, members) => assert_eq!(members.len(), 11),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn typing_notifications() {
+    let hs = MockHomeserver::new();
+    let alice = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&alice, "Test room");
+    hs.join(&roomid, &bob);
+
+    let (bob_cmd, bob_rx) = backend(&hs);
+    login(&bob_cmd, &bob_rx, "bob", "secret");
+    bob_cmd.send(BKCommand::SetTyping(roomid.clone(), true, 4000)).unwrap();
+    for _ in 0..50 {
+        if !hs.typing(&roomid).is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(hs.typing(&roomid), vec![bob.clone()]);
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Typing(..) => true, _ => false }) {
+        BKResponse::Typing(room, users) => {
+            assert_eq!(room, roomid);
+            assert_eq!(users, vec![bob.clone()]);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn presence() {
+    let hs = MockHomeserver::new();
+    let alice = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&alice, "Test room");
+    hs.join(&roomid, &bob);
+
+    let (bob_cmd, bob_rx) = backend(&hs);
+    login(&bob_cmd, &bob_rx, "bob", "secret");
+    bob_cmd.send(BKCommand::SetPresence(PresenceState::Online, Some(strn!("At lunch")))).unwrap();
+    for _ in 0..50 {
+        if hs.presence(&bob).is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(hs.presence(&bob), Some(strn!("online")));
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Presence(_) | BKResponse::SyncError(_) => true, _ => false }) {
+        BKResponse::Presence(presence) => {
+            let p = presence.get(&bob).expect("no presence for bob");
+            assert_eq!(p.presence, PresenceState::Online);
+            assert_eq!(p.status_msg, Some(strn!("At lunch")));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    bob_cmd.send(BKCommand::SetPresence(PresenceState::Unavailable, None)).unwrap();
+    for _ in 0..50 {
+        if hs.presence(&bob) == Some(strn!("unavailable")) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Presence(_) => true, _ => false }) {
+        BKResponse::Presence(presence) => {
+            let p = presence.get(&bob).expect("no presence for bob");
+            assert_eq!(p.presence, PresenceState::Unavailable);
+            assert_eq!(p.status_msg, None);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn read_receipts() {
+    let hs = MockHomeserver::new();
+    let alice = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&alice, "Test room");
+    hs.join(&roomid, &bob);
+    let first = hs.send_text(&roomid, &alice, "first");
+    let second = hs.send_text(&roomid, &alice, "second");
+
+    let (bob_cmd, bob_rx) = backend(&hs);
+    login(&bob_cmd, &bob_rx, "bob", "secret");
+    bob_cmd.send(BKCommand::MarkAsRead(roomid.clone(), first.clone())).unwrap();
+    wait_for(&bob_rx, |r| match *r { BKResponse::MarkedAsRead(..) => true, _ => false });
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) | BKResponse::SyncError(_) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            let room = rooms.iter().find(|r| r.id == roomid).expect("room not found");
+            assert_eq!(room.receipts.get(&bob).map(|r| r.0.clone()), Some(first.clone()));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    bob_cmd.send(BKCommand::MarkAsRead(roomid.clone(), second.clone())).unwrap();
+    wait_for(&bob_rx, |r| match *r { BKResponse::MarkedAsRead(..) => true, _ => false });
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Receipts(..) => true, _ => false }) {
+        BKResponse::Receipts(room, receipts) => {
+            assert_eq!(room, roomid);
+            assert_eq!(receipts.get(&bob).map(|r| r.0.clone()), Some(second.clone()));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn fully_read_marker_follows_other_devices() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    let first = hs.send_text(&roomid, &uid, "first");
+    let second = hs.send_text(&roomid, &uid, "second");
+
+    let (other_cmd, other_rx) = backend(&hs);
+    login(&other_cmd, &other_rx, "alice", "secret");
+    other_cmd.send(BKCommand::MarkAsRead(roomid.clone(), first.clone())).unwrap();
+    wait_for(&other_rx, |r| match *r { BKResponse::MarkedAsRead(..) => true, _ => false });
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Rooms(..) | BKResponse::SyncError(_) => true, _ => false }) {
+        BKResponse::Rooms(rooms, _) => {
+            let room = rooms.iter().find(|r| r.id == roomid).expect("room not found");
+            assert_eq!(room.fully_read, Some(first.clone()));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    other_cmd.send(BKCommand::MarkAsRead(roomid.clone(), second.clone())).unwrap();
+    wait_for(&other_rx, |r| match *r { BKResponse::MarkedAsRead(..) => true, _ => false });
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::FullyRead(..) => true, _ => false }) {
+        BKResponse::FullyRead(room, evid) => {
+            assert_eq!(room, roomid);
+            assert_eq!(evid, second);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn encrypted_messages_between_two_devices() {
+    let hs = MockHomeserver::new();
+    let alice = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&alice, "Secret room");
+    hs.join(&roomid, &bob);
+    hs.send_event(&roomid, &alice, "m.room.encryption", Some(""), json!({ "algorithm": "m.megolm.v1.aes-sha2" }));
+
+    // the first sync of each device uploads its keys
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    let store = Arc::new(MemoryStore::new());
+    let (bob_cmd, bob_rx) = backend_with_store(&hs, &store);
+    bob_cmd.send(BKCommand::Login(strn!("bob"), strn!("secret"), strn!(SERVER))).unwrap();
+    let bob_token = match wait_for(&bob_rx, |r| match *r { BKResponse::Token(..) => true, _ => false }) {
+        BKResponse::Token(_, tk) => tk,
+        r => panic!("Unexpected response {:?}", r),
+    };
+    bob_cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&bob_rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+    assert_eq!(hs.device_ids(&alice).len(), 1);
+    assert_eq!(hs.device_ids(&bob).len(), 1);
+
+    let msg = Message {
+        sender: bob.clone(),
+        room: roomid.clone(),
+        body: strn!("top secret"),
+        id: Some(strn!("txn1")),
+        ..Default::default()
+    };
+    bob_cmd.send(BKCommand::SendMsg(msg)).unwrap();
+    match wait_for(&bob_rx, |r| match *r { BKResponse::SentMsg(..) | BKResponse::SendMsgError(_) => true, _ => false }) {
+        BKResponse::SentMsg(..) => {}
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // the key is only shared once, and the members are only requested once
+    let again = Message {
+        sender: bob.clone(),
+        room: roomid.clone(),
+        body: strn!("still secret"),
+        id: Some(strn!("txn1b")),
+        ..Default::default()
+    };
+    bob_cmd.send(BKCommand::SendMsg(again)).unwrap();
+    wait_for(&bob_rx, |r| match *r { BKResponse::SentMsg(..) => true, _ => false });
+    assert_eq!(hs.count_requests("get", "/members"), 1);
+    assert_eq!(hs.count_requests("put", "/sendToDevice/"), 1);
+    assert_eq!(hs.events(&roomid).into_iter().filter(|ev| ev["type"] == "m.room.encrypted").count(), 2);
+
+    // the server only sees the encrypted event
+    let ev = hs.events(&roomid).into_iter().last().unwrap();
+    assert_eq!(ev["type"], "m.room.encrypted");
+    assert_eq!(ev["content"]["algorithm"], "m.megolm.v1.aes-sha2");
+    assert!(!ev.to_string().contains("top secret"));
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(..) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            let m = msgs.iter().find(|m| m.sender == bob).expect("message not decrypted");
+            assert_eq!(m.body, "top secret");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // bob restarts with the same token and the stored keys, and reads the answer
+    drop(bob_cmd);
+    let (bob_cmd, bob_rx) = backend_with_store(&hs, &store);
+    bob_cmd.send(BKCommand::SetToken(bob_token, bob.clone(), strn!(SERVER))).unwrap();
+    wait_for(&bob_rx, |r| match *r { BKResponse::Token(..) => true, _ => false });
+
+    let answer = Message {
+        sender: alice.clone(),
+        room: roomid.clone(),
+        body: strn!("got it"),
+        id: Some(strn!("txn2")),
+        ..Default::default()
+    };
+    cmd.send(BKCommand::SendMsg(answer)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::SentMsg(..) => true, _ => false });
+    assert_eq!(hs.events(&roomid).into_iter().last().unwrap()["type"], "m.room.encrypted");
+
+    bob_cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&bob_rx, |r| match *r { BKResponse::RoomMessages(..) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            let m = msgs.iter().find(|m| m.sender == alice).expect("answer not decrypted");
+            assert_eq!(m.body, "got it");
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    assert_eq!(hs.device_ids(&bob).len(), 1);
+
+    // the store only has the encrypted events, they're decrypted when they're loaded
+    let stored: Vec<JsonValue> = store.chunks(&roomid).unwrap().into_iter().flat_map(|c| c.events).collect();
+    assert!(stored.iter().any(|ev| ev["type"] == "m.room.encrypted"));
+    assert!(!stored.iter().any(|ev| ev.to_string().contains("got it")));
+}
+
+#[test]
+fn undecryptable_and_unknown_events_are_kept() {
+    let hs = MockHomeserver::new();
+    let uid = hs.add_user("alice", "secret");
+    let bob = hs.add_user("bob", "secret");
+    let roomid = hs.create_room(&uid, "Test room");
+    hs.join(&roomid, &bob);
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    cmd.send(BKCommand::Sync).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Sync(_) => true, _ => false });
+
+    hs.send_event(&roomid, &bob, "m.room.encryption", Some(""), json!({ "algorithm": "m.megolm.v1.aes-sha2" }));
+    hs.send_event(&roomid, &bob, "m.room.encrypted", None, json!({
+        "algorithm": "m.megolm.v1.aes-sha2",
+        "ciphertext": "AwgAEnAc",
+        "sender_key": "unknown",
+        "device_id": "BOBDEVICE",
+        "session_id": "unknown",
+    }));
+    hs.send_event(&roomid, &bob, "org.example.poll", None, json!({ "question": "?", "body": "Poll: ?" }));
+    hs.send_event(&roomid, &bob, "m.call.hangup", None, json!({ "call_id": "1", "version": 0 }));
+
+    cmd.send(BKCommand::Sync).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RoomMessages(_) => true, _ => false }) {
+        BKResponse::RoomMessages(msgs) => {
+            assert_eq!(msgs.len(), 2);
+            assert!(msgs[0].is_undecryptable());
+            assert!(msgs[1].is_unsupported());
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    match wait_for(&rx, |r| match *r { BKResponse::RoomEncrypted(_) => true, _ => false }) {
+        BKResponse::RoomEncrypted(room) => assert_eq!(room, roomid),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn manage_devices() {
+    let hs = MockHomeserver::new();
+    hs.add_user("alice", "secret");
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "alice", "secret");
+    let (other_cmd, other_rx) = backend(&hs);
+    login(&other_cmd, &other_rx, "alice", "secret");
+
+    other_cmd.send(BKCommand::GetDevices).unwrap();
+    let other = match wait_for(&other_rx, |r| match *r { BKResponse::Devices(..) => true, _ => false }) {
+        BKResponse::Devices(_, current) => current,
+        r => panic!("Unexpected response {:?}", r),
+    };
+
+    cmd.send(BKCommand::RenameDevice(other.clone(), strn!("Laptop"))).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::RenamedDevice(..) => true, _ => false });
+
+    cmd.send(BKCommand::GetDevices).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Devices(..) => true, _ => false }) {
+        BKResponse::Devices(devices, current) => {
+            assert_eq!(devices.len(), 2);
+            assert!(current != other);
+            let d = devices.iter().find(|d| d.device_id == current).unwrap();
+            assert_eq!(d.display_name, Some(strn!("Fractal")));
+            let d = devices.iter().find(|d| d.device_id == other).unwrap();
+            assert_eq!(d.display_name, Some(strn!("Laptop")));
+            assert!(d.last_seen_ts.is_some());
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // deleting needs the password
+    cmd.send(BKCommand::DeleteDevice(other.clone(), strn!("wrong"))).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::DeleteDeviceError(_) => true, _ => false });
+
+    cmd.send(BKCommand::DeleteDevice(other.clone(), strn!("secret"))).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::DeletedDevice(_) | BKResponse::DeleteDeviceError(_) => true, _ => false }) {
+        BKResponse::DeletedDevice(d) => assert_eq!(d, other),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // the deleted session is signed out
+    other_cmd.send(BKCommand::GetDevices).unwrap();
+    wait_for(&other_rx, |r| match *r { BKResponse::DevicesError(_) => true, _ => false });
+
+    cmd.send(BKCommand::GetDevices).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Devices(..) => true, _ => false }) {
+        BKResponse::Devices(devices, _) => assert_eq!(devices.len(), 1),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn user_interactive_auth() {
+    let hs = MockHomeserver::new();
+    hs.set_uia_flows(&[&["m.login.password"]], &[&["m.login.recaptcha", "m.login.terms"]]);
+    let (cmd, rx) = backend(&hs);
+
+    // nothing to complete
+    cmd.send(BKCommand::UiaAuth(AuthData::Dummy)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::UiaError(_) => true, _ => false });
+
+    cmd.send(BKCommand::Register(strn!("bob"), strn!("secret"), SERVER.to_string())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => {
+            assert_eq!(state.next_stage(), Some(strn!(uia::RECAPTCHA)));
+            assert_eq!(state.recaptcha_public_key(), Some(strn!("mock-public-key")));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // a failed stage can be retried
+    cmd.send(BKCommand::UiaAuth(AuthData::Recaptcha(strn!("invalid")))).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => {
+            assert_eq!(state.errcode, Some(strn!("M_FORBIDDEN")));
+            assert_eq!(state.next_stage(), Some(strn!(uia::RECAPTCHA)));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::UiaAuth(AuthData::Recaptcha(strn!("valid")))).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => {
+            assert_eq!(state.next_stage(), Some(strn!(uia::TERMS)));
+            assert_eq!(state.terms_policies("en").len(), 1);
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::UiaAuth(AuthData::Terms)).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Token(..) | BKResponse::RegisterError(_) => true, _ => false }) {
+        BKResponse::Token(uid, _) => assert_eq!(uid, "@bob:localhost"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    // the password stage is completed with the current password
+    cmd.send(BKCommand::ChangePassword(strn!("secret"), strn!("newsecret"))).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::ChangePassword => true, _ => false });
+
+    let (other_cmd, other_rx) = backend(&hs);
+    login(&other_cmd, &other_rx, "bob", "newsecret");
+
+    cmd.send(BKCommand::AccountDestruction(strn!("secret"), false)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::AccountDestructionError(_) => true, _ => false });
+
+    cmd.send(BKCommand::AccountDestruction(strn!("newsecret"), false)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::AccountDestruction => true, _ => false });
+
+    other_cmd.send(BKCommand::Login(strn!("bob"), strn!("newsecret"), SERVER.to_string())).unwrap();
+    wait_for(&other_rx, |r| match *r { BKResponse::LoginError(_) => true, _ => false });
+}
+
+#[test]
+fn repeated_dummy_stages_are_returned() {
+    let hs = MockHomeserver::new();
+    hs.set_uia_flows(&[&["m.login.password"]], &[&["m.login.dummy", "m.login.dummy"]]);
+    let (cmd, rx) = backend(&hs);
+
+    // the dummy stage is completed once, the second one is left to the caller
+    cmd.send(BKCommand::Register(strn!("bob"), strn!("secret"), SERVER.to_string())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => {
+            assert_eq!(state.completed, vec![strn!(uia::DUMMY)]);
+            assert_eq!(state.next_stage(), Some(strn!(uia::DUMMY)));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+    assert_eq!(hs.count_requests("post", "/register"), 2);
+
+    cmd.send(BKCommand::UiaAuth(AuthData::Dummy)).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Token(..) | BKResponse::RegisterError(_) => true, _ => false }) {
+        BKResponse::Token(uid, _) => assert_eq!(uid, "@bob:localhost"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
+
+#[test]
+fn register() {
+    let hs = MockHomeserver::new();
+    hs.add_user("alice", "secret");
+    let (cmd, rx) = backend(&hs);
+
+    cmd.send(BKCommand::RegisterAvailable(strn!("alice"), SERVER.to_string())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RegisterAvailable(..) => true, _ => false }) {
+        BKResponse::RegisterAvailable(user, available) => assert!(user == "alice" && !available),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    cmd.send(BKCommand::RegisterAvailable(strn!("bob"), SERVER.to_string())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::RegisterAvailable(..) => true, _ => false }) {
+        BKResponse::RegisterAvailable(user, available) => assert!(user == "bob" && available),
+        r => panic!("Unexpected response {:?}", r),
+    }
+    cmd.send(BKCommand::RegisterAvailable(strn!("bob:localhost"), SERVER.to_string())).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::RegisterAvailableError(_) => true, _ => false });
+
+    // the dummy stage is completed without asking
+    cmd.send(BKCommand::Register(strn!("carol"), strn!("secret"), SERVER.to_string())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::Token(..) | BKResponse::RegisterError(_) => true, _ => false }) {
+        BKResponse::Token(uid, _) => assert_eq!(uid, "@carol:localhost"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    hs.set_uia_flows(&[&["m.login.password"]], &[&["m.login.email.identity", "m.login.terms"]]);
+    let (cmd, rx) = backend(&hs);
+    cmd.send(BKCommand::Register(strn!("bob"), strn!("secret"), SERVER.to_string())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => assert_eq!(state.next_stage(), Some(strn!(uia::EMAIL))),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::RegisterEmailToken(strn!("https://vector.im"), strn!("bob@example.org"), strn!("clientsecret"))).unwrap();
+    let sid = match wait_for(&rx, |r| match *r { BKResponse::RegisterEmailToken(..) => true, _ => false }) {
+        BKResponse::RegisterEmailToken(sid, _) => sid,
+        r => panic!("Unexpected response {:?}", r),
+    };
+    let email = AuthData::Email(sid.clone(), strn!("clientsecret"), strn!("https://vector.im"));
+
+    // the link in the email wasn't followed yet
+    cmd.send(BKCommand::UiaAuth(email.clone())).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => {
+            assert_eq!(state.errcode, Some(strn!("M_UNAUTHORIZED")));
+            assert_eq!(state.next_stage(), Some(strn!(uia::EMAIL)));
+        }
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    hs.validate_email(&sid);
+    cmd.send(BKCommand::UiaAuth(email)).unwrap();
+    match wait_for(&rx, |r| match *r { BKResponse::UiaStage(_) => true, _ => false }) {
+        BKResponse::UiaStage(state) => assert_eq!(state.next_stage(), Some(strn!(uia::TERMS))),
+        r => panic!("Unexpected response {:?}", r),
+    }
+
+    cmd.send(BKCommand::UiaAuth(AuthData::Terms)).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::Token(..) => true, _ => false });
+
+    let (cmd, rx) = backend(&hs);
+    login(&cmd, &rx, "bob", "secret");
+}
+
+#[test]
+fn discover_server() {
+    let hs = MockHomeserver::new();
+    hs.set_well_known("example.org", json!({
+        "m.homeserver": { "base_url": format!("{}/", SERVER) },
+        "m.identity_server": { "base_url": "https://vector.im" },
+    }));
+    let (cmd, rx) = backend(&hs);
+
+    let discover = |server: &str| -> ServerInfo {
+        cmd.send(BKCommand::DiscoverServer(server.to_string())).unwrap();
+        match wait_for(&rx, |r| match *r { BKResponse::ServerDiscovered(..) | BKResponse::DiscoverServerError(_) => true, _ => false }) {
+            BKResponse::ServerDiscovered(s, info) => {
+                assert_eq!(s, server);
+                info
+            }
+            r => panic!("Unexpected response {:?}", r),
+        }
+    };
+
+    let info = discover("example.org");
+    assert_eq!(info.homeserver, SERVER);
+    assert_eq!(info.identity_server, Some(strn!("https://vector.im")));
+    assert!(info.versions.contains(&strn!("r0.4.0")));
+    assert!(info.supports_password() && !info.sso_only());
+
+    // without the .well-known file the server name is the homeserver
+    let info = discover("localhost:8008");
+    assert_eq!(info.homeserver, "https://localhost:8008");
+    assert_eq!(info.identity_server, None);
+
+    hs.set_login_types(&["m.login.sso", "m.login.token"]);
+    let info = discover(SERVER);
+    assert_eq!(info.homeserver, SERVER);
+    assert!(info.sso_only());
+
+    // not a matrix server
+    cmd.send(BKCommand::DiscoverServer(strn!("example.com"))).unwrap();
+    wait_for(&rx, |r| match *r { BKResponse::DiscoverServerError(_) => true, _ => false });
+}
+
+#[test]
+fn sso_login() {
+    let hs = MockHomeserver::new();
+    hs.add_user("alice", "secret");
+    let token = hs.add_login_token("alice");
+    let (cmd, rx) = backend(&hs);
+
+    cmd.send(BKCommand::SsoLogin(SERVER.to_string(), false)).unwrap();
+    let url = match wait_for(&rx, |r| match *r { BKResponse::SsoUrl(_) => true, _ => false }) {
+        BKResponse::SsoUrl(url) => Url::parse(&url).unwrap(),
+        r => panic!("Unexpected response {:?}", r),
+    };
+    assert_eq!(url.path(), "/_matrix/client/r0/login/sso/redirect");
+    let redirect = url.query_pairs().find(|&(ref k, _)| k == "redirectUrl").unwrap().1.into_owned();
+
+    // the browser is redirected with the login token after the sign-on
+    let redirect = Url::parse(&redirect).unwrap();
+    let addr = format!("{}:{}", redirect.host_str().unwrap(), redirect.port().unwrap());
+    let mut stream = TcpStream::connect(&addr).unwrap();
+    write!(stream, "GET /?loginToken={} HTTP/1.1\r\nHost: {}\r\n\r\n", token, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    match wait_for(&rx, |r| match *r { BKResponse::Token(..) | BKResponse::LoginError(_) => true, _ => false }) {
+        BKResponse::Token(uid, _) => assert_eq!(uid, "@alice:localhost"),
+        r => panic!("Unexpected response {:?}", r),
+    }
+}
@@ -0,0 +1,1097 @@
+//! In-process stand-in for a matrix homeserver.
+//!
+//! `MockHomeserver` implements the `Transport` trait, so a `Backend` built with
+//! `Backend::with_transport` talks to it instead of the network. It keeps users, rooms and a
+//! single event stream in memory and answers the client-server endpoints used by the backend.
+
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use serde_json;
+use serde_json::Value as JsonValue;
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use fractal_matrix_api::backend::BKResponse;
+use fractal_matrix_api::error::Error;
+use fractal_matrix_api::transport::Transport;
+
+pub const SERVER: &'static str = "http://localhost:8008";
+pub const DOMAIN: &'static str = "localhost";
+
+struct MockRoom {
+    members: HashSet<String>,
+    // the heroes of the room summary when they are set by the test, their member events aren't
+    // sent with lazy loading
+    heroes: Option<Vec<String>>,
+}
+
+struct State {
+    // user id -> password
+    users: HashMap<String, String>,
+    // access token -> user id
+    tokens: HashMap<String, String>,
+    // access token -> device id
+    devices: HashMap<String, String>,
+    // (user id, device id) -> device display name
+    device_names: HashMap<(String, String), String>,
+    // user-interactive auth session id -> completed stages
+    uia_sessions: HashMap<String, Vec<String>>,
+    // the auth flows for the account requests and for the registration
+    uia_flows: Vec<Vec<String>>,
+    register_flows: Vec<Vec<String>>,
+    // email validation sid -> if the link was followed
+    email_sids: HashMap<String, bool>,
+    // server name -> .well-known/matrix/client file
+    well_known: HashMap<String, JsonValue>,
+    login_types: Vec<String>,
+    // single sign-on login token -> user id
+    login_tokens: HashMap<String, String>,
+    displaynames: HashMap<String, String>,
+    rooms: HashMap<String, MockRoom>,
+    // every room event in the order the server received them
+    stream: Vec<JsonValue>,
+    // (room id, txn id) -> event id
+    txns: HashMap<(String, String), String>,
+    media: HashMap<String, Vec<u8>>,
+    counter: u64,
+    // max number of timeline events per room in a sync, older events make the timeline limited
+    timeline_limit: usize,
+    // uploaded filters, the filter id is the position in this list
+    filters: Vec<JsonValue>,
+    // room id -> users typing in the room
+    typing: HashMap<String, HashSet<String>>,
+    // (room id, user id) -> (event id, stream position when it was sent)
+    receipts: HashMap<(String, String), (String, usize)>,
+    // (room id, user id) -> (m.fully_read event id, stream position when it was set)
+    fully_read: HashMap<(String, String), (String, usize)>,
+    // user id -> (m.presence content, stream position when it was set)
+    presence: HashMap<String, (JsonValue, usize)>,
+    // (user id, device id) -> (uploaded device keys, stream position when they were uploaded)
+    device_keys: HashMap<(String, String), (JsonValue, usize)>,
+    // (user id, device id) -> uploaded one time keys that weren't claimed, as (key id, key)
+    one_time_keys: HashMap<(String, String), Vec<(String, JsonValue)>>,
+    // (user id, device id) -> to-device events waiting for the next sync of the device
+    to_device: HashMap<(String, String), Vec<JsonValue>>,
+    // every request received, as (method, path)
+    requests: Vec<(String, String)>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.counter += 1;
+        self.counter
+    }
+
+    fn user_id(&self, localpart: &str) -> String {
+        match localpart.starts_with('@') {
+            true => localpart.to_string(),
+            false => format!("@{}:{}", localpart, DOMAIN),
+        }
+    }
+
+    fn push_event(&mut self, roomid: &str, sender: &str, evtype: &str, state_key: Option<&str>, content: JsonValue) -> String {
+        let id = format!("${}:{}", self.next_id(), DOMAIN);
+        let mut ev = json!({
+            "type": evtype,
+            "room_id": roomid,
+            "sender": sender,
+            "event_id": id,
+            "origin_server_ts": 1_500_000_000_000i64 + 1000 * self.stream.len() as i64,
+            "unsigned": { "age": 0 },
+            "content": content,
+        });
+
+        if let Some(sk) = state_key {
+            ev["state_key"] = json!(sk);
+        }
+
+        self.stream.push(ev);
+        id
+    }
+
+    /// Adds a redaction event and removes the content of the redacted event
+    fn redact(&mut self, roomid: &str, sender: &str, evid: &str, reason: &JsonValue) -> String {
+        let mut content = json!({});
+        if !reason.is_null() {
+            content["reason"] = reason.clone();
+        }
+        let id = self.push_event(roomid, sender, "m.room.redaction", None, content);
+
+        let redaction = {
+            let ev = self.stream.last_mut().unwrap();
+            ev["redacts"] = json!(evid);
+            ev.clone()
+        };
+        if let Some(ev) = self.stream.iter_mut().find(|ev| ev["event_id"] == evid) {
+            ev["content"] = json!({});
+            ev["unsigned"]["redacted_because"] = redaction;
+        }
+
+        id
+    }
+
+    fn room_events(&self, roomid: &str) -> Vec<JsonValue> {
+        self.stream.iter().filter(|ev| ev["room_id"] == roomid).cloned().collect()
+    }
+
+    /// The filter sent in the `filter` query param, uploaded before or inline
+    fn filter(&self, query: &HashMap<String, String>) -> JsonValue {
+        match query.get("filter") {
+            Some(f) => match f.parse::<usize>() {
+                Ok(i) => self.filters.get(i).cloned().unwrap_or(json!({})),
+                Err(_) => serde_json::from_str(f).unwrap_or(json!({})),
+            },
+            None => json!({}),
+        }
+    }
+
+    /// The last member event of each user in the room before the stream position `at`
+    fn member_events(&self, roomid: &str, at: usize) -> Vec<JsonValue> {
+        let mut members: HashMap<String, JsonValue> = HashMap::new();
+        for ev in self.stream.iter().take(at) {
+            if ev["room_id"] == roomid && ev["type"] == "m.room.member" {
+                let key = ev["state_key"].as_str().unwrap_or_default().to_string();
+                members.insert(key, ev.clone());
+            }
+        }
+
+        let mut events: Vec<JsonValue> = members.into_iter().map(|(_, ev)| ev).collect();
+        events.sort_by_key(|ev| ev["state_key"].as_str().unwrap_or_default().to_string());
+        events
+    }
+
+    fn is_member(&self, roomid: &str, uid: &str) -> bool {
+        match self.rooms.get(roomid) {
+            Some(r) => r.members.contains(uid),
+            None => false,
+        }
+    }
+
+    fn create_room(&mut self, creator: &str, name: Option<&str>) -> String {
+        let roomid = format!("!{}:{}", self.next_id(), DOMAIN);
+        let mut members = HashSet::new();
+        members.insert(creator.to_string());
+        self.rooms.insert(roomid.clone(), MockRoom { members: members, heroes: None });
+
+        self.push_event(&roomid, creator, "m.room.create", Some(""), json!({ "creator": creator }));
+        self.push_event(&roomid, creator, "m.room.member", Some(creator), json!({ "membership": "join" }));
+        self.push_event(&roomid, creator, "m.room.power_levels", Some(""), json!({ "users": { creator: 100 } }));
+        if let Some(n) = name {
+            self.push_event(&roomid, creator, "m.room.name", Some(""), json!({ "name": n }));
+        }
+
+        roomid
+    }
+}
+
+pub struct MockHomeserver {
+    state: Mutex<State>,
+}
+
+fn merror(errcode: &str, error: &str) -> Error {
+    Error::MatrixError(json!({
+        "errcode": errcode,
+        "error": error,
+    }))
+}
+
+fn token(t: &str) -> usize {
+    t.trim_left_matches(|c: char| c.is_alphabetic()).parse().unwrap_or(0)
+}
+
+impl MockHomeserver {
+    pub fn new() -> Arc<MockHomeserver> {
+        Arc::new(MockHomeserver {
+            state: Mutex::new(State {
+                users: HashMap::new(),
+                tokens: HashMap::new(),
+                devices: HashMap::new(),
+                device_names: HashMap::new(),
+                uia_sessions: HashMap::new(),
+                uia_flows: vec![vec!["m.login.password".to_string()]],
+                register_flows: vec![vec!["m.login.dummy".to_string()]],
+                email_sids: HashMap::new(),
+                well_known: HashMap::new(),
+                login_types: vec!["m.login.password".to_string()],
+                login_tokens: HashMap::new(),
+                displaynames: HashMap::new(),
+                rooms: HashMap::new(),
+                stream: vec![],
+                txns: HashMap::new(),
+                media: HashMap::new(),
+                counter: 0,
+                timeline_limit: 100,
+                filters: vec![],
+                typing: HashMap::new(),
+                receipts: HashMap::new(),
+                fully_read: HashMap::new(),
+                presence: HashMap::new(),
+                device_keys: HashMap::new(),
+                one_time_keys: HashMap::new(),
+                to_device: HashMap::new(),
+                requests: vec![],
+            }),
+        })
+    }
+
+    /// Registers a new user and returns the full user id
+    pub fn add_user(&self, localpart: &str, password: &str) -> String {
+        let mut st = self.state.lock().unwrap();
+        let uid = st.user_id(localpart);
+        st.users.insert(uid.clone(), password.to_string());
+        uid
+    }
+
+    /// Creates a room with `creator` as the only member and returns the room id
+    pub fn create_room(&self, creator: &str, name: &str) -> String {
+        self.state.lock().unwrap().create_room(creator, Some(name))
+    }
+
+    /// Joins `uid` to `roomid` without going through the API
+    pub fn join(&self, roomid: &str, uid: &str) {
+        let mut st = self.state.lock().unwrap();
+        if let Some(r) = st.rooms.get_mut(roomid) {
+            r.members.insert(uid.to_string());
+        }
+        st.push_event(roomid, uid, "m.room.member", Some(uid), json!({ "membership": "join" }));
+    }
+
+    /// Sets the heroes of the room summary, instead of the first members of the room
+    pub fn set_heroes(&self, roomid: &str, heroes: &[&str]) {
+        let mut st = self.state.lock().unwrap();
+        if let Some(r) = st.rooms.get_mut(roomid) {
+            r.heroes = Some(heroes.iter().map(|h| h.to_string()).collect());
+        }
+    }
+
+    /// Sets the auth flows of the account requests and of the registration
+    pub fn set_uia_flows(&self, flows: &[&[&str]], register_flows: &[&[&str]]) {
+        let to_vec = |fs: &[&[&str]]| -> Vec<Vec<String>> {
+            fs.iter().map(|f| f.iter().map(|s| s.to_string()).collect()).collect()
+        };
+        let mut st = self.state.lock().unwrap();
+        st.uia_flows = to_vec(flows);
+        st.register_flows = to_vec(register_flows);
+    }
+
+    /// Serves the `.well-known/matrix/client` file of the server `name`
+    pub fn set_well_known(&self, name: &str, js: JsonValue) {
+        self.state.lock().unwrap().well_known.insert(name.to_string(), js);
+    }
+
+    /// Creates a login token for `user`, as the identity provider would do after a single
+    /// sign-on
+    pub fn add_login_token(&self, user: &str) -> String {
+        let mut st = self.state.lock().unwrap();
+        let token = format!("logintoken{}", st.next_id());
+        let uid = st.user_id(user);
+        st.login_tokens.insert(token.clone(), uid);
+        token
+    }
+
+    /// Sets the login types returned by `GET /login`
+    pub fn set_login_types(&self, types: &[&str]) {
+        self.state.lock().unwrap().login_types = types.iter().map(|t| t.to_string()).collect();
+    }
+
+    /// Follows the validation link sent to an email
+    pub fn validate_email(&self, sid: &str) {
+        self.state.lock().unwrap().email_sids.insert(sid.to_string(), true);
+    }
+
+    /// Sets the max number of timeline events per room returned by /sync
+    pub fn set_timeline_limit(&self, limit: usize) {
+        self.state.lock().unwrap().timeline_limit = limit;
+    }
+
+    /// The number of requests received with `method` and a path that contains `part`
+    pub fn count_requests(&self, method: &str, part: &str) -> usize {
+        self.state.lock().unwrap().requests.iter()
+            .filter(|&&(ref m, ref p)| m == method && p.contains(part))
+            .count()
+    }
+
+    /// The filters uploaded by the clients
+    pub fn filters(&self) -> Vec<JsonValue> {
+        self.state.lock().unwrap().filters.clone()
+    }
+
+    /// The users typing in a room
+    pub fn typing(&self, roomid: &str) -> Vec<String> {
+        let st = self.state.lock().unwrap();
+        st.typing.get(roomid).map(|t| t.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// The presence state set by a user
+    pub fn presence(&self, uid: &str) -> Option<String> {
+        let st = self.state.lock().unwrap();
+        st.presence.get(uid).and_then(|&(ref p, _)| p["presence"].as_str().map(|s| s.to_string()))
+    }
+
+    /// Sends a text message as `sender` and returns the event id
+    pub fn send_text(&self, roomid: &str, sender: &str, body: &str) -> String {
+        let content = json!({ "msgtype": "m.text", "body": body });
+        self.state.lock().unwrap().push_event(roomid, sender, "m.room.message", None, content)
+    }
+
+    /// Sends any event as `sender` and returns the event id
+    pub fn send_event(&self, roomid: &str, sender: &str, evtype: &str, state_key: Option<&str>, content: JsonValue) -> String {
+        self.state.lock().unwrap().push_event(roomid, sender, evtype, state_key, content)
+    }
+
+    /// All the events of a room, oldest first
+    pub fn events(&self, roomid: &str) -> Vec<JsonValue> {
+        self.state.lock().unwrap().room_events(roomid)
+    }
+
+    /// The devices of a user that uploaded their keys
+    pub fn device_ids(&self, uid: &str) -> Vec<String> {
+        let st = self.state.lock().unwrap();
+        st.device_keys.keys().filter(|&&(ref u, _)| u == uid).map(|&(_, ref d)| d.clone()).collect()
+    }
+
+    pub fn room_ids(&self) -> Vec<String> {
+        self.state.lock().unwrap().rooms.keys().cloned().collect()
+    }
+
+    /// Checks the user-interactive auth of a request, one stage at a time.
+    ///
+    /// Without auth a new session is started. While the stages of a flow aren't completed
+    /// the flows and the completed stages are returned as a 401 error, with the error of the
+    /// last stage if it failed
+    fn uia(&self, st: &mut State, uid: &str, flows: &[Vec<String>], body: &JsonValue) -> Result<(), Error> {
+        let auth = &body["auth"];
+        let session = match auth["session"].as_str() {
+            Some(s) => s.to_string(),
+            None if auth.is_null() => {
+                let s = format!("uia{}", st.next_id());
+                st.uia_sessions.insert(s.clone(), vec![]);
+                s
+            }
+            None => return Err(merror("M_MISSING_PARAM", "Missing session")),
+        };
+        let mut completed = match st.uia_sessions.get(&session) {
+            Some(c) => c.clone(),
+            None => return Err(merror("M_UNKNOWN", "Unknown session")),
+        };
+
+        let mut failure = None;
+        if !auth.is_null() {
+            let stage = auth["type"].as_str().unwrap_or_default().to_string();
+            let expected = flows.iter().any(|f| f.len() > completed.len() && f.starts_with(&completed) && f[completed.len()] == stage);
+            let valid = match stage.as_str() {
+                "m.login.password" => {
+                    let password = auth["password"].as_str().unwrap_or_default();
+                    !uid.is_empty() && st.users.get(uid).map(|p| p == password).unwrap_or(false)
+                }
+                "m.login.email.identity" => {
+                    let sid = auth["threepid_creds"]["sid"].as_str().unwrap_or_default();
+                    st.email_sids.get(sid).cloned().unwrap_or(false)
+                }
+                "m.login.recaptcha" => auth["response"] == "valid",
+                "m.login.terms" | "m.login.dummy" => true,
+                _ => false,
+            };
+
+            match (expected && valid, stage.as_str()) {
+                (true, _) => completed.push(stage),
+                (false, "m.login.email.identity") => failure = Some(("M_UNAUTHORIZED", "Email not validated")),
+                (false, _) => failure = Some(("M_FORBIDDEN", "Invalid authentication")),
+            };
+            st.uia_sessions.insert(session.clone(), completed.clone());
+        }
+
+        if flows.iter().any(|f| *f == completed) {
+            st.uia_sessions.remove(&session);
+            return Ok(());
+        }
+
+        let mut r = json!({
+            "flows": flows.iter().map(|f| json!({ "stages": f })).collect::<Vec<JsonValue>>(),
+            "params": {
+                "m.login.recaptcha": { "public_key": "mock-public-key" },
+                "m.login.terms": { "policies": { "privacy_policy": {
+                    "version": "1.0",
+                    "en": { "name": "Privacy Policy", "url": "http://localhost/privacy" },
+                } } },
+            },
+            "session": session,
+            "completed": completed,
+        });
+        if let Some((errcode, error)) = failure {
+            r["errcode"] = json!(errcode);
+            r["error"] = json!(error);
+        }
+        Err(Error::MatrixError(r))
+    }
+
+    fn auth(&self, st: &State, query: &HashMap<String, String>) -> Result<String, Error> {
+        let tk = query.get("access_token").cloned().unwrap_or_default();
+        match st.tokens.get(&tk) {
+            Some(uid) => Ok(uid.clone()),
+            None => Err(merror("M_UNKNOWN_TOKEN", "Unrecognised access token")),
+        }
+    }
+
+    fn client(&self, method: &str, path: &[&str], query: &HashMap<String, String>, body: &JsonValue) -> Result<JsonValue, Error> {
+        let mut st = self.state.lock().unwrap();
+
+        if let ("get", &["login"]) = (method, path) {
+            let flows: Vec<JsonValue> = st.login_types.iter().map(|t| json!({ "type": t })).collect();
+            return Ok(json!({ "flows": flows }));
+        }
+
+        if let ("post", &["login"]) = (method, path) {
+            let uid = match body["type"].as_str() {
+                // the tokens can be used only once
+                Some("m.login.token") => {
+                    let token = body["token"].as_str().unwrap_or_default();
+                    match st.login_tokens.remove(token) {
+                        Some(uid) => uid,
+                        None => return Err(merror("M_FORBIDDEN", "Invalid login token")),
+                    }
+                }
+                _ => {
+                    let user = body["user"].as_str().unwrap_or_default();
+                    let uid = st.user_id(user);
+                    let password = body["password"].as_str().unwrap_or_default();
+
+                    if !st.users.get(&uid).map(|p| p == password).unwrap_or(false) {
+                        return Err(merror("M_FORBIDDEN", "Invalid password"));
+                    }
+                    uid
+                }
+            };
+
+            // each login is a new device, unless the client sends the id of an existing one
+            let device = match body["device_id"].as_str() {
+                Some(d) => d.to_string(),
+                None => format!("MOCKDEVICE{}", st.next_id()),
+            };
+            let tk = format!("token{}", st.next_id());
+            st.tokens.insert(tk.clone(), uid.clone());
+            st.devices.insert(tk.clone(), device.clone());
+            if let Some(name) = body["initial_device_display_name"].as_str() {
+                st.device_names.insert((uid.clone(), device.clone()), name.to_string());
+            }
+            return Ok(json!({
+                "user_id": uid,
+                "access_token": tk,
+                "device_id": device,
+            }));
+        }
+
+        if let ("get", &["register", "available"]) = (method, path) {
+            let username = query.get("username").cloned().unwrap_or_default();
+            if username.is_empty() || username.contains(':') {
+                return Err(merror("M_INVALID_USERNAME", "Invalid username"));
+            }
+            if st.users.contains_key(&st.user_id(&username)) {
+                return Err(merror("M_USER_IN_USE", "User ID already taken"));
+            }
+            return Ok(json!({ "available": true }));
+        }
+
+        if let ("post", &["register", "email", "requestToken"]) = (method, path) {
+            let sid = format!("sid{}", st.next_id());
+            st.email_sids.insert(sid.clone(), false);
+            return Ok(json!({ "sid": sid }));
+        }
+
+        if let ("post", &["register"]) = (method, path) {
+            let flows = st.register_flows.clone();
+            self.uia(&mut st, "", &flows, body)?;
+
+            let uid = st.user_id(body["username"].as_str().unwrap_or_default());
+            if st.users.contains_key(&uid) {
+                return Err(merror("M_USER_IN_USE", "User ID already taken"));
+            }
+            let password = body["password"].as_str().unwrap_or_default().to_string();
+            st.users.insert(uid.clone(), password);
+
+            let device = format!("MOCKDEVICE{}", st.next_id());
+            let tk = format!("token{}", st.next_id());
+            st.tokens.insert(tk.clone(), uid.clone());
+            st.devices.insert(tk.clone(), device.clone());
+            if let Some(name) = body["initial_device_display_name"].as_str() {
+                st.device_names.insert((uid.clone(), device.clone()), name.to_string());
+            }
+            return Ok(json!({
+                "user_id": uid,
+                "access_token": tk,
+                "device_id": device,
+            }));
+        }
+
+        let uid = self.auth(&st, query)?;
+        let tk = query.get("access_token").cloned().unwrap_or_default();
+        let device = st.devices.get(&tk).cloned().unwrap_or_default();
+
+        if path.len() > 2 && path[0] == "rooms" && !st.is_member(path[1], &uid) {
+            return Err(merror("M_FORBIDDEN", "You are not in this room"));
+        }
+
+        match (method, path) {
+            ("post", &["logout"]) => {
+                st.tokens.remove(&tk);
+                Ok(json!({}))
+            }
+            ("post", &["account", "password"]) => {
+                let flows = st.uia_flows.clone();
+                self.uia(&mut st, &uid, &flows, body)?;
+                let password = body["new_password"].as_str().unwrap_or_default().to_string();
+                st.users.insert(uid.clone(), password);
+                Ok(json!({}))
+            }
+            ("post", &["account", "deactivate"]) => {
+                let flows = st.uia_flows.clone();
+                self.uia(&mut st, &uid, &flows, body)?;
+                st.users.remove(&uid);
+                st.tokens.retain(|_, u| *u != uid);
+                Ok(json!({}))
+            }
+            ("get", &["account", "whoami"]) => {
+                Ok(json!({ "user_id": uid, "device_id": device }))
+            }
+            ("get", &["devices"]) => {
+                let mut ids: Vec<String> = st.tokens.iter()
+                    .filter(|&(_, u)| *u == uid)
+                    .filter_map(|(t, _)| st.devices.get(t).cloned())
+                    .collect();
+                ids.sort();
+                ids.dedup();
+                let devices: Vec<JsonValue> = ids.iter().map(|d| json!({
+                    "device_id": d,
+                    "display_name": st.device_names.get(&(uid.clone(), d.clone())),
+                    "last_seen_ip": "127.0.0.1",
+                    "last_seen_ts": 1_500_000_000_000i64,
+                })).collect();
+                Ok(json!({ "devices": devices }))
+            }
+            ("put", &["devices", d]) => {
+                let name = body["display_name"].as_str().unwrap_or_default().to_string();
+                st.device_names.insert((uid.clone(), d.to_string()), name);
+                Ok(json!({}))
+            }
+            ("delete", &["devices", d]) => {
+                let flows = st.uia_flows.clone();
+                self.uia(&mut st, &uid, &flows, body)?;
+
+                // deleting a device logs it out
+                let tokens: Vec<String> = st.devices.iter()
+                    .filter(|&(t, dev)| dev == d && st.tokens.get(t) == Some(&uid))
+                    .map(|(t, _)| t.clone())
+                    .collect();
+                for t in tokens {
+                    st.tokens.remove(&t);
+                    st.devices.remove(&t);
+                }
+                let key = (uid.clone(), d.to_string());
+                st.device_names.remove(&key);
+                st.device_keys.remove(&key);
+                st.one_time_keys.remove(&key);
+                Ok(json!({}))
+            }
+            ("post", &["user", user, "filter"]) if user == uid => {
+                st.filters.push(body.clone());
+                Ok(json!({ "filter_id": format!("{}", st.filters.len() - 1) }))
+            }
+            ("get", &["sync"]) => {
+                if let Some(fid) = query.get("filter") {
+                    let known = fid.parse::<usize>().map(|i| i < st.filters.len()).unwrap_or(false);
+                    if !known && !fid.starts_with('{') {
+                        return Err(merror("M_NOT_FOUND", "Unknown filter"));
+                    }
+                }
+                let mut r = self.sync(&st, &uid, query);
+
+                // the to-device events are only delivered once
+                let key = (uid.clone(), device.clone());
+                let to_device = st.to_device.remove(&key).unwrap_or_default();
+                let otks = st.one_time_keys.get(&key).map(|k| k.len()).unwrap_or(0);
+                r["to_device"] = json!({ "events": to_device });
+                r["device_one_time_keys_count"] = json!({ "signed_curve25519": otks });
+
+                Ok(r)
+            }
+            ("post", &["keys", "upload"]) => {
+                let key = (uid.clone(), device.clone());
+                if !body["device_keys"].is_null() {
+                    let pos = st.stream.len();
+                    st.device_keys.insert(key.clone(), (body["device_keys"].clone(), pos));
+                }
+
+                let otks = st.one_time_keys.entry(key).or_insert(vec![]);
+                for (id, k) in body["one_time_keys"].as_object().cloned().unwrap_or_default() {
+                    otks.push((id, k));
+                }
+                Ok(json!({ "one_time_key_counts": { "signed_curve25519": otks.len() } }))
+            }
+            ("post", &["keys", "query"]) => {
+                let mut device_keys = serde_json::Map::new();
+                for user in body["device_keys"].as_object().map(|u| u.keys().cloned().collect()).unwrap_or(vec![]) {
+                    let mut devices = serde_json::Map::new();
+                    for (&(ref u, ref d), &(ref keys, _)) in st.device_keys.iter() {
+                        if *u == user {
+                            devices.insert(d.clone(), keys.clone());
+                        }
+                    }
+                    device_keys.insert(user, json!(devices));
+                }
+                Ok(json!({ "device_keys": device_keys, "failures": {} }))
+            }
+            ("post", &["keys", "claim"]) => {
+                let mut claimed = json!({});
+                for (user, devices) in body["one_time_keys"].as_object().cloned().unwrap_or_default() {
+                    for (d, _) in devices.as_object().cloned().unwrap_or_default() {
+                        let otk = st.one_time_keys.get_mut(&(user.clone(), d.clone())).and_then(|k| k.pop());
+                        if let Some((id, k)) = otk {
+                            claimed[user.as_str()][d.as_str()][id.as_str()] = k;
+                        }
+                    }
+                }
+                Ok(json!({ "one_time_keys": claimed, "failures": {} }))
+            }
+            ("put", &["sendToDevice", evtype, _txn]) => {
+                for (user, devices) in body["messages"].as_object().cloned().unwrap_or_default() {
+                    for (d, content) in devices.as_object().cloned().unwrap_or_default() {
+                        let targets: Vec<String> = match d.as_str() {
+                            "*" => st.device_keys.keys().filter(|&&(ref u, _)| *u == user).map(|&(_, ref d)| d.clone()).collect(),
+                            _ => vec![d.clone()],
+                        };
+                        for target in targets {
+                            st.to_device.entry((user.clone(), target)).or_insert(vec![]).push(json!({
+                                "type": evtype,
+                                "sender": uid,
+                                "content": content,
+                            }));
+                        }
+                    }
+                }
+                Ok(json!({}))
+            }
+            ("get", &["profile", user, "displayname"]) => {
+                match st.displaynames.get(user) {
+                    Some(n) => Ok(json!({ "displayname": n })),
+                    None => Ok(json!({})),
+                }
+            }
+            ("put", &["profile", user, "displayname"]) if user == uid => {
+                let name = body["displayname"].as_str().unwrap_or_default().to_string();
+                st.displaynames.insert(uid.clone(), name);
+                Ok(json!({}))
+            }
+            ("post", &["createRoom"]) => {
+                let roomid = st.create_room(&uid, body["name"].as_str());
+                for invited in body["invite"].as_array().unwrap_or(&vec![]) {
+                    let inv = invited.as_str().unwrap_or_default();
+                    st.push_event(&roomid, &uid, "m.room.member", Some(inv), json!({ "membership": "invite" }));
+                }
+                Ok(json!({ "room_id": roomid }))
+            }
+            ("post", &["join", roomid]) => {
+                match st.rooms.get_mut(roomid) {
+                    Some(r) => { r.members.insert(uid.clone()); }
+                    None => { return Err(merror("M_NOT_FOUND", "No known servers")); }
+                };
+                st.push_event(roomid, &uid, "m.room.member", Some(&uid), json!({ "membership": "join" }));
+                Ok(json!({ "room_id": roomid }))
+            }
+            ("post", &["rooms", roomid, "leave"]) => {
+                if let Some(r) = st.rooms.get_mut(roomid) {
+                    r.members.remove(&uid);
+                }
+                st.push_event(roomid, &uid, "m.room.member", Some(&uid), json!({ "membership": "leave" }));
+                Ok(json!({}))
+            }
+            ("post", &["rooms", roomid, "invite"]) => {
+                let invited = body["user_id"].as_str().unwrap_or_default();
+                st.push_event(roomid, &uid, "m.room.member", Some(invited), json!({ "membership": "invite" }));
+                Ok(json!({}))
+            }
+            ("put", &["rooms", roomid, "send", evtype, txn]) => {
+                let key = (roomid.to_string(), txn.to_string());
+                if let Some(id) = st.txns.get(&key) {
+                    return Ok(json!({ "event_id": id }));
+                }
+                let id = st.push_event(roomid, &uid, evtype, None, body.clone());
+                st.txns.insert(key, id.clone());
+                Ok(json!({ "event_id": id }))
+            }
+            ("put", &["rooms", roomid, "redact", evid, txn]) => {
+                let key = (roomid.to_string(), txn.to_string());
+                if let Some(id) = st.txns.get(&key) {
+                    return Ok(json!({ "event_id": id }));
+                }
+                let id = st.redact(roomid, &uid, evid, &body["reason"]);
+                st.txns.insert(key, id.clone());
+                Ok(json!({ "event_id": id }))
+            }
+            ("put", &["rooms", roomid, "typing", user]) if user == uid => {
+                let typing = st.typing.entry(roomid.to_string()).or_insert(HashSet::new());
+                match body["typing"].as_bool().unwrap_or(false) {
+                    true => typing.insert(uid.clone()),
+                    false => typing.remove(&uid),
+                };
+                Ok(json!({}))
+            }
+            ("put", &["presence", user, "status"]) if user == uid => {
+                let pos = st.stream.len();
+                let mut content = json!({
+                    "presence": body["presence"].clone(),
+                    "last_active_ago": 0,
+                    "currently_active": body["presence"] == "online",
+                });
+                if !body["status_msg"].is_null() {
+                    content["status_msg"] = body["status_msg"].clone();
+                }
+                st.presence.insert(uid.clone(), (content, pos));
+                Ok(json!({}))
+            }
+            ("put", &["rooms", roomid, "state", evtype]) => {
+                let id = st.push_event(roomid, &uid, evtype, Some(""), body.clone());
+                Ok(json!({ "event_id": id }))
+            }
+            ("get", &["rooms", roomid, "state", evtype]) => {
+                let ev = st.room_events(roomid).into_iter()
+                    .filter(|ev| ev["type"] == evtype && ev["state_key"] == "")
+                    .last();
+                match ev {
+                    Some(ev) => Ok(ev["content"].clone()),
+                    None => Err(merror("M_NOT_FOUND", "Event not found")),
+                }
+            }
+            ("get", &["rooms", roomid, "event", evid]) => {
+                let ev = st.room_events(roomid).into_iter().find(|ev| ev["event_id"] == evid);
+                ev.ok_or(merror("M_NOT_FOUND", "Event not found"))
+            }
+            ("get", &["rooms", roomid, "joined_members"]) => {
+                let mut joined = serde_json::Map::new();
+                for m in st.rooms[roomid].members.iter() {
+                    joined.insert(m.clone(), json!({
+                        "display_name": st.displaynames.get(m),
+                        "avatar_url": null,
+                    }));
+                }
+                Ok(json!({ "joined": joined }))
+            }
+            ("get", &["rooms", roomid, "members"]) => {
+                let at = query.get("at").map(|t| token(t)).unwrap_or(st.stream.len());
+                let chunk: Vec<JsonValue> = st.member_events(roomid, at).into_iter()
+                    .filter(|ev| match query.get("membership") {
+                        Some(m) => ev["content"]["membership"] == m.as_str(),
+                        None => true,
+                    })
+                    .collect();
+                Ok(json!({ "chunk": chunk }))
+            }
+            ("get", &["rooms", roomid, "messages"]) => Ok(self.messages(&st, roomid, query)),
+            ("post", &["rooms", roomid, "read_markers"]) => {
+                let pos = st.stream.len();
+                let key = (roomid.to_string(), uid.clone());
+                if let Some(evid) = body["m.read"].as_str() {
+                    st.receipts.insert(key.clone(), (evid.to_string(), pos));
+                }
+                if let Some(evid) = body["m.fully_read"].as_str() {
+                    st.fully_read.insert(key, (evid.to_string(), pos));
+                }
+                Ok(json!({}))
+            }
+            ("post", &["rooms", roomid, "receipt", "m.read", evid]) => {
+                let pos = st.stream.len();
+                st.receipts.insert((roomid.to_string(), uid.clone()), (evid.to_string(), pos));
+                Ok(json!({}))
+            }
+            _ => Err(merror("M_UNRECOGNIZED", "Unrecognized request")),
+        }
+    }
+
+    fn sync(&self, st: &State, uid: &str, query: &HashMap<String, String>) -> JsonValue {
+        let since = query.get("since").map(|s| token(s));
+        let lazy = st.filter(query)["room"]["state"]["lazy_load_members"] == true;
+
+        let mut join = serde_json::Map::new();
+        for (roomid, room) in st.rooms.iter() {
+            if !room.members.contains(uid) {
+                continue;
+            }
+
+            let events: Vec<(usize, &JsonValue)> = st.stream.iter()
+                .enumerate()
+                .filter(|&(_, ev)| ev["room_id"] == *roomid)
+                .collect();
+
+            // tokens are positions in the event stream, for /sync and /messages
+            let (state, timeline): (Vec<JsonValue>, Vec<(usize, &JsonValue)>) = match since {
+                None => (
+                    events.iter().filter(|&&(_, ev)| !ev["state_key"].is_null()).map(|&(_, ev)| ev.clone()).collect(),
+                    events.iter().filter(|&&(_, ev)| ev["state_key"].is_null()).cloned().collect(),
+                ),
+                Some(s) => (
+                    vec![],
+                    events.iter().filter(|&&(i, _)| i >= s).cloned().collect(),
+                ),
+            };
+
+            let typing: Vec<String> = st.typing.get(roomid).map(|t| t.iter().cloned().collect()).unwrap_or_default();
+
+            // the receipts sent since the last sync, grouped by event
+            let mut receipts = serde_json::Map::new();
+            for (&(ref r, ref user), &(ref evid, pos)) in st.receipts.iter() {
+                if r == roomid && since.map(|s| pos >= s).unwrap_or(true) {
+                    let ts = 1_500_000_000_000i64 + 1000 * pos as i64;
+                    let ev = receipts.entry(evid.clone()).or_insert(json!({ "m.read": {} }));
+                    ev["m.read"][user.as_str()] = json!({ "ts": ts });
+                }
+            }
+
+            let mut account_data: Vec<JsonValue> = vec![];
+            if let Some(&(ref evid, pos)) = st.fully_read.get(&(roomid.clone(), uid.to_string())) {
+                if since.map(|s| pos >= s).unwrap_or(true) {
+                    account_data.push(json!({
+                        "type": "m.fully_read",
+                        "content": { "event_id": evid },
+                    }));
+                }
+            }
+
+            if since.is_some() && timeline.is_empty() && typing.is_empty() && receipts.is_empty() && account_data.is_empty() {
+                continue;
+            }
+
+            let limited = timeline.len() > st.timeline_limit;
+            let skip = if limited { timeline.len() - st.timeline_limit } else { 0 };
+            let timeline: Vec<(usize, &JsonValue)> = timeline.into_iter().skip(skip).collect();
+            let prev_batch = match timeline.first() {
+                Some(&(i, _)) => i,
+                None => st.stream.len(),
+            };
+
+            // with lazy loading only the members of the timeline senders and the heroes are sent
+            let mut summary = json!({});
+            let state: Vec<JsonValue> = match lazy {
+                false => state,
+                true => {
+                    let members = st.member_events(roomid, st.stream.len());
+                    let joined = members.iter().filter(|ev| ev["content"]["membership"] == "join").count();
+                    let invited = members.iter().filter(|ev| ev["content"]["membership"] == "invite").count();
+                    let heroes: Vec<String> = members.iter()
+                        .filter(|ev| ev["content"]["membership"] == "join" || ev["content"]["membership"] == "invite")
+                        .filter_map(|ev| ev["state_key"].as_str())
+                        .filter(|m| *m != uid)
+                        .take(5)
+                        .map(|m| m.to_string())
+                        .collect();
+                    let heroes = room.heroes.clone().unwrap_or(heroes);
+
+                    if since.is_none() {
+                        summary = json!({
+                            "m.heroes": heroes,
+                            "m.joined_member_count": joined,
+                            "m.invited_member_count": invited,
+                        });
+                    }
+
+                    let mut needed: HashSet<String> = timeline.iter()
+                        .filter_map(|&(_, ev)| ev["sender"].as_str())
+                        .map(|s| s.to_string())
+                        .collect();
+                    needed.insert(uid.to_string());
+                    if room.heroes.is_none() {
+                        needed.extend(heroes);
+                    }
+
+                    state.into_iter()
+                        .filter(|ev| ev["type"] != "m.room.member")
+                        .chain(members.into_iter().filter(|ev| {
+                            needed.contains(ev["state_key"].as_str().unwrap_or_default())
+                        }))
+                        .collect()
+                }
+            };
+
+            join.insert(roomid.clone(), json!({
+                "state": { "events": state },
+                "timeline": {
+                    "events": timeline.iter().map(|&(_, ev)| ev.clone()).collect::<Vec<JsonValue>>(),
+                    "limited": limited,
+                    "prev_batch": format!("s{}", prev_batch),
+                },
+                "ephemeral": { "events": [{
+                    "type": "m.typing",
+                    "content": { "user_ids": typing },
+                }, {
+                    "type": "m.receipt",
+                    "content": receipts,
+                }] },
+                "account_data": { "events": account_data },
+                "summary": summary,
+                "unread_notifications": {
+                    "notification_count": 0,
+                    "highlight_count": 0,
+                },
+            }));
+        }
+
+        // the presence changed since the last sync, unless the filter drops it
+        let mut presence: Vec<JsonValue> = vec![];
+        if st.filter(query)["presence"]["types"] != json!([]) {
+            for (user, &(ref content, pos)) in st.presence.iter() {
+                if since.map(|s| pos >= s).unwrap_or(true) {
+                    presence.push(json!({
+                        "type": "m.presence",
+                        "sender": user,
+                        "content": content,
+                    }));
+                }
+            }
+        }
+
+        // the users that uploaded new device keys since the last sync
+        let changed: Vec<String> = match since {
+            Some(s) => st.device_keys.iter()
+                .filter(|&(_, &(_, pos))| pos >= s)
+                .map(|(&(ref u, _), _)| u.clone())
+                .collect::<HashSet<String>>()
+                .into_iter()
+                .collect(),
+            None => vec![],
+        };
+
+        json!({
+            "next_batch": format!("s{}", st.stream.len()),
+            "rooms": {
+                "join": join,
+                "leave": {},
+                "invite": {},
+            },
+            "account_data": { "events": [] },
+            "presence": { "events": presence },
+            "device_lists": { "changed": changed, "left": [] },
+        })
+    }
+
+    fn messages(&self, st: &State, roomid: &str, query: &HashMap<String, String>) -> JsonValue {
+        let events: Vec<(usize, &JsonValue)> = st.stream.iter()
+            .enumerate()
+            .filter(|&(_, ev)| ev["room_id"] == roomid)
+            .collect();
+        let limit = query.get("limit").and_then(|l| l.parse().ok()).unwrap_or(10);
+        let from = query.get("from").map(|f| token(f)).unwrap_or(st.stream.len());
+        let to = query.get("to").map(|t| token(t));
+
+        let (chunk, end): (Vec<(usize, &JsonValue)>, usize) = match query.get("dir").map(|d| d.as_str()) {
+            Some("f") => {
+                let chunk: Vec<(usize, &JsonValue)> = events.iter()
+                    .filter(|&&(i, _)| i >= from && to.map(|t| i < t).unwrap_or(true))
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                let end = chunk.last().map(|&(i, _)| i + 1).unwrap_or(from);
+                (chunk, end)
+            }
+            _ => {
+                let chunk: Vec<(usize, &JsonValue)> = events.iter()
+                    .rev()
+                    .filter(|&&(i, _)| i < from && to.map(|t| i >= t).unwrap_or(true))
+                    .take(limit)
+                    .cloned()
+                    .collect();
+                let end = chunk.last().map(|&(i, _)| i).unwrap_or(from);
+                (chunk, end)
+            }
+        };
+
+        let mut state: Vec<JsonValue> = vec![];
+        if st.filter(query)["lazy_load_members"] == true {
+            let senders: HashSet<&str> = chunk.iter().filter_map(|&(_, ev)| ev["sender"].as_str()).collect();
+            state = st.member_events(roomid, st.stream.len()).into_iter()
+                .filter(|ev| senders.contains(ev["state_key"].as_str().unwrap_or_default()))
+                .collect();
+        }
+
+        json!({
+            "start": format!("s{}", from),
+            "end": format!("s{}", end),
+            "chunk": chunk.iter().map(|&(_, ev)| ev.clone()).collect::<Vec<JsonValue>>(),
+            "state": state,
+        })
+    }
+}
+
+impl Transport for MockHomeserver {
+    fn json_q(&self, method: &str, url: &Url, attrs: &JsonValue, _timeout: u64) -> Result<JsonValue, Error> {
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let segments: Vec<String> = url.path_segments()
+            .map(|segs| segs.map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy().to_string()).collect())
+            .unwrap_or(vec![]);
+        let path: Vec<&str> = segments.iter().map(|s| s.as_str()).collect();
+        self.state.lock().unwrap().requests.push((method.to_string(), segments.join("/")));
+
+        // the homeserver only answers at DOMAIN, other servers only have a .well-known file
+        let host = url.host_str().unwrap_or_default();
+        if path == [".well-known", "matrix", "client"] {
+            return match self.state.lock().unwrap().well_known.get(host) {
+                Some(js) => Ok(js.clone()),
+                None => Err(merror("M_NOT_FOUND", "Not found")),
+            };
+        }
+        if host != DOMAIN {
+            return Err(merror("M_NOT_FOUND", "Not found"));
+        }
+
+        if path == ["_matrix", "client", "versions"] {
+            return Ok(json!({ "versions": ["r0.3.0", "r0.4.0"] }));
+        }
+        if path.len() > 3 && path[..3] == ["_matrix", "client", "r0"] {
+            return self.client(method, &path[3..], &query, attrs);
+        }
+
+        Err(merror("M_UNRECOGNIZED", "Unrecognized request"))
+    }
+
+    fn get_media(&self, url: &str) -> Result<Vec<u8>, Error> {
+        let url = Url::parse(url)?;
+        let id = url.path_segments().and_then(|s| s.last()).unwrap_or_default().to_string();
+
+        match self.state.lock().unwrap().media.get(&id) {
+            Some(content) => Ok(content.clone()),
+            None => Err(merror("M_NOT_FOUND", "Not found")),
+        }
+    }
+
+    fn put_media(&self, url: &str, file: Vec<u8>) -> Result<JsonValue, Error> {
+        let url = Url::parse(url)?;
+        if url.path() != "/_matrix/media/r0/upload" {
+            return Err(merror("M_UNRECOGNIZED", "Unrecognized request"));
+        }
+
+        let mut st = self.state.lock().unwrap();
+        let id = format!("media{}", st.next_id());
+        st.media.insert(id.clone(), file);
+
+        Ok(json!({ "content_uri": format!("mxc://{}/{}", DOMAIN, id) }))
+    }
+}
+
+/// Waits until the backend sends a response that matches `f` and returns it, ignoring any
+/// other response received before it
+pub fn wait_for<F>(rx: &Receiver<BKResponse>, f: F) -> BKResponse
+    where F: Fn(&BKResponse) -> bool {
+    loop {
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(r) => {
+                if f(&r) {
+                    return r;
+                }
+            }
+            Err(_) => panic!("Timeout waiting for the backend response"),
+        }
+    }
+}